@@ -0,0 +1,643 @@
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+
+/// One VM instruction. Operands are indices: into the program's constants,
+/// the current frame's local slots, the function's code for jumps, or the
+/// program's functions for calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    /// Pushes a constant.
+    Const(u32),
+    /// Pushes the value of a function that returns nothing.
+    Unit,
+    Load(u16),
+    /// Pops a value into a local slot.
+    Store(u16),
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitAnd,
+    BitXor,
+    Shl,
+    Shr,
+    Neg,
+    Not,
+    Jump(u32),
+    /// Pops a `bool` and jumps if it is false.
+    JumpIfFalse(u32),
+    /// Calls a function with its arguments on top of the stack, first
+    /// argument deepest.
+    Call(u32),
+    /// Returns the value on top of the stack to the caller.
+    Ret,
+    /// Pops this many fields, first field deepest, into a struct.
+    Struct(u16),
+    /// Pops this many fields into an enum value with the given tag, the
+    /// variant's index in its enum.
+    Variant(u16, u16),
+    /// Replaces a struct or enum value with one of its fields.
+    Field(u16),
+    /// Replaces an enum value with its tag, as a `u32`.
+    Tag,
+    /// Fails because no arm of a match expression matched.
+    NoMatch,
+}
+
+/// Instructions without operands, in opcode order.
+const SIMPLE_OPS: [Op; 21] = [
+    Op::Unit,
+    Op::Pop,
+    Op::Add,
+    Op::Sub,
+    Op::Mul,
+    Op::Div,
+    Op::Mod,
+    Op::Eq,
+    Op::Ne,
+    Op::Lt,
+    Op::Le,
+    Op::Gt,
+    Op::Ge,
+    Op::BitAnd,
+    Op::BitXor,
+    Op::Shl,
+    Op::Shr,
+    Op::Neg,
+    Op::Not,
+    Op::Ret,
+    Op::Tag,
+];
+
+const OP_NO_MATCH: u8 = 0x3f;
+const OP_CONST: u8 = 0x40;
+const OP_LOAD: u8 = 0x41;
+const OP_STORE: u8 = 0x42;
+const OP_JUMP: u8 = 0x43;
+const OP_JUMP_IF_FALSE: u8 = 0x44;
+const OP_CALL: u8 = 0x45;
+const OP_STRUCT: u8 = 0x46;
+const OP_VARIANT: u8 = 0x47;
+const OP_FIELD: u8 = 0x48;
+
+impl Op {
+    /// The instruction's name in disassembly.
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Const(_) => "const",
+            Op::Unit => "unit",
+            Op::Load(_) => "load",
+            Op::Store(_) => "store",
+            Op::Pop => "pop",
+            Op::Add => "add",
+            Op::Sub => "sub",
+            Op::Mul => "mul",
+            Op::Div => "div",
+            Op::Mod => "mod",
+            Op::Eq => "eq",
+            Op::Ne => "ne",
+            Op::Lt => "lt",
+            Op::Le => "le",
+            Op::Gt => "gt",
+            Op::Ge => "ge",
+            Op::BitAnd => "bitand",
+            Op::BitXor => "bitxor",
+            Op::Shl => "shl",
+            Op::Shr => "shr",
+            Op::Neg => "neg",
+            Op::Not => "not",
+            Op::Jump(_) => "jump",
+            Op::JumpIfFalse(_) => "jump_if_false",
+            Op::Call(_) => "call",
+            Op::Ret => "ret",
+            Op::Struct(_) => "struct",
+            Op::Variant(..) => "variant",
+            Op::Field(_) => "field",
+            Op::Tag => "tag",
+            Op::NoMatch => "no_match",
+        }
+    }
+}
+
+/// A literal value in the constant pool.
+#[derive(Debug, Clone)]
+pub(crate) enum Constant {
+    I32(i32),
+    I64(i64),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Char(char),
+    String(String),
+}
+
+impl Constant {
+    fn tag(&self) -> u8 {
+        match self {
+            Constant::I32(_) => 0,
+            Constant::I64(_) => 1,
+            Constant::U32(_) => 2,
+            Constant::U64(_) => 3,
+            Constant::F32(_) => 4,
+            Constant::F64(_) => 5,
+            Constant::Bool(_) => 6,
+            Constant::Char(_) => 7,
+            Constant::String(_) => 8,
+        }
+    }
+
+    /// The constant's bits, for every kind but strings.
+    fn bits(&self) -> u64 {
+        match *self {
+            Constant::I32(v) => v as u32 as u64,
+            Constant::I64(v) => v as u64,
+            Constant::U32(v) => v as u64,
+            Constant::U64(v) => v,
+            Constant::F32(v) => v.to_bits() as u64,
+            Constant::F64(v) => v.to_bits(),
+            Constant::Bool(v) => v as u64,
+            Constant::Char(v) => v as u64,
+            Constant::String(_) => 0,
+        }
+    }
+}
+
+/// Constants are equal if they have the same type and bits, so the pool
+/// can share `0.0` without mixing it up with `-0.0`.
+impl PartialEq for Constant {
+    fn eq(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (Constant::String(a), Constant::String(b)) => a == b,
+            _ => self.tag() == other.tag() && self.bits() == other.bits(),
+        }
+    }
+}
+
+impl Eq for Constant {}
+
+impl Hash for Constant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.tag().hash(state);
+        match self {
+            Constant::String(s) => s.hash(state),
+            _ => self.bits().hash(state),
+        }
+    }
+}
+
+impl std::fmt::Display for Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Constant::I32(v) => write!(f, "i32 {}", v),
+            Constant::I64(v) => write!(f, "i64 {}", v),
+            Constant::U32(v) => write!(f, "u32 {}", v),
+            Constant::U64(v) => write!(f, "u64 {}", v),
+            Constant::F32(v) => write!(f, "f32 {:?}", v),
+            Constant::F64(v) => write!(f, "f64 {:?}", v),
+            Constant::Bool(v) => write!(f, "bool {}", v),
+            Constant::Char(v) => write!(f, "char {:?}", v),
+            Constant::String(v) => write!(f, "string {:?}", v),
+        }
+    }
+}
+
+/// One function, or one instance of a generic function, compiled to
+/// bytecode.
+#[derive(Debug)]
+pub(crate) struct Function {
+    pub name: String,
+    pub params: u16,
+    /// How many local slots a call needs, parameters first.
+    pub locals: u16,
+    pub code: Vec<Op>,
+    /// `file:line:col` of the instructions that can fail at run time, by
+    /// instruction index, for reporting where they failed.
+    pub locations: Vec<(u32, String)>,
+}
+
+impl Function {
+    /// Where the instruction at `pc` came from, if it can fail.
+    pub fn location(&self, pc: usize) -> Option<&str> {
+        self.locations.iter().find(|(at, _)| *at as usize == pc).map(|(_, l)| l.as_str())
+    }
+}
+
+/// A whole program compiled to bytecode.
+#[derive(Debug, Default)]
+pub(crate) struct Program {
+    pub constants: Vec<Constant>,
+    pub functions: Vec<Function>,
+    /// The function execution starts at.
+    pub main: u32,
+}
+
+/// The first bytes of every `.c4b` file.
+const MAGIC: &[u8; 4] = b"C4B\0";
+/// Bumped whenever the format changes, so old files are rejected instead
+/// of being misread.
+const VERSION: u16 = 2;
+
+impl Program {
+    /// A listing of the constants and every function's instructions.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        out.push_str("constants:\n");
+        for (i, constant) in self.constants.iter().enumerate() {
+            let _ = writeln!(out, "  #{:<4} {}", i, constant);
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            let main = if index as u32 == self.main { ", entry" } else { "" };
+            let _ = writeln!(
+                out,
+                "\nfn {} #{} (params {}, locals {}{}):",
+                function.name, index, function.params, function.locals, main
+            );
+            for (pc, op) in function.code.iter().enumerate() {
+                let operands = match *op {
+                    Op::Const(i) => format!("#{:<5} ; {}", i, self.constants[i as usize]),
+                    Op::Load(slot) | Op::Store(slot) => slot.to_string(),
+                    Op::Jump(target) | Op::JumpIfFalse(target) => format!("-> {:04}", target),
+                    Op::Call(f) => format!("#{:<5} ; {}", f, self.functions[f as usize].name),
+                    Op::Struct(n) | Op::Field(n) => n.to_string(),
+                    Op::Variant(tag, n) => format!("{} {}", tag, n),
+                    _ => String::new(),
+                };
+                let line = format!("  {:04}  {:<14}{}", pc, op.name(), operands);
+                let _ = writeln!(out, "{}", line.trim_end());
+            }
+        }
+        out
+    }
+
+    /// The program in the `.c4b` format: a magic number and version, then
+    /// the entry function, the constant pool and the functions. Numbers
+    /// are little-endian; strings and lists are prefixed with their length.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&self.main.to_le_bytes());
+
+        out.extend_from_slice(&(self.constants.len() as u32).to_le_bytes());
+        for constant in &self.constants {
+            out.push(constant.tag());
+            match constant {
+                Constant::String(s) => write_string(&mut out, s),
+                Constant::I32(_) | Constant::U32(_) | Constant::F32(_) | Constant::Char(_) => {
+                    out.extend_from_slice(&(constant.bits() as u32).to_le_bytes())
+                }
+                Constant::Bool(_) => out.push(constant.bits() as u8),
+                _ => out.extend_from_slice(&constant.bits().to_le_bytes()),
+            }
+        }
+
+        out.extend_from_slice(&(self.functions.len() as u32).to_le_bytes());
+        for function in &self.functions {
+            write_string(&mut out, &function.name);
+            out.extend_from_slice(&function.params.to_le_bytes());
+            out.extend_from_slice(&function.locals.to_le_bytes());
+            out.extend_from_slice(&(function.code.len() as u32).to_le_bytes());
+            for op in &function.code {
+                encode_op(*op, &mut out);
+            }
+            out.extend_from_slice(&(function.locations.len() as u32).to_le_bytes());
+            for (pc, location) in &function.locations {
+                out.extend_from_slice(&pc.to_le_bytes());
+                write_string(&mut out, location);
+            }
+        }
+        out
+    }
+
+    /// Reads a program written by `encode`, checking that every index in it
+    /// is in bounds so the VM can trust it.
+    pub fn decode(bytes: &[u8]) -> Result<Program, String> {
+        let mut reader = Reader { bytes, at: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err("it doesn't start with the bytecode magic number".to_string());
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(format!("it is format version {}, but this compiler reads version {}", version, VERSION));
+        }
+        let main = reader.u32()?;
+
+        let mut constants = Vec::new();
+        for _ in 0..reader.u32()? {
+            let constant = match reader.u8()? {
+                0 => Constant::I32(reader.u32()? as i32),
+                1 => Constant::I64(reader.u64()? as i64),
+                2 => Constant::U32(reader.u32()?),
+                3 => Constant::U64(reader.u64()?),
+                4 => Constant::F32(f32::from_bits(reader.u32()?)),
+                5 => Constant::F64(f64::from_bits(reader.u64()?)),
+                6 => Constant::Bool(reader.u8()? != 0),
+                7 => Constant::Char(char::from_u32(reader.u32()?).ok_or("a char constant isn't a valid character")?),
+                8 => Constant::String(reader.string()?),
+                tag => return Err(format!("unknown constant tag {}", tag)),
+            };
+            constants.push(constant);
+        }
+
+        let mut functions = Vec::new();
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let params = reader.u16()?;
+            let locals = reader.u16()?;
+            let mut code = Vec::new();
+            for _ in 0..reader.u32()? {
+                code.push(decode_op(&mut reader)?);
+            }
+            let mut locations = Vec::new();
+            for _ in 0..reader.u32()? {
+                locations.push((reader.u32()?, reader.string()?));
+            }
+            functions.push(Function { name, params, locals, code, locations });
+        }
+        if reader.at != bytes.len() {
+            return Err("there are unexpected bytes after the last function".to_string());
+        }
+
+        let program = Program { constants, functions, main };
+        program.validate()?;
+        Ok(program)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let entry = self.functions.get(self.main as usize).ok_or("the entry function doesn't exist")?;
+        if entry.params != 0 {
+            return Err("the entry function takes parameters".to_string());
+        }
+        for function in &self.functions {
+            let bad = |what: &str| Err(format!("`{}` {}", function.name, what));
+            if function.params > function.locals {
+                return bad("has more parameters than local slots");
+            }
+            if !matches!(function.code.last(), Some(Op::Ret | Op::NoMatch | Op::Jump(_))) {
+                return bad("can run past the end of its code");
+            }
+            for op in &function.code {
+                let in_bounds = match *op {
+                    Op::Const(i) => (i as usize) < self.constants.len(),
+                    Op::Load(slot) | Op::Store(slot) => slot < function.locals,
+                    Op::Jump(target) | Op::JumpIfFalse(target) => (target as usize) < function.code.len(),
+                    Op::Call(f) => (f as usize) < self.functions.len(),
+                    _ => true,
+                };
+                if !in_bounds {
+                    return bad(&format!("has an out-of-bounds `{}`", op.name()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn encode_op(op: Op, out: &mut Vec<u8>) {
+    if let Some(opcode) = SIMPLE_OPS.iter().position(|simple| *simple == op) {
+        out.push(opcode as u8);
+        return;
+    }
+    let (opcode, operands): (u8, &[u32]) = match op {
+        Op::Const(i) => (OP_CONST, &[i]),
+        Op::Load(slot) => (OP_LOAD, &[slot as u32]),
+        Op::Store(slot) => (OP_STORE, &[slot as u32]),
+        Op::Jump(target) => (OP_JUMP, &[target]),
+        Op::JumpIfFalse(target) => (OP_JUMP_IF_FALSE, &[target]),
+        Op::Call(f) => (OP_CALL, &[f]),
+        Op::Struct(n) => (OP_STRUCT, &[n as u32]),
+        Op::Variant(tag, n) => (OP_VARIANT, &[tag as u32, n as u32]),
+        Op::Field(i) => (OP_FIELD, &[i as u32]),
+        Op::NoMatch => (OP_NO_MATCH, &[]),
+        _ => unreachable!("`{}` has no operands", op.name()),
+    };
+    out.push(opcode);
+    for operand in operands {
+        out.extend_from_slice(&operand.to_le_bytes());
+    }
+}
+
+fn decode_op(reader: &mut Reader) -> Result<Op, String> {
+    let opcode = reader.u8()?;
+    if let Some(op) = SIMPLE_OPS.get(opcode as usize) {
+        return Ok(*op);
+    }
+    let mut small = || -> Result<u16, String> {
+        u16::try_from(reader.u32()?).map_err(|_| "an operand is out of range".to_string())
+    };
+    Ok(match opcode {
+        OP_NO_MATCH => Op::NoMatch,
+        OP_LOAD => Op::Load(small()?),
+        OP_STORE => Op::Store(small()?),
+        OP_STRUCT => Op::Struct(small()?),
+        OP_VARIANT => Op::Variant(small()?, small()?),
+        OP_FIELD => Op::Field(small()?),
+        OP_CONST => Op::Const(reader.u32()?),
+        OP_JUMP => Op::Jump(reader.u32()?),
+        OP_JUMP_IF_FALSE => Op::JumpIfFalse(reader.u32()?),
+        OP_CALL => Op::Call(reader.u32()?),
+        _ => return Err(format!("unknown opcode {:#04x}", opcode)),
+    })
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    at: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8], String> {
+        let bytes = self.bytes.get(self.at..self.at + n).ok_or("the file ends too soon")?;
+        self.at += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "a string isn't valid UTF-8".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen;
+    use crate::compiler::testing;
+    use crate::diagnostic::codes;
+    use crate::vm;
+
+    fn program(source: &str) -> Program {
+        codegen::compile(&testing::lower(source))
+    }
+
+    const ADD: &str = "fn add(a | i32, b | i32) -> i32 { ret a + b; }\nfn main() -> i32 { ret add(1, 2) * 3; }";
+
+    /// Uses every kind of constant and instruction but `no_match`.
+    const EVERYTHING: &str = "
+        struct pair { a | i64, b | u64 }
+        enum shape { dot, circle(f64), label { text | string } }
+        fn area(s | shape) -> f64 {
+            ret @ s { shape.circle(r) -> r * r * 3.0; _ -> 0.0; };
+        }
+        fn nothing() { }
+        fn main() -> i32 {
+            nothing();
+            let p | pair <- pair { a <- -4, b <- 18446744073709551615 };
+            let t | shape <- shape.label { text <- \"tab\\there\" };
+            let mut n | i32 <- 0;
+            while n < 10 && n >= 0 && n <= 99 && 'x' != 'y' { n <- n + 2 - 1; }
+            let small | f32 <- 1.5;
+            let flags | u32 <- 6 & 3 ^ 1;
+            if p.a < 0 && !false && area(shape.circle(2.0)) > 1.0 {
+                ret n << 1 >> 1;
+            }
+            ret -n % 7;
+        }";
+
+    #[test]
+    fn decoding_an_encoded_program_gives_it_back() {
+        for source in [ADD, EVERYTHING] {
+            let program = program(source);
+            let bytes = program.encode();
+            let decoded = Program::decode(&bytes).expect("an encoded program should decode");
+            assert_eq!(decoded.disassemble(), program.disassemble());
+            assert_eq!(decoded.encode(), bytes);
+            assert_eq!(vm::run(&decoded).ok(), vm::run(&program).ok());
+        }
+    }
+
+    #[test]
+    fn the_disassembly_lists_constants_and_each_function() {
+        let expected = "\
+constants:
+  #0    i32 1
+  #1    i32 2
+  #2    i32 3
+
+fn main #0 (params 0, locals 5, entry):
+  0000  const         #0     ; i32 1
+  0001  store         0
+  0002  const         #1     ; i32 2
+  0003  store         1
+  0004  load          0
+  0005  load          1
+  0006  call          #1     ; add
+  0007  store         2
+  0008  const         #2     ; i32 3
+  0009  store         3
+  0010  load          2
+  0011  load          3
+  0012  mul
+  0013  store         4
+  0014  load          4
+  0015  ret
+
+fn add #1 (params 2, locals 3):
+  0000  load          0
+  0001  load          1
+  0002  add
+  0003  store         2
+  0004  load          2
+  0005  ret
+";
+        assert_eq!(program(ADD).disassemble(), expected);
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let bytes = program(ADD).encode();
+        let with = |at: usize, byte: u8| {
+            let mut bytes = bytes.clone();
+            bytes[at] = byte;
+            Program::decode(&bytes).err()
+        };
+        assert_eq!(with(0, b'X').as_deref(), Some("it doesn't start with the bytecode magic number"));
+        assert_eq!(with(4, 9).as_deref(), Some("it is format version 9, but this compiler reads version 2"));
+        assert_eq!(with(6, 7).as_deref(), Some("the entry function doesn't exist"));
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(Program::decode(&longer).err().as_deref(), Some("there are unexpected bytes after the last function"));
+        for end in 0..bytes.len() {
+            assert!(Program::decode(&bytes[..end]).is_err(), "a file cut off after {} bytes decoded", end);
+        }
+    }
+
+    #[test]
+    fn out_of_bounds_operands_are_rejected() {
+        let damaged = |change: fn(&mut Program)| {
+            let mut program = program(ADD);
+            change(&mut program);
+            Program::decode(&program.encode()).err()
+        };
+        assert_eq!(damaged(|p| p.functions[0].code[0] = Op::Const(3)).as_deref(), Some("`main` has an out-of-bounds `const`"));
+        assert_eq!(damaged(|p| p.functions[1].code[0] = Op::Load(3)).as_deref(), Some("`add` has an out-of-bounds `load`"));
+        assert_eq!(damaged(|p| p.functions[0].code[6] = Op::Call(2)).as_deref(), Some("`main` has an out-of-bounds `call`"));
+        assert_eq!(damaged(|p| p.functions[0].code[1] = Op::Jump(16)).as_deref(), Some("`main` has an out-of-bounds `jump`"));
+        assert_eq!(damaged(|p| p.functions[1].code[5] = Op::Pop).as_deref(), Some("`add` can run past the end of its code"));
+    }
+
+    /// Swapping any one instruction for another that decodes can leave the
+    /// wrong values on the stack, which the VM reports instead of panicking.
+    #[test]
+    fn the_vm_reports_instructions_that_find_the_wrong_values() {
+        let source = "
+            struct pair { a | i32, b | i32 }
+            enum maybe { none, some(i32) }
+            fn first(p | pair) -> i32 { ret p.a; }
+            fn main() -> i32 {
+                let p | pair <- pair { a <- 1, b <- 2 };
+                let m | maybe <- maybe.some(p.b);
+                ret first(p) * 2 - p.b;
+            }";
+        let original = program(source);
+        let replacements = SIMPLE_OPS.iter().copied().chain([Op::NoMatch, Op::Field(5), Op::Struct(40), Op::Variant(0, 40)]);
+        let mut invalid = 0;
+        for replacement in replacements {
+            for (function, at) in original.functions.iter().enumerate().flat_map(|(f, func)| (0..func.code.len()).map(move |at| (f, at))) {
+                let mut program = program(source);
+                program.functions[function].code[at] = replacement;
+                let Ok(program) = Program::decode(&program.encode()) else {
+                    continue;
+                };
+                if let Err(diagnostic) = vm::run(&program)
+                    && diagnostic.code == Some(codes::INVALID_BYTECODE)
+                {
+                    invalid += 1;
+                }
+            }
+        }
+        assert!(invalid > 0);
+    }
+}
@@ -0,0 +1,589 @@
+use crate::diagnostic::codes;
+use crate::ir::{self, BinaryOp, BlockId, Constant, InstructionKind, Terminator, Type, TypeKind, UnaryOp};
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// Translates the program's IR into a single C11 file, `root` being the
+/// file it was compiled from. Integers wrap on overflow as they do in the
+/// interpreter, and runtime errors exit with status 101.
+///
+/// Every IR value becomes a C variable declared at the top of its function
+/// and every block a label, so control flow is a series of `goto`s. A phi
+/// gets a second variable that its predecessors assign before jumping to
+/// its block, which copies it into the phi's own at its start.
+pub(crate) fn generate(module: &ir::Module, root: &str) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "/* Generated by c4 from {}. */", root);
+    out.push_str(PRELUDE);
+    for (name, c_type, unsigned, bits) in INTEGERS {
+        out.push_str(&division_helpers(name, c_type, unsigned, bits));
+    }
+
+    let order = module.layout_order();
+    out.push('\n');
+    for index in &order {
+        let _ = writeln!(out, "typedef struct {0} {0};", module.types[*index as usize].symbol());
+    }
+    for index in &order {
+        out.push('\n');
+        out.push_str(&type_definition(module, &module.types[*index as usize]));
+    }
+
+    out.push('\n');
+    for function in &module.functions {
+        let _ = writeln!(out, "{};", prototype(module, function));
+    }
+    for function in &module.functions {
+        out.push('\n');
+        out.push_str(&Generator { module, function, lines: Vec::new() }.function_definition());
+    }
+
+    let main = &module.functions[module.main as usize];
+    out.push_str("\nint main(void) {\n");
+    match main.ret {
+        Type::I32 => {
+            let _ = writeln!(out, "    return (int){}();", main.symbol());
+        }
+        _ => {
+            let _ = writeln!(out, "    {}();\n    return 0;", main.symbol());
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+const PRELUDE: &str = r#"
+#include <math.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+_Noreturn static void c4_fail(const char *code, const char *message, const char *at) {
+    fprintf(stderr, "error[%s]: %s\n  = note: at %s\n", code, message, at);
+    exit(101);
+}
+
+typedef struct {
+    const char *bytes;
+    size_t length;
+} c4_string;
+
+static bool c4_strings_equal(c4_string a, c4_string b) {
+    return a.length == b.length && memcmp(a.bytes, b.bytes, a.length) == 0;
+}
+"#;
+
+/// Every integer type as (c4 name, C type, unsigned, bits).
+const INTEGERS: [(&str, &str, bool, u32); 4] =
+    [("i32", "int32_t", false, 32), ("i64", "int64_t", false, 64), ("u32", "uint32_t", true, 32), ("u64", "uint64_t", true, 64)];
+
+/// `/` and `%` for one integer type, which fail on a zero divisor and wrap
+/// when the quotient overflows.
+fn division_helpers(name: &str, c_type: &str, unsigned: bool, bits: u32) -> String {
+    let overflow = |result: &str| match unsigned {
+        true => String::new(),
+        false => format!("\n    if (b == -1) return {};", result),
+    };
+    format!(
+        r#"
+static inline {t} c4_div_{n}({t} a, {t} b, const char *at) {{
+    if (b == 0) c4_fail("{code}", "attempt to divide by zero", at);{div}
+    return a / b;
+}}
+
+static inline {t} c4_rem_{n}({t} a, {t} b, const char *at) {{
+    if (b == 0) c4_fail("{code}", "attempt to divide by zero", at);{rem}
+    return a % b;
+}}
+"#,
+        t = c_type,
+        n = name,
+        code = codes::DIVISION_BY_ZERO,
+        div = overflow(&format!("({})(0u - (uint{}_t)a)", c_type, bits)),
+        rem = overflow("0"),
+    )
+}
+
+/// C keywords and names the standard headers may define, which fields
+/// can't be called.
+const RESERVED: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern",
+    "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return", "short", "signed",
+    "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void", "volatile", "while", "bool",
+    "true", "false", "main", "errno", "stdin", "stdout", "stderr", "exit", "fmod", "fmodf", "memcmp", "as", "tag",
+];
+
+/// A name that is safe to use for a field in C.
+fn identifier(name: &str) -> String {
+    if RESERVED.contains(&name) || name.starts_with("c4_") || name.starts_with('_') {
+        format!("{}_", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// The C type of a value of type `ty`. `unit` values are never looked at,
+/// but phis and copies may still pass them around.
+fn c_type(module: &ir::Module, ty: Type) -> String {
+    match ty {
+        Type::I32 => "int32_t".to_string(),
+        Type::I64 => "int64_t".to_string(),
+        Type::U32 | Type::Char => "uint32_t".to_string(),
+        Type::U64 => "uint64_t".to_string(),
+        Type::F32 => "float".to_string(),
+        Type::F64 => "double".to_string(),
+        Type::Bool => "bool".to_string(),
+        // Strings carry their length, as they may contain NULs.
+        Type::String => "c4_string".to_string(),
+        Type::Unit => "uint8_t".to_string(),
+        Type::Named(index) => module.types[index as usize].symbol(),
+    }
+}
+
+/// The C name of the field of a variant at `index`.
+fn variant_field(index: usize) -> String {
+    format!("_{}", index)
+}
+
+/// A struct becomes a C struct. An enum becomes a tag, the index of its
+/// variant, and a union of the variants' fields.
+fn type_definition(module: &ir::Module, definition: &ir::TypeDefinition) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "struct {} {{", definition.symbol());
+    match &definition.kind {
+        TypeKind::Struct(fields) => {
+            for (name, ty) in fields {
+                let _ = writeln!(out, "    {} {};", c_type(module, *ty), identifier(name));
+            }
+            if fields.is_empty() {
+                out.push_str("    char empty;\n");
+            }
+        }
+        TypeKind::Enum(variants) => {
+            out.push_str("    uint32_t tag;\n");
+            let mut members = String::new();
+            for (name, fields) in variants.iter().filter(|(_, fields)| !fields.is_empty()) {
+                members.push_str("        struct {");
+                for (index, ty) in fields.iter().enumerate() {
+                    let _ = write!(members, " {} {};", c_type(module, *ty), variant_field(index));
+                }
+                let _ = writeln!(members, " }} {};", identifier(name));
+            }
+            if !members.is_empty() {
+                let _ = write!(out, "    union {{\n{}    }} as;\n", members);
+            }
+        }
+    }
+    out.push_str("};\n");
+    out
+}
+
+fn prototype(module: &ir::Module, function: &ir::Function) -> String {
+    let params: Vec<String> =
+        function.params.iter().map(|param| format!("{} {}", c_type(module, function.type_of(*param)), value(*param))).collect();
+    let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+    let ret = if function.ret == Type::Unit { "void".to_string() } else { c_type(module, function.ret) };
+    format!("static {} {}({})", ret, function.symbol(), params)
+}
+
+fn value(value: ir::Value) -> String {
+    format!("v{}", value.0)
+}
+
+/// The variable a phi's predecessors assign.
+fn incoming(phi: ir::Value) -> String {
+    format!("p{}", phi.0)
+}
+
+fn label(block: BlockId) -> String {
+    format!("bb{}", block.0)
+}
+
+struct Generator<'m> {
+    module: &'m ir::Module,
+    function: &'m ir::Function,
+    /// The body of the function.
+    lines: Vec<String>,
+}
+
+impl Generator<'_> {
+    fn line(&mut self, line: impl AsRef<str>) {
+        self.lines.push(format!("    {}", line.as_ref()));
+    }
+
+    fn c_type(&self, ty: Type) -> String {
+        c_type(self.module, ty)
+    }
+
+    fn type_of(&self, value: ir::Value) -> Type {
+        self.function.type_of(value)
+    }
+
+    /// Where a runtime error in this function happened, as a C string.
+    fn location(&self, location: &Option<String>) -> String {
+        c_string(location.as_deref().unwrap_or(&self.function.name))
+    }
+
+    fn function_definition(mut self) -> String {
+        let function = self.function;
+        let params: HashSet<ir::Value> = function.params.iter().copied().collect();
+        for block in &function.blocks {
+            for instruction in &block.instructions {
+                let Some(result) = instruction.result else {
+                    continue;
+                };
+                let c_type = self.c_type(self.type_of(result));
+                if instruction.is_phi() {
+                    self.line(format!("{} {}, {};", c_type, value(result), incoming(result)));
+                } else if !params.contains(&result) {
+                    self.line(format!("{} {};", c_type, value(result)));
+                }
+            }
+        }
+
+        // Blocks are laid out so that most jumps go to the next one, which
+        // doesn't need a `goto` or a label.
+        let order = function.reverse_postorder();
+        let mut jumped_to = HashSet::new();
+        for (position, block) in order.iter().enumerate() {
+            let following = order.get(position + 1).copied();
+            match function.block(*block).terminator {
+                Terminator::Jump(to) if Some(to) != following => {
+                    jumped_to.insert(to);
+                }
+                Terminator::Branch(_, then, otherwise) => {
+                    jumped_to.insert(then);
+                    if Some(otherwise) != following {
+                        jumped_to.insert(otherwise);
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (position, block) in order.iter().enumerate() {
+            if jumped_to.contains(block) {
+                self.lines.push(format!("{}:;", label(*block)));
+            }
+            self.block(*block, order.get(position + 1).copied());
+        }
+
+        let mut definition = format!("{} {{\n", prototype(self.module, function));
+        for line in self.lines {
+            definition.push_str(&line);
+            definition.push('\n');
+        }
+        definition.push_str("}\n");
+        definition
+    }
+
+    fn block(&mut self, id: BlockId, following: Option<BlockId>) {
+        let block = self.function.block(id);
+        for instruction in &block.instructions {
+            match (instruction.result, &instruction.kind) {
+                (Some(result), InstructionKind::Phi(_)) => self.line(format!("{} = {};", value(result), incoming(result))),
+                (Some(result), kind) => {
+                    let expression = self.expression(kind, self.type_of(result), &instruction.location);
+                    self.line(format!("{} = {};", value(result), expression));
+                }
+                (None, kind) => {
+                    let expression = self.expression(kind, Type::Unit, &instruction.location);
+                    self.line(format!("{};", expression));
+                }
+            }
+        }
+        match &block.terminator {
+            Terminator::Jump(to) => {
+                self.phi_moves(id, *to);
+                if following != Some(*to) {
+                    self.line(format!("goto {};", label(*to)));
+                }
+            }
+            Terminator::Branch(condition, then, otherwise) => {
+                let moves = self.moves(id, *then);
+                if moves.is_empty() {
+                    self.line(format!("if ({}) goto {};", value(*condition), label(*then)));
+                } else {
+                    self.line(format!("if ({}) {{", value(*condition)));
+                    for line in moves {
+                        self.line(format!("    {}", line));
+                    }
+                    self.line(format!("    goto {};", label(*then)));
+                    self.line("}");
+                }
+                self.phi_moves(id, *otherwise);
+                if following != Some(*otherwise) {
+                    self.line(format!("goto {};", label(*otherwise)));
+                }
+            }
+            Terminator::Return(Some(result)) => self.line(format!("return {};", value(*result))),
+            Terminator::Return(None) => self.line("return;"),
+            Terminator::NoMatch(location) => {
+                let location = self.location(location);
+                self.line(format!("c4_fail(\"{}\", \"no match arm matched the value\", {});", codes::NO_MATCHING_ARM, location));
+            }
+            Terminator::Unreachable => self.line("abort();"),
+        }
+    }
+
+    /// The assignments to the phis of `to` when control comes from `from`.
+    fn moves(&self, from: BlockId, to: BlockId) -> Vec<String> {
+        let moves = self.function.phi_moves(from, to);
+        moves.into_iter().map(|(phi, source)| format!("{} = {};", incoming(phi), value(source))).collect()
+    }
+
+    fn phi_moves(&mut self, from: BlockId, to: BlockId) {
+        for line in self.moves(from, to) {
+            self.line(line);
+        }
+    }
+
+    /// The C expression an instruction computes, a value of type `ty`.
+    fn expression(&self, kind: &InstructionKind, ty: Type, location: &Option<String>) -> String {
+        let list = |values: &[ir::Value]| values.iter().map(|v| value(*v)).collect::<Vec<_>>().join(", ");
+        match kind {
+            InstructionKind::Const(constant) => constant_value(constant),
+            InstructionKind::Copy(a) => value(*a),
+            InstructionKind::Unary(op, a) => {
+                let ty = self.type_of(*a);
+                match (op, ty) {
+                    (UnaryOp::Neg, Type::I32 | Type::I64) => {
+                        format!("({})(0u - ({}){})", self.c_type(ty), unsigned_of(ty), value(*a))
+                    }
+                    (UnaryOp::Neg, _) => format!("-{}", value(*a)),
+                    (UnaryOp::Not, Type::Bool) => format!("!{}", value(*a)),
+                    (UnaryOp::Not, _) => format!("({})~{}", self.c_type(ty), value(*a)),
+                }
+            }
+            InstructionKind::Binary(op, a, b) => self.binary(*op, self.type_of(*a), value(*a), value(*b), location),
+            InstructionKind::Call(callee, args) => {
+                format!("{}({})", self.module.functions[*callee as usize].symbol(), list(args))
+            }
+            InstructionKind::Phi(_) => unreachable!("phis are assigned by their predecessors"),
+            InstructionKind::Struct(fields) if fields.is_empty() => format!("({}){{ 0 }}", self.c_type(ty)),
+            InstructionKind::Struct(fields) => format!("({}){{ {} }}", self.c_type(ty), list(fields)),
+            InstructionKind::Variant(tag, fields) => {
+                if fields.is_empty() {
+                    return format!("({}){{ .tag = {} }}", self.c_type(ty), tag);
+                }
+                let variant = identifier(&self.variants(ty)[*tag as usize].0);
+                format!("({}){{ .tag = {}, .as.{} = {{ {} }} }}", self.c_type(ty), tag, variant, list(fields))
+            }
+            InstructionKind::Field(a, index) => {
+                let Some(TypeKind::Struct(fields)) = self.definition(self.type_of(*a)) else {
+                    unreachable!("the verifier checked `field` is on a struct");
+                };
+                format!("{}.{}", value(*a), identifier(&fields[*index as usize].0))
+            }
+            InstructionKind::Tag(a) => format!("{}.tag", value(*a)),
+            InstructionKind::Payload(a, tag, index) => {
+                let variant = identifier(&self.variants(self.type_of(*a))[*tag as usize].0);
+                format!("{}.as.{}.{}", value(*a), variant, variant_field(*index as usize))
+            }
+        }
+    }
+
+    fn definition(&self, ty: Type) -> Option<&TypeKind> {
+        match ty {
+            Type::Named(index) => Some(&self.module.types[index as usize].kind),
+            _ => None,
+        }
+    }
+
+    fn variants(&self, ty: Type) -> &[(String, Vec<Type>)] {
+        match self.definition(ty) {
+            Some(TypeKind::Enum(variants)) => variants,
+            _ => unreachable!("the verifier checked variants belong to enums"),
+        }
+    }
+
+    /// A binary operator on operands of type `ty`. Signed arithmetic is
+    /// done on the unsigned type of the same width so that it wraps instead
+    /// of being undefined.
+    fn binary(&self, op: BinaryOp, ty: Type, left: String, right: String, location: &Option<String>) -> String {
+        let c_type = self.c_type(ty);
+        let signed_integer = matches!(ty, Type::I32 | Type::I64);
+        match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul if signed_integer => {
+                let unsigned = unsigned_of(ty);
+                format!("({})(({}){} {} ({}){})", c_type, unsigned, left, c_operator(op), unsigned, right)
+            }
+            BinaryOp::Div | BinaryOp::Rem if ty.is_integer() => {
+                let helper = if op == BinaryOp::Div { "div" } else { "rem" };
+                let name = INTEGERS.iter().find(|(_, c, _, _)| *c == c_type).map_or("i32", |(name, ..)| *name);
+                format!("c4_{}_{}({}, {}, {})", helper, name, left, right, self.location(location))
+            }
+            BinaryOp::Rem if ty == Type::F32 => format!("fmodf({}, {})", left, right),
+            BinaryOp::Rem => format!("fmod({}, {})", left, right),
+            BinaryOp::Shl | BinaryOp::Shr => {
+                let mask = if matches!(ty, Type::I64 | Type::U64) { 63 } else { 31 };
+                let amount = format!("((unsigned){} & {}u)", right, mask);
+                match (op, signed_integer) {
+                    (BinaryOp::Shl, true) => format!("({})(({}){} << {})", c_type, unsigned_of(ty), left, amount),
+                    (BinaryOp::Shl, false) => format!("({})({} << {})", c_type, left, amount),
+                    _ => format!("({})({} >> {})", c_type, left, amount),
+                }
+            }
+            BinaryOp::Eq if ty == Type::String => format!("c4_strings_equal({}, {})", left, right),
+            BinaryOp::Ne if ty == Type::String => format!("!c4_strings_equal({}, {})", left, right),
+            _ => format!("{} {} {}", left, c_operator(op), right),
+        }
+    }
+}
+
+fn c_operator(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Rem => "%",
+        BinaryOp::And => "&",
+        BinaryOp::Xor => "^",
+        BinaryOp::Shl => "<<",
+        BinaryOp::Shr => ">>",
+        BinaryOp::Eq => "==",
+        BinaryOp::Ne => "!=",
+        BinaryOp::Lt => "<",
+        BinaryOp::Le => "<=",
+        BinaryOp::Gt => ">",
+        BinaryOp::Ge => ">=",
+    }
+}
+
+fn unsigned_of(ty: Type) -> &'static str {
+    match ty {
+        Type::I64 | Type::U64 => "uint64_t",
+        _ => "uint32_t",
+    }
+}
+
+fn constant_value(constant: &Constant) -> String {
+    match *constant {
+        Constant::I32(i32::MIN) => "INT32_MIN".to_string(),
+        Constant::I32(v) if v < 0 => format!("({})", v),
+        Constant::I32(v) => v.to_string(),
+        Constant::I64(i64::MIN) => "INT64_MIN".to_string(),
+        Constant::I64(v) => format!("INT64_C({})", v),
+        Constant::U32(v) => format!("UINT32_C({})", v),
+        Constant::U64(v) => format!("UINT64_C({})", v),
+        Constant::F32(v) => float_literal(v.into(), "f"),
+        Constant::F64(v) => float_literal(v, ""),
+        Constant::Bool(v) => v.to_string(),
+        Constant::Char(v) => format!("UINT32_C({:#x})", v as u32),
+        Constant::String(ref v) => format!("(c4_string){{ {}, {} }}", c_string(v), v.len()),
+        Constant::Unit => "0".to_string(),
+    }
+}
+
+/// A float literal with `suffix` for its type. Converting an `f32` to
+/// `f64` is exact, so printing it as one loses nothing.
+fn float_literal(value: f64, suffix: &str) -> String {
+    if value.is_nan() {
+        return "NAN".to_string();
+    }
+    if value.is_infinite() {
+        return if value < 0.0 { "(-INFINITY)".to_string() } else { "INFINITY".to_string() };
+    }
+    let text = match suffix {
+        "f" => format!("{:?}f", value as f32),
+        _ => format!("{:?}", value),
+    };
+    if value.is_sign_negative() { format!("({})", text) } else { text }
+}
+
+/// A C string literal with the same bytes as `value`.
+fn c_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in value.bytes() {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            // Escaped so no `??` sequence is read as a trigraph.
+            b'?' => out.push_str("\\?"),
+            b'\\' => out.push_str("\\\\"),
+            b' '..=b'~' => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "\\{:03o}", byte);
+            }
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing::{self, programs};
+    use crate::{codegen, vm};
+    use std::process::Command;
+
+    /// Translates `source` to C, builds it with `cc -std=c11` and runs it.
+    /// Returns the exit code and what the program wrote to stderr.
+    fn run(source: &str) -> (i32, String) {
+        let root = testing::root_file(source);
+        let c_file = root.path().with_extension("c");
+        let executable = c_file.with_extension("");
+        std::fs::write(&c_file, generate(&testing::lower(source), "main.c4l")).expect("the temp directory is writable");
+        let built = Command::new("cc")
+            .args(["-std=c11", "-o"])
+            .arg(&executable)
+            .arg(&c_file)
+            .arg("-lm")
+            .output()
+            .expect("the tests need `cc` on the `PATH`");
+        assert!(built.status.success(), "`cc` rejected the C: {}", String::from_utf8_lossy(&built.stderr));
+        let ran = Command::new(&executable).output().expect("the program should start");
+        (ran.status.code().expect("the program should exit"), String::from_utf8_lossy(&ran.stderr).into_owned())
+    }
+
+    #[test]
+    fn integer_arithmetic_wraps() {
+        let (source, code) = programs::WRAPPING;
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn structs_are_passed_returned_and_nested() {
+        let (source, code) = programs::STRUCTS;
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn enums_are_matched_by_variant_and_payload() {
+        let (source, code) = programs::ENUMS;
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn num_functions_get_an_instance_per_type() {
+        let (source, code) = programs::NUM_INSTANCES;
+        let module = testing::lower(source);
+        let instances: Vec<&str> = module.functions.iter().map(|f| f.name.as_str()).filter(|n| n.starts_with("pow")).collect();
+        assert_eq!(instances, ["pow<i64>", "pow<f64>", "pow<u32>", "pow<i32>"]);
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn arguments_past_the_registers_are_passed() {
+        let (source, code) = programs::MANY_ARGUMENTS;
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn dividing_by_zero_exits_with_101() {
+        let (source, code) = programs::DIVIDE_BY_ZERO;
+        let (status, stderr) = run(source);
+        assert_eq!(status, code);
+        assert!(stderr.starts_with("error[R0001]: attempt to divide by zero\n  = note: at "), "{}", stderr);
+        assert!(stderr.trim_end().ends_with("main.c4l:3:21"), "{}", stderr);
+    }
+
+    #[test]
+    fn strings_compare_past_nul_bytes() {
+        let (source, code) = programs::STRING_NULS;
+        assert_eq!(run(source).0, code);
+        assert_eq!(vm::run(&codegen::compile(&testing::lower(source))).ok(), Some(code));
+    }
+}
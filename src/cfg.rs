@@ -0,0 +1,356 @@
+use crate::diagnostic::{codes, Diagnostic};
+use crate::module::{ModuleId, ModuleTree, Resolved};
+use crate::parser::*;
+use crate::span::Span;
+
+pub(crate) type BlockId = usize;
+
+/// A straight run of statements. Control enters at the top and leaves
+/// through one of `successors` once the last statement has run; a block
+/// ending in `ret` has no successors.
+#[derive(Debug)]
+pub(crate) struct BasicBlock<'p> {
+    pub statements: Vec<&'p Statement>,
+    pub successors: Vec<BlockId>,
+    /// Why the block exists, for blocks that start after code which never
+    /// falls through, such as the statements following a `ret`.
+    pub cause: Option<Cause>,
+}
+
+/// The statement after which a block starts, and what to say about it if
+/// the block turns out to be unreachable.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Cause {
+    pub block: BlockId,
+    pub span: Span,
+    pub label: &'static str,
+}
+
+/// The control-flow graph of one function body.
+#[derive(Debug)]
+pub(crate) struct Cfg<'p> {
+    pub blocks: Vec<BasicBlock<'p>>,
+    /// Every `while` and `for` loop as (header, block after the loop,
+    /// statement span).
+    pub loops: Vec<(BlockId, BlockId, Span)>,
+}
+
+impl<'p> Cfg<'p> {
+    /// Where every function body starts.
+    pub const ENTRY: BlockId = 0;
+    /// Where control goes if the body runs to its closing `}` without a
+    /// `ret`.
+    pub const END: BlockId = 1;
+
+    /// Builds the graph of a function body. `module` is where the function
+    /// is declared, for resolving the enum variants in match patterns.
+    pub fn build(statements: &'p [Statement], modules: &ModuleTree, module: ModuleId) -> Cfg<'p> {
+        let mut builder = Builder {
+            cfg: Cfg { blocks: Vec::new(), loops: Vec::new() },
+            current: Cfg::ENTRY,
+            loops: Vec::new(),
+            modules,
+            module,
+        };
+        builder.new_block(None);
+        builder.new_block(None);
+        builder.build_statements(statements);
+        builder.edge(builder.current, Cfg::END);
+        builder.cfg
+    }
+
+    /// Which blocks can run, starting from the entry.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack = vec![Cfg::ENTRY];
+        while let Some(block) = stack.pop() {
+            if reachable[block] {
+                continue;
+            }
+            reachable[block] = true;
+            stack.extend(&self.blocks[block].successors);
+        }
+        reachable
+    }
+}
+
+struct Builder<'p, 'm> {
+    cfg: Cfg<'p>,
+    /// The block statements are being added to.
+    current: BlockId,
+    /// The innermost loop last, as (`continue` target, `break` target).
+    loops: Vec<(BlockId, BlockId)>,
+    modules: &'m ModuleTree,
+    module: ModuleId,
+}
+
+impl<'p> Builder<'p, '_> {
+    fn new_block(&mut self, cause: Option<Cause>) -> BlockId {
+        self.cfg.blocks.push(BasicBlock { statements: Vec::new(), successors: Vec::new(), cause });
+        self.cfg.blocks.len() - 1
+    }
+
+    fn edge(&mut self, from: BlockId, to: BlockId) {
+        if !self.cfg.blocks[from].successors.contains(&to) {
+            self.cfg.blocks[from].successors.push(to);
+        }
+    }
+
+    fn cause(&self, span: Span, label: &'static str) -> Option<Cause> {
+        Some(Cause { block: self.current, span, label })
+    }
+
+    fn build_statements(&mut self, statements: &'p [Statement]) {
+        for statement in statements {
+            self.build_statement(statement);
+        }
+    }
+
+    fn build_statement(&mut self, statement: &'p Statement) {
+        self.cfg.blocks[self.current].statements.push(statement);
+        match &statement.kind {
+            StatementKind::Let { .. } | StatementKind::Set { .. } | StatementKind::FunctionCall { .. } => {}
+            StatementKind::Ret { .. } => {
+                self.current = self.new_block(self.cause(statement.span, "any code following this `ret` is unreachable"));
+            }
+            StatementKind::Break | StatementKind::Continue => {
+                let Some((continue_target, break_target)) = self.loops.last().copied() else {
+                    return;
+                };
+                let (target, label) = match statement.kind {
+                    StatementKind::Break => (break_target, "any code following this `break` is unreachable"),
+                    _ => (continue_target, "any code following this `continue` is unreachable"),
+                };
+                self.edge(self.current, target);
+                self.current = self.new_block(self.cause(statement.span, label));
+            }
+            StatementKind::Block(block) => self.build_statements(&block.statements),
+            StatementKind::If { then_block, else_branch, .. } => {
+                let head = self.current;
+                let join = self.new_block(self.cause(statement.span, "every branch of this `if` leaves the block"));
+
+                let then_start = self.new_block(None);
+                self.edge(head, then_start);
+                self.current = then_start;
+                self.build_statements(&then_block.statements);
+                self.edge(self.current, join);
+
+                match else_branch {
+                    Some(else_branch) => {
+                        let else_start = self.new_block(None);
+                        self.edge(head, else_start);
+                        self.current = else_start;
+                        self.build_statement(else_branch);
+                        self.edge(self.current, join);
+                    }
+                    None => self.edge(head, join),
+                }
+                self.current = join;
+            }
+            StatementKind::Match { arms, .. } => {
+                let head = self.current;
+                let join = self.new_block(self.cause(statement.span, "every arm of this match leaves the block"));
+                for arm in arms {
+                    let start = self.new_block(None);
+                    self.edge(head, start);
+                    self.current = start;
+                    self.build_statement(&arm.body);
+                    self.edge(self.current, join);
+                }
+                if !self.is_exhaustive(arms) {
+                    self.edge(head, join);
+                }
+                self.current = join;
+            }
+            StatementKind::While { condition, body } => {
+                let always = matches!(condition.kind, ExpressionKind::BoolLiteral(true));
+                self.build_loop(statement.span, &body.statements, !always);
+            }
+            StatementKind::For { body, .. } => self.build_loop(statement.span, &body.statements, true),
+        }
+    }
+
+    /// A loop header runs before every iteration, deciding whether to run
+    /// the body again or leave. `can_exit` is false for `while true`, which
+    /// can only be left with `break`.
+    fn build_loop(&mut self, span: Span, body: &'p [Statement], can_exit: bool) {
+        let header = self.new_block(None);
+        self.edge(self.current, header);
+        let after = self.new_block(Some(Cause {
+            block: header,
+            span,
+            label: "this loop never ends, so code after it never runs",
+        }));
+        let body_start = self.new_block(None);
+        self.edge(header, body_start);
+        if can_exit {
+            self.edge(header, after);
+        }
+
+        self.loops.push((header, after));
+        self.current = body_start;
+        self.build_statements(body);
+        self.edge(self.current, header);
+        self.loops.pop();
+
+        self.cfg.loops.push((header, after, span));
+        self.current = after;
+    }
+
+    /// Whether the arms cover every possible value, so control can't skip
+    /// past all of them. Arms with a catch-all pattern, both `true` and
+    /// `false`, or every variant of an enum are exhaustive.
+    fn is_exhaustive(&self, arms: &[MatchArm<Statement>]) -> bool {
+        let mut patterns: Vec<&Pattern> = Vec::new();
+        for arm in arms {
+            flatten_or(&arm.pattern, &mut patterns);
+        }
+        if patterns.iter().any(|p| is_irrefutable(p)) {
+            return true;
+        }
+        let has = |value: bool| patterns.iter().any(|p| matches!(p.kind, PatternKind::Literal(Literal::Bool(b)) if b == value));
+        if has(true) && has(false) {
+            return true;
+        }
+
+        let mut covered: Vec<&str> = Vec::new();
+        let mut enum_variants: Option<&Vec<EnumVariant>> = None;
+        for pattern in patterns {
+            let PatternKind::Constructor { path, fields } = &pattern.kind else {
+                continue;
+            };
+            if !fields_irrefutable(fields) {
+                continue;
+            }
+            let Some((Resolved::Item(module, index), used)) = self.modules.lookup_prefix(self.module, path) else {
+                continue;
+            };
+            match (self.modules.object(module, index), &path[used..]) {
+                (ProgramObject::Enum { variants, .. }, [variant]) => {
+                    enum_variants = Some(variants);
+                    covered.push(variant);
+                }
+                (ProgramObject::Struct { .. }, []) => return true,
+                _ => {}
+            }
+        }
+        enum_variants.is_some_and(|variants| variants.iter().all(|v| covered.contains(&v.name.as_str())))
+    }
+}
+
+fn flatten_or<'a>(pattern: &'a Pattern, out: &mut Vec<&'a Pattern>) {
+    match &pattern.kind {
+        PatternKind::Or(alternatives) => {
+            for alternative in alternatives {
+                flatten_or(alternative, out);
+            }
+        }
+        _ => out.push(pattern),
+    }
+}
+
+/// Whether a pattern matches every value of its type.
+fn is_irrefutable(pattern: &Pattern) -> bool {
+    match &pattern.kind {
+        PatternKind::Wildcard | PatternKind::Binding(_) => true,
+        PatternKind::Or(alternatives) => alternatives.iter().any(is_irrefutable),
+        PatternKind::Literal(_) | PatternKind::Range { .. } | PatternKind::Constructor { .. } => false,
+    }
+}
+
+fn fields_irrefutable(fields: &PatternFields) -> bool {
+    match fields {
+        PatternFields::Unit => true,
+        PatternFields::Tuple(patterns) => patterns.iter().all(is_irrefutable),
+        PatternFields::Struct(fields) => fields.iter().all(|(_, p)| is_irrefutable(p)),
+    }
+}
+
+/// Builds the control-flow graph of every function and reports functions
+/// that can reach their end without returning a value, statements that
+/// can never run, and loops that never end.
+pub(crate) fn check(modules: &ModuleTree) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for (module, obj) in modules.objects() {
+        let ProgramObject::Function { name, statements, return_type, signature_span, span, .. } = obj else {
+            continue;
+        };
+        let cfg = Cfg::build(statements, modules, module);
+        let reachable = cfg.reachable();
+
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            let (Some(cause), Some(first)) = (block.cause, block.statements.first()) else {
+                continue;
+            };
+            if reachable[id] || !reachable[cause.block] {
+                continue;
+            }
+            diagnostics.push(
+                Diagnostic::warning("unreachable statement")
+                    .with_code(codes::UNREACHABLE_CODE)
+                    .with_primary(first.span, "this statement never runs")
+                    .with_secondary(cause.span, cause.label),
+            );
+        }
+
+        for (header, after, loop_span) in &cfg.loops {
+            if reachable[*header] && !reachable[*after] {
+                diagnostics.push(
+                    Diagnostic::note("this loop never ends")
+                        .with_code(codes::INFINITE_LOOP)
+                        .with_primary(*loop_span, "no `break` leaves this loop")
+                        .with_note("that's fine if the program is meant to run until it is stopped"),
+                );
+            }
+        }
+
+        if return_type.is_some() && reachable[Cfg::END] {
+            let closing = Span::new(span.file, span.end.saturating_sub(1), span.end);
+            diagnostics.push(
+                Diagnostic::error(format!("not all paths through `{}` return a value", name))
+                    .with_code(codes::MISSING_RETURN)
+                    .with_primary(closing, "control can reach the end of the function here")
+                    .with_secondary(*signature_span, "declared to return a value")
+                    .with_note("help: add a `ret` on every path, or at the end of the function"),
+            );
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing;
+
+    #[test]
+    fn every_path_returning_is_fine() {
+        let source = "
+            enum opt { some(i32), none }
+            fn f(b | bool) -> i32 { if b { ret 1; } else { ret 2; } }
+            fn g(o | opt) -> i32 { @o { opt.some(x) -> { ret x; } ~ -> { ret 0; } } }";
+        assert!(testing::pass_codes(check, source).is_empty());
+    }
+
+    #[test]
+    fn a_path_without_ret_is_an_error() {
+        assert_eq!(testing::pass_codes(check, "fn f(b | bool) -> i32 { if b { ret 1; } }"), [codes::MISSING_RETURN]);
+        assert_eq!(testing::pass_codes(check, "fn f() -> i32 { while true { break; } }"), [codes::MISSING_RETURN]);
+    }
+
+    #[test]
+    fn statements_after_ret_or_break_are_unreachable() {
+        assert_eq!(testing::pass_codes(check, "fn f() -> i32 { ret 1; ret 2; }"), [codes::UNREACHABLE_CODE]);
+        let source = "fn f() -> i32 { while true { break; ret 2; } ret 1; }";
+        assert_eq!(testing::pass_codes(check, source), [codes::UNREACHABLE_CODE]);
+    }
+
+    #[test]
+    fn a_loop_without_break_never_ends() {
+        let diagnostics = testing::pass_diagnostics(check, "fn f() { while true {} }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::INFINITE_LOOP));
+        assert!(!diagnostics[0].is_error());
+        assert!(testing::pass_codes(check, "fn f(b | bool) { while b {} }").is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::bytecode::{Constant, Function, Op, Program};
+use crate::ir::{self, BlockId, InstructionKind, Terminator};
+use std::collections::HashMap;
+
+/// Compiles the program's IR to bytecode. Every IR function becomes the
+/// bytecode function with the same index.
+///
+/// Every IR value gets a local slot of its own, parameters first, so an
+/// instruction loads its operands, runs and stores its result. Phis are
+/// resolved by storing into their slots at the end of each predecessor.
+pub(crate) fn compile(module: &ir::Module) -> Program {
+    let mut codegen = Codegen { program: Program::default(), constants: HashMap::new(), state: FunctionState::default() };
+    codegen.program.main = module.main;
+    for function in &module.functions {
+        codegen.compile_function(function);
+    }
+    codegen.program
+}
+
+/// What is being built for the function currently being compiled.
+#[derive(Debug, Default)]
+struct FunctionState {
+    code: Vec<Op>,
+    locations: Vec<(u32, String)>,
+    /// The local slot of every IR value, by number.
+    slots: Vec<u16>,
+    /// Where each block's code starts, once it has been emitted.
+    starts: HashMap<BlockId, u32>,
+    /// Jumps waiting for the start of the block they go to.
+    jumps: Vec<(usize, BlockId)>,
+}
+
+struct Codegen {
+    program: Program,
+    constants: HashMap<Constant, u32>,
+    state: FunctionState,
+}
+
+impl Codegen {
+    fn compile_function(&mut self, function: &ir::Function) {
+        self.state = FunctionState { slots: vec![0; function.values.len()], ..FunctionState::default() };
+        let mut next = 0;
+        let values = function.blocks.iter().flat_map(|block| &block.instructions).filter_map(|i| i.result);
+        for value in function.params.iter().copied().chain(values) {
+            self.state.slots[value.0 as usize] = next;
+            next += 1;
+        }
+
+        let order = function.reverse_postorder();
+        for (position, block) in order.iter().enumerate() {
+            self.state.starts.insert(*block, self.state.code.len() as u32);
+            let following = order.get(position + 1).copied();
+            self.block(function, *block, following);
+        }
+        for (at, block) in std::mem::take(&mut self.state.jumps) {
+            let target = self.state.starts[&block];
+            match &mut self.state.code[at] {
+                Op::Jump(to) | Op::JumpIfFalse(to) => *to = target,
+                op => unreachable!("`{}` isn't a jump", op.name()),
+            }
+        }
+
+        let state = std::mem::take(&mut self.state);
+        self.program.functions.push(Function {
+            name: function.name.clone(),
+            params: function.params.len() as u16,
+            locals: next,
+            code: state.code,
+            locations: state.locations,
+        });
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.state.code.push(op);
+        self.state.code.len() - 1
+    }
+
+    /// Emits an instruction that can fail at run time, remembering where in
+    /// the source it came from.
+    fn emit_at(&mut self, op: Op, location: Option<&String>) {
+        let pc = self.emit(op) as u32;
+        if let Some(location) = location {
+            self.state.locations.push((pc, location.clone()));
+        }
+    }
+
+    /// Emits a jump to the start of `block`, patched once it is known.
+    fn jump(&mut self, op: Op, block: BlockId) {
+        let at = self.emit(op);
+        self.state.jumps.push((at, block));
+    }
+
+    fn constant(&mut self, constant: &ir::Constant) {
+        let constant = match constant {
+            ir::Constant::I32(v) => Constant::I32(*v),
+            ir::Constant::I64(v) => Constant::I64(*v),
+            ir::Constant::U32(v) => Constant::U32(*v),
+            ir::Constant::U64(v) => Constant::U64(*v),
+            ir::Constant::F32(v) => Constant::F32(*v),
+            ir::Constant::F64(v) => Constant::F64(*v),
+            ir::Constant::Bool(v) => Constant::Bool(*v),
+            ir::Constant::Char(v) => Constant::Char(*v),
+            ir::Constant::String(v) => Constant::String(v.clone()),
+            ir::Constant::Unit => {
+                self.emit(Op::Unit);
+                return;
+            }
+        };
+        let next = self.program.constants.len() as u32;
+        let index = *self.constants.entry(constant.clone()).or_insert(next);
+        if index == next {
+            self.program.constants.push(constant);
+        }
+        self.emit(Op::Const(index));
+    }
+
+    fn load(&mut self, value: ir::Value) {
+        self.emit(Op::Load(self.state.slots[value.0 as usize]));
+    }
+
+    fn store(&mut self, value: ir::Value) {
+        self.emit(Op::Store(self.state.slots[value.0 as usize]));
+    }
+
+    /// Emits a block, leaving out a jump to `following`, the block emitted
+    /// after it.
+    fn block(&mut self, function: &ir::Function, id: BlockId, following: Option<BlockId>) {
+        let block = function.block(id);
+        for instruction in block.instructions.iter().filter(|i| !i.is_phi()) {
+            self.instruction(instruction);
+        }
+        match &block.terminator {
+            Terminator::Jump(to) => self.edge(function, id, *to, following),
+            Terminator::Branch(condition, then, otherwise) => {
+                self.load(*condition);
+                if function.phi_moves(id, *otherwise).is_empty() {
+                    self.jump(Op::JumpIfFalse(0), *otherwise);
+                    self.edge(function, id, *then, following);
+                } else {
+                    let to_else = self.emit(Op::JumpIfFalse(0));
+                    self.edge(function, id, *then, None);
+                    let target = self.state.code.len() as u32;
+                    if let Op::JumpIfFalse(to) = &mut self.state.code[to_else] {
+                        *to = target;
+                    }
+                    self.edge(function, id, *otherwise, following);
+                }
+            }
+            Terminator::Return(Some(value)) => {
+                self.load(*value);
+                self.emit(Op::Ret);
+            }
+            // Control never reaches `unreachable`, but the code after it
+            // must still end in a return.
+            Terminator::Return(None) | Terminator::Unreachable => {
+                self.emit(Op::Unit);
+                self.emit(Op::Ret);
+            }
+            Terminator::NoMatch(location) => self.emit_at(Op::NoMatch, location.as_ref()),
+        }
+    }
+
+    /// Emits the way from `from` to `to`: the phi moves of the edge, then a
+    /// jump unless `to` is emitted next.
+    fn edge(&mut self, function: &ir::Function, from: BlockId, to: BlockId, following: Option<BlockId>) {
+        // Every source is loaded before any phi is stored, since a phi may
+        // be the source of another on a loop's back edge.
+        let moves = function.phi_moves(from, to);
+        for (_, source) in &moves {
+            self.load(*source);
+        }
+        for (phi, _) in moves.iter().rev() {
+            self.store(*phi);
+        }
+        if following != Some(to) {
+            self.jump(Op::Jump(0), to);
+        }
+    }
+
+    fn instruction(&mut self, instruction: &ir::Instruction) {
+        let location = instruction.location.as_ref();
+        for operand in instruction.operands() {
+            self.load(operand);
+        }
+        match &instruction.kind {
+            InstructionKind::Const(constant) => self.constant(constant),
+            InstructionKind::Copy(_) => {}
+            InstructionKind::Unary(op, _) => {
+                self.emit(match op {
+                    ir::UnaryOp::Neg => Op::Neg,
+                    ir::UnaryOp::Not => Op::Not,
+                });
+            }
+            InstructionKind::Binary(op, _, _) => {
+                let op = match op {
+                    ir::BinaryOp::Add => Op::Add,
+                    ir::BinaryOp::Sub => Op::Sub,
+                    ir::BinaryOp::Mul => Op::Mul,
+                    ir::BinaryOp::Div => Op::Div,
+                    ir::BinaryOp::Rem => Op::Mod,
+                    ir::BinaryOp::And => Op::BitAnd,
+                    ir::BinaryOp::Xor => Op::BitXor,
+                    ir::BinaryOp::Shl => Op::Shl,
+                    ir::BinaryOp::Shr => Op::Shr,
+                    ir::BinaryOp::Eq => Op::Eq,
+                    ir::BinaryOp::Ne => Op::Ne,
+                    ir::BinaryOp::Lt => Op::Lt,
+                    ir::BinaryOp::Le => Op::Le,
+                    ir::BinaryOp::Gt => Op::Gt,
+                    ir::BinaryOp::Ge => Op::Ge,
+                };
+                self.emit_at(op, location);
+            }
+            InstructionKind::Call(function, _) => self.emit_at(Op::Call(*function), location),
+            InstructionKind::Phi(_) => unreachable!("phis are stored by their predecessors"),
+            InstructionKind::Struct(fields) => {
+                self.emit(Op::Struct(fields.len() as u16));
+            }
+            InstructionKind::Variant(tag, fields) => {
+                self.emit(Op::Variant(*tag as u16, fields.len() as u16));
+            }
+            InstructionKind::Field(_, index) | InstructionKind::Payload(_, _, index) => {
+                self.emit(Op::Field(*index as u16));
+            }
+            InstructionKind::Tag(_) => {
+                self.emit(Op::Tag);
+            }
+        }
+        match instruction.result {
+            Some(result) => self.store(result),
+            // Calls to functions that return nothing still push a `unit`.
+            None => {
+                self.emit(Op::Pop);
+            }
+        }
+    }
+}
@@ -1,24 +1,705 @@
-use crate::error;
-use crate::tokenizer::*;
-use crate::parser::*;
-use std::fs::*;
-pub fn compile(path: String) {
-    let content: String = String::from_utf8_lossy(&read(path).unwrap()).to_string();
-    let tokens_result = parse(&content);
-    let tokens_vec: Vec<Token>;
-    match tokens_result {
-        Ok(tokens) => tokens_vec = tokens,
-        Err(s) => {
-            error(&s);
-            return;
-        }
-    
-    }
-
-    println!("{:#?}", Parser::new(tokens_vec).parse());
-    
-    
-    
-    
-    
+use crate::bytecode::Program;
+use crate::c_backend;
+use crate::cfg;
+use crate::codegen;
+use crate::diagnostic::{codes, Diagnostic};
+use crate::interpreter;
+use crate::ir;
+#[cfg(feature = "jit")]
+use crate::jit;
+use crate::lower;
+use crate::module::{load, ModuleTree, Resolved, ROOT};
+use crate::mutability;
+use crate::optimize::Pipeline;
+use crate::parser::ProgramObject;
+use crate::resolver;
+use crate::typeck::{self, ItemId, Ty, Types};
+use crate::span::{FileId, SourceMap};
+use crate::symbols;
+use crate::vm;
+use crate::x86_backend;
+use colored::Colorize;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+/// A program that made it through every check, ready to be run or
+/// translated.
+#[derive(Debug)]
+pub(crate) struct Checked {
+    pub sources: SourceMap,
+    pub modules: ModuleTree,
+    pub types: Types,
+}
+
+/// A form of the program `compile` can print instead of the module dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Emit {
+    Bytecode,
+    C,
+    /// x86-64 assembly in GNU syntax.
+    Asm,
+    /// The IR the program lowers to.
+    Ir,
+}
+
+impl Emit {
+    /// The name `--emit=` takes.
+    pub fn name(self) -> &'static str {
+        match self {
+            Emit::Bytecode => "bytecode",
+            Emit::C => "c",
+            Emit::Asm => "asm",
+            Emit::Ir => "ir",
+        }
+    }
+}
+
+/// What `build` translates the program into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Target {
+    /// A `.c4b` file for the VM.
+    Bytecode,
+    /// A C11 source file.
+    C,
+    /// An x86-64 executable, assembled and linked by `cc`.
+    X86_64,
+}
+
+/// What `run` executes the program with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Backend {
+    /// Walks the checked syntax tree.
+    Interpreter,
+    /// Compiles to bytecode first.
+    Vm,
+    /// Compiles the optimized IR to machine code in memory.
+    Jit,
+}
+
+/// Deeper recursion than this is reported as a stack overflow. Every
+/// backend `run` can use shares the limit, so a program overflows under
+/// all of them or none; the interpreter recurses on its thread's stack
+/// and can't safely allow more.
+pub(crate) const MAX_CALL_DEPTH: usize = 20_000;
+
+/// Checks the program and prints it: the module tree by default, or the
+/// form `emit` asks for. An `.c4ir` file is parsed, verified and printed
+/// back instead. Every form is printed from the IR after `pipeline`
+/// optimized it. Returns the exit code: 1 if anything failed to check.
+pub fn compile(path: String, emit: Option<Emit>, pipeline: &Pipeline) -> i32 {
+    if path.ends_with(".c4ir") {
+        if let Some(mut module) = load_ir(&path)
+            && optimize(&mut module, pipeline)
+        {
+            print!("{}", module.print());
+            return 0;
+        }
+        return 1;
+    }
+    if let Some(emit) = emit {
+        let Some((checked, main)) = check_program(&path) else {
+            return 1;
+        };
+        let root = root_name(&checked);
+        let module = match emit {
+            Emit::C => lower_native(&checked, main, pipeline, "C"),
+            Emit::Asm => lower_native(&checked, main, pipeline, "x86-64"),
+            Emit::Bytecode | Emit::Ir => lower_program(&checked, main, pipeline),
+        };
+        let Some(module) = module else {
+            return 1;
+        };
+        match emit {
+            Emit::Bytecode => print!("{}", codegen::compile(&module).disassemble()),
+            Emit::C => print!("{}", c_backend::generate(&module, &root)),
+            Emit::Asm => print!("{}", x86_backend::generate(&module, &root)),
+            Emit::Ir => print!("{}", module.print()),
+        }
+        return 0;
+    }
+    let Some(Checked { modules, types, .. }) = check(&path) else {
+        return 1;
+    };
+    println!("{:#?}", modules);
+    let mut generic: Vec<_> = types.instances.iter().collect();
+    generic.sort_by_key(|(id, _)| **id);
+    for ((module, index), instances) in generic {
+        let name = modules.qualified_name(*module, modules.object(*module, *index).name());
+        let instances: Vec<String> = instances.iter().map(|ty| ty.name(&modules)).collect();
+        println!("`{}` is instantiated for {}", name, instances.join(", "));
+    }
+    0
+}
+
+/// Runs a program and returns the exit code for the process: what `main`
+/// returned, 1 if the program didn't compile, or 101 if it failed while
+/// running. A `.c4b` file is run on the VM without being checked again.
+/// The VM and the JIT run the IR after `pipeline` optimized it.
+pub fn run(path: String, backend: Backend, pipeline: &Pipeline) -> i32 {
+    if path.ends_with(".c4b") {
+        let Some(program) = load_bytecode(&path) else {
+            return 1;
+        };
+        return exit_code(vm::run(&program), None);
+    }
+    let Some((checked, main)) = check_program(&path) else {
+        return 1;
+    };
+    let result = match backend {
+        Backend::Interpreter => interpreter::run(&checked, main),
+        Backend::Vm => match lower_program(&checked, main, pipeline) {
+            Some(module) => vm::run(&codegen::compile(&module)),
+            None => return 1,
+        },
+        Backend::Jit => match lower_native(&checked, main, pipeline, "native code") {
+            Some(module) => return run_jit(&module),
+            None => return 1,
+        },
+    };
+    exit_code(result, Some(&checked.sources))
+}
+
+/// Runs the program's IR with the JIT and returns the exit code.
+#[cfg(feature = "jit")]
+fn run_jit(module: &ir::Module) -> i32 {
+    exit_code(jit::run(module), None)
+}
+
+#[cfg(not(feature = "jit"))]
+fn run_jit(_: &ir::Module) -> i32 {
+    Diagnostic::error("this build of c4 can't run programs with `--jit`")
+        .with_note("help: build c4 with `--features jit`")
+        .emit(None);
+    1
+}
+
+/// Translates the program for `target` and writes it to `output`, or next
+/// to the root file with the target's extension. Every target is
+/// translated from the IR after `pipeline` optimized it. Returns the exit
+/// code.
+pub fn build(path: String, target: Target, output: Option<String>, pipeline: &Pipeline) -> i32 {
+    let Some((checked, main)) = check_program(&path) else {
+        return 1;
+    };
+    let module = match target {
+        Target::Bytecode => lower_program(&checked, main, pipeline),
+        Target::C => lower_native(&checked, main, pipeline, "C"),
+        Target::X86_64 => lower_native(&checked, main, pipeline, "x86-64"),
+    };
+    let Some(module) = module else {
+        return 1;
+    };
+    let (bytes, extension) = match target {
+        Target::Bytecode => (codegen::compile(&module).encode(), "c4b"),
+        Target::C => (c_backend::generate(&module, &root_name(&checked)).into_bytes(), "c"),
+        Target::X86_64 => return build_executable(&module, &root_name(&checked), &path, output),
+    };
+    let output = output.unwrap_or_else(|| Path::new(&path).with_extension(extension).to_string_lossy().into_owned());
+    if let Err(error) = fs::write(&output, bytes) {
+        Diagnostic::error(format!("couldn't write `{}`: {}", output, error)).emit(None);
+        return 1;
+    }
+    println!("wrote {}", output.bright_green());
+    0
+}
+
+/// Generates assembly for the program and has `cc` assemble and link it
+/// into an executable at `output`, or next to the root file without an
+/// extension.
+fn build_executable(module: &ir::Module, root: &str, path: &str, output: Option<String>) -> i32 {
+    let source = x86_backend::generate(module, root);
+    let output = output.unwrap_or_else(|| Path::new(path).with_extension("").to_string_lossy().into_owned());
+    let assembly = format!("{}.s", output);
+    if let Err(error) = fs::write(&assembly, source) {
+        Diagnostic::error(format!("couldn't write `{}`: {}", assembly, error)).emit(None);
+        return 1;
+    }
+    let linked = process::Command::new("cc").args(["-o", &output, &assembly, "-lm"]).output();
+    let _ = fs::remove_file(&assembly);
+    match linked {
+        Ok(result) if result.status.success() => {}
+        Ok(result) => {
+            Diagnostic::error("`cc` couldn't assemble and link the program")
+                .with_note(String::from_utf8_lossy(&result.stderr).trim_end().to_string())
+                .emit(None);
+            return 1;
+        }
+        Err(error) => {
+            Diagnostic::error(format!("couldn't run `cc`: {}", error))
+                .with_note("linking executables needs a C toolchain on the `PATH`")
+                .emit(None);
+            return 1;
+        }
+    }
+    println!("wrote {}", output.bright_green());
+    0
+}
+
+fn load_bytecode(path: &str) -> Option<Program> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            Diagnostic::error(format!("couldn't read `{}`: {}", path, error)).emit(None);
+            return None;
+        }
+    };
+    match Program::decode(&bytes) {
+        Ok(program) => Some(program),
+        Err(reason) => {
+            Diagnostic::error(format!("`{}` isn't a valid bytecode file", path))
+                .with_note(reason)
+                .emit(None);
+            None
+        }
+    }
+}
+
+/// Reads and verifies a module of IR text.
+fn load_ir(path: &str) -> Option<ir::Module> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) => {
+            Diagnostic::error(format!("couldn't read `{}`: {}", path, error)).emit(None);
+            return None;
+        }
+    };
+    let module = match ir::parse(&text) {
+        Ok(module) => module,
+        Err(reason) => {
+            Diagnostic::error(format!("`{}` isn't valid IR", path)).with_note(reason).emit(None);
+            return None;
+        }
+    };
+    match module.verify() {
+        Ok(()) => Some(module),
+        Err(errors) => {
+            malformed_ir(format!("`{}` is malformed", path), errors).emit(None);
+            None
+        }
+    }
+}
+
+/// Lowers the program to IR, checks it is well formed and optimizes it.
+fn lower_program(checked: &Checked, main: usize, pipeline: &Pipeline) -> Option<ir::Module> {
+    let mut module = lower::lower(checked, main);
+    if let Err(errors) = module.verify() {
+        malformed_ir("the IR lowered from the program is malformed", errors).emit(None);
+        return None;
+    }
+    optimize(&mut module, pipeline).then_some(module)
+}
+
+/// Lowers the program for a backend that stores fields inline, which
+/// can't compile a type that contains itself.
+fn lower_native(checked: &Checked, main: usize, pipeline: &Pipeline, target: &str) -> Option<ir::Module> {
+    if let Err(diagnostic) = layout_order(checked, target) {
+        report(&[diagnostic], &checked.sources);
+        return None;
+    }
+    lower_program(checked, main, pipeline)
+}
+
+/// Runs the pipeline over a module, reporting the pass that left it
+/// malformed if one did. Returns whether it went through.
+fn optimize(module: &mut ir::Module, pipeline: &Pipeline) -> bool {
+    match pipeline.run(module) {
+        Ok(()) => true,
+        Err((pass, errors)) => {
+            malformed_ir(format!("the IR is malformed after the `{}` pass", pass.name()), errors).emit(None);
+            false
+        }
+    }
+}
+
+fn malformed_ir(message: impl Into<String>, errors: Vec<String>) -> Diagnostic {
+    errors.into_iter().fold(Diagnostic::error(message), Diagnostic::with_note)
+}
+
+/// The exit code for a finished run, reporting the error it failed with.
+fn exit_code(result: Result<i32, Diagnostic>, sources: Option<&SourceMap>) -> i32 {
+    match result {
+        Ok(code) => code,
+        Err(diagnostic) => {
+            diagnostic.emit(sources);
+            101
+        }
+    }
+}
+
+/// The name of the file the program was compiled from.
+fn root_name(checked: &Checked) -> String {
+    checked.sources.get(FileId(0)).name.clone()
+}
+
+/// Checks the program and finds its `main`, reporting any problems.
+fn check_program(path: &str) -> Option<(Checked, usize)> {
+    let checked = check(path)?;
+    match main_function(&checked) {
+        Ok(main) => Some((checked, main)),
+        Err(diagnostic) => {
+            report(&[diagnostic], &checked.sources);
+            None
+        }
+    }
+}
+
+/// Every struct and enum in the order `target` has to lay them out, or an
+/// error for a type that contains itself.
+pub(crate) fn layout_order(checked: &Checked, target: &str) -> Result<Vec<ItemId>, Diagnostic> {
+    checked.types.layout_order().map_err(|(module, index)| {
+        let name = checked.modules.qualified_name(module, checked.modules.object(module, index).name());
+        Diagnostic::error(format!("`{}` contains itself, so it can't be compiled to {}", name, target))
+            .with_code(codes::UNSIZED_TYPE)
+            .with_primary(checked.modules.definition_span(Resolved::Item(module, index)), "")
+            .with_note(format!("{} stores fields inline, so a type that contains itself would be infinitely big", target))
+            .with_note("help: run the program with `c4 run` instead")
+    })
+}
+
+/// Finds the function a program starts at: `main` in the root file, which
+/// takes nothing and returns either nothing or the `i32` exit code.
+pub(crate) fn main_function(checked: &Checked) -> Result<usize, Diagnostic> {
+    let main = checked
+        .modules
+        .item(ROOT, "main")
+        .filter(|index| matches!(checked.modules.object(ROOT, *index), ProgramObject::Function { .. }));
+    let Some(main) = main else {
+        return Err(Diagnostic::error("there is no `main` function to run")
+            .with_code(codes::MISSING_MAIN)
+            .with_note("help: add `fn main() -> i32 { ... }` to the root file"));
+    };
+    let ProgramObject::Function { arguments, signature_span, .. } = checked.modules.object(ROOT, main) else {
+        unreachable!("`main` was checked to be a function");
+    };
+    let signature = &checked.types.functions[&(ROOT, main)];
+    if !arguments.is_empty() || !matches!(signature.ret, Ty::I32 | Ty::Unit) {
+        return Err(Diagnostic::error("`main` must take no parameters and return `i32` or nothing")
+            .with_code(codes::MISSING_MAIN)
+            .with_primary(*signature_span, "")
+            .with_note("the value `main` returns becomes the exit code"));
+    }
+    Ok(main)
+}
+
+/// Runs every pass over the program rooted at `path` and reports what they
+/// found. Returns `None` if there were errors.
+fn check(path: &str) -> Option<Checked> {
+    let (sources, diagnostics, program) = analyze(path);
+    if report(&diagnostics, &sources) {
+        return None;
+    }
+    let (modules, types) = program?;
+    Some(Checked { sources, modules, types })
+}
+
+/// Runs every pass over the program rooted at `path` and returns what they
+/// found, along with the program unless checking stopped early.
+///
+/// Syntax errors leave holes in the tree, such as a `let` that was skipped,
+/// and the later passes would report their consequences as errors of their
+/// own. So if any file failed to load, lex or parse, checking stops there.
+fn analyze(path: &str) -> (SourceMap, Vec<Diagnostic>, Option<(ModuleTree, Types)>) {
+    let mut sources = SourceMap::new();
+    let (mut modules, mut diagnostics) = load(Path::new(path), &mut sources);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return (sources, diagnostics, None);
+    }
+    diagnostics.extend(modules.resolve_imports());
+    diagnostics.extend(symbols::check_duplicates(&modules));
+    diagnostics.extend(resolver::resolve(&modules));
+    diagnostics.extend(mutability::check(&modules));
+    diagnostics.extend(cfg::check(&modules));
+    let (types, type_diagnostics) = typeck::check(&modules);
+    diagnostics.extend(type_diagnostics);
+    (sources, diagnostics, Some((modules, types)))
+}
+
+/// Emits every diagnostic and returns whether any of them was an error, in
+/// which case compilation should stop.
+fn report(diagnostics: &[Diagnostic], sources: &SourceMap) -> bool {
+    for diagnostic in diagnostics {
+        diagnostic.emit(Some(sources));
+        eprintln!();
+    }
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    match errors {
+        0 => false,
+        1 => {
+            Diagnostic::error("aborting due to 1 previous error").emit(None);
+            true
+        }
+        n => {
+            Diagnostic::error(format!("aborting due to {} previous errors", n)).emit(None);
+            true
+        }
+    }
+}
+
+/// Helpers for testing the passes on programs written inline.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::optimize::Level;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A root file in a temp directory of its own. The directory, and
+    /// anything else written to it, is removed when this is dropped.
+    pub(crate) struct RootFile {
+        path: PathBuf,
+    }
+
+    impl RootFile {
+        pub(crate) fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// Writes another file, at `relative` to the root file's directory.
+        pub(crate) fn with_file(self, relative: &str, source: &str) -> RootFile {
+            let path = self.path.with_file_name(relative);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).expect("the temp directory is writable");
+            }
+            fs::write(&path, source).expect("the temp directory is writable");
+            self
+        }
+    }
+
+    impl Drop for RootFile {
+        fn drop(&mut self) {
+            if let Some(dir) = self.path.parent() {
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+    }
+
+    /// Writes `source` to a root file of its own in the temp directory.
+    pub(crate) fn root_file(source: &str) -> RootFile {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("c4-test-{}-{}", process::id(), FILES.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&dir).expect("the temp directory is writable");
+        let path = dir.join("main.c4l");
+        fs::write(&path, source).expect("the temp directory is writable");
+        RootFile { path }
+    }
+
+    /// The module tree of `source`, which must load and have valid
+    /// imports, for testing one pass at a time.
+    pub(crate) fn modules(source: &str) -> ModuleTree {
+        let mut sources = SourceMap::new();
+        let (mut modules, diagnostics) = load(root_file(source).path(), &mut sources);
+        assert!(diagnostics.is_empty(), "the program should load: {:?}", diagnostics);
+        let diagnostics = modules.resolve_imports();
+        assert!(diagnostics.is_empty(), "the imports should resolve: {:?}", diagnostics);
+        modules
+    }
+
+    /// What `pass` reports for `source`.
+    pub(crate) fn pass_diagnostics(pass: fn(&ModuleTree) -> Vec<Diagnostic>, source: &str) -> Vec<Diagnostic> {
+        pass(&modules(source))
+    }
+
+    /// The codes of what `pass` reports for `source`, in order.
+    pub(crate) fn pass_codes(pass: fn(&ModuleTree) -> Vec<Diagnostic>, source: &str) -> Vec<&'static str> {
+        pass_diagnostics(pass, source).iter().filter_map(|d| d.code).collect()
+    }
+
+    /// Every diagnostic checking `source` produces.
+    pub(crate) fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        analyze(&root_file(source).path().to_string_lossy()).1
+    }
+
+    /// The codes of the diagnostics checking `source` produces, in order.
+    pub(crate) fn codes(source: &str) -> Vec<&'static str> {
+        diagnostics(source).iter().filter_map(|d| d.code).collect()
+    }
+
+    /// Checks `source`, which must be a valid program, and lowers it to
+    /// unoptimized IR.
+    pub(crate) fn lower(source: &str) -> ir::Module {
+        let root = root_file(source);
+        let (checked, main) = check_program(&root.path().to_string_lossy()).expect("the program should check");
+        lower_program(&checked, main, &Pipeline::new(Level::O0)).expect("the program should lower")
+    }
+
+    /// Programs every backend is run on, with the exit code each gives.
+    pub(crate) mod programs {
+        /// Signed and unsigned arithmetic that overflows.
+        pub(crate) const WRAPPING: (&str, i32) = (
+            "
+            fn main() -> i32 {
+                let max | i32 <- 2147483647;
+                let min | i32 <- max + 1;
+                let big | i64 <- 4611686018427387904;
+                let zero | u32 <- 0;
+                let mut score | i32 <- 0;
+                if min == 0 - 2147483647 - 1 { score <- score + 1; }
+                if min / (0 - 1) == min { score <- score + 2; }
+                if big * 4 == 0 { score <- score + 4; }
+                if zero - 1 == 4294967295 { score <- score + 8; }
+                if -min == min { score <- score + 16; }
+                ret score;
+            }",
+            31,
+        );
+
+        /// Structs passed to and returned from functions, and nested.
+        pub(crate) const STRUCTS: (&str, i32) = (
+            "
+            struct point { x | i32, y | i32 }
+            struct line { from | point, to | point }
+
+            fn shift(p | point, by | i32) -> point {
+                ret point { y <- p.y + by, x <- p.x + by };
+            }
+
+            fn length(l | line) -> i32 {
+                ret l.to.x - l.from.x + l.to.y - l.from.y;
+            }
+
+            fn main() -> i32 {
+                let start | point <- point { x <- 1, y <- 2 };
+                let l | line <- line { from <- start, to <- shift(start, 10) };
+                ret length(l) + l.from.y;
+            }",
+            22,
+        );
+
+        /// Enums matched by variant, with tuple and struct payloads.
+        pub(crate) const ENUMS: (&str, i32) = (
+            "
+            enum shape { circle(i32), rect { w | i32, h | i32 }, empty }
+
+            fn area(s | shape) -> i32 {
+                ret @s {
+                    shape.circle(r) -> 3 * r * r;
+                    shape.rect { w, h } -> w * h;
+                    shape.empty -> 0;
+                };
+            }
+
+            fn main() -> i32 {
+                ret area(shape.circle(2)) + area(shape.rect { w <- 3, h <- 5 }) + area(shape.empty);
+            }",
+            27,
+        );
+
+        /// A generic function called with four types of `num`.
+        pub(crate) const NUM_INSTANCES: (&str, i32) = (
+            "
+            fn pow(base | num, e | i32) -> num {
+                if e == 0 { ret 1; }
+                ret base * pow(base, e - 1);
+            }
+
+            fn main() -> i32 {
+                let big | i64 <- pow(2, 40);
+                let half | f64 <- pow(0.5, 3);
+                let small | u32 <- pow(3, 3);
+                if big == 1099511627776 && half == 0.125 && small == 27 { ret pow(3, 3); }
+                ret 1;
+            }",
+            27,
+        );
+
+        /// Divides by zero on line 3, column 21.
+        pub(crate) const DIVIDE_BY_ZERO: (&str, i32) = (
+            "
+            fn divide(a | i32, b | i32) -> i32 {
+                ret a / b;
+            }
+
+            fn main() -> i32 {
+                ret divide(1, 0);
+            }",
+            101,
+        );
+
+        /// Strings that only differ after a NUL.
+        pub(crate) const STRING_NULS: (&str, i32) = (
+            "
+            fn main() -> i32 {
+                let a | string <- \"a\\0b\";
+                let b | string <- \"a\";
+                let mut score | i32 <- 0;
+                if a != b { score <- score + 1; }
+                if a == b { score <- score + 2; }
+                if a != \"a\\0c\" { score <- score + 4; }
+                if \"\\0\" != \"\" { score <- score + 8; }
+                if a == \"a\\0b\" { score <- score + 16; }
+                ret score;
+            }",
+            29,
+        );
+
+        /// More integer, float and struct arguments than fit in registers.
+        pub(crate) const MANY_ARGUMENTS: (&str, i32) = (
+            "
+            struct pair { a | i64, b | i64 }
+
+            fn ints(a | i64, b | i64, c | i64, d | i64, e | i64, f | i64, g | i64, h | i64) -> i64 {
+                ret a - b + c - d + e - f + g * h;
+            }
+
+            fn floats(a | f64, b | f64, c | f64, d | f64, e | f64, f | f64, g | f64, h | f64, i | f64, j | f64) -> f64 {
+                ret a + b * 2.0 + c * 3.0 + d * 4.0 + e * 5.0 + f * 6.0 + g * 7.0 + h * 8.0 + i * 9.0 + j * 10.0;
+            }
+
+            fn mixed(x | i32, p | pair, y | f32, q | pair, z | i32, r | pair, s | pair, t | pair, u | pair, w | f32) -> i64 {
+                ret p.a + q.b * 10 + r.a * 100 + s.b * 1000 + t.a * 10000 + u.b * 100000;
+            }
+
+            fn main() -> i32 {
+                let mut score | i32 <- 0;
+                if ints(1, 2, 3, 4, 5, 6, 7, 8) == 53 { score <- score + 1; }
+                if floats(1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 2.0) == 65.0 { score <- score + 2; }
+                let p | pair <- pair { a <- 1, b <- 2 };
+                let q | pair <- pair { a <- 3, b <- 4 };
+                if mixed(0, p, 0.5, q, 0, p, q, p, q, 1.5) == 414141 { score <- score + 4; }
+                ret score;
+            }",
+            7,
+        );
+
+        pub(crate) const ALL: [(&str, i32); 7] =
+            [WRAPPING, STRUCTS, ENUMS, NUM_INSTANCES, DIVIDE_BY_ZERO, STRING_NULS, MANY_ARGUMENTS];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimize::Level;
+
+    #[test]
+    fn syntax_errors_stop_checking() {
+        // Were the broken `let` checked, `x` would be reported as undefined
+        // and `main` as not returning.
+        assert_eq!(testing::codes("fn main() -> i32 { let x | i32 <- ; ret x; }"), [codes::UNEXPECTED_TOKEN]);
+        assert_eq!(testing::codes("fn main() -> i32 { let x | i32 <- 1; ret y; }"), [codes::UNDEFINED_NAME]);
+    }
+
+    #[test]
+    fn an_unknown_type_is_pointed_at_on_its_own() {
+        let source = "fn main() -> i32 { let s | str <- \"x\"; ret 0; }";
+        let diagnostics = testing::diagnostics(source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::UNKNOWN_TYPE));
+        let span = diagnostics[0].labels[0].span;
+        assert_eq!(&source[span.start..span.end], "str");
+    }
+
+    #[test]
+    fn checking_exits_with_1_after_errors() {
+        let pipeline = Pipeline::new(Level::O0);
+        let compile_root = |source: &str, emit| {
+            let root = testing::root_file(source);
+            compile(root.path().to_string_lossy().into_owned(), emit, &pipeline)
+        };
+        assert_eq!(compile_root("fn main() -> i32 { ret 0; }", None), 0);
+        assert_eq!(compile_root("fn main() -> i32 { ret true; }", None), 1);
+        assert_eq!(compile_root("fn main() -> i32 { ret true; }", Some(Emit::Ir)), 1);
+        assert_eq!(compile_root("fn main() -> i32 { ret 0; }", Some(Emit::Ir)), 0);
+    }
 }
@@ -0,0 +1,414 @@
+use crate::span::{FileId, SourceFile, SourceMap, Span};
+use colored::{ColoredString, Colorize};
+
+/// Stable codes for every diagnostic the compiler can produce, so users can
+/// search for them and tests can match on them.
+pub(crate) mod codes {
+    pub const UNRECOGNISED_TEXT: &str = "E0001";
+    pub const UNEXPECTED_TOKEN: &str = "E0002";
+    pub const UNEXPECTED_EOF: &str = "E0003";
+    pub const INVALID_NUMBER: &str = "E0004";
+    pub const CHAINED_COMPARISON: &str = "E0005";
+    pub const BREAK_OUTSIDE_LOOP: &str = "E0006";
+    pub const MODULE_CYCLE: &str = "E0007";
+    pub const MISSING_MODULE: &str = "E0008";
+    pub const DUPLICATE_MODULE: &str = "E0009";
+    pub const UNRESOLVED_IMPORT: &str = "E0010";
+    pub const PRIVATE_ITEM: &str = "E0011";
+    pub const UNDEFINED_NAME: &str = "E0012";
+    pub const EXPECTED_VALUE: &str = "E0013";
+    pub const DUPLICATE_BINDING: &str = "E0014";
+    pub const INCONSISTENT_BINDINGS: &str = "E0015";
+    pub const MISMATCHED_TYPES: &str = "E0016";
+    pub const UNKNOWN_TYPE: &str = "E0017";
+    pub const ARGUMENT_COUNT: &str = "E0018";
+    pub const INVALID_OPERANDS: &str = "E0019";
+    pub const NO_FIELD: &str = "E0020";
+    pub const MISSING_FIELD: &str = "E0021";
+    pub const NOT_CALLABLE: &str = "E0022";
+    pub const LITERAL_OUT_OF_RANGE: &str = "E0023";
+    pub const DUPLICATE_DEFINITION: &str = "E0024";
+    pub const ASSIGN_TO_IMMUTABLE: &str = "E0025";
+    pub const MISSING_RETURN: &str = "E0026";
+    pub const MISSING_MAIN: &str = "E0027";
+    pub const UNSIZED_TYPE: &str = "E0028";
+
+    pub const SHADOWED_BINDING: &str = "W0001";
+    pub const UNUSED_MUT: &str = "W0002";
+    pub const UNREACHABLE_CODE: &str = "W0003";
+
+    pub const INFINITE_LOOP: &str = "N0001";
+
+    pub const DIVISION_BY_ZERO: &str = "R0001";
+    pub const STACK_OVERFLOW: &str = "R0002";
+    pub const NO_MATCHING_ARM: &str = "R0003";
+    pub const INVALID_BYTECODE: &str = "R0004";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+/// A span of source text with a message attached. Primary labels mark where
+/// the problem is; secondary labels point at related code.
+#[derive(Debug, Clone)]
+pub(crate) struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Diagnostic {
+    pub severity: Severity,
+    pub code: Option<&'static str>,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code: None,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, message)
+    }
+
+    pub fn note(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Note, message)
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Diagnostic {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.labels.push(Label { span, message: message.into(), primary: true });
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.labels.push(Label { span, message: message.into(), primary: false });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// The span of the first primary label, if any.
+    pub fn primary_span(&self) -> Option<Span> {
+        self.labels.iter().find(|l| l.primary).map(|l| l.span)
+    }
+
+    /// Writes the rendered diagnostic to stderr.
+    pub fn emit(&self, sources: Option<&SourceMap>) {
+        eprint!("{}", self.render(sources));
+    }
+
+    /// Renders the diagnostic the way rustc does: a header line, the
+    /// `file:line:col` of the primary label, then every labelled source line
+    /// with its spans underlined, followed by any notes. Labels in other
+    /// files get their own `:::` section.
+    pub fn render(&self, sources: Option<&SourceMap>) -> String {
+        let mut out = String::new();
+        let header = match self.code {
+            Some(code) => format!("{}[{}]", self.severity_name(), code),
+            None => self.severity_name().to_string(),
+        };
+        out.push_str(&format!("{}{} {}\n", self.paint(&header), ":".bold(), self.message.bold()));
+
+        let sources = match sources {
+            Some(sources) if !self.labels.is_empty() => sources,
+            _ => {
+                for note in &self.notes {
+                    out.push_str(&format!("  {} {}\n", "=".blue().bold(), note_text(note)));
+                }
+                return out;
+            }
+        };
+
+        let gutter_width = self
+            .labels
+            .iter()
+            .map(|l| sources.get(l.span.file).line_col(l.span.start).0.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = " ".repeat(gutter_width);
+
+        // The primary label's file comes first, then the rest in the order
+        // their labels were added.
+        let mut files: Vec<FileId> = Vec::new();
+        if let Some(span) = self.primary_span() {
+            files.push(span.file);
+        }
+        for label in &self.labels {
+            if !files.contains(&label.span.file) {
+                files.push(label.span.file);
+            }
+        }
+
+        for (i, file) in files.into_iter().enumerate() {
+            let source = sources.get(file);
+            let labels: Vec<&Label> = self.labels.iter().filter(|l| l.span.file == file).collect();
+            let location_span = labels
+                .iter()
+                .find(|l| l.primary)
+                .unwrap_or(&labels[0])
+                .span;
+            let arrow = if i == 0 { "-->" } else { ":::" };
+            out.push_str(&format!("{}{} {}\n", gutter, arrow.blue().bold(), source.location(location_span)));
+            self.render_lines(&mut out, source, &labels, gutter_width);
+        }
+
+        if !self.notes.is_empty() {
+            out.push_str(&format!("{} {}\n", gutter, "|".blue().bold()));
+        }
+        for note in &self.notes {
+            out.push_str(&format!("{} {} {}\n", gutter, "=".blue().bold(), note_text(note)));
+        }
+        out
+    }
+
+    /// Every line of `source` that one of `labels` starts on, with the labels
+    /// underlined beneath it.
+    fn render_lines(&self, out: &mut String, source: &SourceFile, labels: &[&Label], gutter_width: usize) {
+        let gutter = " ".repeat(gutter_width);
+        let bar = "|".blue().bold();
+        out.push_str(&format!("{} {}\n", gutter, bar));
+
+        let mut lines: Vec<usize> = labels.iter().map(|l| source.line_col(l.span.start).0).collect();
+        lines.sort();
+        lines.dedup();
+
+        let mut previous_line: Option<usize> = None;
+        for line in lines {
+            if let Some(previous) = previous_line
+                && line > previous + 1
+            {
+                out.push_str(&format!("{}\n", "...".blue().bold()));
+            }
+            previous_line = Some(line);
+
+            let text = source.line_text(line);
+            out.push_str(&format!(
+                "{} {} {}\n",
+                format!("{:>width$}", line, width = gutter_width).blue().bold(),
+                bar,
+                text.replace('\t', TAB)
+            ));
+
+            let mut line_labels: Vec<&Label> = labels
+                .iter()
+                .copied()
+                .filter(|l| source.line_col(l.span.start).0 == line)
+                .collect();
+            line_labels.sort_by_key(|l| (!l.primary, l.span.start));
+            for label in line_labels {
+                let (_, start_col) = source.line_col(label.span.start);
+                let (end_line, end_col) = source.line_col(label.span.end);
+                let line_len = text.chars().count();
+                let end_col = if end_line != line { line_len + 1 } else { end_col };
+                let start_col = display_column(text, start_col);
+                let end_col = display_column(text, end_col);
+                let width = end_col.saturating_sub(start_col).max(1);
+                let marker = if label.primary { "^" } else { "-" }.repeat(width);
+                let underline = if label.message.is_empty() {
+                    marker
+                } else {
+                    format!("{} {}", marker, label.message)
+                };
+                let underline = if label.primary {
+                    self.paint(&underline)
+                } else {
+                    underline.blue().bold()
+                };
+                out.push_str(&format!("{} {} {}{}\n", gutter, bar, " ".repeat(start_col - 1), underline));
+            }
+        }
+    }
+
+    fn severity_name(&self) -> &'static str {
+        match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+
+    fn paint(&self, text: &str) -> ColoredString {
+        match self.severity {
+            Severity::Error => text.red().bold(),
+            Severity::Warning => text.yellow().bold(),
+            Severity::Note => text.green().bold(),
+        }
+    }
+}
+
+/// Tabs are drawn as this many spaces so underlines can line up with them.
+const TAB: &str = "    ";
+
+/// Converts a 1-based character column into the column it is drawn at once
+/// tabs are expanded.
+fn display_column(text: &str, column: usize) -> usize {
+    let tabs = text.chars().take(column - 1).filter(|c| *c == '\t').count();
+    column + tabs * (TAB.len() - 1)
+}
+
+/// Notes that start with `help:` keep their own prefix; everything else is
+/// shown as a `note:`.
+fn note_text(note: &str) -> String {
+    match note.strip_prefix("help: ") {
+        Some(help) => format!("{} {}", "help:".bold(), help),
+        None => format!("{} {}", "note:".bold(), note),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A source map with `source` as its only file.
+    fn sources(source: &str) -> SourceMap {
+        let mut sources = SourceMap::new();
+        sources.add("main.c4l".to_string(), source.to_string());
+        sources
+    }
+
+    /// The span of the `index`th occurrence of `text` in `source`.
+    fn span_of(source: &str, text: &str, index: usize) -> Span {
+        let start = source.match_indices(text).nth(index).expect("the text should occur").0;
+        Span::new(FileId(0), start, start + text.len())
+    }
+
+    fn render(diagnostic: &Diagnostic, sources: Option<&SourceMap>) -> String {
+        colored::control::set_override(false);
+        diagnostic.render(sources)
+    }
+
+    #[test]
+    fn a_single_line_label() {
+        let source = "fn main() -> i32 {\n    ret x;\n}";
+        let diagnostic = Diagnostic::error("cannot find value `x` in this scope")
+            .with_code(codes::UNDEFINED_NAME)
+            .with_primary(span_of(source, "x", 0), "not found in this scope");
+        assert_eq!(
+            render(&diagnostic, Some(&sources(source))),
+            "error[E0012]: cannot find value `x` in this scope
+ --> main.c4l:2:9
+  |
+2 |     ret x;
+  |         ^ not found in this scope
+"
+        );
+    }
+
+    #[test]
+    fn a_label_over_several_lines_is_underlined_to_the_end_of_its_first() {
+        let source = "fn f() -> i32 {\n    ret 1 +\n        2;\n}";
+        let start = span_of(source, "1 +", 0);
+        let diagnostic = Diagnostic::warning("long").with_primary(start.to(span_of(source, "2;", 0)), "this");
+        assert_eq!(
+            render(&diagnostic, Some(&sources(source))),
+            "warning: long
+ --> main.c4l:2:9
+  |
+2 |     ret 1 +
+  |         ^^^ this
+"
+        );
+    }
+
+    #[test]
+    fn secondary_labels_on_distant_lines_are_separated_by_an_ellipsis() {
+        let source = "fn add() {}\n\n\n\n\n\n\n\n\nfn add() {}\nfn other() {}";
+        let diagnostic = Diagnostic::error("the name `add` is defined more than once")
+            .with_code(codes::DUPLICATE_DEFINITION)
+            .with_primary(span_of(source, "add", 1), "redefined here")
+            .with_secondary(span_of(source, "add", 0), "first defined here")
+            .with_note("help: rename one of them");
+        assert_eq!(
+            render(&diagnostic, Some(&sources(source))),
+            "error[E0024]: the name `add` is defined more than once
+  --> main.c4l:10:4
+   |
+ 1 | fn add() {}
+   |    --- first defined here
+...
+10 | fn add() {}
+   |    ^^^ redefined here
+   |
+   = help: rename one of them
+"
+        );
+    }
+
+    #[test]
+    fn labels_on_adjacent_lines_have_no_ellipsis() {
+        let source = "let a | i32 <- 1;\nlet b | bool <- a;";
+        let diagnostic = Diagnostic::error("mismatched types")
+            .with_primary(span_of(source, "a", 1), "expected `bool`")
+            .with_secondary(span_of(source, "i32", 0), "declared here");
+        assert_eq!(
+            render(&diagnostic, Some(&sources(source))),
+            "error: mismatched types
+ --> main.c4l:2:17
+  |
+1 | let a | i32 <- 1;
+  |         --- declared here
+2 | let b | bool <- a;
+  |                 ^ expected `bool`
+"
+        );
+    }
+
+    #[test]
+    fn tabs_are_expanded_and_underlines_follow_them() {
+        let source = "fn f() {\n\t\tret y;\n}";
+        let diagnostic = Diagnostic::error("oops").with_primary(span_of(source, "y", 0), "here");
+        assert_eq!(
+            render(&diagnostic, Some(&sources(source))),
+            "error: oops
+ --> main.c4l:2:7
+  |
+2 |         ret y;
+  |             ^ here
+"
+        );
+    }
+
+    #[test]
+    fn without_sources_only_the_header_and_notes_are_shown() {
+        let diagnostic = Diagnostic::error("attempt to divide by zero")
+            .with_code(codes::DIVISION_BY_ZERO)
+            .with_primary(Span::new(FileId(0), 0, 1), "ignored")
+            .with_note("in `main` at main.c4l:3:12");
+        assert_eq!(
+            render(&diagnostic, None),
+            "error[R0001]: attempt to divide by zero\n  = note: in `main` at main.c4l:3:12\n"
+        );
+        let aborting = Diagnostic::error("aborting due to 1 previous error");
+        assert_eq!(render(&aborting, None), "error: aborting due to 1 previous error\n");
+    }
+}
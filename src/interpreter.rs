@@ -0,0 +1,532 @@
+use crate::compiler::{Checked, MAX_CALL_DEPTH};
+use crate::diagnostic::{codes, Diagnostic};
+use crate::module::{ModuleId, Resolved, ROOT};
+use crate::parser::*;
+use crate::span::Span;
+use crate::typeck::{ItemId, Ty};
+use std::collections::HashMap;
+
+/// A value while the program runs. Integer arithmetic wraps on overflow.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    I32(i32),
+    I64(i64),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Char(char),
+    String(String),
+    Struct { fields: Vec<(String, Value)> },
+    /// The fields of a tuple variant are named `0`, `1`, ...
+    Enum { variant: String, fields: Vec<(String, Value)> },
+    /// What a function without a return type returns.
+    Unit,
+}
+
+impl Value {
+    /// An integer literal as a value of type `ty`.
+    fn from_int(value: i128, ty: Ty) -> Value {
+        match ty {
+            Ty::I64 => Value::I64(value as i64),
+            Ty::U32 => Value::U32(value as u32),
+            Ty::U64 => Value::U64(value as u64),
+            Ty::F32 => Value::F32(value as f32),
+            Ty::F64 => Value::F64(value as f64),
+            _ => Value::I32(value as i32),
+        }
+    }
+
+    fn from_float(value: f64, ty: Ty) -> Value {
+        match ty {
+            Ty::F32 => Value::F32(value as f32),
+            _ => Value::F64(value),
+        }
+    }
+
+    fn as_integer(&self) -> Option<i128> {
+        match *self {
+            Value::I32(v) => Some(v.into()),
+            Value::I64(v) => Some(v.into()),
+            Value::U32(v) => Some(v.into()),
+            Value::U64(v) => Some(v.into()),
+            _ => None,
+        }
+    }
+
+    /// An integer of the same type as `self`.
+    fn with_integer(&self, value: i128) -> Value {
+        match self {
+            Value::I64(_) => Value::I64(value as i64),
+            Value::U32(_) => Value::U32(value as u32),
+            Value::U64(_) => Value::U64(value as u64),
+            _ => Value::I32(value as i32),
+        }
+    }
+
+    fn as_float(&self) -> Option<f64> {
+        match *self {
+            Value::F32(v) => Some(v.into()),
+            Value::F64(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// How a statement finished.
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+type Eval<T> = Result<T, Diagnostic>;
+
+/// The interpreter recurses once per nested call and expression, so it
+/// runs on a thread with a stack big enough for `MAX_CALL_DEPTH` calls.
+const STACK_SIZE: usize = 1 << 30;
+
+/// Runs the program's `main` function, the item at `main` in the root
+/// module, and returns its result as the process exit code: the `i32` it
+/// returns, or 0 if it returns nothing.
+pub(crate) fn run(checked: &Checked, main: usize) -> Eval<i32> {
+    let ProgramObject::Function { signature_span, .. } = checked.modules.object(ROOT, main) else {
+        unreachable!("`main` was checked to be a function");
+    };
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new().stack_size(STACK_SIZE).spawn_scoped(scope, || {
+            let mut interpreter = Interpreter { checked, frames: Vec::new() };
+            match interpreter.call((ROOT, main), Vec::new(), None, *signature_span)? {
+                Value::I32(code) => Ok(code),
+                _ => Ok(0),
+            }
+        });
+        match thread {
+            Ok(thread) => thread.join().unwrap_or_else(|_| Err(Diagnostic::error("the interpreter crashed"))),
+            Err(error) => Err(Diagnostic::error(format!("couldn't start the interpreter: {}", error))),
+        }
+    })
+}
+
+/// The state of one function call.
+struct Frame {
+    /// Where the function is declared, for resolving the names it uses.
+    module: ModuleId,
+    /// What `num` stands for in this call of a generic function.
+    num: Option<Ty>,
+    /// Innermost scope last.
+    scopes: Vec<HashMap<String, Value>>,
+}
+
+struct Interpreter<'c> {
+    checked: &'c Checked,
+    frames: Vec<Frame>,
+}
+
+impl<'c> Interpreter<'c> {
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("code only runs inside a function call")
+    }
+
+    fn call(&mut self, function: ItemId, args: Vec<Value>, num: Option<Ty>, span: Span) -> Eval<Value> {
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return Err(runtime_error(codes::STACK_OVERFLOW, "stack overflow", span)
+                .with_note(format!("calls were nested more than {} deep", MAX_CALL_DEPTH)));
+        }
+        let (module, index) = function;
+        let ProgramObject::Function { arguments, statements, .. } = self.checked.modules.object(module, index) else {
+            unreachable!("only functions are called");
+        };
+        let params = arguments.iter().map(|p| p.name.clone()).zip(args).collect();
+        self.frames.push(Frame { module, num, scopes: vec![params] });
+        for statement in statements {
+            if let Flow::Return(value) = self.exec(statement)? {
+                self.frames.pop();
+                return Ok(value);
+            }
+        }
+        self.frames.pop();
+        Ok(Value::Unit)
+    }
+
+    fn exec(&mut self, statement: &'c Statement) -> Eval<Flow> {
+        match &statement.kind {
+            StatementKind::Let { name, value, .. } => {
+                let value = self.eval(value)?;
+                self.declare(name, value);
+            }
+            StatementKind::Set { name, new_value } => {
+                let value = self.eval(new_value)?;
+                let scopes = &mut self.frame().scopes;
+                if let Some(slot) = scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name)) {
+                    *slot = value;
+                }
+            }
+            StatementKind::Ret { value } => return Ok(Flow::Return(self.eval(value)?)),
+            StatementKind::FunctionCall { call } => {
+                self.eval(call)?;
+            }
+            StatementKind::If { condition, then_block, else_branch } => {
+                if self.eval(condition)? == Value::Bool(true) {
+                    return self.exec_block(&then_block.statements, Vec::new());
+                }
+                if let Some(else_branch) = else_branch {
+                    return self.exec(else_branch);
+                }
+            }
+            StatementKind::Block(block) => return self.exec_block(&block.statements, Vec::new()),
+            StatementKind::While { condition, body } => {
+                while self.eval(condition)? == Value::Bool(true) {
+                    match self.exec_block(&body.statements, Vec::new())? {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Normal | Flow::Continue => {}
+                    }
+                }
+            }
+            StatementKind::For { variable, iterable, body } => {
+                let ForIterable::Range { start, end } = iterable;
+                let start = self.eval(start)?;
+                let end = self.eval(end)?;
+                let (Some(from), Some(to)) = (start.as_integer(), end.as_integer()) else {
+                    unreachable!("the type checker only allows integer ranges");
+                };
+                for i in from..to {
+                    let bindings = vec![(variable.clone(), start.with_integer(i))];
+                    match self.exec_block(&body.statements, bindings)? {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Normal | Flow::Continue => {}
+                    }
+                }
+            }
+            StatementKind::Break => return Ok(Flow::Break),
+            StatementKind::Continue => return Ok(Flow::Continue),
+            StatementKind::Match { scrutinee, arms } => {
+                let value = self.eval(scrutinee)?;
+                for arm in arms {
+                    let mut bindings = Vec::new();
+                    if self.matches(&arm.pattern, &value, &mut bindings) {
+                        self.frame().scopes.push(bindings.into_iter().collect());
+                        let flow = self.exec(&arm.body);
+                        self.frame().scopes.pop();
+                        return flow;
+                    }
+                }
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Runs statements in a new scope that starts with `bindings`.
+    fn exec_block(&mut self, statements: &'c [Statement], bindings: Vec<(String, Value)>) -> Eval<Flow> {
+        self.frame().scopes.push(bindings.into_iter().collect());
+        for statement in statements {
+            let flow = self.exec(statement)?;
+            if !matches!(flow, Flow::Normal) {
+                self.frame().scopes.pop();
+                return Ok(flow);
+            }
+        }
+        self.frame().scopes.pop();
+        Ok(Flow::Normal)
+    }
+
+    fn eval(&mut self, expression: &'c Expression) -> Eval<Value> {
+        Ok(match &expression.kind {
+            ExpressionKind::IntLiteral(value) => Value::from_int(*value, self.type_of(expression.span)),
+            ExpressionKind::FloatLiteral(value) => Value::from_float(*value, self.type_of(expression.span)),
+            ExpressionKind::StringLiteral(value) => Value::String(value.clone()),
+            ExpressionKind::CharLiteral(value) => Value::Char(*value),
+            ExpressionKind::BoolLiteral(value) => Value::Bool(*value),
+            ExpressionKind::Variable(name) => match self.local(name) {
+                Some(value) => value,
+                None => self.path_value(std::slice::from_ref(name)),
+            },
+            ExpressionKind::FieldAccess { object, field } => {
+                if let Some(path) = expression.as_path()
+                    && self.local(&path[0]).is_none()
+                {
+                    return Ok(self.path_value(&path));
+                }
+                match self.eval(object)? {
+                    Value::Struct { fields } => fields.into_iter().find(|(name, _)| name == field).map(|(_, v)| v).unwrap_or(Value::Unit),
+                    _ => unreachable!("the type checker only allows field access on structs"),
+                }
+            }
+            ExpressionKind::Binary { op: BinaryOp::And, left, right } => {
+                Value::Bool(self.eval(left)? == Value::Bool(true) && self.eval(right)? == Value::Bool(true))
+            }
+            ExpressionKind::Binary { op: BinaryOp::Or, left, right } => {
+                Value::Bool(self.eval(left)? == Value::Bool(true) || self.eval(right)? == Value::Bool(true))
+            }
+            ExpressionKind::Binary { op, left, right } => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                binary(*op, left, right, expression.span)?
+            }
+            ExpressionKind::Unary { op: UnaryOp::Neg, operand } if matches!(operand.kind, ExpressionKind::IntLiteral(_)) => {
+                let ExpressionKind::IntLiteral(value) = operand.kind else {
+                    unreachable!();
+                };
+                Value::from_int(value.wrapping_neg(), self.type_of(expression.span))
+            }
+            ExpressionKind::Unary { op, operand } => unary(*op, self.eval(operand)?),
+            ExpressionKind::FunctionCall { callee, args } => return self.eval_call(callee, args, expression.span),
+            ExpressionKind::If { condition, then_value, else_value } => {
+                if self.eval(condition)? == Value::Bool(true) {
+                    self.eval(then_value)?
+                } else {
+                    self.eval(else_value)?
+                }
+            }
+            ExpressionKind::Match { scrutinee, arms } => {
+                let value = self.eval(scrutinee)?;
+                for arm in arms {
+                    let mut bindings = Vec::new();
+                    if self.matches(&arm.pattern, &value, &mut bindings) {
+                        self.frame().scopes.push(bindings.into_iter().collect());
+                        let result = self.eval(&arm.body);
+                        self.frame().scopes.pop();
+                        return result;
+                    }
+                }
+                return Err(runtime_error(codes::NO_MATCHING_ARM, "no match arm matched the value", expression.span)
+                    .with_note("help: add a `~` arm to handle every other value"));
+            }
+            ExpressionKind::StructLiteral { path, fields } => {
+                let mut values = Vec::new();
+                for field in fields {
+                    values.push((field.name.clone(), self.eval(&field.value)?));
+                }
+                match self.resolve(path) {
+                    Some((_, [variant])) => Value::Enum { variant: variant.clone(), fields: values },
+                    _ => Value::Struct { fields: values },
+                }
+            }
+        })
+    }
+
+    fn eval_call(&mut self, callee: &'c Expression, args: &'c [Expression], span: Span) -> Eval<Value> {
+        let mut values = Vec::new();
+        for arg in args {
+            values.push(self.eval(arg)?);
+        }
+        let path = callee.as_path().expect("the type checker only allows calling functions and variants");
+        match self.resolve(&path) {
+            Some((Resolved::Item(module, index), [])) => {
+                let num = self.checked.types.generic_calls.get(&callee.span).map(|ty| self.concrete(*ty));
+                self.call((module, index), values, num, span)
+            }
+            Some((_, [variant])) => Ok(Value::Enum {
+                variant: variant.clone(),
+                fields: values.into_iter().enumerate().map(|(i, v)| (i.to_string(), v)).collect(),
+            }),
+            _ => unreachable!("the type checker only allows calling functions and variants"),
+        }
+    }
+
+    /// What a path that doesn't start with a local refers to, and the
+    /// segments left over after it, such as an enum variant's name.
+    fn resolve<'a>(&mut self, path: &'a [String]) -> Option<(Resolved, &'a [String])> {
+        let module = self.frame().module;
+        let (resolved, used) = self.checked.modules.lookup_prefix(module, path)?;
+        Some((resolved, &path[used..]))
+    }
+
+    /// The value of a path that isn't a local: a unit enum variant.
+    fn path_value(&mut self, path: &[String]) -> Value {
+        match self.resolve(path) {
+            Some((_, [variant])) => Value::Enum { variant: variant.clone(), fields: Vec::new() },
+            _ => unreachable!("the type checker only allows unit variants as values"),
+        }
+    }
+
+    /// The type the type checker gave the expression at `span`, with `num`
+    /// replaced by the type of the current call.
+    fn type_of(&mut self, span: Span) -> Ty {
+        let ty = self.checked.types.expressions.get(&span).copied().unwrap_or(Ty::I32);
+        self.concrete(ty)
+    }
+
+    fn concrete(&mut self, ty: Ty) -> Ty {
+        match ty {
+            Ty::Num => self.frame().num.unwrap_or(Ty::I32),
+            ty => ty,
+        }
+    }
+
+    /// Whether `value` matches `pattern`, adding the variables it binds to
+    /// `bindings`.
+    fn matches(&mut self, pattern: &Pattern, value: &Value, bindings: &mut Vec<(String, Value)>) -> bool {
+        match &pattern.kind {
+            PatternKind::Wildcard => true,
+            PatternKind::Binding(name) => {
+                bindings.push((name.clone(), value.clone()));
+                true
+            }
+            PatternKind::Literal(literal) => literal_matches(literal, value),
+            PatternKind::Range { start, end } => match (start, end, value) {
+                (Literal::Char(start), Literal::Char(end), Value::Char(c)) => start <= c && c < end,
+                (Literal::Int(start), Literal::Int(end), value) => {
+                    value.as_integer().is_some_and(|v| *start <= v && v < *end)
+                }
+                (Literal::Float(start), Literal::Float(end), value) => value.as_float().is_some_and(|v| *start <= v && v < *end),
+                _ => false,
+            },
+            PatternKind::Or(alternatives) => {
+                let before = bindings.len();
+                for alternative in alternatives {
+                    if self.matches(alternative, value, bindings) {
+                        return true;
+                    }
+                    bindings.truncate(before);
+                }
+                false
+            }
+            PatternKind::Constructor { path, fields: patterns } => {
+                let fields = match (self.resolve(path), value) {
+                    (Some((_, [variant])), Value::Enum { variant: actual, fields }) => {
+                        if variant != actual {
+                            return false;
+                        }
+                        fields
+                    }
+                    (Some((_, [])), Value::Struct { fields }) => fields,
+                    _ => return false,
+                };
+                let field = |name: &str| fields.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+                match patterns {
+                    PatternFields::Unit => true,
+                    PatternFields::Tuple(patterns) => patterns.iter().enumerate().all(|(i, pattern)| {
+                        field(&i.to_string()).is_some_and(|value| self.matches(pattern, value, bindings))
+                    }),
+                    PatternFields::Struct(patterns) => patterns
+                        .iter()
+                        .all(|(name, pattern)| field(name).is_some_and(|value| self.matches(pattern, value, bindings))),
+                }
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, value: Value) {
+        if let Some(scope) = self.frame().scopes.last_mut() {
+            scope.insert(name.to_string(), value);
+        }
+    }
+
+    fn local(&mut self, name: &str) -> Option<Value> {
+        self.frame().scopes.iter().rev().find_map(|scope| scope.get(name)).cloned()
+    }
+}
+
+fn literal_matches(literal: &Literal, value: &Value) -> bool {
+    match (literal, value) {
+        (Literal::Int(expected), value) => value.as_integer() == Some(*expected),
+        (Literal::Float(expected), value) => value.as_float() == Some(*expected),
+        (Literal::Bool(expected), Value::Bool(actual)) => expected == actual,
+        (Literal::Char(expected), Value::Char(actual)) => expected == actual,
+        (Literal::String(expected), Value::String(actual)) => expected == actual,
+        _ => false,
+    }
+}
+
+fn runtime_error(code: &'static str, message: &str, span: Span) -> Diagnostic {
+    Diagnostic::error(message).with_code(code).with_primary(span, "")
+}
+
+macro_rules! integer_op {
+    ($variant:ident, $a:expr, $b:expr, $op:expr, $span:expr) => {{
+        let (a, b) = ($a, $b);
+        match $op {
+            BinaryOp::Add => Value::$variant(a.wrapping_add(b)),
+            BinaryOp::Sub => Value::$variant(a.wrapping_sub(b)),
+            BinaryOp::Mul => Value::$variant(a.wrapping_mul(b)),
+            BinaryOp::Div | BinaryOp::Mod if b == 0 => {
+                return Err(runtime_error(codes::DIVISION_BY_ZERO, "attempt to divide by zero", $span));
+            }
+            BinaryOp::Div => Value::$variant(a.wrapping_div(b)),
+            BinaryOp::Mod => Value::$variant(a.wrapping_rem(b)),
+            BinaryOp::BitAnd => Value::$variant(a & b),
+            BinaryOp::BitXor => Value::$variant(a ^ b),
+            op => compare(op, a, b),
+        }
+    }};
+}
+
+macro_rules! float_op {
+    ($variant:ident, $a:expr, $b:expr, $op:expr) => {{
+        let (a, b) = ($a, $b);
+        match $op {
+            BinaryOp::Add => Value::$variant(a + b),
+            BinaryOp::Sub => Value::$variant(a - b),
+            BinaryOp::Mul => Value::$variant(a * b),
+            BinaryOp::Div => Value::$variant(a / b),
+            BinaryOp::Mod => Value::$variant(a % b),
+            op => compare(op, a, b),
+        }
+    }};
+}
+
+fn compare<T: PartialOrd>(op: BinaryOp, a: T, b: T) -> Value {
+    Value::Bool(match op {
+        BinaryOp::Eq => a == b,
+        BinaryOp::Ne => a != b,
+        BinaryOp::Lt => a < b,
+        BinaryOp::Le => a <= b,
+        BinaryOp::Gt => a > b,
+        BinaryOp::Ge => a >= b,
+        _ => unreachable!("`{}` isn't a comparison", op.symbol()),
+    })
+}
+
+/// Applies a binary operator other than `&&` and `||`, which short-circuit.
+fn binary(op: BinaryOp, left: Value, right: Value, span: Span) -> Eval<Value> {
+    if matches!(op, BinaryOp::Shl | BinaryOp::Shr) {
+        let amount = right.as_integer().unwrap_or(0) as u32;
+        return Ok(match (op, left) {
+            (BinaryOp::Shl, Value::I32(a)) => Value::I32(a.wrapping_shl(amount)),
+            (BinaryOp::Shl, Value::I64(a)) => Value::I64(a.wrapping_shl(amount)),
+            (BinaryOp::Shl, Value::U32(a)) => Value::U32(a.wrapping_shl(amount)),
+            (BinaryOp::Shl, Value::U64(a)) => Value::U64(a.wrapping_shl(amount)),
+            (_, Value::I32(a)) => Value::I32(a.wrapping_shr(amount)),
+            (_, Value::I64(a)) => Value::I64(a.wrapping_shr(amount)),
+            (_, Value::U32(a)) => Value::U32(a.wrapping_shr(amount)),
+            (_, Value::U64(a)) => Value::U64(a.wrapping_shr(amount)),
+            _ => unreachable!("the type checker only allows shifting integers"),
+        });
+    }
+    Ok(match (left, right) {
+        (Value::I32(a), Value::I32(b)) => integer_op!(I32, a, b, op, span),
+        (Value::I64(a), Value::I64(b)) => integer_op!(I64, a, b, op, span),
+        (Value::U32(a), Value::U32(b)) => integer_op!(U32, a, b, op, span),
+        (Value::U64(a), Value::U64(b)) => integer_op!(U64, a, b, op, span),
+        (Value::F32(a), Value::F32(b)) => float_op!(F32, a, b, op),
+        (Value::F64(a), Value::F64(b)) => float_op!(F64, a, b, op),
+        (Value::Bool(a), Value::Bool(b)) => match op {
+            BinaryOp::BitAnd => Value::Bool(a & b),
+            BinaryOp::BitXor => Value::Bool(a ^ b),
+            op => compare(op, a, b),
+        },
+        (Value::Char(a), Value::Char(b)) => compare(op, a, b),
+        (Value::String(a), Value::String(b)) => compare(op, a, b),
+        (left, right) => unreachable!("the type checker let through {:?} {} {:?}", left, op.symbol(), right),
+    })
+}
+
+fn unary(op: UnaryOp, value: Value) -> Value {
+    match (op, value) {
+        (UnaryOp::Neg, Value::I32(v)) => Value::I32(v.wrapping_neg()),
+        (UnaryOp::Neg, Value::I64(v)) => Value::I64(v.wrapping_neg()),
+        (UnaryOp::Neg, Value::F32(v)) => Value::F32(-v),
+        (UnaryOp::Neg, Value::F64(v)) => Value::F64(-v),
+        (UnaryOp::Not, Value::Bool(v)) => Value::Bool(!v),
+        (UnaryOp::Not, Value::I32(v)) => Value::I32(!v),
+        (UnaryOp::Not, Value::I64(v)) => Value::I64(!v),
+        (UnaryOp::Not, Value::U32(v)) => Value::U32(!v),
+        (UnaryOp::Not, Value::U64(v)) => Value::U64(!v),
+        (op, value) => unreachable!("the type checker let through {:?} {:?}", op, value),
+    }
+}
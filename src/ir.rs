@@ -0,0 +1,1957 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::hash::{Hash, Hasher};
+
+/// The type of an IR value. Structs and enums are values too, and refer
+/// to the module's type definitions by index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Type {
+    I32,
+    I64,
+    U32,
+    U64,
+    F32,
+    F64,
+    Bool,
+    Char,
+    String,
+    /// What a function without a return type returns.
+    Unit,
+    Named(u32),
+}
+
+impl Type {
+    pub fn is_integer(self) -> bool {
+        matches!(self, Type::I32 | Type::I64 | Type::U32 | Type::U64)
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(self, Type::I32 | Type::I64 | Type::F32 | Type::F64)
+    }
+
+    pub fn is_float(self) -> bool {
+        matches!(self, Type::F32 | Type::F64)
+    }
+
+    /// The name of every type but structs and enums.
+    fn scalar_name(self) -> Option<&'static str> {
+        Some(match self {
+            Type::I32 => "i32",
+            Type::I64 => "i64",
+            Type::U32 => "u32",
+            Type::U64 => "u64",
+            Type::F32 => "f32",
+            Type::F64 => "f64",
+            Type::Bool => "bool",
+            Type::Char => "char",
+            Type::String => "string",
+            Type::Unit => "unit",
+            Type::Named(_) => return None,
+        })
+    }
+
+    /// Whether the type is a single value rather than a struct or enum.
+    pub fn is_scalar(self) -> bool {
+        !matches!(self, Type::Named(_))
+    }
+}
+
+/// The fields of a struct or the variants of an enum, by name.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TypeKind {
+    Struct(Vec<(String, Type)>),
+    Enum(Vec<(String, Vec<Type>)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TypeDefinition {
+    pub name: String,
+    pub kind: TypeKind,
+}
+
+impl TypeDefinition {
+    /// The types of the fields, of every variant for an enum.
+    pub fn field_types(&self) -> Vec<Type> {
+        match &self.kind {
+            TypeKind::Struct(fields) => fields.iter().map(|(_, ty)| *ty).collect(),
+            TypeKind::Enum(variants) => variants.iter().flat_map(|(_, fields)| fields.iter().copied()).collect(),
+        }
+    }
+
+    /// The type's name as a C or assembler symbol.
+    pub fn symbol(&self) -> String {
+        symbol(&self.name)
+    }
+}
+
+/// A name as a symbol C and assemblers accept: `c4_` and the name, with
+/// module separators as `__` and the type of a generic instance after
+/// `__num_`.
+fn symbol(name: &str) -> String {
+    let mut out = String::from("c4_");
+    for c in name.chars() {
+        match c {
+            '.' => out.push_str("__"),
+            '<' => out.push_str("__num_"),
+            '>' => {}
+            c if c.is_ascii_alphanumeric() || c == '_' => out.push(c),
+            _ => out.push('_'),
+        }
+    }
+    out
+}
+
+/// A virtual register. Each is assigned exactly once, by a parameter or an
+/// instruction, and has a type recorded in its function's `values`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct Value(pub u32);
+
+/// A basic block, as its index in its function. Block 0 is the entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct BlockId(pub u32);
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Constant {
+    I32(i32),
+    I64(i64),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Char(char),
+    String(String),
+    Unit,
+}
+
+impl Constant {
+    pub fn ty(&self) -> Type {
+        match self {
+            Constant::I32(_) => Type::I32,
+            Constant::I64(_) => Type::I64,
+            Constant::U32(_) => Type::U32,
+            Constant::U64(_) => Type::U64,
+            Constant::F32(_) => Type::F32,
+            Constant::F64(_) => Type::F64,
+            Constant::Bool(_) => Type::Bool,
+            Constant::Char(_) => Type::Char,
+            Constant::String(_) => Type::String,
+            Constant::Unit => Type::Unit,
+        }
+    }
+
+    /// The constant's bits, for every kind but strings.
+    fn bits(&self) -> u64 {
+        match *self {
+            Constant::I32(v) => v as u32 as u64,
+            Constant::I64(v) => v as u64,
+            Constant::U32(v) => v as u64,
+            Constant::U64(v) => v,
+            Constant::F32(v) => v.to_bits() as u64,
+            Constant::F64(v) => v.to_bits(),
+            Constant::Bool(v) => v as u64,
+            Constant::Char(v) => v as u64,
+            Constant::String(_) | Constant::Unit => 0,
+        }
+    }
+}
+
+/// Constants are equal if they have the same type and bits, so `0.0` and
+/// `-0.0` stay apart.
+impl PartialEq for Constant {
+    fn eq(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (Constant::String(a), Constant::String(b)) => a == b,
+            _ => self.ty() == other.ty() && self.bits() == other.bits(),
+        }
+    }
+}
+
+impl Eq for Constant {}
+
+impl Hash for Constant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ty().hash(state);
+        match self {
+            Constant::String(s) => s.hash(state),
+            _ => self.bits().hash(state),
+        }
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constant::I32(v) => write!(f, "{}", v),
+            Constant::I64(v) => write!(f, "{}", v),
+            Constant::U32(v) => write!(f, "{}", v),
+            Constant::U64(v) => write!(f, "{}", v),
+            Constant::F32(v) => write!(f, "{:?}", v),
+            Constant::F64(v) => write!(f, "{:?}", v),
+            Constant::Bool(v) => write!(f, "{}", v),
+            Constant::Char(v) => write!(f, "{:?}", v),
+            Constant::String(v) => write!(f, "{:?}", v),
+            Constant::Unit => write!(f, "()"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum UnaryOp {
+    Neg,
+    /// Logical not on `bool`s, bitwise not on integers.
+    Not,
+}
+
+/// Integer arithmetic wraps. `div` and `rem` fail at run time when the
+/// divisor is zero, and wrap when the minimum value is divided by -1.
+/// Comparisons produce a `bool`; which integer comparison is meant comes
+/// from the operands' type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+const BINARY_OPS: [(BinaryOp, &str); 15] = [
+    (BinaryOp::Add, "add"),
+    (BinaryOp::Sub, "sub"),
+    (BinaryOp::Mul, "mul"),
+    (BinaryOp::Div, "div"),
+    (BinaryOp::Rem, "rem"),
+    (BinaryOp::And, "and"),
+    (BinaryOp::Xor, "xor"),
+    (BinaryOp::Shl, "shl"),
+    (BinaryOp::Shr, "shr"),
+    (BinaryOp::Eq, "eq"),
+    (BinaryOp::Ne, "ne"),
+    (BinaryOp::Lt, "lt"),
+    (BinaryOp::Le, "le"),
+    (BinaryOp::Gt, "gt"),
+    (BinaryOp::Ge, "ge"),
+];
+
+impl BinaryOp {
+    pub fn name(self) -> &'static str {
+        BINARY_OPS.iter().find(|(op, _)| *op == self).map(|(_, name)| *name).unwrap_or("?")
+    }
+
+    pub fn is_comparison(self) -> bool {
+        matches!(self, BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum InstructionKind {
+    Const(Constant),
+    /// The same value under another name.
+    Copy(Value),
+    Unary(UnaryOp, Value),
+    Binary(BinaryOp, Value, Value),
+    /// Calls a function of the module, by index.
+    Call(u32, Vec<Value>),
+    /// The value that came from whichever predecessor control arrived
+    /// from. Phis come before every other instruction in their block.
+    Phi(Vec<(BlockId, Value)>),
+    /// Builds a struct of the result's type from its fields in order.
+    Struct(Vec<Value>),
+    /// Builds a value of the result's enum type with the given tag, the
+    /// variant's index, and fields.
+    Variant(u32, Vec<Value>),
+    /// A field of a struct, by index.
+    Field(Value, u32),
+    /// The tag of an enum value, as a `u32`.
+    Tag(Value),
+    /// A field of an enum value that has the given tag.
+    Payload(Value, u32, u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Instruction {
+    /// Missing only for calls to functions that return `unit`.
+    pub result: Option<Value>,
+    pub kind: InstructionKind,
+    /// `file:line:col` of instructions that can fail at run time.
+    pub location: Option<String>,
+}
+
+impl Instruction {
+    pub fn operands(&self) -> Vec<Value> {
+        match &self.kind {
+            InstructionKind::Const(_) => Vec::new(),
+            InstructionKind::Copy(a)
+            | InstructionKind::Unary(_, a)
+            | InstructionKind::Field(a, _)
+            | InstructionKind::Tag(a) => vec![*a],
+            InstructionKind::Payload(a, _, _) => vec![*a],
+            InstructionKind::Binary(_, a, b) => vec![*a, *b],
+            InstructionKind::Call(_, args) | InstructionKind::Struct(args) | InstructionKind::Variant(_, args) => {
+                args.clone()
+            }
+            InstructionKind::Phi(incoming) => incoming.iter().map(|(_, value)| *value).collect(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Value> {
+        match &mut self.kind {
+            InstructionKind::Const(_) => Vec::new(),
+            InstructionKind::Copy(a)
+            | InstructionKind::Unary(_, a)
+            | InstructionKind::Field(a, _)
+            | InstructionKind::Tag(a) => vec![a],
+            InstructionKind::Payload(a, _, _) => vec![a],
+            InstructionKind::Binary(_, a, b) => vec![a, b],
+            InstructionKind::Call(_, args) | InstructionKind::Struct(args) | InstructionKind::Variant(_, args) => {
+                args.iter_mut().collect()
+            }
+            InstructionKind::Phi(incoming) => incoming.iter_mut().map(|(_, value)| value).collect(),
+        }
+    }
+
+    pub fn is_phi(&self) -> bool {
+        matches!(self.kind, InstructionKind::Phi(_))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Terminator {
+    Jump(BlockId),
+    /// Goes to the first block if the `bool` is true, else the second.
+    Branch(Value, BlockId, BlockId),
+    /// Returns a value, or nothing from a function that returns `unit`.
+    Return(Option<Value>),
+    /// Fails at run time because no arm of a match expression matched.
+    NoMatch(Option<String>),
+    /// Control never gets here.
+    Unreachable,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(to) => vec![*to],
+            Terminator::Branch(_, then, otherwise) => vec![*then, *otherwise],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Jump(to) => vec![to],
+            Terminator::Branch(_, then, otherwise) => vec![then, otherwise],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Terminator::Branch(condition, _, _) => vec![*condition],
+            Terminator::Return(Some(value)) => vec![*value],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Terminator::Branch(condition, _, _) => vec![condition],
+            Terminator::Return(Some(value)) => vec![value],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Block {
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+/// One function, or one instance of a generic function, in SSA form.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Function {
+    pub name: String,
+    pub params: Vec<Value>,
+    pub ret: Type,
+    /// The type of every value, by number.
+    pub values: Vec<Type>,
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    /// The function's name as a C or assembler symbol.
+    pub fn symbol(&self) -> String {
+        symbol(&self.name)
+    }
+
+    /// A new value of type `ty`, not yet assigned.
+    pub fn new_value(&mut self, ty: Type) -> Value {
+        self.values.push(ty);
+        Value(self.values.len() as u32 - 1)
+    }
+
+    pub fn type_of(&self, value: Value) -> Type {
+        self.values[value.0 as usize]
+    }
+
+    pub fn block(&self, id: BlockId) -> &Block {
+        &self.blocks[id.0 as usize]
+    }
+
+    pub fn block_mut(&mut self, id: BlockId) -> &mut Block {
+        &mut self.blocks[id.0 as usize]
+    }
+
+    /// The blocks that can jump to each block, in block order.
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (index, block) in self.blocks.iter().enumerate() {
+            for successor in block.terminator.successors() {
+                if let Some(list) = predecessors.get_mut(successor.0 as usize)
+                    && !list.contains(&BlockId(index as u32))
+                {
+                    list.push(BlockId(index as u32));
+                }
+            }
+        }
+        predecessors
+    }
+
+    /// The phis of `to` and the values they get when control comes from
+    /// `from`, leaving out the ones that already have the value.
+    pub fn phi_moves(&self, from: BlockId, to: BlockId) -> Vec<(Value, Value)> {
+        let phis = self.block(to).instructions.iter().take_while(|i| i.is_phi());
+        phis.filter_map(|phi| {
+            let InstructionKind::Phi(incoming) = &phi.kind else {
+                return None;
+            };
+            let result = phi.result?;
+            let (_, source) = incoming.iter().find(|(block, _)| *block == from)?;
+            (*source != result).then_some((result, *source))
+        })
+        .collect()
+    }
+
+    /// The blocks reachable from the entry, each before its successors
+    /// except along back edges.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut order = Vec::new();
+        let mut visited = vec![false; self.blocks.len()];
+        // Each entry is a block and how many of its successors have been
+        // visited.
+        let mut stack = vec![(BlockId(0), 0)];
+        visited[0] = true;
+        while let Some((block, next)) = stack.pop() {
+            let successors = self.block(block).terminator.successors();
+            match successors.get(next) {
+                Some(successor) => {
+                    stack.push((block, next + 1));
+                    if let Some(seen) = visited.get_mut(successor.0 as usize)
+                        && !*seen
+                    {
+                        *seen = true;
+                        stack.push((*successor, 0));
+                    }
+                }
+                None => order.push(block),
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// The dominator tree of the blocks reachable from the entry, found
+    /// with the Cooper-Harvey-Kennedy algorithm.
+    pub fn dominators(&self) -> Dominators {
+        let order = self.reverse_postorder();
+        let mut position = vec![usize::MAX; self.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            position[block.0 as usize] = i;
+        }
+        let predecessors = self.predecessors();
+        let mut idom: Vec<Option<BlockId>> = vec![None; self.blocks.len()];
+        idom[0] = Some(BlockId(0));
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in order.iter().skip(1) {
+                let mut new = None;
+                for predecessor in &predecessors[block.0 as usize] {
+                    if idom[predecessor.0 as usize].is_none() {
+                        continue;
+                    }
+                    new = Some(match new {
+                        None => *predecessor,
+                        Some(current) => intersect(&idom, &position, current, *predecessor),
+                    });
+                }
+                if new.is_some() && idom[block.0 as usize] != new {
+                    idom[block.0 as usize] = new;
+                    changed = true;
+                }
+            }
+        }
+        Dominators { idom }
+    }
+
+    /// Removes the blocks control can't reach, such as those holding code
+    /// after a `ret`, and numbers the rest in order.
+    pub fn remove_unreachable_blocks(&mut self) {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack = vec![BlockId(0)];
+        reachable[0] = true;
+        while let Some(block) = stack.pop() {
+            for successor in self.block(block).terminator.successors() {
+                if !reachable[successor.0 as usize] {
+                    reachable[successor.0 as usize] = true;
+                    stack.push(successor);
+                }
+            }
+        }
+        let mut numbers = Vec::new();
+        let mut next = 0;
+        for is_reachable in &reachable {
+            numbers.push(BlockId(next));
+            next += u32::from(*is_reachable);
+        }
+
+        let blocks = std::mem::take(&mut self.blocks);
+        for (block, is_reachable) in blocks.into_iter().zip(&reachable) {
+            if !is_reachable {
+                continue;
+            }
+            let mut block = block;
+            for instruction in &mut block.instructions {
+                if let InstructionKind::Phi(incoming) = &mut instruction.kind {
+                    incoming.retain(|(from, _)| reachable[from.0 as usize]);
+                    for (from, _) in incoming.iter_mut() {
+                        *from = numbers[from.0 as usize];
+                    }
+                }
+            }
+            for successor in block.terminator.successors_mut() {
+                *successor = numbers[successor.0 as usize];
+            }
+            self.blocks.push(block);
+        }
+    }
+
+    /// Removes phis that only ever have one value, other than themselves,
+    /// using that value instead. Lowering adds one wherever a variable is read
+    /// in a block control can reach in more than one way, even when it wasn't
+    /// assigned differently along them.
+    pub fn remove_trivial_phis(&mut self) {
+        let mut replacements: HashMap<Value, Value> = HashMap::new();
+        loop {
+            let mut found = None;
+            'search: for (b, block) in self.blocks.iter().enumerate() {
+                for (i, instruction) in block.instructions.iter().enumerate() {
+                    let (Some(phi), InstructionKind::Phi(incoming)) = (instruction.result, &instruction.kind) else {
+                        continue;
+                    };
+                    let distinct: HashSet<Value> = incoming.iter().map(|(_, v)| *v).filter(|v| *v != phi).collect();
+                    if let Some(only) = distinct.iter().next()
+                        && distinct.len() == 1
+                    {
+                        found = Some((b, i, phi, *only));
+                        break 'search;
+                    }
+                }
+            }
+            let Some((block, index, phi, only)) = found else {
+                break;
+            };
+            self.blocks[block].instructions.remove(index);
+            replacements.insert(phi, only);
+            self.replace_values(&replacements);
+        }
+    }
+
+    /// Replaces every use of a value with what it maps to, following chains.
+    pub fn replace_values(&mut self, replacements: &HashMap<Value, Value>) {
+        let resolve = |mut value: Value| {
+            while let Some(next) = replacements.get(&value) {
+                value = *next;
+            }
+            value
+        };
+        for block in &mut self.blocks {
+            for instruction in &mut block.instructions {
+                for operand in instruction.operands_mut() {
+                    *operand = resolve(*operand);
+                }
+            }
+            for operand in block.terminator.operands_mut() {
+                *operand = resolve(*operand);
+            }
+        }
+    }
+
+    /// Numbers values in the order they are defined, parameters first,
+    /// dropping the ones nothing defines any more.
+    pub fn renumber_values(&mut self) {
+        let mut numbers: HashMap<Value, Value> = HashMap::new();
+        let mut types = Vec::new();
+        let mut number = |value: Value, types: &mut Vec<Type>| {
+            numbers.insert(value, Value(types.len() as u32));
+            types.push(self.values[value.0 as usize]);
+        };
+        for param in &self.params {
+            number(*param, &mut types);
+        }
+        for block in &self.blocks {
+            for instruction in &block.instructions {
+                if let Some(result) = instruction.result {
+                    number(result, &mut types);
+                }
+            }
+        }
+        let rename = |value: &mut Value| *value = numbers[value];
+        self.params.iter_mut().for_each(rename);
+        for block in &mut self.blocks {
+            for instruction in &mut block.instructions {
+                instruction.result.iter_mut().for_each(rename);
+                instruction.operands_mut().into_iter().for_each(rename);
+            }
+            block.terminator.operands_mut().into_iter().for_each(rename);
+        }
+        self.values = types;
+    }
+}
+
+fn intersect(idom: &[Option<BlockId>], position: &[usize], mut a: BlockId, mut b: BlockId) -> BlockId {
+    while a != b {
+        while position[a.0 as usize] > position[b.0 as usize] {
+            a = idom[a.0 as usize].expect("blocks being intersected have dominators");
+        }
+        while position[b.0 as usize] > position[a.0 as usize] {
+            b = idom[b.0 as usize].expect("blocks being intersected have dominators");
+        }
+    }
+    a
+}
+
+/// Each block's immediate dominator. Unreachable blocks have none, and the
+/// entry is its own.
+#[derive(Debug)]
+pub(crate) struct Dominators {
+    pub idom: Vec<Option<BlockId>>,
+}
+
+impl Dominators {
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.idom[block.0 as usize].is_some()
+    }
+
+    /// Whether every path from the entry to `b` goes through `a`.
+    pub fn dominates(&self, a: BlockId, mut b: BlockId) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom[b.0 as usize] {
+                Some(parent) if parent != b => b = parent,
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// A whole program in IR form.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Module {
+    pub types: Vec<TypeDefinition>,
+    pub functions: Vec<Function>,
+    /// The function execution starts at.
+    pub main: u32,
+}
+
+impl Module {
+    /// Every type definition, by index, after the types its fields have.
+    /// Backends that store fields inline need this order and reject types
+    /// that contain themselves before asking for it.
+    pub fn layout_order(&self) -> Vec<u32> {
+        fn visit(module: &Module, index: u32, visited: &mut [bool], order: &mut Vec<u32>) {
+            if std::mem::replace(&mut visited[index as usize], true) {
+                return;
+            }
+            for ty in module.types[index as usize].field_types() {
+                if let Type::Named(field) = ty {
+                    visit(module, field, visited, order);
+                }
+            }
+            order.push(index);
+        }
+        let mut visited = vec![false; self.types.len()];
+        let mut order = Vec::new();
+        for index in 0..self.types.len() as u32 {
+            visit(self, index, &mut visited, &mut order);
+        }
+        order
+    }
+
+    fn type_name(&self, ty: Type) -> String {
+        match ty {
+            Type::Named(index) => match self.types.get(index as usize) {
+                Some(definition) => format!("${}", definition.name),
+                None => format!("$<{}>", index),
+            },
+            _ => ty.scalar_name().unwrap_or_default().to_string(),
+        }
+    }
+
+    /// The module as text, which `parse` reads back.
+    pub fn print(&self) -> String {
+        let mut out = String::new();
+        if let Some(main) = self.functions.get(self.main as usize) {
+            let _ = writeln!(out, "entry @{}", main.name);
+        }
+        for definition in &self.types {
+            let body = match &definition.kind {
+                TypeKind::Struct(fields) => {
+                    let fields: Vec<String> =
+                        fields.iter().map(|(name, ty)| format!("{}: {}", name, self.type_name(*ty))).collect();
+                    format!("struct {{ {} }}", fields.join(", "))
+                }
+                TypeKind::Enum(variants) => {
+                    let variants: Vec<String> = variants
+                        .iter()
+                        .map(|(name, fields)| {
+                            let fields: Vec<String> = fields.iter().map(|ty| self.type_name(*ty)).collect();
+                            format!("{}({})", name, fields.join(", "))
+                        })
+                        .collect();
+                    format!("enum {{ {} }}", variants.join(", "))
+                }
+            };
+            let _ = writeln!(out, "\ntype ${} = {}", definition.name, body);
+        }
+        for function in &self.functions {
+            out.push('\n');
+            self.print_function(function, &mut out);
+        }
+        out
+    }
+
+    fn print_function(&self, function: &Function, out: &mut String) {
+        let params: Vec<String> = function
+            .params
+            .iter()
+            .map(|param| format!("{}: {}", param, self.type_name(function.type_of(*param))))
+            .collect();
+        let _ = write!(out, "fn @{}({})", function.name, params.join(", "));
+        if function.ret != Type::Unit {
+            let _ = write!(out, " -> {}", self.type_name(function.ret));
+        }
+        out.push_str(" {\n");
+        for (index, block) in function.blocks.iter().enumerate() {
+            let _ = writeln!(out, "{}:", BlockId(index as u32));
+            for instruction in &block.instructions {
+                out.push_str("    ");
+                if let Some(result) = instruction.result {
+                    let _ = write!(out, "{}: {} = ", result, self.type_name(function.type_of(result)));
+                }
+                out.push_str(&self.instruction_text(&instruction.kind));
+                if let Some(location) = &instruction.location {
+                    let _ = write!(out, " at {:?}", location);
+                }
+                out.push('\n');
+            }
+            let terminator = match &block.terminator {
+                Terminator::Jump(to) => format!("jump {}", to),
+                Terminator::Branch(condition, then, otherwise) => format!("br {}, {}, {}", condition, then, otherwise),
+                Terminator::Return(Some(value)) => format!("ret {}", value),
+                Terminator::Return(None) => "ret".to_string(),
+                Terminator::NoMatch(Some(location)) => format!("no_match at {:?}", location),
+                Terminator::NoMatch(None) => "no_match".to_string(),
+                Terminator::Unreachable => "unreachable".to_string(),
+            };
+            let _ = writeln!(out, "    {}", terminator);
+        }
+        out.push_str("}\n");
+    }
+
+    fn instruction_text(&self, kind: &InstructionKind) -> String {
+        let list = |values: &[Value]| values.iter().map(Value::to_string).collect::<Vec<_>>().join(", ");
+        match kind {
+            InstructionKind::Const(constant) => format!("const {}", constant),
+            InstructionKind::Copy(a) => format!("copy {}", a),
+            InstructionKind::Unary(UnaryOp::Neg, a) => format!("neg {}", a),
+            InstructionKind::Unary(UnaryOp::Not, a) => format!("not {}", a),
+            InstructionKind::Binary(op, a, b) => format!("{} {}, {}", op.name(), a, b),
+            InstructionKind::Call(function, args) => {
+                let name = self.functions.get(*function as usize).map_or("<missing>", |f| f.name.as_str());
+                format!("call @{}({})", name, list(args))
+            }
+            InstructionKind::Phi(incoming) => {
+                let incoming: Vec<String> =
+                    incoming.iter().map(|(block, value)| format!("[{}: {}]", block, value)).collect();
+                format!("phi {}", incoming.join(", "))
+            }
+            InstructionKind::Struct(fields) => format!("struct ({})", list(fields)),
+            InstructionKind::Variant(tag, fields) => format!("variant {} ({})", tag, list(fields)),
+            InstructionKind::Field(a, index) => format!("field {}, {}", a, index),
+            InstructionKind::Tag(a) => format!("tag {}", a),
+            InstructionKind::Payload(a, tag, index) => format!("payload {}, {}, {}", a, tag, index),
+        }
+    }
+
+    /// Checks that the module is well formed: every value is defined once
+    /// before it is used, phis agree with the control flow graph, and
+    /// every instruction's operands have the types it needs. Returns every
+    /// problem found.
+    pub fn verify(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        match self.functions.get(self.main as usize) {
+            Some(main) if !main.params.is_empty() => errors.push(format!("the entry `@{}` takes parameters", main.name)),
+            Some(_) => {}
+            None => errors.push("the entry function doesn't exist".to_string()),
+        }
+        for function in &self.functions {
+            Verifier { module: self, function, errors: &mut errors }.verify();
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+struct Verifier<'m> {
+    module: &'m Module,
+    function: &'m Function,
+    errors: &'m mut Vec<String>,
+}
+
+impl Verifier<'_> {
+    fn error(&mut self, block: Option<BlockId>, message: String) {
+        match block {
+            Some(block) => self.errors.push(format!("`@{}` {}: {}", self.function.name, block, message)),
+            None => self.errors.push(format!("`@{}`: {}", self.function.name, message)),
+        }
+    }
+
+    fn verify(&mut self) {
+        let function = self.function;
+        if function.blocks.is_empty() {
+            self.error(None, "has no blocks".to_string());
+            return;
+        }
+        let before = self.errors.len();
+        let count = function.blocks.len() as u32;
+        for (index, block) in function.blocks.iter().enumerate() {
+            for target in block.terminator.successors() {
+                if target.0 >= count {
+                    self.error(Some(BlockId(index as u32)), format!("jumps to `{}`, which doesn't exist", target));
+                }
+            }
+        }
+        if self.errors.len() > before {
+            return;
+        }
+
+        // Where each value is defined: its block and index in it, with
+        // parameters before the entry's first instruction.
+        let mut definitions: HashMap<Value, (BlockId, isize)> = HashMap::new();
+        let mut defined_twice = HashSet::new();
+        for param in &function.params {
+            if definitions.insert(*param, (BlockId(0), -1)).is_some() {
+                defined_twice.insert(*param);
+            }
+        }
+        for (index, block) in function.blocks.iter().enumerate() {
+            for (position, instruction) in block.instructions.iter().enumerate() {
+                if let Some(result) = instruction.result
+                    && definitions.insert(result, (BlockId(index as u32), position as isize)).is_some()
+                {
+                    defined_twice.insert(result);
+                }
+            }
+        }
+        let mut defined_twice: Vec<Value> = defined_twice.into_iter().collect();
+        defined_twice.sort();
+        for value in defined_twice {
+            self.error(None, format!("`{}` is defined more than once", value));
+        }
+        for value in definitions.keys() {
+            if value.0 as usize >= function.values.len() {
+                self.error(None, format!("`{}` has no type", value));
+                return;
+            }
+        }
+
+        let predecessors = function.predecessors();
+        if !predecessors[0].is_empty() {
+            self.error(Some(BlockId(0)), "the entry block is jumped to".to_string());
+        }
+        let dominators = function.dominators();
+        for (index, block) in function.blocks.iter().enumerate() {
+            let id = BlockId(index as u32);
+            let mut phis_done = false;
+            for (position, instruction) in block.instructions.iter().enumerate() {
+                if instruction.is_phi() && phis_done {
+                    self.error(Some(id), "a phi comes after other instructions".to_string());
+                }
+                phis_done |= !instruction.is_phi();
+                if let InstructionKind::Phi(incoming) = &instruction.kind {
+                    self.check_phi(id, incoming, &predecessors[index]);
+                }
+                for (operand, from, at) in self.uses(instruction, id, position) {
+                    self.check_use(&definitions, &dominators, operand, from, at);
+                }
+                self.check_instruction(id, instruction);
+            }
+            for operand in block.terminator.operands() {
+                self.check_use(&definitions, &dominators, operand, id, block.instructions.len() as isize);
+            }
+            self.check_terminator(id, &block.terminator);
+        }
+    }
+
+    /// Every operand of an instruction at `position` in `block`, with
+    /// where it is used: at the instruction, or at the end of the
+    /// predecessor a phi's value comes from.
+    fn uses(&self, instruction: &Instruction, block: BlockId, position: usize) -> Vec<(Value, BlockId, isize)> {
+        match &instruction.kind {
+            InstructionKind::Phi(incoming) => incoming.iter().map(|(from, value)| (*value, *from, isize::MAX)).collect(),
+            _ => instruction.operands().into_iter().map(|value| (value, block, position as isize)).collect(),
+        }
+    }
+
+    /// Checks that `value`, used in `block` at `position`, is defined at a
+    /// point that dominates the use.
+    fn check_use(
+        &mut self,
+        definitions: &HashMap<Value, (BlockId, isize)>,
+        dominators: &Dominators,
+        value: Value,
+        block: BlockId,
+        position: isize,
+    ) {
+        let Some(&(defined_in, defined_at)) = definitions.get(&value) else {
+            self.error(Some(block), format!("`{}` is used but never defined", value));
+            return;
+        };
+        if !dominators.is_reachable(block) {
+            return;
+        }
+        let before = if defined_in == block { defined_at < position } else { dominators.dominates(defined_in, block) };
+        if !before {
+            self.error(Some(block), format!("`{}` is used where its definition doesn't dominate", value));
+        }
+    }
+
+    fn check_phi(&mut self, block: BlockId, incoming: &[(BlockId, Value)], predecessors: &[BlockId]) {
+        let mut from: Vec<BlockId> = incoming.iter().map(|(block, _)| *block).collect();
+        from.sort();
+        let mut expected = predecessors.to_vec();
+        expected.sort();
+        if from != expected {
+            let names = |blocks: &[BlockId]| blocks.iter().map(BlockId::to_string).collect::<Vec<_>>().join(", ");
+            self.error(
+                Some(block),
+                format!("a phi has values from [{}], but the block's predecessors are [{}]", names(&from), names(&expected)),
+            );
+        }
+    }
+
+    fn ty(&self, value: Value) -> Type {
+        self.function.values.get(value.0 as usize).copied().unwrap_or(Type::Unit)
+    }
+
+    fn name(&self, ty: Type) -> String {
+        self.module.type_name(ty)
+    }
+
+    fn check_instruction(&mut self, block: BlockId, instruction: &Instruction) {
+        let kind = &instruction.kind;
+        let result = instruction.result.map(|value| self.ty(value));
+        let text = self.module.instruction_text(kind);
+        let expect = |verifier: &mut Self, ok: bool, why: &str| {
+            if !ok {
+                verifier.error(Some(block), format!("`{}` {}", text, why));
+            }
+        };
+        let needs_result = !matches!(kind, InstructionKind::Call(..));
+        if needs_result && result.is_none() {
+            expect(self, false, "has no result");
+            return;
+        }
+        let result_ty = result.unwrap_or(Type::Unit);
+        match kind {
+            InstructionKind::Const(constant) => {
+                expect(self, constant.ty() == result_ty, "has a result of a different type than the constant")
+            }
+            InstructionKind::Copy(a) => {
+                expect(self, self.ty(*a) == result_ty, "has a result of a different type than its operand")
+            }
+            InstructionKind::Unary(op, a) => {
+                let ty = self.ty(*a);
+                let ok = match op {
+                    UnaryOp::Neg => ty.is_signed(),
+                    UnaryOp::Not => ty.is_integer() || ty == Type::Bool,
+                };
+                expect(self, ok, &format!("can't be applied to `{}`", self.name(ty)));
+                expect(self, result_ty == ty, "has a result of a different type than its operand");
+            }
+            InstructionKind::Binary(op, a, b) => {
+                let (left, right) = (self.ty(*a), self.ty(*b));
+                let ok = match op {
+                    BinaryOp::Shl | BinaryOp::Shr => left.is_integer() && right.is_integer(),
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => {
+                        left == right && (left.is_integer() || left.is_float())
+                    }
+                    BinaryOp::And | BinaryOp::Xor => left == right && (left.is_integer() || left == Type::Bool),
+                    _ => left == right && left.is_scalar() && left != Type::Unit,
+                };
+                expect(
+                    self,
+                    ok,
+                    &format!("can't be applied to `{}` and `{}`", self.name(left), self.name(right)),
+                );
+                let expected = if op.is_comparison() { Type::Bool } else { left };
+                expect(self, result_ty == expected, &format!("should produce `{}`", self.name(expected)));
+            }
+            InstructionKind::Call(callee, args) => {
+                let Some(callee) = self.module.functions.get(*callee as usize) else {
+                    expect(self, false, "calls a function that doesn't exist");
+                    return;
+                };
+                let params: Vec<Type> = callee.params.iter().map(|p| callee.type_of(*p)).collect();
+                let args: Vec<Type> = args.iter().map(|a| self.ty(*a)).collect();
+                expect(self, params == args, "passes arguments of the wrong number or types");
+                match result {
+                    None => expect(self, callee.ret == Type::Unit, "drops the result of a function that returns one"),
+                    Some(ty) => expect(self, ty == callee.ret, "has a result of a different type than the function returns"),
+                }
+            }
+            InstructionKind::Phi(incoming) => {
+                let ok = incoming.iter().all(|(_, value)| self.ty(*value) == result_ty);
+                expect(self, ok, "merges values of a different type than its result");
+            }
+            InstructionKind::Struct(fields) => {
+                let ok = match self.definition(result_ty) {
+                    Some(TypeKind::Struct(expected)) => {
+                        fields.iter().map(|f| self.ty(*f)).eq(expected.iter().map(|(_, ty)| *ty))
+                    }
+                    _ => false,
+                };
+                expect(self, ok, "doesn't match the fields of its result's struct type");
+            }
+            InstructionKind::Variant(tag, fields) => {
+                let ok = match self.definition(result_ty) {
+                    Some(TypeKind::Enum(variants)) => variants
+                        .get(*tag as usize)
+                        .is_some_and(|(_, expected)| fields.iter().map(|f| self.ty(*f)).eq(expected.iter().copied())),
+                    _ => false,
+                };
+                expect(self, ok, "doesn't match a variant of its result's enum type");
+            }
+            InstructionKind::Field(a, index) => {
+                let ok = match self.definition(self.ty(*a)) {
+                    Some(TypeKind::Struct(fields)) => fields.get(*index as usize).is_some_and(|(_, ty)| *ty == result_ty),
+                    _ => false,
+                };
+                expect(self, ok, "doesn't name a field of its operand's struct type with the result's type");
+            }
+            InstructionKind::Tag(a) => {
+                let ok = matches!(self.definition(self.ty(*a)), Some(TypeKind::Enum(_)));
+                expect(self, ok, "needs an enum operand");
+                expect(self, result_ty == Type::U32, "should produce `u32`");
+            }
+            InstructionKind::Payload(a, tag, index) => {
+                let ok = match self.definition(self.ty(*a)) {
+                    Some(TypeKind::Enum(variants)) => variants
+                        .get(*tag as usize)
+                        .and_then(|(_, fields)| fields.get(*index as usize))
+                        .is_some_and(|ty| *ty == result_ty),
+                    _ => false,
+                };
+                expect(self, ok, "doesn't name a field of a variant of its operand's enum type with the result's type");
+            }
+        }
+    }
+
+    fn definition(&self, ty: Type) -> Option<&'_ TypeKind> {
+        match ty {
+            Type::Named(index) => self.module.types.get(index as usize).map(|d| &d.kind),
+            _ => None,
+        }
+    }
+
+    fn check_terminator(&mut self, block: BlockId, terminator: &Terminator) {
+        match terminator {
+            Terminator::Branch(condition, _, _) if self.ty(*condition) != Type::Bool => {
+                self.error(Some(block), format!("branches on `{}`, which isn't a `bool`", condition));
+            }
+            Terminator::Return(value) => {
+                let ty = value.map_or(Type::Unit, |value| self.ty(value));
+                let ret = self.function.ret;
+                if ty != ret || (value.is_none() != (ret == Type::Unit)) {
+                    let message =
+                        format!("returns `{}` from a function that returns `{}`", self.name(ty), self.name(ret));
+                    self.error(Some(block), message);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reads a module in the form `Module::print` writes. Values and blocks
+/// may be numbered however the text likes: values keep their numbers, and
+/// blocks are numbered in the order they are defined.
+pub(crate) fn parse(text: &str) -> Result<Module, String> {
+    let tokens = tokenize(text)?;
+    let mut parser = Parser { tokens, at: 0, types: HashMap::new(), functions: HashMap::new() };
+    parser.declare_names()?;
+    parser.module()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A keyword, type, opcode or `bb` block name.
+    Word(String),
+    Number(String),
+    Value(u32),
+    Global(String),
+    TypeName(String),
+    Str(String),
+    Char(char),
+    Punct(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) | Token::Number(word) => write!(f, "`{}`", word),
+            Token::Value(n) => write!(f, "`%{}`", n),
+            Token::Global(name) => write!(f, "`@{}`", name),
+            Token::TypeName(name) => write!(f, "`${}`", name),
+            Token::Str(s) => write!(f, "{:?}", s),
+            Token::Char(c) => write!(f, "{:?}", c),
+            Token::Punct(p) => write!(f, "`{}`", p),
+        }
+    }
+}
+
+/// Whether `c` can be part of a `@function` or `$type` name.
+fn is_name_char(c: char) -> bool {
+    !c.is_whitespace() && !"(),[]{}=;\"".contains(c)
+}
+
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while let Some(&c) = chars.peek() {
+        let take_while = |chars: &mut std::iter::Peekable<std::str::Chars>, f: &dyn Fn(char) -> bool| {
+            let mut out = String::new();
+            while let Some(&c) = chars.peek()
+                && f(c)
+            {
+                out.push(c);
+                chars.next();
+            }
+            out
+        };
+        let token = match c {
+            '\n' => {
+                line += 1;
+                chars.next();
+                continue;
+            }
+            _ if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            ';' => {
+                take_while(&mut chars, &|c| c != '\n');
+                continue;
+            }
+            '%' => {
+                chars.next();
+                let digits = take_while(&mut chars, &|c| c.is_ascii_digit());
+                Token::Value(digits.parse().map_err(|_| format!("line {}: `%` needs a number after it", line))?)
+            }
+            '@' | '$' => {
+                chars.next();
+                let name = take_while(&mut chars, &is_name_char);
+                if name.is_empty() {
+                    return Err(format!("line {}: `{}` needs a name after it", line, c));
+                }
+                if c == '@' { Token::Global(name) } else { Token::TypeName(name) }
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut contents = String::new();
+                loop {
+                    match chars.next() {
+                        None | Some('\n') => return Err(format!("line {}: unterminated literal", line)),
+                        Some(end) if end == c => break,
+                        Some('\\') => contents.push(unescape(&mut chars).ok_or(format!("line {}: bad escape", line))?),
+                        Some(other) => contents.push(other),
+                    }
+                }
+                if c == '"' {
+                    Token::Str(contents)
+                } else {
+                    let mut contents = contents.chars();
+                    match (contents.next(), contents.next()) {
+                        (Some(c), None) => Token::Char(c),
+                        _ => return Err(format!("line {}: a char literal must hold one character", line)),
+                    }
+                }
+            }
+            '-' if text_after_is(&chars, "->") => {
+                chars.next();
+                chars.next();
+                Token::Punct("->")
+            }
+            _ if c.is_ascii_digit() || c == '-' => {
+                let mut number = String::new();
+                number.push(c);
+                chars.next();
+                number.push_str(&take_while(&mut chars, &|c| c.is_ascii_alphanumeric() || "._+-".contains(c)));
+                Token::Number(number)
+            }
+            _ if c.is_alphabetic() || c == '_' => Token::Word(take_while(&mut chars, &|c| c.is_alphanumeric() || c == '_')),
+            _ => {
+                chars.next();
+                match ["(", ")", "{", "}", "[", "]", ",", ":", "="].iter().find(|p| p.starts_with(c)) {
+                    Some(punct) => Token::Punct(punct),
+                    None => return Err(format!("line {}: unexpected `{}`", line, c)),
+                }
+            }
+        };
+        tokens.push((token, line));
+    }
+    Ok(tokens)
+}
+
+fn text_after_is(chars: &std::iter::Peekable<std::str::Chars>, text: &str) -> bool {
+    chars.clone().take(text.len()).eq(text.chars())
+}
+
+/// Reads the rest of an escape sequence as Rust's `{:?}` writes them.
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<char> {
+    Some(match chars.next()? {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        '\\' => '\\',
+        '\'' => '\'',
+        '"' => '"',
+        'u' => {
+            if chars.next()? != '{' {
+                return None;
+            }
+            let mut hex = String::new();
+            loop {
+                match chars.next()? {
+                    '}' => break,
+                    c => hex.push(c),
+                }
+            }
+            char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+        }
+        _ => return None,
+    })
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    at: usize,
+    /// Every type and function name, declared before any is used so they
+    /// can refer to each other in any order.
+    types: HashMap<String, u32>,
+    functions: HashMap<String, u32>,
+}
+
+/// The values and blocks of the function being parsed. Values keep their
+/// numbers from the text; blocks get one in the order they are named.
+#[derive(Default)]
+struct FunctionNames {
+    /// The type of every value, by number, once it is defined.
+    types: Vec<Option<Type>>,
+    /// Every value used, for reporting the ones never defined.
+    used: HashSet<u32>,
+    blocks: HashMap<u32, BlockId>,
+    defined_blocks: HashSet<u32>,
+}
+
+impl FunctionNames {
+    fn value(&mut self, number: u32) -> Value {
+        if self.types.len() <= number as usize {
+            self.types.resize(number as usize + 1, None);
+        }
+        self.used.insert(number);
+        Value(number)
+    }
+
+    fn block(&mut self, number: u32) -> BlockId {
+        let next = BlockId(self.blocks.len() as u32);
+        *self.blocks.entry(number).or_insert(next)
+    }
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        self.tokens.get(self.at).or(self.tokens.last()).map_or(1, |(_, line)| *line)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.peek().cloned().ok_or_else(|| format!("line {}: the text ends too early", self.line()))?;
+        self.at += 1;
+        Ok(token)
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, String> {
+        match self.peek() {
+            Some(token) => Err(format!("line {}: expected {}, found {}", self.line(), expected, token)),
+            None => Err(format!("line {}: expected {}, but the text ends", self.line(), expected)),
+        }
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w == word)
+    }
+
+    fn punct(&mut self, punct: &str) -> Result<(), String> {
+        if !self.is_punct(punct) {
+            return self.unexpected(&format!("`{}`", punct));
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Word(word)) => {
+                let word = word.clone();
+                self.at += 1;
+                Ok(word)
+            }
+            _ => self.unexpected("a word"),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T, String> {
+        match self.peek() {
+            Some(Token::Number(n)) => {
+                let parsed = n.parse().map_err(|_| format!("line {}: `{}` is out of range", self.line(), n))?;
+                self.at += 1;
+                Ok(parsed)
+            }
+            _ => self.unexpected("a number"),
+        }
+    }
+
+    fn declare_names(&mut self) -> Result<(), String> {
+        for i in 1..self.tokens.len() {
+            let (previous, line) = &self.tokens[i - 1];
+            let (names, name) = match (previous, &self.tokens[i].0) {
+                (Token::Word(w), Token::TypeName(name)) if w == "type" => (&mut self.types, name),
+                (Token::Word(w), Token::Global(name)) if w == "fn" => (&mut self.functions, name),
+                _ => continue,
+            };
+            let next = names.len() as u32;
+            if names.insert(name.clone(), next).is_some() {
+                return Err(format!("line {}: `{}` is defined more than once", line, name));
+            }
+        }
+        Ok(())
+    }
+
+    fn module(&mut self) -> Result<Module, String> {
+        let mut module = Module { types: Vec::new(), functions: Vec::new(), main: 0 };
+        let mut main = None;
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Word(w) if w == "entry" => {
+                    self.at += 1;
+                    main = Some(self.global()?);
+                }
+                Token::Word(w) if w == "type" => {
+                    self.at += 1;
+                    module.types.push(self.type_definition()?);
+                }
+                Token::Word(w) if w == "fn" => {
+                    self.at += 1;
+                    module.functions.push(self.function()?);
+                }
+                _ => return self.unexpected("`entry`, `type` or `fn`"),
+            }
+        }
+        module.main = main.ok_or("there's no `entry` line naming the entry function")?;
+        Ok(module)
+    }
+
+    fn global(&mut self) -> Result<u32, String> {
+        match self.next()? {
+            Token::Global(name) => {
+                self.functions.get(&name).copied().ok_or_else(|| format!("line {}: there's no function `@{}`", self.line(), name))
+            }
+            _ => {
+                self.at -= 1;
+                self.unexpected("a function name")
+            }
+        }
+    }
+
+    fn ty(&mut self) -> Result<Type, String> {
+        let ty = match self.next()? {
+            Token::TypeName(name) => match self.types.get(&name) {
+                Some(index) => Type::Named(*index),
+                None => return Err(format!("line {}: there's no type `${}`", self.line(), name)),
+            },
+            Token::Word(word) => {
+                let scalars = [
+                    Type::I32,
+                    Type::I64,
+                    Type::U32,
+                    Type::U64,
+                    Type::F32,
+                    Type::F64,
+                    Type::Bool,
+                    Type::Char,
+                    Type::String,
+                    Type::Unit,
+                ];
+                match scalars.into_iter().find(|ty| ty.scalar_name() == Some(word.as_str())) {
+                    Some(ty) => ty,
+                    None => {
+                        self.at -= 1;
+                        return self.unexpected("a type");
+                    }
+                }
+            }
+            _ => {
+                self.at -= 1;
+                return self.unexpected("a type");
+            }
+        };
+        Ok(ty)
+    }
+
+    /// Parses items separated by commas up to a closing `end`, which it
+    /// consumes.
+    fn list<T>(&mut self, end: &str, mut item: impl FnMut(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        let mut items = Vec::new();
+        while !self.is_punct(end) {
+            items.push(item(self)?);
+            if !self.is_punct(end) {
+                self.punct(",")?;
+            }
+        }
+        self.punct(end)?;
+        Ok(items)
+    }
+
+    fn type_definition(&mut self) -> Result<TypeDefinition, String> {
+        let name = match self.next()? {
+            Token::TypeName(name) => name,
+            _ => {
+                self.at -= 1;
+                return self.unexpected("a type name");
+            }
+        };
+        self.punct("=")?;
+        let kind = match self.word()?.as_str() {
+            "struct" => {
+                self.punct("{")?;
+                TypeKind::Struct(self.list("}", |parser| {
+                    let name = parser.word()?;
+                    parser.punct(":")?;
+                    Ok((name, parser.ty()?))
+                })?)
+            }
+            "enum" => {
+                self.punct("{")?;
+                TypeKind::Enum(self.list("}", |parser| {
+                    let name = parser.word()?;
+                    parser.punct("(")?;
+                    Ok((name, parser.list(")", Parser::ty)?))
+                })?)
+            }
+            _ => {
+                self.at -= 1;
+                return self.unexpected("`struct` or `enum`");
+            }
+        };
+        Ok(TypeDefinition { name, kind })
+    }
+
+    fn value(&mut self, names: &mut FunctionNames) -> Result<Value, String> {
+        match self.next()? {
+            Token::Value(n) => Ok(names.value(n)),
+            _ => {
+                self.at -= 1;
+                self.unexpected("a value")
+            }
+        }
+    }
+
+    fn block_name(&mut self, names: &mut FunctionNames) -> Result<(BlockId, u32), String> {
+        let word = self.word()?;
+        match word.strip_prefix("bb").and_then(|n| n.parse::<u32>().ok()) {
+            Some(n) => Ok((names.block(n), n)),
+            None => {
+                self.at -= 1;
+                self.unexpected("a block name")
+            }
+        }
+    }
+
+    fn location(&mut self) -> Result<Option<String>, String> {
+        if !self.is_word("at") {
+            return Ok(None);
+        }
+        self.at += 1;
+        match self.next()? {
+            Token::Str(location) => Ok(Some(location)),
+            _ => {
+                self.at -= 1;
+                self.unexpected("a location string")
+            }
+        }
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        let name = match self.next()? {
+            Token::Global(name) => name,
+            _ => {
+                self.at -= 1;
+                return self.unexpected("a function name");
+            }
+        };
+        let mut names = FunctionNames::default();
+        self.punct("(")?;
+        let params = self.list(")", |parser| {
+            let value = parser.value(&mut names)?;
+            parser.punct(":")?;
+            let ty = parser.ty()?;
+            parser.define(&mut names, value, ty)?;
+            Ok(value)
+        })?;
+        let ret = if self.is_punct("->") {
+            self.at += 1;
+            self.ty()?
+        } else {
+            Type::Unit
+        };
+        self.punct("{")?;
+
+        let mut blocks: Vec<Option<Block>> = Vec::new();
+        let mut order = Vec::new();
+        while !self.is_punct("}") {
+            let (id, number) = self.block_name(&mut names)?;
+            order.push(id);
+            if !names.defined_blocks.insert(number) {
+                return Err(format!("line {}: `bb{}` is defined more than once", self.line(), number));
+            }
+            self.punct(":")?;
+            let mut instructions = Vec::new();
+            let terminator = loop {
+                if let Some(terminator) = self.terminator(&mut names)? {
+                    break terminator;
+                }
+                instructions.push(self.instruction(&mut names)?);
+            };
+            if blocks.len() <= id.0 as usize {
+                blocks.resize(id.0 as usize + 1, None);
+            }
+            blocks[id.0 as usize] = Some(Block { instructions, terminator });
+        }
+        self.punct("}")?;
+
+        if let Some(number) = names.blocks.keys().filter(|n| !names.defined_blocks.contains(n)).min() {
+            return Err(format!("`@{}` jumps to `bb{}`, which isn't defined", name, number));
+        }
+        let mut numbers = vec![BlockId(0); blocks.len()];
+        for (position, id) in order.iter().enumerate() {
+            numbers[id.0 as usize] = BlockId(position as u32);
+        }
+        let mut finished = Vec::new();
+        for id in order {
+            let mut block = blocks[id.0 as usize].take().expect("every defined block was parsed");
+            for successor in block.terminator.successors_mut() {
+                *successor = numbers[successor.0 as usize];
+            }
+            for instruction in &mut block.instructions {
+                if let InstructionKind::Phi(incoming) = &mut instruction.kind {
+                    for (from, _) in incoming {
+                        *from = numbers[from.0 as usize];
+                    }
+                }
+            }
+            finished.push(block);
+        }
+        if let Some(number) = names.used.iter().filter(|n| names.types[**n as usize].is_none()).min() {
+            return Err(format!("`@{}` uses `%{}`, which isn't defined", name, number));
+        }
+        // Numbers the text skips get a type nothing uses.
+        let values = names.types.into_iter().map(|ty| ty.unwrap_or(Type::Unit)).collect();
+        Ok(Function { name, params, ret, values, blocks: finished })
+    }
+
+    fn define(&self, names: &mut FunctionNames, value: Value, ty: Type) -> Result<(), String> {
+        let slot = &mut names.types[value.0 as usize];
+        if slot.is_some() {
+            return Err(format!("line {}: a value is defined more than once", self.line()));
+        }
+        *slot = Some(ty);
+        Ok(())
+    }
+
+    fn terminator(&mut self, names: &mut FunctionNames) -> Result<Option<Terminator>, String> {
+        let Some(Token::Word(word)) = self.peek() else {
+            return Ok(None);
+        };
+        let terminator = match word.as_str() {
+            "jump" => {
+                self.at += 1;
+                Terminator::Jump(self.block_name(names)?.0)
+            }
+            "br" => {
+                self.at += 1;
+                let condition = self.value(names)?;
+                self.punct(",")?;
+                let then = self.block_name(names)?.0;
+                self.punct(",")?;
+                Terminator::Branch(condition, then, self.block_name(names)?.0)
+            }
+            "ret" => {
+                self.at += 1;
+                match self.peek() {
+                    Some(Token::Value(_)) => Terminator::Return(Some(self.value(names)?)),
+                    _ => Terminator::Return(None),
+                }
+            }
+            "no_match" => {
+                self.at += 1;
+                Terminator::NoMatch(self.location()?)
+            }
+            "unreachable" => {
+                self.at += 1;
+                Terminator::Unreachable
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(terminator))
+    }
+
+    fn instruction(&mut self, names: &mut FunctionNames) -> Result<Instruction, String> {
+        let result = match self.peek() {
+            Some(Token::Value(_)) => {
+                let value = self.value(names)?;
+                self.punct(":")?;
+                let ty = self.ty()?;
+                self.define(names, value, ty)?;
+                self.punct("=")?;
+                Some((value, ty))
+            }
+            _ => None,
+        };
+        let opcode = self.word()?;
+        let kind = match opcode.as_str() {
+            "const" => InstructionKind::Const(self.constant(result.map_or(Type::Unit, |(_, ty)| ty))?),
+            "copy" => InstructionKind::Copy(self.value(names)?),
+            "neg" => InstructionKind::Unary(UnaryOp::Neg, self.value(names)?),
+            "not" => InstructionKind::Unary(UnaryOp::Not, self.value(names)?),
+            "call" => {
+                let function = self.global()?;
+                self.punct("(")?;
+                InstructionKind::Call(function, self.list(")", |parser| parser.value(names))?)
+            }
+            "phi" => {
+                let mut incoming = Vec::new();
+                loop {
+                    self.punct("[")?;
+                    let (block, _) = self.block_name(names)?;
+                    self.punct(":")?;
+                    incoming.push((block, self.value(names)?));
+                    self.punct("]")?;
+                    if !self.is_punct(",") {
+                        break;
+                    }
+                    self.at += 1;
+                }
+                InstructionKind::Phi(incoming)
+            }
+            "struct" => {
+                self.punct("(")?;
+                InstructionKind::Struct(self.list(")", |parser| parser.value(names))?)
+            }
+            "variant" => {
+                let tag = self.number()?;
+                self.punct("(")?;
+                InstructionKind::Variant(tag, self.list(")", |parser| parser.value(names))?)
+            }
+            "field" => {
+                let value = self.value(names)?;
+                self.punct(",")?;
+                InstructionKind::Field(value, self.number()?)
+            }
+            "tag" => InstructionKind::Tag(self.value(names)?),
+            "payload" => {
+                let value = self.value(names)?;
+                self.punct(",")?;
+                let tag = self.number()?;
+                self.punct(",")?;
+                InstructionKind::Payload(value, tag, self.number()?)
+            }
+            name => match BINARY_OPS.iter().find(|(_, n)| *n == name) {
+                Some((op, _)) => {
+                    let a = self.value(names)?;
+                    self.punct(",")?;
+                    InstructionKind::Binary(*op, a, self.value(names)?)
+                }
+                None => {
+                    self.at -= 1;
+                    return self.unexpected("an instruction");
+                }
+            },
+        };
+        let location = self.location()?;
+        Ok(Instruction { result: result.map(|(value, _)| value), kind, location })
+    }
+
+    /// A constant of type `ty`, which the result's type gives.
+    fn constant(&mut self, ty: Type) -> Result<Constant, String> {
+        let line = self.line();
+        let name = ty.scalar_name().unwrap_or("struct or enum");
+        let bad = |token: &Token| format!("line {}: {} isn't a constant of type `{}`", line, token, name);
+        let token = self.next()?;
+        let number = match &token {
+            Token::Number(n) => n.clone(),
+            Token::Word(w) if ["NaN", "inf"].contains(&w.as_str()) => w.clone(),
+            _ => String::new(),
+        };
+        let constant = match (ty, &token) {
+            (Type::Bool, Token::Word(w)) if w == "true" || w == "false" => Constant::Bool(w == "true"),
+            (Type::Char, Token::Char(c)) => Constant::Char(*c),
+            (Type::String, Token::Str(s)) => Constant::String(s.clone()),
+            (Type::Unit, Token::Punct("(")) => {
+                self.punct(")")?;
+                Constant::Unit
+            }
+            (Type::I32, _) => Constant::I32(number.parse().map_err(|_| bad(&token))?),
+            (Type::I64, _) => Constant::I64(number.parse().map_err(|_| bad(&token))?),
+            (Type::U32, _) => Constant::U32(number.parse().map_err(|_| bad(&token))?),
+            (Type::U64, _) => Constant::U64(number.parse().map_err(|_| bad(&token))?),
+            (Type::F32, _) => Constant::F32(number.parse().map_err(|_| bad(&token))?),
+            (Type::F64, _) => Constant::F64(number.parse().map_err(|_| bad(&token))?),
+            _ => return Err(bad(&token)),
+        };
+        Ok(constant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uses every kind of type, instruction and terminator.
+    const EVERYTHING: &str = r#"entry @main
+
+type $point = struct { x: i64, y: i64 }
+
+type $shape = enum { dot($point), line($point, $point), none() }
+
+fn @main() -> i32 {
+bb0:
+    %0: i64 = const -3
+    %1: i64 = const 4
+    %2: $point = struct (%0, %1)
+    %3: $shape = variant 0 (%2)
+    %4: i64 = call @area(%3) at "main.c4l:9:5"
+    %5: string = const "done\n"
+    %6: char = const 'x'
+    %7: f32 = const 1.5
+    %8: f64 = const -0.25
+    call @log(%5, %6, %7, %8)
+    %9: i32 = const 0
+    jump bb1
+bb1:
+    %10: i32 = phi [bb0: %9], [bb2: %13]
+    %11: i32 = const 10
+    %12: bool = lt %10, %11
+    br %12, bb2, bb3
+bb2:
+    %13: i32 = add %10, %11
+    jump bb1
+bb3:
+    %14: bool = not %12
+    %15: i32 = neg %10
+    %16: i32 = shl %15, %11
+    ret %16
+}
+
+fn @area(%0: $shape) -> i64 {
+bb0:
+    %1: u32 = tag %0
+    %2: u32 = const 0
+    %3: bool = eq %1, %2
+    br %3, bb1, bb2
+bb1:
+    %4: $point = payload %0, 0, 0
+    %5: i64 = field %4, 0
+    %6: i64 = field %4, 1
+    %7: i64 = mul %5, %6
+    %8: i64 = div %7, %6 at "main.c4l:2:9"
+    ret %8
+bb2:
+    no_match at "main.c4l:1:1"
+}
+
+fn @log(%0: string, %1: char, %2: f32, %3: f64) {
+bb0:
+    %4: string = copy %0
+    ret
+}
+
+fn @never() -> u64 {
+bb0:
+    unreachable
+}
+"#;
+
+    fn parse_ok(text: &str) -> Module {
+        parse(text).unwrap_or_else(|error| panic!("the text didn't parse: {}", error))
+    }
+
+    /// The verifier's errors for a module that is expected to have some.
+    fn errors(text: &str) -> Vec<String> {
+        parse_ok(text).verify().expect_err("the module should be malformed")
+    }
+
+    #[test]
+    fn printing_parsed_text_gives_it_back() {
+        let module = parse_ok(EVERYTHING);
+        assert_eq!(module.verify(), Ok(()));
+        assert_eq!(module.print(), EVERYTHING);
+        assert_eq!(parse_ok(&module.print()), module);
+    }
+
+    #[test]
+    fn blocks_are_renumbered_in_the_order_they_are_defined() {
+        let text = "entry @main\nfn @main() -> i32 {\nbb5:\n    jump bb2\nbb2:\n    %0: i32 = const 1\n    ret %0\n}\n";
+        let printed = parse_ok(text).print();
+        assert_eq!(printed, "entry @main\n\nfn @main() -> i32 {\nbb0:\n    jump bb1\nbb1:\n    %0: i32 = const 1\n    ret %0\n}\n");
+        assert_eq!(parse_ok(&printed).print(), printed);
+    }
+
+    #[test]
+    fn a_use_must_be_dominated_by_its_definition() {
+        let errors = errors(
+            "entry @main
+            fn @main() -> i32 {
+            bb0:
+                %0: bool = const true
+                br %0, bb1, bb2
+            bb1:
+                %1: i32 = const 1
+                jump bb2
+            bb2:
+                ret %1
+            }",
+        );
+        assert_eq!(errors, ["`@main` bb2: `%1` is used where its definition doesn't dominate"]);
+    }
+
+    #[test]
+    fn a_use_must_come_after_its_definition_in_the_same_block() {
+        let errors = errors(
+            "entry @main
+            fn @main() -> i32 {
+            bb0:
+                %1: i32 = add %0, %0
+                %0: i32 = const 1
+                ret %1
+            }",
+        );
+        assert_eq!(
+            errors,
+            [
+                "`@main` bb0: `%0` is used where its definition doesn't dominate",
+                "`@main` bb0: `%0` is used where its definition doesn't dominate",
+            ]
+        );
+    }
+
+    #[test]
+    fn phis_must_come_first() {
+        let errors = errors(
+            "entry @main
+            fn @main() -> i32 {
+            bb0:
+                %0: i32 = const 1
+                jump bb1
+            bb1:
+                %1: i32 = const 2
+                %2: i32 = phi [bb0: %0]
+                ret %2
+            }",
+        );
+        assert_eq!(errors, ["`@main` bb1: a phi comes after other instructions"]);
+    }
+
+    #[test]
+    fn results_must_have_the_type_the_instruction_produces() {
+        let errors = errors(
+            "entry @main
+            fn @main() -> i32 {
+            bb0:
+                %0: i32 = const 1
+                %1: i64 = add %0, %0
+                %2: i32 = lt %0, %0
+                ret %0
+            }",
+        );
+        assert_eq!(errors, ["`@main` bb0: `add %0, %0` should produce `i32`", "`@main` bb0: `lt %0, %0` should produce `bool`"]);
+    }
+
+    #[test]
+    fn returns_must_match_the_function() {
+        let errors = errors(
+            "entry @main
+            fn @main() -> i32 {
+            bb0:
+                %0: i64 = const 1
+                ret %0
+            }",
+        );
+        assert_eq!(errors, ["`@main` bb0: returns `i64` from a function that returns `i32`"]);
+    }
+}
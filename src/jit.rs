@@ -0,0 +1,741 @@
+use crate::compiler::MAX_CALL_DEPTH;
+use crate::diagnostic::{codes, Diagnostic};
+use crate::ir::{self, BinaryOp, BlockId, Constant, InstructionKind, Terminator, Type, TypeKind, UnaryOp};
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, Signature, TrapCode, Value};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module};
+use std::collections::HashMap;
+
+/// Compiled code runs on a thread with a stack big enough for
+/// `MAX_CALL_DEPTH` calls.
+const STACK_SIZE: usize = 1 << 30;
+
+/// Compiles the IR of a program to machine code in memory and runs its
+/// entry function, returning its result as the process exit code: the
+/// `i32` it returns, or 0 if it returns nothing. No type may contain
+/// itself, since values are laid out inline.
+///
+/// Compiled code can't unwind back into the compiler, so a program that
+/// fails while running reports the error and exits the process with 101
+/// itself.
+pub(crate) fn run(program: &ir::Module) -> Result<i32, Diagnostic> {
+    let mut jit = Jit::new(program)?;
+    let ids = program
+        .functions
+        .iter()
+        .map(|function| {
+            let signature = jit.signature(function);
+            jit.module.declare_anonymous_function(&signature).map_err(|error| jit_error(&function.name, error))
+        })
+        .collect::<Result<Vec<FuncId>, Diagnostic>>()?;
+    for (function, id) in program.functions.iter().zip(&ids) {
+        jit.define(program, function, *id, &ids)?;
+    }
+    jit.module.finalize_definitions().map_err(|error| jit_error("the program", error))?;
+
+    let main = &program.functions[program.main as usize];
+    let code = jit.module.get_finalized_function(ids[program.main as usize]) as usize;
+    let returns_code = main.ret == Type::I32;
+    let result = std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new().stack_size(STACK_SIZE).spawn_scoped(scope, || {
+            // SAFETY: the entry function was compiled with the platform's
+            // calling convention and takes nothing.
+            unsafe {
+                if returns_code {
+                    std::mem::transmute::<usize, extern "C" fn() -> i32>(code)()
+                } else {
+                    std::mem::transmute::<usize, extern "C" fn()>(code)();
+                    0
+                }
+            }
+        });
+        match thread {
+            Ok(thread) => thread.join().map_err(|_| Diagnostic::error("the compiled program crashed")),
+            Err(error) => Err(Diagnostic::error(format!("couldn't start the compiled program: {}", error))),
+        }
+    });
+    // SAFETY: the program has finished, so none of its code is running.
+    unsafe { jit.module.free_memory() };
+    result
+}
+
+fn jit_error(name: &str, error: impl std::fmt::Display) -> Diagnostic {
+    Diagnostic::error(format!("the JIT couldn't compile `{}`", name)).with_note(error.to_string())
+}
+
+/// Reports a runtime error compiled code ran into and exits.
+extern "C" fn fail(failures: *const Vec<Diagnostic>, index: u32) {
+    // SAFETY: compiled code passes the failures the JIT made for it, which
+    // live until the program has finished.
+    let failures = unsafe { &*failures };
+    failures[index as usize].emit(None);
+    std::process::exit(101);
+}
+
+/// Orders strings the way the VM does, returning -1, 0 or 1.
+extern "C" fn compare_strings(a: *const u8, b: *const u8) -> i32 {
+    // SAFETY: strings in compiled code point at the JIT's string
+    // constants, which live until the program has finished.
+    let (a, b) = unsafe { (string_bytes(a), string_bytes(b)) };
+    a.cmp(b) as i32
+}
+
+/// The bytes of a string constant. Strings may contain NULs, so their
+/// length is stored in the 8 bytes before them.
+///
+/// # Safety
+///
+/// `string` must point just past the length of a string constant.
+unsafe fn string_bytes<'s>(string: *const u8) -> &'s [u8] {
+    unsafe {
+        let length = std::ptr::read_unaligned(string.sub(8) as *const u64);
+        std::slice::from_raw_parts(string, length as usize)
+    }
+}
+
+extern "C" fn remainder_f64(a: f64, b: f64) -> f64 {
+    a % b
+}
+
+extern "C" fn remainder_f32(a: f32, b: f32) -> f32 {
+    a % b
+}
+
+/// How the values of an IR type are spread over Cranelift values: scalars
+/// are one value, structs are their fields' values in order, and enums are
+/// a tag followed by 64-bit slots for the largest payload.
+#[derive(Debug, Clone)]
+struct Layout {
+    /// The type of each Cranelift value.
+    parts: Vec<types::Type>,
+    /// Where each field starts, per variant for enums.
+    fields: Vec<Vec<usize>>,
+}
+
+struct Jit {
+    module: JITModule,
+    context: Context,
+    builder_context: FunctionBuilderContext,
+    layouts: Vec<Layout>,
+    /// Functions in the compiler that compiled code calls.
+    runtime: Runtime<FuncId>,
+    /// The errors compiled code can fail with, passed to `fail` by index.
+    /// Compiled code holds the address of the vector itself, so it is
+    /// boxed to keep it in place.
+    #[allow(clippy::box_collection)]
+    failures: Box<Vec<Diagnostic>>,
+    /// The string constants compiled code points at, each after its
+    /// length.
+    strings: Vec<Box<[u8]>>,
+    /// How deeply calls are nested while the program runs.
+    depth: Box<i64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Runtime<T> {
+    fail: T,
+    compare_strings: T,
+    remainder_f64: T,
+    remainder_f32: T,
+}
+
+impl Jit {
+    fn new(program: &ir::Module) -> Result<Jit, Diagnostic> {
+        let flags = [("opt_level", "speed"), ("enable_multi_ret_implicit_sret", "true")];
+        let mut builder = JITBuilder::with_flags(&flags, cranelift_module::default_libcall_names())
+            .map_err(|error| Diagnostic::error("couldn't set up the JIT").with_note(error.to_string()))?;
+        builder.symbol("c4_fail", fail as *const u8);
+        builder.symbol("c4_compare_strings", compare_strings as *const u8);
+        builder.symbol("c4_remainder_f64", remainder_f64 as *const u8);
+        builder.symbol("c4_remainder_f32", remainder_f32 as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let pointer = module.target_config().pointer_type();
+        let mut import = |name: &str, params: &[types::Type], returns: &[types::Type]| {
+            let mut signature = module.make_signature();
+            signature.params.extend(params.iter().map(|ty| AbiParam::new(*ty)));
+            signature.returns.extend(returns.iter().map(|ty| AbiParam::new(*ty)));
+            module.declare_function(name, Linkage::Import, &signature).map_err(|error| jit_error(name, error))
+        };
+        let runtime = Runtime {
+            fail: import("c4_fail", &[pointer, types::I32], &[])?,
+            compare_strings: import("c4_compare_strings", &[pointer, pointer], &[types::I32])?,
+            remainder_f64: import("c4_remainder_f64", &[types::F64, types::F64], &[types::F64])?,
+            remainder_f32: import("c4_remainder_f32", &[types::F32, types::F32], &[types::F32])?,
+        };
+
+        let mut layouts = vec![None; program.types.len()];
+        for index in 0..program.types.len() {
+            layout(program, index, &mut layouts);
+        }
+        Ok(Jit {
+            context: module.make_context(),
+            module,
+            builder_context: FunctionBuilderContext::new(),
+            layouts: layouts.into_iter().map(|layout| layout.expect("every type was laid out")).collect(),
+            runtime,
+            failures: Box::default(),
+            strings: Vec::new(),
+            depth: Box::new(0),
+        })
+    }
+
+    fn signature(&self, function: &ir::Function) -> Signature {
+        let mut signature = self.module.make_signature();
+        for param in &function.params {
+            let parts = parts(&self.layouts, function.type_of(*param));
+            signature.params.extend(parts.into_iter().map(AbiParam::new));
+        }
+        signature.returns.extend(parts(&self.layouts, function.ret).into_iter().map(AbiParam::new));
+        signature
+    }
+
+    fn define(&mut self, program: &ir::Module, function: &ir::Function, id: FuncId, ids: &[FuncId]) -> Result<(), Diagnostic> {
+        self.context.func.signature = self.signature(function);
+        let builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder_context);
+        let runtime = Runtime {
+            fail: self.module.declare_func_in_func(self.runtime.fail, builder.func),
+            compare_strings: self.module.declare_func_in_func(self.runtime.compare_strings, builder.func),
+            remainder_f64: self.module.declare_func_in_func(self.runtime.remainder_f64, builder.func),
+            remainder_f32: self.module.declare_func_in_func(self.runtime.remainder_f32, builder.func),
+        };
+        let failures_address = &*self.failures as *const Vec<Diagnostic> as i64;
+        let translator = Translator {
+            builder,
+            module: &mut self.module,
+            program,
+            function,
+            ids,
+            layouts: &self.layouts,
+            runtime,
+            callees: HashMap::new(),
+            failures: &mut self.failures,
+            failures_address,
+            strings: &mut self.strings,
+            depth: &mut *self.depth as *mut i64 as i64,
+            values: HashMap::new(),
+            blocks: Vec::new(),
+        };
+        translator.translate();
+        let defined = self.module.define_function(id, &mut self.context);
+        self.module.clear_context(&mut self.context);
+        defined.map_err(|error| jit_error(&function.name, error))
+    }
+}
+
+/// Works out the layout of the type at `index`, and of the types it
+/// contains first. Types can't contain themselves by now.
+fn layout(program: &ir::Module, index: usize, layouts: &mut Vec<Option<Layout>>) -> Layout {
+    if let Some(layout) = &layouts[index] {
+        return layout.clone();
+    }
+    let mut parts_of = |ty: Type| match ty {
+        Type::Named(index) => layout(program, index as usize, layouts).parts,
+        ty => scalar_parts(ty),
+    };
+    let layout = match &program.types[index].kind {
+        TypeKind::Struct(fields) => {
+            let mut parts = Vec::new();
+            let mut starts = Vec::new();
+            for (_, ty) in fields {
+                starts.push(parts.len());
+                parts.extend(parts_of(*ty));
+            }
+            Layout { parts, fields: vec![starts] }
+        }
+        TypeKind::Enum(variants) => {
+            let mut size = 0;
+            let mut fields = Vec::new();
+            for (_, payload) in variants {
+                let mut starts = Vec::new();
+                let mut end = 1;
+                for ty in payload {
+                    starts.push(end);
+                    end += parts_of(*ty).len();
+                }
+                size = size.max(end - 1);
+                fields.push(starts);
+            }
+            let mut parts = vec![types::I32];
+            parts.extend(std::iter::repeat_n(types::I64, size));
+            Layout { parts, fields }
+        }
+    };
+    layouts[index] = Some(layout.clone());
+    layout
+}
+
+fn scalar_parts(ty: Type) -> Vec<types::Type> {
+    match ty {
+        Type::I32 | Type::U32 | Type::Char => vec![types::I32],
+        Type::I64 | Type::U64 | Type::String => vec![types::I64],
+        Type::F32 => vec![types::F32],
+        Type::F64 => vec![types::F64],
+        Type::Bool => vec![types::I8],
+        Type::Unit => Vec::new(),
+        Type::Named(_) => unreachable!("structs and enums have a layout"),
+    }
+}
+
+fn parts(layouts: &[Layout], ty: Type) -> Vec<types::Type> {
+    match ty {
+        Type::Named(index) => layouts[index as usize].parts.clone(),
+        ty => scalar_parts(ty),
+    }
+}
+
+/// Translates one IR function into Cranelift's IR. Each IR block becomes a
+/// Cranelift block whose parameters are its phis.
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    module: &'a mut JITModule,
+    program: &'a ir::Module,
+    function: &'a ir::Function,
+    ids: &'a [FuncId],
+    layouts: &'a [Layout],
+    runtime: Runtime<FuncRef>,
+    callees: HashMap<u32, FuncRef>,
+    failures: &'a mut Vec<Diagnostic>,
+    /// Where the failures are, for compiled code to pass to `fail`.
+    failures_address: i64,
+    strings: &'a mut Vec<Box<[u8]>>,
+    /// The address of the call depth counter.
+    depth: i64,
+    values: HashMap<ir::Value, Vec<Value>>,
+    blocks: Vec<Block>,
+}
+
+impl Translator<'_> {
+    fn translate(mut self) {
+        let entry = self.builder.create_block();
+        self.builder.append_block_params_for_function_params(entry);
+        for block in &self.function.blocks {
+            let target = self.builder.create_block();
+            for instruction in block.instructions.iter().filter(|instruction| instruction.is_phi()) {
+                let result = instruction.result.expect("phis have results");
+                let parts = parts(self.layouts, self.function.type_of(result));
+                let params = parts.into_iter().map(|ty| self.builder.append_block_param(target, ty)).collect();
+                self.values.insert(result, params);
+            }
+            self.blocks.push(target);
+        }
+
+        self.builder.switch_to_block(entry);
+        let mut params = self.builder.block_params(entry).to_vec().into_iter();
+        for param in &self.function.params {
+            let count = parts(self.layouts, self.function.type_of(*param)).len();
+            self.values.insert(*param, params.by_ref().take(count).collect());
+        }
+        self.enter();
+        self.builder.ins().jump(self.blocks[0], &[]);
+
+        for block in self.function.reverse_postorder() {
+            self.builder.switch_to_block(self.blocks[block.0 as usize]);
+            let ir_block = self.function.block(block);
+            for instruction in ir_block.instructions.iter().filter(|instruction| !instruction.is_phi()) {
+                let values = self.instruction(instruction);
+                if let Some(result) = instruction.result {
+                    self.values.insert(result, values);
+                }
+            }
+            self.terminator(block, &ir_block.terminator);
+        }
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn value(&self, value: ir::Value) -> Value {
+        self.values[&value][0]
+    }
+
+    fn iconst(&mut self, ty: types::Type, value: i64) -> Value {
+        self.builder.ins().iconst(ty, value)
+    }
+
+    /// Counts the call and fails if calls are nested too deeply.
+    fn enter(&mut self) {
+        let address = self.iconst(types::I64, self.depth);
+        let depth = self.builder.ins().load(types::I64, MemFlags::trusted(), address, 0);
+        let depth = self.builder.ins().iadd_imm(depth, 1);
+        self.builder.ins().store(MemFlags::trusted(), depth, address, 0);
+        let overflow = self.builder.ins().icmp_imm(IntCC::SignedGreaterThan, depth, MAX_CALL_DEPTH as i64);
+        let diagnostic = Diagnostic::error("stack overflow")
+            .with_code(codes::STACK_OVERFLOW)
+            .with_note(format!("calls were nested more than {} deep", MAX_CALL_DEPTH))
+            .with_note(format!("in `{}`", self.function.name));
+        self.fail_if(overflow, diagnostic);
+    }
+
+    fn leave(&mut self) {
+        let address = self.iconst(types::I64, self.depth);
+        let depth = self.builder.ins().load(types::I64, MemFlags::trusted(), address, 0);
+        let depth = self.builder.ins().iadd_imm(depth, -1);
+        self.builder.ins().store(MemFlags::trusted(), depth, address, 0);
+    }
+
+    /// Fails with the diagnostic if `condition` holds, and carries on in a
+    /// new block otherwise.
+    fn fail_if(&mut self, condition: Value, diagnostic: Diagnostic) {
+        let failed = self.builder.create_block();
+        let next = self.builder.create_block();
+        self.builder.ins().brif(condition, failed, &[], next, &[]);
+        self.builder.switch_to_block(failed);
+        self.builder.set_cold_block(failed);
+        self.fail(diagnostic);
+        self.builder.switch_to_block(next);
+    }
+
+    fn fail(&mut self, diagnostic: Diagnostic) {
+        let index = self.failures.len() as i64;
+        self.failures.push(diagnostic);
+        let failures = self.iconst(types::I64, self.failures_address);
+        let index = self.iconst(types::I32, index);
+        self.builder.ins().call(self.runtime.fail, &[failures, index]);
+        self.builder.ins().trap(TrapCode::unwrap_user(1));
+    }
+
+    /// Where a runtime error happened, for its note.
+    fn location_note(&self, location: Option<&str>) -> String {
+        match location {
+            Some(location) => format!("in `{}` at {}", self.function.name, location),
+            None => format!("in `{}`", self.function.name),
+        }
+    }
+
+    fn instruction(&mut self, instruction: &ir::Instruction) -> Vec<Value> {
+        let location = instruction.location.as_deref();
+        match &instruction.kind {
+            InstructionKind::Const(constant) => self.constant(constant),
+            InstructionKind::Copy(a) => self.values[a].clone(),
+            InstructionKind::Unary(op, a) => {
+                let ty = self.function.type_of(*a);
+                let a = self.value(*a);
+                vec![match op {
+                    UnaryOp::Neg if ty.is_float() => self.builder.ins().fneg(a),
+                    UnaryOp::Neg => self.builder.ins().ineg(a),
+                    UnaryOp::Not if ty == Type::Bool => self.builder.ins().bxor_imm(a, 1),
+                    UnaryOp::Not => self.builder.ins().bnot(a),
+                }]
+            }
+            InstructionKind::Binary(op, a, b) => {
+                let ty = self.function.type_of(*a);
+                let (a, b) = (self.value(*a), self.value(*b));
+                vec![self.binary(*op, ty, a, b, location)]
+            }
+            InstructionKind::Call(callee, args) => {
+                let callee = match self.callees.get(callee) {
+                    Some(callee) => *callee,
+                    None => {
+                        let reference = self.module.declare_func_in_func(self.ids[*callee as usize], self.builder.func);
+                        self.callees.insert(*callee, reference);
+                        reference
+                    }
+                };
+                let args: Vec<Value> = args.iter().flat_map(|arg| self.values[arg].clone()).collect();
+                let call = self.builder.ins().call(callee, &args);
+                self.builder.inst_results(call).to_vec()
+            }
+            InstructionKind::Phi(_) => unreachable!("phis are block parameters"),
+            InstructionKind::Struct(fields) => fields.iter().flat_map(|field| self.values[field].clone()).collect(),
+            InstructionKind::Variant(tag, fields) => {
+                let result = instruction.result.expect("variants have results");
+                let size = parts(self.layouts, self.function.type_of(result)).len();
+                let mut values = vec![self.iconst(types::I32, i64::from(*tag))];
+                for field in fields {
+                    for part in self.values[field].clone() {
+                        values.push(self.pack(part));
+                    }
+                }
+                while values.len() < size {
+                    values.push(self.iconst(types::I64, 0));
+                }
+                values
+            }
+            InstructionKind::Field(a, index) => {
+                let Type::Named(ty) = self.function.type_of(*a) else {
+                    unreachable!("fields are read from structs");
+                };
+                let TypeKind::Struct(fields) = &self.program.types[ty as usize].kind else {
+                    unreachable!("fields are read from structs");
+                };
+                let start = self.layouts[ty as usize].fields[0][*index as usize];
+                let count = parts(self.layouts, fields[*index as usize].1).len();
+                self.values[a][start..start + count].to_vec()
+            }
+            InstructionKind::Tag(a) => vec![self.value(*a)],
+            InstructionKind::Payload(a, tag, index) => {
+                let Type::Named(ty) = self.function.type_of(*a) else {
+                    unreachable!("payloads are read from enums");
+                };
+                let TypeKind::Enum(variants) = &self.program.types[ty as usize].kind else {
+                    unreachable!("payloads are read from enums");
+                };
+                let start = self.layouts[ty as usize].fields[*tag as usize][*index as usize];
+                let field = parts(self.layouts, variants[*tag as usize].1[*index as usize]);
+                let slots = self.values[a][start..start + field.len()].to_vec();
+                slots.into_iter().zip(field).map(|(slot, ty)| self.unpack(slot, ty)).collect()
+            }
+        }
+    }
+
+    fn constant(&mut self, constant: &Constant) -> Vec<Value> {
+        let value = match *constant {
+            Constant::I32(v) => self.iconst(types::I32, i64::from(v as u32)),
+            Constant::U32(v) => self.iconst(types::I32, i64::from(v)),
+            Constant::I64(v) => self.iconst(types::I64, v),
+            Constant::U64(v) => self.iconst(types::I64, v as i64),
+            Constant::F32(v) => self.builder.ins().f32const(v),
+            Constant::F64(v) => self.builder.ins().f64const(v),
+            Constant::Bool(v) => self.iconst(types::I8, i64::from(v)),
+            Constant::Char(v) => self.iconst(types::I32, i64::from(u32::from(v))),
+            Constant::String(ref v) => {
+                let string: Box<[u8]> = (v.len() as u64).to_ne_bytes().iter().chain(v.as_bytes()).copied().collect();
+                let address = string.as_ptr() as i64 + 8;
+                self.strings.push(string);
+                self.iconst(types::I64, address)
+            }
+            Constant::Unit => return Vec::new(),
+        };
+        vec![value]
+    }
+
+    fn binary(&mut self, op: BinaryOp, ty: Type, a: Value, b: Value, location: Option<&str>) -> Value {
+        if op.is_comparison() {
+            return self.compare(op, ty, a, b);
+        }
+        match op {
+            BinaryOp::Add if ty.is_float() => self.builder.ins().fadd(a, b),
+            BinaryOp::Sub if ty.is_float() => self.builder.ins().fsub(a, b),
+            BinaryOp::Mul if ty.is_float() => self.builder.ins().fmul(a, b),
+            BinaryOp::Div if ty.is_float() => self.builder.ins().fdiv(a, b),
+            BinaryOp::Rem if ty == Type::F64 => self.call(self.runtime.remainder_f64, &[a, b]),
+            BinaryOp::Rem if ty == Type::F32 => self.call(self.runtime.remainder_f32, &[a, b]),
+            BinaryOp::Add => self.builder.ins().iadd(a, b),
+            BinaryOp::Sub => self.builder.ins().isub(a, b),
+            BinaryOp::Mul => self.builder.ins().imul(a, b),
+            BinaryOp::And => self.builder.ins().band(a, b),
+            BinaryOp::Xor => self.builder.ins().bxor(a, b),
+            BinaryOp::Shl => self.builder.ins().ishl(a, b),
+            BinaryOp::Shr if ty.is_signed() => self.builder.ins().sshr(a, b),
+            BinaryOp::Shr => self.builder.ins().ushr(a, b),
+            BinaryOp::Div | BinaryOp::Rem => {
+                let zero = self.builder.ins().icmp_imm(IntCC::Equal, b, 0);
+                let diagnostic = Diagnostic::error("attempt to divide by zero")
+                    .with_code(codes::DIVISION_BY_ZERO)
+                    .with_note(self.location_note(location));
+                self.fail_if(zero, diagnostic);
+                self.divide(op, ty, a, b)
+            }
+            _ => unreachable!("comparisons are handled above"),
+        }
+    }
+
+    /// Divides by a divisor that isn't zero. Signed division of the
+    /// smallest value by -1 wraps instead of trapping, as it does in the VM.
+    fn divide(&mut self, op: BinaryOp, ty: Type, a: Value, b: Value) -> Value {
+        if !ty.is_signed() {
+            return match op {
+                BinaryOp::Div => self.builder.ins().udiv(a, b),
+                _ => self.builder.ins().urem(a, b),
+            };
+        }
+        let value_type = self.builder.func.dfg.value_type(b);
+        let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, b, -1);
+        let one = self.iconst(value_type, 1);
+        let divisor = self.builder.ins().select(minus_one, one, b);
+        if op == BinaryOp::Div {
+            let quotient = self.builder.ins().sdiv(a, divisor);
+            let negated = self.builder.ins().ineg(a);
+            self.builder.ins().select(minus_one, negated, quotient)
+        } else {
+            let remainder = self.builder.ins().srem(a, divisor);
+            let zero = self.iconst(value_type, 0);
+            self.builder.ins().select(minus_one, zero, remainder)
+        }
+    }
+
+    fn compare(&mut self, op: BinaryOp, ty: Type, a: Value, b: Value) -> Value {
+        if ty.is_float() {
+            let condition = match op {
+                BinaryOp::Eq => FloatCC::Equal,
+                BinaryOp::Ne => FloatCC::NotEqual,
+                BinaryOp::Lt => FloatCC::LessThan,
+                BinaryOp::Le => FloatCC::LessThanOrEqual,
+                BinaryOp::Gt => FloatCC::GreaterThan,
+                _ => FloatCC::GreaterThanOrEqual,
+            };
+            return self.builder.ins().fcmp(condition, a, b);
+        }
+        let signed = ty.is_signed() || ty == Type::String;
+        let condition = match op {
+            BinaryOp::Eq => IntCC::Equal,
+            BinaryOp::Ne => IntCC::NotEqual,
+            BinaryOp::Lt if signed => IntCC::SignedLessThan,
+            BinaryOp::Le if signed => IntCC::SignedLessThanOrEqual,
+            BinaryOp::Gt if signed => IntCC::SignedGreaterThan,
+            BinaryOp::Ge if signed => IntCC::SignedGreaterThanOrEqual,
+            BinaryOp::Lt => IntCC::UnsignedLessThan,
+            BinaryOp::Le => IntCC::UnsignedLessThanOrEqual,
+            BinaryOp::Gt => IntCC::UnsignedGreaterThan,
+            _ => IntCC::UnsignedGreaterThanOrEqual,
+        };
+        if ty == Type::String {
+            let order = self.call(self.runtime.compare_strings, &[a, b]);
+            return self.builder.ins().icmp_imm(condition, order, 0);
+        }
+        self.builder.ins().icmp(condition, a, b)
+    }
+
+    fn call(&mut self, function: FuncRef, args: &[Value]) -> Value {
+        let call = self.builder.ins().call(function, args);
+        self.builder.inst_results(call)[0]
+    }
+
+    /// Widens a value to fill a 64-bit enum slot.
+    fn pack(&mut self, value: Value) -> Value {
+        match self.builder.func.dfg.value_type(value) {
+            types::I64 => value,
+            types::F64 => self.builder.ins().bitcast(types::I64, MemFlags::new(), value),
+            types::F32 => {
+                let bits = self.builder.ins().bitcast(types::I32, MemFlags::new(), value);
+                self.builder.ins().uextend(types::I64, bits)
+            }
+            _ => self.builder.ins().uextend(types::I64, value),
+        }
+    }
+
+    /// Reads a value of type `ty` back out of a 64-bit enum slot.
+    fn unpack(&mut self, slot: Value, ty: types::Type) -> Value {
+        match ty {
+            types::I64 => slot,
+            types::F64 => self.builder.ins().bitcast(types::F64, MemFlags::new(), slot),
+            types::F32 => {
+                let bits = self.builder.ins().ireduce(types::I32, slot);
+                self.builder.ins().bitcast(types::F32, MemFlags::new(), bits)
+            }
+            ty => self.builder.ins().ireduce(ty, slot),
+        }
+    }
+
+    /// The values a jump from `from` passes to the phis of `to`.
+    fn arguments(&self, from: BlockId, to: BlockId) -> Vec<Value> {
+        let mut arguments = Vec::new();
+        for instruction in &self.function.block(to).instructions {
+            if let InstructionKind::Phi(incoming) = &instruction.kind {
+                let (_, value) = incoming.iter().find(|(block, _)| *block == from).expect("phis cover predecessors");
+                arguments.extend(&self.values[value]);
+            }
+        }
+        arguments
+    }
+
+    fn terminator(&mut self, block: BlockId, terminator: &Terminator) {
+        match terminator {
+            Terminator::Jump(target) => {
+                let arguments = self.arguments(block, *target);
+                self.builder.ins().jump(self.blocks[target.0 as usize], &arguments);
+            }
+            Terminator::Branch(condition, then, otherwise) => {
+                let condition = self.value(*condition);
+                let then_arguments = self.arguments(block, *then);
+                let otherwise_arguments = self.arguments(block, *otherwise);
+                let (then, otherwise) = (self.blocks[then.0 as usize], self.blocks[otherwise.0 as usize]);
+                self.builder.ins().brif(condition, then, &then_arguments, otherwise, &otherwise_arguments);
+            }
+            Terminator::Return(value) => {
+                self.leave();
+                let values = value.map(|value| self.values[&value].clone()).unwrap_or_default();
+                self.builder.ins().return_(&values);
+            }
+            Terminator::NoMatch(location) => {
+                let diagnostic = Diagnostic::error("no match arm matched the value")
+                    .with_code(codes::NO_MATCHING_ARM)
+                    .with_note(self.location_note(location.as_deref()));
+                self.fail(diagnostic);
+            }
+            Terminator::Unreachable => {
+                self.builder.ins().trap(TrapCode::unwrap_user(1));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing::{self, programs};
+    use crate::{codegen, vm};
+    use std::process::Command;
+
+    /// Set in the copy of the test binary `run_in_child` starts.
+    const CHILD: &str = "C4_JIT_TEST_CHILD";
+
+    /// Recursion `depth` calls deep that exits with 7.
+    fn nested(depth: usize) -> String {
+        format!(
+            "
+            fn down(n | i32) -> i32 {{
+                if n == 0 {{ ret 0; }}
+                ret down(n - 1) + 1;
+            }}
+
+            fn main() -> i32 {{
+                ret down({0}) - {0} + 7;
+            }}",
+            depth
+        )
+    }
+
+    /// Runs `source` in the test `test` of a copy of this binary, since
+    /// compiled code exits the process when the program fails. Returns the
+    /// exit code and what the program wrote to stderr.
+    fn run_in_child(test: &str, source: &str) -> (i32, String) {
+        if std::env::var_os(CHILD).is_some() {
+            std::process::exit(run(&testing::lower(source)).expect("the program compiles"));
+        }
+        let ran = Command::new(std::env::current_exe().expect("the test binary has a path"))
+            .args([&format!("jit::tests::{}", test), "--exact", "--nocapture"])
+            .env(CHILD, "1")
+            .output()
+            .expect("the test binary should start");
+        (ran.status.code().expect("the program should exit"), String::from_utf8_lossy(&ran.stderr).into_owned())
+    }
+
+    #[test]
+    fn programs_exit_with_their_result() {
+        for (source, code) in programs::ALL {
+            if code != 101 {
+                assert_eq!(run(&testing::lower(source)).ok(), Some(code), "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn dividing_by_zero_exits_with_101() {
+        let (source, code) = programs::DIVIDE_BY_ZERO;
+        let (status, stderr) = run_in_child("dividing_by_zero_exits_with_101", source);
+        assert_eq!(status, code);
+        assert!(stderr.starts_with("error[R0001]: attempt to divide by zero"), "{}", stderr);
+        assert!(stderr.trim_end().ends_with("main.c4l:3:21"), "{}", stderr);
+    }
+
+    #[test]
+    fn calls_nest_up_to_the_limit() {
+        let source = nested(MAX_CALL_DEPTH - 10);
+        assert_eq!(run(&testing::lower(&source)).ok(), Some(7));
+        assert_eq!(vm::run(&codegen::compile(&testing::lower(&source))).ok(), Some(7));
+    }
+
+    #[test]
+    fn calls_nested_past_the_limit_overflow_the_stack() {
+        let source = nested(MAX_CALL_DEPTH + 10);
+        let (status, stderr) = run_in_child("calls_nested_past_the_limit_overflow_the_stack", &source);
+        assert_eq!(status, 101);
+        assert!(stderr.starts_with("error[R0002]: stack overflow"), "{}", stderr);
+        let error = vm::run(&codegen::compile(&testing::lower(&source))).expect_err("the VM has the same limit");
+        assert_eq!(error.code, Some(codes::STACK_OVERFLOW));
+    }
+}
@@ -0,0 +1,862 @@
+use crate::compiler::Checked;
+use crate::ir::{self, BlockId, Constant, InstructionKind, Terminator, Value};
+use crate::module::{ModuleId, Resolved, ROOT};
+use crate::parser::*;
+use crate::span::Span;
+use crate::typeck::{ItemId, Payload, Ty};
+use std::collections::HashMap;
+
+/// Lowers the checked program to IR, starting from `main`, the index of
+/// the root module's `main` function. Like the bytecode compiler, only
+/// functions reachable from `main` are lowered, once for every type `num`
+/// stands for in calls to them.
+///
+/// Variables become SSA values as they are lowered, using the algorithm
+/// from Braun et al., "Simple and Efficient Construction of Static Single
+/// Assignment Form": reading a variable looks for its definition in the
+/// current block, then in its predecessors, adding a phi where control
+/// merges.
+pub(crate) fn lower(checked: &Checked, main: usize) -> ir::Module {
+    let mut lowering = Lowering {
+        checked,
+        module: ir::Module { types: Vec::new(), functions: Vec::new(), main: 0 },
+        types: HashMap::new(),
+        functions: HashMap::new(),
+        queue: Vec::new(),
+        state: FunctionState::new(ROOT, None, empty_function(String::new(), ir::Type::Unit)),
+    };
+    lowering.declare_types();
+    lowering.module.main = lowering.function((ROOT, main), None);
+    while let Some((id, num, index)) = lowering.queue.pop() {
+        lowering.lower_function(id, num, index);
+    }
+    lowering.module
+}
+
+fn empty_function(name: String, ret: ir::Type) -> ir::Function {
+    ir::Function { name, params: Vec::new(), ret, values: Vec::new(), blocks: Vec::new() }
+}
+
+/// A source variable, which may be assigned many SSA values over its life.
+type Variable = usize;
+
+/// What is being built for the function currently being lowered.
+struct FunctionState {
+    module: ModuleId,
+    num: Option<Ty>,
+    function: ir::Function,
+    current: BlockId,
+    /// Blocks whose terminator has been set.
+    filled: Vec<bool>,
+    /// Blocks whose predecessors are all known.
+    sealed: Vec<bool>,
+    predecessors: Vec<Vec<BlockId>>,
+    /// The value each variable has at the end of each block, where known.
+    definitions: HashMap<(Variable, BlockId), Value>,
+    /// Phis added to unsealed blocks, which get their operands once the
+    /// block is sealed.
+    incomplete: HashMap<BlockId, Vec<(Variable, Value)>>,
+    variable_types: Vec<ir::Type>,
+    /// Innermost scope last, mapping each name to its variable.
+    scopes: Vec<HashMap<String, Variable>>,
+    /// The innermost loop last, as (`continue` target, `break` target).
+    loops: Vec<(BlockId, BlockId)>,
+}
+
+impl FunctionState {
+    fn new(module: ModuleId, num: Option<Ty>, function: ir::Function) -> FunctionState {
+        FunctionState {
+            module,
+            num,
+            function,
+            current: BlockId(0),
+            filled: Vec::new(),
+            sealed: Vec::new(),
+            predecessors: Vec::new(),
+            definitions: HashMap::new(),
+            incomplete: HashMap::new(),
+            variable_types: Vec::new(),
+            scopes: vec![HashMap::new()],
+            loops: Vec::new(),
+        }
+    }
+}
+
+struct Lowering<'c> {
+    checked: &'c Checked,
+    module: ir::Module,
+    /// The IR type of every struct and enum.
+    types: HashMap<ItemId, u32>,
+    /// The index of every function instance that has been asked for.
+    functions: HashMap<(ItemId, Option<Ty>), u32>,
+    /// Instances that have an index but haven't been lowered yet.
+    queue: Vec<(ItemId, Option<Ty>, u32)>,
+    state: FunctionState,
+}
+
+impl<'c> Lowering<'c> {
+    /// Gives every struct and enum an IR type, in a stable order.
+    fn declare_types(&mut self) {
+        let mut ids: Vec<ItemId> =
+            self.checked.types.structs.keys().chain(self.checked.types.enums.keys()).copied().collect();
+        ids.sort();
+        for (index, id) in ids.iter().enumerate() {
+            self.types.insert(*id, index as u32);
+        }
+        for (module, item) in ids {
+            let name = self.checked.modules.qualified_name(module, self.checked.modules.object(module, item).name());
+            let kind = match self.checked.types.structs.get(&(module, item)) {
+                Some(fields) => ir::TypeKind::Struct(fields.iter().map(|f| (f.name.clone(), self.ir_type(f.ty))).collect()),
+                None => ir::TypeKind::Enum(
+                    self.checked.types.enums[&(module, item)]
+                        .iter()
+                        .map(|variant| {
+                            let fields = payload_types(&variant.payload).into_iter().map(|ty| self.ir_type(ty)).collect();
+                            (variant.name.clone(), fields)
+                        })
+                        .collect(),
+                ),
+            };
+            self.module.types.push(ir::TypeDefinition { name, kind });
+        }
+    }
+
+    fn ir_type(&self, ty: Ty) -> ir::Type {
+        match self.concrete(ty) {
+            Ty::I32 => ir::Type::I32,
+            Ty::I64 => ir::Type::I64,
+            Ty::U32 => ir::Type::U32,
+            Ty::U64 => ir::Type::U64,
+            Ty::F32 => ir::Type::F32,
+            Ty::F64 => ir::Type::F64,
+            Ty::Bool => ir::Type::Bool,
+            Ty::Char => ir::Type::Char,
+            Ty::String => ir::Type::String,
+            Ty::Struct(id) | Ty::Enum(id) => ir::Type::Named(self.types[&id]),
+            Ty::Num | Ty::Unit | Ty::Error => ir::Type::Unit,
+        }
+    }
+
+    fn concrete(&self, ty: Ty) -> Ty {
+        match ty {
+            Ty::Num => self.state.num.unwrap_or(Ty::I32),
+            ty => ty,
+        }
+    }
+
+    /// The type the type checker gave the expression at `span`, with `num`
+    /// replaced by the type of the instance being lowered.
+    fn type_of(&self, span: Span) -> Ty {
+        let ty = self.checked.types.expressions.get(&span).copied().unwrap_or(Ty::I32);
+        self.concrete(ty)
+    }
+
+    /// The index of a function instance, queueing it for lowering the
+    /// first time it is asked for.
+    fn function(&mut self, id: ItemId, num: Option<Ty>) -> u32 {
+        if let Some(index) = self.functions.get(&(id, num)) {
+            return *index;
+        }
+        let index = self.module.functions.len() as u32;
+        let (module, item) = id;
+        let mut name = self.checked.modules.qualified_name(module, self.checked.modules.object(module, item).name());
+        if let Some(num) = num {
+            name = format!("{}<{}>", name, num.name(&self.checked.modules));
+        }
+        let signature = &self.checked.types.functions[&id];
+        let ret = match signature.ret {
+            Ty::Num => num.unwrap_or(Ty::I32),
+            ty => ty,
+        };
+        let mut function = empty_function(name, ir::Type::Unit);
+        for param in &signature.params {
+            let ty = match param {
+                Ty::Num => num.unwrap_or(Ty::I32),
+                ty => *ty,
+            };
+            let value = function.new_value(self.ir_type(ty));
+            function.params.push(value);
+        }
+        function.ret = self.ir_type(ret);
+        self.module.functions.push(function);
+        self.functions.insert((id, num), index);
+        self.queue.push((id, num, index));
+        index
+    }
+
+    fn lower_function(&mut self, id: ItemId, num: Option<Ty>, index: u32) {
+        let (module, item) = id;
+        let ProgramObject::Function { arguments, statements, .. } = self.checked.modules.object(module, item) else {
+            unreachable!("only functions are lowered");
+        };
+        // Recursive calls look up the signature while the body is built, so
+        // the module keeps a copy without blocks until it is done.
+        let function = self.module.functions[index as usize].clone();
+        let params = function.params.clone();
+        self.state = FunctionState::new(module, num, function);
+        let entry = self.new_block();
+        self.seal(entry);
+        self.state.current = entry;
+        for (param, value) in arguments.iter().zip(params) {
+            let variable = self.declare(&param.name, self.state.function.type_of(value));
+            self.write(variable, entry, value);
+        }
+        for statement in statements {
+            self.statement(statement);
+        }
+        // Functions that return a value never get here; the CFG check
+        // makes sure of that.
+        if self.state.function.ret == ir::Type::Unit {
+            self.terminate(Terminator::Return(None));
+        } else {
+            self.terminate(Terminator::Unreachable);
+        }
+
+        let mut function = std::mem::replace(&mut self.state.function, empty_function(String::new(), ir::Type::Unit));
+        function.remove_unreachable_blocks();
+        function.remove_trivial_phis();
+        function.renumber_values();
+        self.module.functions[index as usize] = function;
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.state.function.blocks.push(ir::Block { instructions: Vec::new(), terminator: Terminator::Unreachable });
+        self.state.filled.push(false);
+        self.state.sealed.push(false);
+        self.state.predecessors.push(Vec::new());
+        BlockId(self.state.function.blocks.len() as u32 - 1)
+    }
+
+    /// The block code is being added to. After a terminator, such as the
+    /// jump of a `break`, code is unreachable and goes in a new block with
+    /// no predecessors, which is removed at the end.
+    fn block(&mut self) -> BlockId {
+        if self.state.filled[self.state.current.0 as usize] {
+            let dead = self.new_block();
+            self.seal(dead);
+            self.state.current = dead;
+        }
+        self.state.current
+    }
+
+    fn switch_to(&mut self, block: BlockId) {
+        self.state.current = block;
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        let block = self.block();
+        for successor in terminator.successors() {
+            self.state.predecessors[successor.0 as usize].push(block);
+        }
+        self.state.function.block_mut(block).terminator = terminator;
+        self.state.filled[block.0 as usize] = true;
+    }
+
+    fn jump(&mut self, target: BlockId) {
+        self.terminate(Terminator::Jump(target));
+    }
+
+    /// Branches to a new block if `condition` holds, or to `fail`, and
+    /// continues in the new block.
+    fn test(&mut self, condition: Value, fail: BlockId) {
+        let ok = self.new_block();
+        self.terminate(Terminator::Branch(condition, ok, fail));
+        self.seal(ok);
+        self.switch_to(ok);
+    }
+
+    fn emit(&mut self, kind: InstructionKind, ty: Option<ir::Type>, location: Option<String>) -> Option<Value> {
+        let block = self.block();
+        let result = ty.map(|ty| self.state.function.new_value(ty));
+        self.state.function.block_mut(block).instructions.push(ir::Instruction { result, kind, location });
+        result
+    }
+
+    fn emit_value(&mut self, kind: InstructionKind, ty: ir::Type) -> Value {
+        self.emit(kind, Some(ty), None).expect("a result type was given")
+    }
+
+    fn constant(&mut self, constant: Constant) -> Value {
+        let ty = constant.ty();
+        self.emit_value(InstructionKind::Const(constant), ty)
+    }
+
+    fn location(&self, span: Span) -> Option<String> {
+        Some(self.checked.sources.get(span.file).location(span))
+    }
+
+    /// A new variable with no name, for values that merge across blocks,
+    /// such as the result of an `if` expression.
+    fn variable(&mut self, ty: ir::Type) -> Variable {
+        self.state.variable_types.push(ty);
+        self.state.variable_types.len() - 1
+    }
+
+    /// Gives a name a new variable in the innermost scope.
+    fn declare(&mut self, name: &str, ty: ir::Type) -> Variable {
+        let variable = self.variable(ty);
+        if let Some(scope) = self.state.scopes.last_mut() {
+            scope.insert(name.to_string(), variable);
+        }
+        variable
+    }
+
+    fn local(&self, name: &str) -> Option<Variable> {
+        self.state.scopes.iter().rev().find_map(|scope| scope.get(name)).copied()
+    }
+
+    fn write(&mut self, variable: Variable, block: BlockId, value: Value) {
+        self.state.definitions.insert((variable, block), value);
+    }
+
+    /// Assigns a variable in the block code is being added to.
+    fn assign(&mut self, variable: Variable, value: Value) {
+        let block = self.block();
+        self.write(variable, block, value);
+    }
+
+    fn read(&mut self, variable: Variable, block: BlockId) -> Value {
+        if let Some(value) = self.state.definitions.get(&(variable, block)) {
+            return *value;
+        }
+        let value = if !self.state.sealed[block.0 as usize] {
+            let phi = self.new_phi(variable, block);
+            self.state.incomplete.entry(block).or_default().push((variable, phi));
+            phi
+        } else if let [predecessor] = self.state.predecessors[block.0 as usize][..] {
+            self.read(variable, predecessor)
+        } else {
+            // Defining the phi first ends the search at loops.
+            let phi = self.new_phi(variable, block);
+            self.write(variable, block, phi);
+            self.add_phi_operands(variable, phi, block);
+            phi
+        };
+        self.write(variable, block, value);
+        value
+    }
+
+    /// Reads a variable in the block code is being added to.
+    fn current_value(&mut self, variable: Variable) -> Value {
+        let block = self.block();
+        self.read(variable, block)
+    }
+
+    /// Adds a phi with no operands yet to the start of `block`.
+    fn new_phi(&mut self, variable: Variable, block: BlockId) -> Value {
+        let phi = self.state.function.new_value(self.state.variable_types[variable]);
+        let instructions = &mut self.state.function.block_mut(block).instructions;
+        let position = instructions.iter().take_while(|i| i.is_phi()).count();
+        let instruction = ir::Instruction { result: Some(phi), kind: InstructionKind::Phi(Vec::new()), location: None };
+        instructions.insert(position, instruction);
+        phi
+    }
+
+    fn add_phi_operands(&mut self, variable: Variable, phi: Value, block: BlockId) {
+        for predecessor in self.state.predecessors[block.0 as usize].clone() {
+            let value = self.read(variable, predecessor);
+            let instruction = self.state.function.block_mut(block).instructions.iter_mut().find(|i| i.result == Some(phi));
+            if let Some(ir::Instruction { kind: InstructionKind::Phi(incoming), .. }) = instruction {
+                incoming.push((predecessor, value));
+            }
+        }
+    }
+
+    /// Records that every predecessor of `block` is known, completing the
+    /// phis added to it while they weren't.
+    fn seal(&mut self, block: BlockId) {
+        for (variable, phi) in self.state.incomplete.remove(&block).unwrap_or_default() {
+            self.add_phi_operands(variable, phi, block);
+        }
+        self.state.sealed[block.0 as usize] = true;
+    }
+
+    /// What a path that doesn't start with a local refers to, and the
+    /// segments left over after it, such as an enum variant's name.
+    fn resolve<'a>(&self, path: &'a [String]) -> Option<(Resolved, &'a [String])> {
+        let (resolved, used) = self.checked.modules.lookup_prefix(self.state.module, path)?;
+        Some((resolved, &path[used..]))
+    }
+
+    /// The tag of the enum variant named by a path, and the names and types
+    /// of its fields in order.
+    fn variant(&self, resolved: Resolved, name: &str) -> (u32, Vec<(String, Ty)>) {
+        let Resolved::Item(module, index) = resolved else {
+            unreachable!("only enums have variants");
+        };
+        let variants = &self.checked.types.enums[&(module, index)];
+        let tag = variants.iter().position(|v| v.name == name).expect("the type checker checked the variant exists");
+        let fields = match &variants[tag].payload {
+            Payload::Unit => Vec::new(),
+            Payload::Tuple(types) => types.iter().enumerate().map(|(i, ty)| (i.to_string(), *ty)).collect(),
+            Payload::Struct(fields) => fields.iter().map(|f| (f.name.clone(), f.ty)).collect(),
+        };
+        (tag as u32, fields)
+    }
+
+    /// The fields of the struct named by a path, in declaration order.
+    fn struct_fields(&self, resolved: Resolved) -> Vec<(String, Ty)> {
+        let Resolved::Item(module, index) = resolved else {
+            unreachable!("only structs have fields");
+        };
+        self.checked.types.structs[&(module, index)].iter().map(|f| (f.name.clone(), f.ty)).collect()
+    }
+
+    fn block_statements(&mut self, statements: &'c [Statement]) {
+        self.state.scopes.push(HashMap::new());
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.state.scopes.pop();
+    }
+
+    fn statement(&mut self, statement: &'c Statement) {
+        match &statement.kind {
+            StatementKind::Let { name, value, .. } => {
+                let ty = self.ir_type(self.type_of(value.span));
+                let value = self.value(value);
+                let variable = self.declare(name, ty);
+                self.assign(variable, value);
+            }
+            StatementKind::Set { name, new_value } => {
+                let value = self.value(new_value);
+                if let Some(variable) = self.local(name) {
+                    self.assign(variable, value);
+                }
+            }
+            StatementKind::Ret { value } => {
+                if self.state.function.ret == ir::Type::Unit {
+                    self.expression(value);
+                    self.terminate(Terminator::Return(None));
+                } else {
+                    let value = self.value(value);
+                    self.terminate(Terminator::Return(Some(value)));
+                }
+            }
+            StatementKind::FunctionCall { call } => {
+                self.expression(call);
+            }
+            StatementKind::If { condition, then_block, else_branch } => {
+                let condition = self.value(condition);
+                let (then, end) = (self.new_block(), self.new_block());
+                let otherwise = if else_branch.is_some() { self.new_block() } else { end };
+                self.terminate(Terminator::Branch(condition, then, otherwise));
+                self.seal(then);
+                self.switch_to(then);
+                self.block_statements(&then_block.statements);
+                self.jump(end);
+                if let Some(else_branch) = else_branch {
+                    self.seal(otherwise);
+                    self.switch_to(otherwise);
+                    self.statement(else_branch);
+                    self.jump(end);
+                }
+                self.seal(end);
+                self.switch_to(end);
+            }
+            StatementKind::Block(block) => self.block_statements(&block.statements),
+            StatementKind::While { condition, body } => {
+                let header = self.new_block();
+                self.jump(header);
+                self.switch_to(header);
+                let condition = self.value(condition);
+                let (inside, exit) = (self.new_block(), self.new_block());
+                self.terminate(Terminator::Branch(condition, inside, exit));
+                self.seal(inside);
+                self.switch_to(inside);
+                self.state.loops.push((header, exit));
+                self.block_statements(&body.statements);
+                self.state.loops.pop();
+                self.jump(header);
+                self.seal(header);
+                self.seal(exit);
+                self.switch_to(exit);
+            }
+            StatementKind::For { variable, iterable, body } => {
+                let ForIterable::Range { start, end } = iterable;
+                let ty = self.type_of(start.span);
+                let ir_ty = self.ir_type(ty);
+                self.state.scopes.push(HashMap::new());
+                let start = self.value(start);
+                let limit = self.value(end);
+                let counter = self.declare(variable, ir_ty);
+                self.assign(counter, start);
+
+                let header = self.new_block();
+                self.jump(header);
+                self.switch_to(header);
+                let current = self.current_value(counter);
+                let more = self.emit_value(InstructionKind::Binary(ir::BinaryOp::Lt, current, limit), ir::Type::Bool);
+                let (inside, step, exit) = (self.new_block(), self.new_block(), self.new_block());
+                self.terminate(Terminator::Branch(more, inside, exit));
+                self.seal(inside);
+                self.switch_to(inside);
+                self.state.loops.push((step, exit));
+                self.block_statements(&body.statements);
+                self.state.loops.pop();
+                self.jump(step);
+                self.seal(step);
+                self.switch_to(step);
+                let current = self.current_value(counter);
+                let one = self.constant(integer_constant(1, ty));
+                let next = self.emit_value(InstructionKind::Binary(ir::BinaryOp::Add, current, one), ir_ty);
+                self.assign(counter, next);
+                self.jump(header);
+                self.seal(header);
+                self.seal(exit);
+                self.switch_to(exit);
+                self.state.scopes.pop();
+            }
+            StatementKind::Break => {
+                if let Some((_, exit)) = self.state.loops.last().copied() {
+                    self.jump(exit);
+                }
+            }
+            StatementKind::Continue => {
+                if let Some((next, _)) = self.state.loops.last().copied() {
+                    self.jump(next);
+                }
+            }
+            StatementKind::Match { scrutinee, arms } => {
+                let end = self.new_block();
+                self.lower_match(scrutinee, arms, end, |lowering, body| lowering.statement(body));
+                // A match statement does nothing when no arm matches.
+                self.jump(end);
+                self.seal(end);
+                self.switch_to(end);
+            }
+        }
+    }
+
+    /// Lowers the arms of a match in order, each testing the scrutinee
+    /// against its pattern and running its body on a match, then jumping
+    /// to `end`. Continues in a block reached when no arm matches.
+    fn lower_match<T>(
+        &mut self,
+        scrutinee: &'c Expression,
+        arms: &'c [MatchArm<T>],
+        end: BlockId,
+        mut body: impl FnMut(&mut Self, &'c T),
+    ) {
+        let ty = self.type_of(scrutinee.span);
+        let value = self.value(scrutinee);
+        for arm in arms {
+            let next = self.new_block();
+            self.state.scopes.push(HashMap::new());
+            self.pattern(&arm.pattern, value, ty, next);
+            body(self, &arm.body);
+            self.jump(end);
+            self.state.scopes.pop();
+            self.seal(next);
+            self.switch_to(next);
+        }
+    }
+
+    /// Tests `value`, of type `ty`, against `pattern`, branching to `fail`
+    /// if it doesn't match and continuing with the pattern's variables
+    /// assigned in the innermost scope if it does.
+    fn pattern(&mut self, pattern: &Pattern, value: Value, ty: Ty, fail: BlockId) {
+        match &pattern.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Binding(name) => {
+                // The alternatives of an or-pattern bind the same names, so
+                // they assign the same variables.
+                let variable = match self.state.scopes.last().and_then(|scope| scope.get(name)) {
+                    Some(variable) => *variable,
+                    None => self.declare(name, self.ir_type(ty)),
+                };
+                self.assign(variable, value);
+            }
+            PatternKind::Literal(literal) => {
+                let constant = self.constant(literal_constant(literal, ty));
+                let equal = self.emit_value(InstructionKind::Binary(ir::BinaryOp::Eq, value, constant), ir::Type::Bool);
+                self.test(equal, fail);
+            }
+            PatternKind::Range { start, end } => {
+                let start = self.constant(literal_constant(start, ty));
+                let above = self.emit_value(InstructionKind::Binary(ir::BinaryOp::Ge, value, start), ir::Type::Bool);
+                self.test(above, fail);
+                let end = self.constant(literal_constant(end, ty));
+                let below = self.emit_value(InstructionKind::Binary(ir::BinaryOp::Lt, value, end), ir::Type::Bool);
+                self.test(below, fail);
+            }
+            PatternKind::Or(alternatives) => {
+                let matched = self.new_block();
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    if i + 1 == alternatives.len() {
+                        self.pattern(alternative, value, ty, fail);
+                        break;
+                    }
+                    let next = self.new_block();
+                    self.pattern(alternative, value, ty, next);
+                    self.jump(matched);
+                    self.seal(next);
+                    self.switch_to(next);
+                }
+                self.jump(matched);
+                self.seal(matched);
+                self.switch_to(matched);
+            }
+            PatternKind::Constructor { path, fields: patterns } => {
+                let (fields, tag) = match self.resolve(path) {
+                    Some((resolved, [variant])) => {
+                        let (tag, fields) = self.variant(resolved, variant);
+                        let actual = self.emit_value(InstructionKind::Tag(value), ir::Type::U32);
+                        let expected = self.constant(Constant::U32(tag));
+                        let equal =
+                            self.emit_value(InstructionKind::Binary(ir::BinaryOp::Eq, actual, expected), ir::Type::Bool);
+                        self.test(equal, fail);
+                        (fields, Some(tag))
+                    }
+                    Some((resolved, [])) => (self.struct_fields(resolved), None),
+                    _ => unreachable!("the type checker only allows struct and variant patterns"),
+                };
+                let named: Vec<(usize, &Pattern)> = match patterns {
+                    PatternFields::Unit => Vec::new(),
+                    PatternFields::Tuple(patterns) => patterns.iter().enumerate().collect(),
+                    PatternFields::Struct(patterns) => patterns
+                        .iter()
+                        .filter_map(|(name, pattern)| Some((fields.iter().position(|(n, _)| n == name)?, pattern)))
+                        .collect(),
+                };
+                for (index, pattern) in named {
+                    if matches!(pattern.kind, PatternKind::Wildcard) {
+                        continue;
+                    }
+                    let field_ty = fields[index].1;
+                    let kind = match tag {
+                        Some(tag) => InstructionKind::Payload(value, tag, index as u32),
+                        None => InstructionKind::Field(value, index as u32),
+                    };
+                    let field = self.emit_value(kind, self.ir_type(field_ty));
+                    self.pattern(pattern, field, field_ty, fail);
+                }
+            }
+        }
+    }
+
+    /// Lowers an expression that must produce a value, giving calls to
+    /// functions that return nothing a `unit` constant.
+    fn value(&mut self, expression: &'c Expression) -> Value {
+        match self.expression(expression) {
+            Some(value) => value,
+            None => self.constant(Constant::Unit),
+        }
+    }
+
+    /// Lowers an expression, returning its value unless it is a call to a
+    /// function that returns nothing.
+    fn expression(&mut self, expression: &'c Expression) -> Option<Value> {
+        let ty = self.type_of(expression.span);
+        let ir_ty = self.ir_type(ty);
+        let value = match &expression.kind {
+            ExpressionKind::IntLiteral(value) => self.constant(integer_constant(*value, ty)),
+            ExpressionKind::FloatLiteral(value) => self.constant(float_constant(*value, ty)),
+            ExpressionKind::StringLiteral(value) => self.constant(Constant::String(value.clone())),
+            ExpressionKind::CharLiteral(value) => self.constant(Constant::Char(*value)),
+            ExpressionKind::BoolLiteral(value) => self.constant(Constant::Bool(*value)),
+            ExpressionKind::Variable(name) => match self.local(name) {
+                Some(variable) => self.current_value(variable),
+                None => self.path(std::slice::from_ref(name), ir_ty),
+            },
+            ExpressionKind::FieldAccess { object, field } => {
+                if let Some(path) = expression.as_path()
+                    && self.local(&path[0]).is_none()
+                {
+                    return Some(self.path(&path, ir_ty));
+                }
+                let Ty::Struct(id) = self.type_of(object.span) else {
+                    unreachable!("the type checker only allows field access on structs");
+                };
+                let index = self.checked.types.structs[&id].iter().position(|f| f.name == *field).unwrap_or(0);
+                let object = self.value(object);
+                self.emit_value(InstructionKind::Field(object, index as u32), ir_ty)
+            }
+            ExpressionKind::Binary { op: op @ (BinaryOp::And | BinaryOp::Or), left, right } => {
+                // `a && b` is `if a { b } else { false }`, and `a || b` is
+                // `if a { true } else { b }`.
+                let result = self.variable(ir::Type::Bool);
+                let left = self.value(left);
+                self.assign(result, left);
+                let (long, end) = (self.new_block(), self.new_block());
+                let terminator = match op {
+                    BinaryOp::And => Terminator::Branch(left, long, end),
+                    _ => Terminator::Branch(left, end, long),
+                };
+                self.terminate(terminator);
+                self.seal(long);
+                self.switch_to(long);
+                let right = self.value(right);
+                self.assign(result, right);
+                self.jump(end);
+                self.seal(end);
+                self.switch_to(end);
+                self.current_value(result)
+            }
+            ExpressionKind::Binary { op, left, right } => {
+                let left = self.value(left);
+                let right = self.value(right);
+                let op = match op {
+                    BinaryOp::Add => ir::BinaryOp::Add,
+                    BinaryOp::Sub => ir::BinaryOp::Sub,
+                    BinaryOp::Mul => ir::BinaryOp::Mul,
+                    BinaryOp::Div => ir::BinaryOp::Div,
+                    BinaryOp::Mod => ir::BinaryOp::Rem,
+                    BinaryOp::Eq => ir::BinaryOp::Eq,
+                    BinaryOp::Ne => ir::BinaryOp::Ne,
+                    BinaryOp::Lt => ir::BinaryOp::Lt,
+                    BinaryOp::Le => ir::BinaryOp::Le,
+                    BinaryOp::Gt => ir::BinaryOp::Gt,
+                    BinaryOp::Ge => ir::BinaryOp::Ge,
+                    BinaryOp::BitAnd => ir::BinaryOp::And,
+                    BinaryOp::BitXor => ir::BinaryOp::Xor,
+                    BinaryOp::Shl => ir::BinaryOp::Shl,
+                    BinaryOp::Shr => ir::BinaryOp::Shr,
+                    BinaryOp::And | BinaryOp::Or => unreachable!("`&&` and `||` short-circuit"),
+                };
+                let location = if matches!(op, ir::BinaryOp::Div | ir::BinaryOp::Rem) {
+                    self.location(expression.span)
+                } else {
+                    None
+                };
+                self.emit(InstructionKind::Binary(op, left, right), Some(ir_ty), location).expect("a result type was given")
+            }
+            ExpressionKind::Unary { op: UnaryOp::Neg, operand } if matches!(operand.kind, ExpressionKind::IntLiteral(_)) => {
+                let ExpressionKind::IntLiteral(value) = operand.kind else {
+                    unreachable!();
+                };
+                self.constant(integer_constant(value.wrapping_neg(), ty))
+            }
+            ExpressionKind::Unary { op, operand } => {
+                let operand = self.value(operand);
+                let op = match op {
+                    UnaryOp::Neg => ir::UnaryOp::Neg,
+                    UnaryOp::Not => ir::UnaryOp::Not,
+                };
+                self.emit_value(InstructionKind::Unary(op, operand), ir_ty)
+            }
+            ExpressionKind::FunctionCall { callee, args } => {
+                let args: Vec<Value> = args.iter().map(|arg| self.value(arg)).collect();
+                let path = callee.as_path().expect("the type checker only allows calling functions and variants");
+                match self.resolve(&path) {
+                    Some((Resolved::Item(module, index), [])) => {
+                        let num = self.checked.types.generic_calls.get(&callee.span).map(|ty| self.concrete(*ty));
+                        let function = self.function((module, index), num);
+                        let ret = self.module.functions[function as usize].ret;
+                        let result = (ret != ir::Type::Unit).then_some(ret);
+                        // Calls can fail too, when they are nested too deeply.
+                        let location = self.location(expression.span);
+                        return self.emit(InstructionKind::Call(function, args), result, location);
+                    }
+                    Some((resolved, [variant])) => {
+                        let (tag, _) = self.variant(resolved, variant);
+                        self.emit_value(InstructionKind::Variant(tag, args), ir_ty)
+                    }
+                    _ => unreachable!("the type checker only allows calling functions and variants"),
+                }
+            }
+            ExpressionKind::If { condition, then_value, else_value } => {
+                let result = self.variable(ir_ty);
+                let condition = self.value(condition);
+                let (then, otherwise, end) = (self.new_block(), self.new_block(), self.new_block());
+                self.terminate(Terminator::Branch(condition, then, otherwise));
+                for (block, value) in [(then, then_value), (otherwise, else_value)] {
+                    self.seal(block);
+                    self.switch_to(block);
+                    let value = self.value(value);
+                    self.assign(result, value);
+                    self.jump(end);
+                }
+                self.seal(end);
+                self.switch_to(end);
+                self.current_value(result)
+            }
+            ExpressionKind::Match { scrutinee, arms } => {
+                let result = self.variable(ir_ty);
+                let end = self.new_block();
+                self.lower_match(scrutinee, arms, end, |lowering, body| {
+                    let value = lowering.value(body);
+                    lowering.assign(result, value);
+                });
+                let location = self.location(expression.span);
+                self.terminate(Terminator::NoMatch(location));
+                self.seal(end);
+                self.switch_to(end);
+                self.current_value(result)
+            }
+            ExpressionKind::StructLiteral { path, fields: inits } => {
+                // Fields are evaluated in the order they are written, then
+                // put in the order they are declared.
+                let mut values = HashMap::new();
+                for init in inits {
+                    let value = self.value(&init.value);
+                    values.insert(init.name.as_str(), value);
+                }
+                let (fields, tag) = match self.resolve(path) {
+                    Some((resolved, [variant])) => {
+                        let (tag, fields) = self.variant(resolved, variant);
+                        (fields, Some(tag))
+                    }
+                    Some((resolved, _)) => (self.struct_fields(resolved), None),
+                    None => unreachable!("the type checker only allows struct and variant literals"),
+                };
+                let fields: Vec<Value> = fields.iter().map(|(name, _)| values[name.as_str()]).collect();
+                let kind = match tag {
+                    Some(tag) => InstructionKind::Variant(tag, fields),
+                    None => InstructionKind::Struct(fields),
+                };
+                self.emit_value(kind, ir_ty)
+            }
+        };
+        Some(value)
+    }
+
+    /// The value of a path that isn't a local: a unit enum variant.
+    fn path(&mut self, path: &[String], ty: ir::Type) -> Value {
+        match self.resolve(path) {
+            Some((resolved, [variant])) => {
+                let (tag, _) = self.variant(resolved, variant);
+                self.emit_value(InstructionKind::Variant(tag, Vec::new()), ty)
+            }
+            _ => unreachable!("the type checker only allows unit variants as values"),
+        }
+    }
+}
+
+fn payload_types(payload: &Payload) -> Vec<Ty> {
+    match payload {
+        Payload::Unit => Vec::new(),
+        Payload::Tuple(types) => types.clone(),
+        Payload::Struct(fields) => fields.iter().map(|f| f.ty).collect(),
+    }
+}
+
+fn integer_constant(value: i128, ty: Ty) -> Constant {
+    match ty {
+        Ty::I64 => Constant::I64(value as i64),
+        Ty::U32 => Constant::U32(value as u32),
+        Ty::U64 => Constant::U64(value as u64),
+        Ty::F32 => Constant::F32(value as f32),
+        Ty::F64 => Constant::F64(value as f64),
+        _ => Constant::I32(value as i32),
+    }
+}
+
+fn float_constant(value: f64, ty: Ty) -> Constant {
+    match ty {
+        Ty::F32 => Constant::F32(value as f32),
+        _ => Constant::F64(value),
+    }
+}
+
+/// A pattern literal as a constant of the type of the value it is matched
+/// against.
+fn literal_constant(literal: &Literal, ty: Ty) -> Constant {
+    match literal {
+        Literal::Int(value) => integer_constant(*value, ty),
+        Literal::Float(value) => float_constant(*value, ty),
+        Literal::Bool(value) => Constant::Bool(*value),
+        Literal::Char(value) => Constant::Char(*value),
+        Literal::String(value) => Constant::String(value.clone()),
+    }
+}
@@ -2,51 +2,235 @@
 use colored::Colorize;
 use std::env;
 use std::path::*;
+mod bytecode;
+mod c_backend;
+mod cfg;
+mod codegen;
 mod compiler;
-use compiler::compile;
+mod diagnostic;
+use compiler::{build, compile, run, Backend, Emit, Target};
+use diagnostic::Diagnostic;
+use optimize::{Level, Pass, Pipeline};
 mod function;
+mod interpreter;
+mod ir;
+#[cfg(feature = "jit")]
+mod jit;
+mod lower;
 mod tokenizer;
+mod typeck;
+mod module;
+mod mutability;
+mod optimize;
 mod parser;
+mod resolver;
+mod span;
+mod symbols;
+mod vm;
+mod x86_backend;
+
+/// What to do with the root file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    /// Check the program and dump it, or check an IR file and print it
+    /// back.
+    Check,
+    /// Check the program, then run its `main`.
+    Run,
+    /// Compile the program to a `.c4b` bytecode file or C source.
+    Build,
+}
+
+/// Everything asked for on the command line.
+#[derive(Debug)]
+struct Options {
+    command: Command,
+    file: String,
+    emit: Option<Emit>,
+    backend: Backend,
+    target: Target,
+    output: Option<String>,
+    /// The passes run over the IR before any backend sees it.
+    pipeline: Pipeline,
+}
+
+const USAGE: &str = "usage: c4 [run [--interpret | --jit] | build [--target bytecode|c|x86_64] [-o <file>]] \
+     [--emit=bytecode|c|asm|ir] [-O0|-O1|-O2] [--enable-pass=<pass>] [--disable-pass=<pass>] <file.c4l>";
+
 fn main() {
-    println!("");
-    let file = ensure_valid_root_file(env::args().collect());
-    match file {
-        Some(path) => compile(path),
-        None => {
-            error("Compiler failed on step: validate root file.\n");
-            return;
+    // Everything but the output asked for goes to stderr, so `--emit` can
+    // be redirected into a file.
+    eprintln!();
+    let Some(options) = parse_args(env::args().skip(1).collect()) else {
+        Diagnostic::error("Compiler failed on step: validate root file.").emit(None);
+        std::process::exit(1);
+    };
+    match options.command {
+        Command::Check => std::process::exit(compile(options.file, options.emit, &options.pipeline)),
+        Command::Run => std::process::exit(run(options.file, options.backend, &options.pipeline)),
+        Command::Build => std::process::exit(build(options.file, options.target, options.output, &options.pipeline)),
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Option<Options> {
+    let mut args = args.into_iter().peekable();
+    let command = match args.peek().map(String::as_str) {
+        Some("run") => Command::Run,
+        Some("build") => Command::Build,
+        _ => Command::Check,
+    };
+    if command != Command::Check {
+        args.next();
+    }
+    let mut options = Options {
+        command,
+        file: String::new(),
+        emit: None,
+        backend: Backend::Vm,
+        target: Target::Bytecode,
+        output: None,
+        pipeline: Pipeline::new(Level::O0),
+    };
+    let mut level = Level::O0;
+    // Applied after the level, wherever they are on the command line.
+    let mut toggles = Vec::new();
+    // The first flag that changes the pipeline, for when nothing runs it.
+    let mut optimization_flag = None;
+    let mut files = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--emit=bytecode" => options.emit = Some(Emit::Bytecode),
+            "--emit=c" => options.emit = Some(Emit::C),
+            "--emit=asm" => options.emit = Some(Emit::Asm),
+            "--emit=ir" => options.emit = Some(Emit::Ir),
+            "-O0" | "-O1" | "-O2" => {
+                level = match arg.as_str() {
+                    "-O0" => Level::O0,
+                    "-O1" => Level::O1,
+                    _ => Level::O2,
+                };
+                optimization_flag.get_or_insert_with(|| arg.clone());
+            }
+            "--interpret" if command == Command::Run => options.backend = Backend::Interpreter,
+            "--jit" if command == Command::Run => options.backend = Backend::Jit,
+            "-o" if command == Command::Build => match args.next() {
+                Some(output) => options.output = Some(output),
+                None => {
+                    Diagnostic::error("`-o` needs a file name after it").with_note(USAGE).emit(None);
+                    return None;
+                }
+            },
+            "--target" if command == Command::Build => match args.next().as_deref() {
+                Some("bytecode") => options.target = Target::Bytecode,
+                Some("c") => options.target = Target::C,
+                Some("x86_64") => options.target = Target::X86_64,
+                target => {
+                    let message = match target {
+                        Some(target) => format!("unknown target `{}`", target),
+                        None => "`--target` needs a target after it".to_string(),
+                    };
+                    Diagnostic::error(message).with_note(USAGE).emit(None);
+                    return None;
+                }
+            },
+            flag if flag.starts_with("--enable-pass=") || flag.starts_with("--disable-pass=") => {
+                let (toggle, name) = flag.split_once('=').expect("the flag has an `=`");
+                let Some(pass) = Pass::from_name(name) else {
+                    Diagnostic::error(format!("unknown pass `{}`", name))
+                        .with_note(format!("the passes are {}", Pass::names()))
+                        .emit(None);
+                    return None;
+                };
+                toggles.push((pass, toggle == "--enable-pass"));
+                optimization_flag.get_or_insert_with(|| flag.to_string());
+            }
+            flag if flag.starts_with('-') => {
+                Diagnostic::error(format!("unknown option `{}`", flag)).with_note(USAGE).emit(None);
+                return None;
+            }
+            _ => files.push(arg),
+        }
+    }
+    options.pipeline = Pipeline::new(level);
+    for (pass, enabled) in toggles {
+        options.pipeline.set(pass, enabled);
+    }
+    if let Some(emit) = options.emit
+        && command != Command::Check
+    {
+        let command = if command == Command::Run { "run" } else { "build" };
+        Diagnostic::error(format!("`--emit={}` can't be used with `c4 {}`", emit.name(), command))
+            .with_note("`--emit` prints the program instead of running or building it")
+            .with_note(format!("help: remove `{}` to print it", command))
+            .emit(None);
+        return None;
+    }
+    options.file = ensure_valid_root_file(files, command)?;
+    if options.file.ends_with(".c4b") && options.backend != Backend::Vm {
+        let flag = if options.backend == Backend::Jit { "--jit" } else { "--interpret" };
+        Diagnostic::error(format!("`{}` can't run a bytecode file", flag))
+            .with_note("`.c4b` files only run on the VM")
+            .with_note(format!("help: remove `{}`, or pass the `.c4l` file instead", flag))
+            .emit(None);
+        return None;
+    }
+    if let Some(flag) = optimization_flag {
+        // The interpreter walks the syntax tree, a `.c4b` file is past the
+        // IR already, and the module dump is printed before lowering.
+        let ignored_by = if options.file.ends_with(".c4b") {
+            Some("when running a bytecode file")
+        } else if command == Command::Run && options.backend == Backend::Interpreter {
+            Some("with `--interpret`")
+        } else if command == Command::Check && options.emit.is_none() && !options.file.ends_with(".c4ir") {
+            Some("on the module dump")
+        } else {
+            None
+        };
+        if let Some(ignored_by) = ignored_by {
+            Diagnostic::error(format!("`{}` has no effect {}", flag, ignored_by))
+                .with_note("the optimization options only apply to the IR the other backends compile from")
+                .with_note(format!("help: remove `{}`", flag))
+                .emit(None);
+            return None;
         }
     }
+    if options.file.ends_with(".c4ir") && options.emit.is_some_and(|emit| emit != Emit::Ir) {
+        Diagnostic::error("an IR file can only be printed back as IR")
+            .with_note("help: remove the `--emit` option")
+            .emit(None);
+        return None;
+    }
+    Some(options)
 }
 
-fn ensure_valid_root_file(mut args: Vec<String>) -> Option<String> {
-    args.remove(0);
+fn ensure_valid_root_file(args: Vec<String>, command: Command) -> Option<String> {
     if args.len() != 1 {
-        error("you must pass one file path to the compiler, it will find the rest.\n");
+        Diagnostic::error("you must pass one file path to the compiler, it will find the rest.")
+            .with_note(USAGE)
+            .emit(None);
         return None;
     }
     let file = args[0].clone();
     drop(args);
     if !Path::new(&file).exists() {
-        error("the chosen file doesn't exist.");
+        Diagnostic::error(format!("the chosen file `{}` doesn't exist.", file)).emit(None);
         return None;
     }
 
-    let file_components: Vec<String> = file.split('.').map(String::from).collect();
-    if file_components.len() < 2 {
-        error("please pass a file with a file extension.");
-        return None;
-    }
-    if file_components[1] != "c4l" {
-        error("please pass a file with the .c4l extension.");
-        return None;
+    match Path::new(&file).extension() {
+        None => {
+            Diagnostic::error("please pass a file with a file extension.").emit(None);
+            return None;
+        }
+        Some(extension) if extension == "c4b" && command == Command::Run => {}
+        Some(extension) if extension == "c4ir" && command == Command::Check => {}
+        Some(extension) if extension != "c4l" => {
+            Diagnostic::error("please pass a file with the .c4l extension.").emit(None);
+            return None;
+        }
+        Some(_) => {}
     }
 
-    println!("chosen root file: {}", file.bright_green());
-    return Some(file);
-}
-
-fn error(message: &str) {
-    eprintln!("{}", message.to_string().red());
-    return;
+    eprintln!("chosen root file: {}", file.bright_green());
+    Some(file)
 }
@@ -0,0 +1,562 @@
+use crate::diagnostic::{codes, Diagnostic};
+use crate::parser::{Parser, ProgramObject, UseDecl, UseKind};
+use crate::span::{SourceMap, Span};
+use crate::tokenizer::parse;
+use std::collections::HashMap;
+use std::fs::read;
+use std::path::{Path, PathBuf};
+
+pub(crate) type ModuleId = usize;
+
+/// The root file's module. Its items have unqualified names.
+pub(crate) const ROOT: ModuleId = 0;
+
+#[derive(Debug)]
+pub(crate) struct Module {
+    pub name: String,
+    pub parent: Option<ModuleId>,
+    pub children: Vec<ModuleId>,
+    /// The functions, structs and enums declared in this module. `mod`
+    /// declarations are turned into `children` instead.
+    pub objs: Vec<ProgramObject>,
+    /// The `use` declarations written in this module.
+    pub uses: Vec<UseDecl>,
+    /// Names brought in by single `use` declarations, filled in by
+    /// `ModuleTree::resolve_imports`.
+    pub imports: Vec<Import>,
+    /// Modules glob-imported with `use a.*;`.
+    pub globs: Vec<ModuleId>,
+    /// The `mod` declaration, or the start of the root file.
+    pub span: Span,
+    /// Where `mod name;` declarations inside this module look for files.
+    dir: PathBuf,
+}
+
+/// Every module of the program, with the root file's module at `ROOT`.
+#[derive(Debug)]
+pub(crate) struct ModuleTree {
+    pub modules: Vec<Module>,
+}
+
+/// A name bound by `use`.
+#[derive(Debug)]
+pub(crate) struct Import {
+    pub name: String,
+    pub target: Resolved,
+    pub span: Span,
+}
+
+/// What a dotted path such as `math.add` refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resolved {
+    Module(ModuleId),
+    /// An object, by its module and index into that module's `objs`.
+    Item(ModuleId, usize),
+}
+
+impl ModuleTree {
+    pub fn get(&self, id: ModuleId) -> &Module {
+        &self.modules[id]
+    }
+
+    pub fn child(&self, id: ModuleId, name: &str) -> Option<ModuleId> {
+        self.modules[id].children.iter().copied().find(|c| self.modules[*c].name == name)
+    }
+
+    pub fn item(&self, id: ModuleId, name: &str) -> Option<usize> {
+        self.modules[id].objs.iter().position(|o| o.name() == name)
+    }
+
+    pub fn object(&self, id: ModuleId, index: usize) -> &ProgramObject {
+        &self.modules[id].objs[index]
+    }
+
+    /// The names of the modules leading from the root to `id`.
+    pub fn module_path(&self, id: ModuleId) -> Vec<String> {
+        let mut path = Vec::new();
+        let mut current = Some(id);
+        while let Some(module) = current {
+            if module != ROOT {
+                path.push(self.modules[module].name.clone());
+            }
+            current = self.modules[module].parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// `math.add` for the item `add` in module `math`, just `add` in the
+    /// root module.
+    pub fn qualified_name(&self, id: ModuleId, name: &str) -> String {
+        let mut path = self.module_path(id);
+        path.push(name.to_string());
+        path.join(".")
+    }
+
+    /// What `name` means inside module `scope`: one of its own items or
+    /// submodules, then a name it imported, then something from a module
+    /// it glob-imported.
+    pub fn lookup_name(&self, scope: ModuleId, name: &str) -> Option<Resolved> {
+        if let Some(resolved) = self.lookup_member(scope, name) {
+            return Some(resolved)
+        }
+        let module = &self.modules[scope];
+        if let Some(import) = module.imports.iter().find(|i| i.name == name) {
+            return Some(import.target)
+        }
+        module.globs.iter().find_map(|glob| match self.lookup_member(*glob, name)? {
+            Resolved::Item(m, i) if !self.object(m, i).is_public() => None,
+            resolved => Some(resolved),
+        })
+    }
+
+    /// An item or submodule declared directly in `module`.
+    fn lookup_member(&self, module: ModuleId, name: &str) -> Option<Resolved> {
+        if let Some(index) = self.item(module, name) {
+            Some(Resolved::Item(module, index))
+        } else {
+            self.child(module, name).map(Resolved::Module)
+        }
+    }
+
+    /// Whether code in `module` is inside `ancestor` (or is `ancestor`).
+    pub fn is_within(&self, module: ModuleId, ancestor: ModuleId) -> bool {
+        let mut current = Some(module);
+        while let Some(id) = current {
+            if id == ancestor {
+                return true
+            }
+            current = self.modules[id].parent;
+        }
+        false
+    }
+
+    /// Private items can be used from the module declaring them and its
+    /// submodules; `pub` items and modules can be used from anywhere.
+    pub fn is_visible(&self, from: ModuleId, target: Resolved) -> bool {
+        match target {
+            Resolved::Module(_) => true,
+            Resolved::Item(module, index) => self.object(module, index).is_public() || self.is_within(from, module),
+        }
+    }
+
+    /// The declaration `resolved` refers to, for pointing diagnostics at.
+    pub fn definition_span(&self, resolved: Resolved) -> Span {
+        match resolved {
+            Resolved::Module(module) => self.modules[module].span,
+            Resolved::Item(module, index) => match self.object(module, index) {
+                ProgramObject::Function { signature_span, .. } => *signature_span,
+                obj => obj.span(),
+            },
+        }
+    }
+
+    /// Resolves as many leading segments of `path` as name modules and
+    /// items, looking first in `from` and then at the root. Returns what
+    /// they refer to and how many segments were used, so callers can treat
+    /// the rest as fields or enum variants.
+    pub fn lookup_prefix(&self, from: ModuleId, path: &[String]) -> Option<(Resolved, usize)> {
+        let first = path.first()?;
+        let mut current = self.lookup_name(from, first).or_else(|| self.lookup_member(ROOT, first))?;
+        let mut used = 1;
+        for segment in &path[1..] {
+            let Resolved::Module(module) = current else {
+                break
+            };
+            current = match self.lookup_member(module, segment) {
+                Some(resolved) => resolved,
+                None => break,
+            };
+            used += 1;
+        }
+        Some((current, used))
+    }
+
+    /// Resolves the whole of `path`, or nothing.
+    pub fn lookup(&self, from: ModuleId, path: &[String]) -> Option<Resolved> {
+        match self.lookup_prefix(from, path)? {
+            (resolved, used) if used == path.len() => Some(resolved),
+            _ => None,
+        }
+    }
+
+    /// Binds the names of every `use` declaration. Imports may go through
+    /// other imports, so this repeats until no more of them resolve.
+    pub fn resolve_imports(&mut self) -> Vec<Diagnostic> {
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        let mut pending: Vec<(ModuleId, UseDecl)> = Vec::new();
+        for (id, module) in self.modules.iter_mut().enumerate() {
+            pending.extend(module.uses.iter().cloned().map(|decl| (id, decl)));
+        }
+
+        loop {
+            let before = pending.len();
+            let mut unresolved = Vec::new();
+            for (module, decl) in pending {
+                match self.lookup(module, &decl.path) {
+                    Some(target) => self.bind_import(module, &decl, target, &mut diagnostics),
+                    None => unresolved.push((module, decl)),
+                }
+            }
+            pending = unresolved;
+            if pending.is_empty() || pending.len() == before {
+                break
+            }
+        }
+
+        for (module, decl) in pending {
+            let (found, used) = match self.lookup_prefix(module, &decl.path) {
+                Some((resolved, used)) => (Some(resolved), used),
+                None => (None, 0),
+            };
+            let missing = &decl.path[used];
+            let label = match found {
+                None => format!("no `{}` in this module or at the root", missing),
+                Some(Resolved::Module(_)) => format!("no `{}` in module `{}`", missing, decl.path[..used].join(".")),
+                Some(Resolved::Item(..)) => format!("`{}` is not a module", decl.path[..used].join(".")),
+            };
+            diagnostics.push(
+                Diagnostic::error(format!("unresolved import `{}`", decl.path.join(".")))
+                    .with_code(codes::UNRESOLVED_IMPORT)
+                    .with_primary(decl.span, label),
+            );
+        }
+        diagnostics
+    }
+
+    fn bind_import(&mut self, module: ModuleId, decl: &UseDecl, target: Resolved, diagnostics: &mut Vec<Diagnostic>) {
+        if !self.is_visible(module, target) {
+            diagnostics.push(
+                Diagnostic::error(format!("`{}` is private", decl.path.join(".")))
+                    .with_code(codes::PRIVATE_ITEM)
+                    .with_primary(decl.span, "imported here")
+                    .with_secondary(self.definition_span(target), "declared here without `pub`")
+                    .with_note("help: mark the item `pub` to use it from other modules"),
+            );
+        }
+
+        let name = match &decl.kind {
+            UseKind::Glob => {
+                match target {
+                    Resolved::Module(glob) => self.modules[module].globs.push(glob),
+                    Resolved::Item(..) => diagnostics.push(
+                        Diagnostic::error(format!("`{}` is not a module", decl.path.join(".")))
+                            .with_code(codes::UNRESOLVED_IMPORT)
+                            .with_primary(decl.span, "only modules can be glob-imported"),
+                    ),
+                }
+                return
+            }
+            UseKind::Single { alias: Some(alias) } => alias.clone(),
+            UseKind::Single { alias: None } => decl.path.last().cloned().unwrap_or_default(),
+        };
+
+        let existing = self
+            .lookup_member(module, &name)
+            .map(|resolved| self.definition_span(resolved))
+            .or_else(|| self.modules[module].imports.iter().find(|i| i.name == name).map(|i| i.span));
+        if let Some(existing) = existing {
+            diagnostics.push(
+                Diagnostic::error(format!("the name `{}` is defined more than once", name))
+                    .with_code(codes::UNRESOLVED_IMPORT)
+                    .with_primary(decl.span, format!("`{}` imported here", name))
+                    .with_secondary(existing, "already defined here")
+                    .with_note("help: rename the import with `as`"),
+            );
+            return
+        }
+        self.modules[module].imports.push(Import { name, target, span: decl.span });
+    }
+
+    /// Every object in the program along with the module declaring it.
+    pub fn objects(&self) -> impl Iterator<Item = (ModuleId, &ProgramObject)> {
+        self.modules
+            .iter()
+            .enumerate()
+            .flat_map(|(id, module)| module.objs.iter().map(move |obj| (id, obj)))
+    }
+}
+
+/// Reads the root file and every file its `mod` declarations pull in.
+/// `mod name;` in the root file `dir/root.c4l` is read from `dir/name.c4l`;
+/// inside module `m` (a file or inline) it is read from `dir/m/name.c4l`.
+pub(crate) fn load(root: &Path, sources: &mut SourceMap) -> (ModuleTree, Vec<Diagnostic>) {
+    let mut loader = Loader {
+        sources,
+        tree: ModuleTree { modules: Vec::new() },
+        diagnostics: Vec::new(),
+        stack: Vec::new(),
+        loaded: HashMap::new(),
+    };
+    let dir = root.parent().map(Path::to_path_buf).unwrap_or_default();
+    loader.load_file(root, String::new(), None, None, dir);
+    (loader.tree, loader.diagnostics)
+}
+
+struct Loader<'s> {
+    sources: &'s mut SourceMap,
+    tree: ModuleTree,
+    diagnostics: Vec<Diagnostic>,
+    /// Canonical paths of the files being loaded, outermost first.
+    stack: Vec<PathBuf>,
+    /// Every file loaded so far and the `mod` declaration that loaded it.
+    loaded: HashMap<PathBuf, Option<Span>>,
+}
+
+impl Loader<'_> {
+    fn load_file(&mut self, path: &Path, name: String, parent: Option<ModuleId>, declaration: Option<Span>, dir: PathBuf) {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if let Some(position) = self.stack.iter().position(|p| *p == canonical) {
+            let chain: Vec<String> = self.stack[position..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.display().to_string())
+                .collect();
+            let mut diagnostic = Diagnostic::error(format!("module `{}` includes itself", name))
+                .with_code(codes::MODULE_CYCLE)
+                .with_note(format!("the cycle is {}", chain.join(" -> ")));
+            if let Some(span) = declaration {
+                diagnostic = diagnostic.with_primary(span, "this loads a file that is already being loaded");
+            }
+            self.diagnostics.push(diagnostic);
+            return;
+        }
+        if let Some(previous) = self.loaded.get(&canonical) {
+            let mut diagnostic = Diagnostic::error(format!("`{}` is loaded as more than one module", path.display()))
+                .with_code(codes::DUPLICATE_MODULE);
+            if let Some(span) = declaration {
+                diagnostic = diagnostic.with_primary(span, "loaded again here");
+            }
+            if let Some(span) = previous {
+                diagnostic = diagnostic.with_secondary(*span, "first loaded here");
+            }
+            self.diagnostics.push(diagnostic);
+            return;
+        }
+
+        let text = match read(path) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+            Err(err) => {
+                let mut diagnostic = Diagnostic::error(format!("couldn't read `{}`: {}", path.display(), err))
+                    .with_code(codes::MISSING_MODULE);
+                if let Some(span) = declaration {
+                    diagnostic = diagnostic
+                        .with_primary(span, format!("module `{}` is declared here", name))
+                        .with_note(format!("help: create `{}`, or write the module inline with `mod {} {{ ... }}`", path.display(), name));
+                }
+                self.diagnostics.push(diagnostic);
+                return;
+            }
+        };
+        let file = self.sources.add(path.display().to_string(), text);
+        let (tokens, lex_diagnostics) = parse(&self.sources.get(file).text, file);
+        let (program, parse_diagnostics) = Parser::new(tokens).parse();
+        self.diagnostics.extend(lex_diagnostics);
+        self.diagnostics.extend(parse_diagnostics);
+
+        let span = declaration.unwrap_or(Span::new(file, 0, 0));
+        let id = self.add_module(name, parent, span, dir);
+        self.loaded.insert(canonical.clone(), declaration);
+        self.stack.push(canonical);
+        self.add_objects(id, program.objs);
+        self.stack.pop();
+    }
+
+    fn add_module(&mut self, name: String, parent: Option<ModuleId>, span: Span, dir: PathBuf) -> ModuleId {
+        let id = self.tree.modules.len();
+        self.tree.modules.push(Module {
+            name,
+            parent,
+            children: Vec::new(),
+            objs: Vec::new(),
+            uses: Vec::new(),
+            imports: Vec::new(),
+            globs: Vec::new(),
+            span,
+            dir,
+        });
+        if let Some(parent) = parent {
+            self.tree.modules[parent].children.push(id);
+        }
+        id
+    }
+
+    fn add_objects(&mut self, module: ModuleId, objs: Vec<ProgramObject>) {
+        for obj in objs {
+            let (name, body, span) = match obj {
+                ProgramObject::Module { name, body, span } => (name, body, span),
+                ProgramObject::Use(decl) => {
+                    self.tree.modules[module].uses.push(decl);
+                    continue;
+                }
+                _ => {
+                    self.tree.modules[module].objs.push(obj);
+                    continue;
+                }
+            };
+            if let Some(existing) = self.tree.child(module, &name) {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("module `{}` is declared more than once", name))
+                        .with_code(codes::DUPLICATE_MODULE)
+                        .with_primary(span, "declared again here")
+                        .with_secondary(self.tree.modules[existing].span, "first declared here"),
+                );
+                continue;
+            }
+            let parent_dir = &self.tree.modules[module].dir;
+            let dir = parent_dir.join(&name);
+            match body {
+                Some(objs) => {
+                    let id = self.add_module(name, Some(module), span, dir);
+                    self.add_objects(id, objs);
+                }
+                None => {
+                    let path = parent_dir.join(format!("{}.c4l", name));
+                    self.load_file(&path, name, Some(module), Some(span), dir);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing::{self, RootFile};
+
+    fn load_root(root: &RootFile) -> (ModuleTree, Vec<Diagnostic>) {
+        load(root.path(), &mut SourceMap::new())
+    }
+
+    /// Every module's path, in the order they were loaded.
+    fn module_paths(tree: &ModuleTree) -> Vec<String> {
+        (0..tree.modules.len()).map(|id| tree.module_path(id).join(".")).collect()
+    }
+
+    #[test]
+    fn mod_declarations_load_files_from_the_declaring_module_s_directory() {
+        let root = testing::root_file("mod a;\nfn main() {}")
+            .with_file("a.c4l", "mod b;\npub fn in_a() {}")
+            .with_file("a/b.c4l", "pub fn in_b() {}");
+        let (tree, diagnostics) = load_root(&root);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(module_paths(&tree), ["", "a", "a.b"]);
+        let b = tree.child(tree.child(ROOT, "a").expect("`a` should load"), "b").expect("`a.b` should load");
+        assert!(tree.item(b, "in_b").is_some());
+        assert_eq!(tree.lookup(ROOT, &["a".to_string(), "b".to_string(), "in_b".to_string()]), Some(Resolved::Item(b, 0)));
+    }
+
+    #[test]
+    fn inline_modules_look_for_their_files_in_a_directory_of_their_own() {
+        let root = testing::root_file("mod m { mod n; mod k { mod deep; } }")
+            .with_file("m/n.c4l", "fn f() {}")
+            .with_file("m/k/deep.c4l", "fn g() {}");
+        let (tree, diagnostics) = load_root(&root);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(module_paths(&tree), ["", "m", "m.n", "m.k", "m.k.deep"]);
+    }
+
+    #[test]
+    fn a_missing_module_file_says_where_it_was_looked_for() {
+        let root = testing::root_file("mod gone;");
+        let (_, diagnostics) = load_root(&root);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::MISSING_MODULE));
+        assert_eq!(diagnostics[0].labels[0].message, "module `gone` is declared here");
+        let expected = root.path().with_file_name("gone.c4l");
+        assert_eq!(
+            diagnostics[0].notes,
+            [format!("help: create `{}`, or write the module inline with `mod gone {{ ... }}`", expected.display())]
+        );
+    }
+
+    #[test]
+    fn a_file_that_loads_itself_is_a_cycle() {
+        let root = testing::root_file("mod main;\nfn f() {}");
+        let (tree, diagnostics) = load_root(&root);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::MODULE_CYCLE));
+        assert_eq!(diagnostics[0].message, "module `main` includes itself");
+        assert_eq!(module_paths(&tree), [""]);
+    }
+
+    #[test]
+    fn a_module_declared_twice_is_reported() {
+        let root = testing::root_file("mod a;\nmod a { }").with_file("a.c4l", "");
+        let (_, diagnostics) = load_root(&root);
+        let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, [Some(codes::DUPLICATE_MODULE)]);
+    }
+
+    /// Loads a single file and binds its imports.
+    fn imports(source: &str) -> (ModuleTree, Vec<Diagnostic>) {
+        let root = testing::root_file(source);
+        let (mut tree, diagnostics) = load_root(&root);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let diagnostics = tree.resolve_imports();
+        (tree, diagnostics)
+    }
+
+    fn path(segments: &str) -> Vec<String> {
+        segments.split('.').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn use_as_binds_the_alias_instead_of_the_last_segment() {
+        let (tree, diagnostics) = imports("mod a { mod b { pub fn f() {} } }\nuse a.b as c;");
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let b = tree.lookup(ROOT, &path("a.b"));
+        assert!(b.is_some());
+        assert_eq!(tree.lookup_name(ROOT, "c"), b);
+        assert_eq!(tree.lookup_name(ROOT, "b"), None);
+        assert!(tree.lookup(ROOT, &path("c.f")).is_some());
+    }
+
+    #[test]
+    fn glob_imports_skip_private_items() {
+        let (tree, diagnostics) = imports("mod m { pub fn shown() {} fn hidden() {} }\nuse m.*;");
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let m = tree.child(ROOT, "m").expect("`m` is declared inline");
+        assert_eq!(tree.lookup_name(ROOT, "shown"), Some(Resolved::Item(m, 0)));
+        assert_eq!(tree.lookup_name(ROOT, "hidden"), None);
+    }
+
+    #[test]
+    fn importing_a_private_item_is_reported() {
+        let (_, diagnostics) = imports("mod m { fn hidden() {} }\nuse m.hidden;");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::PRIVATE_ITEM));
+        assert_eq!(diagnostics[0].message, "`m.hidden` is private");
+        assert_eq!(diagnostics[0].labels[0].message, "imported here");
+        assert_eq!(diagnostics[0].labels[1].message, "declared here without `pub`");
+    }
+
+    #[test]
+    fn unresolved_imports_say_which_segment_is_missing() {
+        let (_, diagnostics) = imports("mod m { pub fn f() {} }\nuse nowhere.f;\nuse m.g;\nuse m.f.x;");
+        let labels: Vec<_> = diagnostics.iter().map(|d| (d.code, d.labels[0].message.as_str())).collect();
+        assert_eq!(
+            labels,
+            [
+                (Some(codes::UNRESOLVED_IMPORT), "no `nowhere` in this module or at the root"),
+                (Some(codes::UNRESOLVED_IMPORT), "no `g` in module `m`"),
+                (Some(codes::UNRESOLVED_IMPORT), "`m.f` is not a module"),
+            ]
+        );
+    }
+
+    #[test]
+    fn submodules_see_their_ancestors_private_items() {
+        let (tree, diagnostics) = imports("mod p { fn secret() {} mod c { use p.secret; } }\nmod other { }");
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let p = tree.child(ROOT, "p").expect("`p` is declared inline");
+        let c = tree.child(p, "c").expect("`c` is declared inline");
+        let other = tree.child(ROOT, "other").expect("`other` is declared inline");
+        let secret = Resolved::Item(p, 0);
+        assert_eq!(tree.lookup_name(c, "secret"), Some(secret));
+        assert!(tree.is_visible(p, secret));
+        assert!(tree.is_visible(c, secret));
+        assert!(!tree.is_visible(ROOT, secret));
+        assert!(!tree.is_visible(other, secret));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::diagnostic::{codes, Diagnostic};
+use crate::module::ModuleTree;
+use crate::parser::*;
+use crate::span::Span;
+use std::collections::HashMap;
+
+/// Where a variable came from, which decides whether it can be marked `mut`
+/// and how to suggest doing so.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BindingKind {
+    Let,
+    Parameter,
+    ForVariable,
+    Pattern,
+}
+
+struct Binding {
+    kind: BindingKind,
+    mutable: bool,
+    span: Span,
+    /// Whether `<-` assigns to it anywhere, so a needless `mut` can be
+    /// pointed out.
+    assigned: bool,
+}
+
+/// Rejects `<-` on variables that weren't declared `mut`. Bindings are
+/// immutable by default; only `let mut` variables and `mut` parameters can
+/// be assigned to after they are declared.
+pub(crate) fn check(modules: &ModuleTree) -> Vec<Diagnostic> {
+    let mut checker = Checker { scopes: Vec::new(), diagnostics: Vec::new() };
+    for (_, obj) in modules.objects() {
+        if let ProgramObject::Function { arguments, statements, .. } = obj {
+            checker.push_scope();
+            for param in arguments {
+                checker.declare(&param.name, BindingKind::Parameter, param.mutable, param.span);
+            }
+            for statement in statements {
+                checker.check_statement(statement);
+            }
+            checker.pop_scope();
+        }
+    }
+    checker.diagnostics
+}
+
+struct Checker {
+    /// Innermost scope last.
+    scopes: Vec<HashMap<String, Binding>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    fn check_statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let { name, name_span, mutable, .. } => self.declare(name, BindingKind::Let, *mutable, *name_span),
+            StatementKind::Set { name, .. } => self.assign(name, statement.span),
+            StatementKind::If { then_block, else_branch, .. } => {
+                self.check_block(then_block);
+                if let Some(else_branch) = else_branch {
+                    self.check_statement(else_branch);
+                }
+            }
+            StatementKind::Block(block) | StatementKind::While { body: block, .. } => self.check_block(block),
+            StatementKind::For { variable, body, .. } => {
+                self.push_scope();
+                self.declare(variable, BindingKind::ForVariable, false, statement.span);
+                self.check_block(body);
+                self.pop_scope();
+            }
+            StatementKind::Match { arms, .. } => {
+                for arm in arms {
+                    self.push_scope();
+                    self.bind_pattern(&arm.pattern);
+                    self.check_statement(&arm.body);
+                    self.pop_scope();
+                }
+            }
+            StatementKind::Ret { .. } | StatementKind::FunctionCall { .. } | StatementKind::Break | StatementKind::Continue => {}
+        }
+    }
+
+    fn check_block(&mut self, block: &Block) {
+        self.push_scope();
+        for statement in &block.statements {
+            self.check_statement(statement);
+        }
+        self.pop_scope();
+    }
+
+    /// Pattern bindings are never mutable, but they still shadow outer
+    /// variables of the same name.
+    fn bind_pattern(&mut self, pattern: &Pattern) {
+        match &pattern.kind {
+            PatternKind::Binding(name) => self.declare(name, BindingKind::Pattern, false, pattern.span),
+            PatternKind::Or(alternatives) => {
+                if let Some(first) = alternatives.first() {
+                    self.bind_pattern(first);
+                }
+            }
+            PatternKind::Constructor { fields: PatternFields::Tuple(patterns), .. } => {
+                for pattern in patterns {
+                    self.bind_pattern(pattern);
+                }
+            }
+            PatternKind::Constructor { fields: PatternFields::Struct(fields), .. } => {
+                for (_, pattern) in fields {
+                    self.bind_pattern(pattern);
+                }
+            }
+            PatternKind::Constructor { fields: PatternFields::Unit, .. }
+            | PatternKind::Wildcard
+            | PatternKind::Literal(_)
+            | PatternKind::Range { .. } => {}
+        }
+    }
+
+    fn declare(&mut self, name: &str, kind: BindingKind, mutable: bool, span: Span) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), Binding { kind, mutable, span, assigned: false });
+        }
+    }
+
+    fn assign(&mut self, name: &str, span: Span) {
+        // Names that aren't locals are reported by the resolver.
+        let Some(binding) = self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name)) else {
+            return;
+        };
+        binding.assigned = true;
+        if binding.mutable {
+            return;
+        }
+        let (message, label, help) = match binding.kind {
+            BindingKind::Let => (
+                format!("cannot assign twice to immutable variable `{}`", name),
+                format!("`{}` is declared here without `mut`", name),
+                Some(format!("help: make the variable mutable: `let mut {}`", name)),
+            ),
+            BindingKind::Parameter => (
+                format!("cannot assign to immutable parameter `{}`", name),
+                format!("`{}` is declared here without `mut`", name),
+                Some(format!("help: make the parameter mutable: `mut {}`", name)),
+            ),
+            BindingKind::ForVariable => (
+                format!("cannot assign to loop variable `{}`", name),
+                "the loop sets it on every iteration".to_string(),
+                Some("help: copy it into a `let mut` variable inside the loop".to_string()),
+            ),
+            BindingKind::Pattern => (
+                format!("cannot assign to `{}`, which is bound by a pattern", name),
+                "bound here".to_string(),
+                None,
+            ),
+        };
+        let mut diagnostic = Diagnostic::error(message)
+            .with_code(codes::ASSIGN_TO_IMMUTABLE)
+            .with_primary(span, "cannot assign")
+            .with_secondary(binding.span, label);
+        if let Some(help) = help {
+            diagnostic = diagnostic.with_note(help);
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Ends a scope, warning about `mut` variables in it that were never
+    /// assigned to.
+    fn pop_scope(&mut self) {
+        let Some(scope) = self.scopes.pop() else {
+            return;
+        };
+        let mut unneeded: Vec<(&String, &Binding)> = scope.iter().filter(|(_, b)| b.mutable && !b.assigned).collect();
+        unneeded.sort_by_key(|(_, b)| b.span.start);
+        for (name, binding) in unneeded {
+            self.diagnostics.push(
+                Diagnostic::warning(format!("variable `{}` doesn't need to be mutable", name))
+                    .with_code(codes::UNUSED_MUT)
+                    .with_primary(binding.span, "it is never assigned to")
+                    .with_note("help: remove the `mut`"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing;
+
+    #[test]
+    fn mutable_bindings_can_be_assigned() {
+        let source = "fn f(mut a | i32) -> i32 { let mut b | i32 <- 1; a <- a + 1; b <- a; ret b; }";
+        assert!(testing::pass_codes(check, source).is_empty());
+    }
+
+    #[test]
+    fn assigning_to_an_immutable_binding_suggests_mut() {
+        let source = "
+            enum opt { some(i32), none }
+            fn f(a | i32, o | opt) -> i32 {
+                let b | i32 <- 1;
+                b <- 2;
+                a <- 3;
+                for i in 0..3 { i <- 4; }
+                @o { opt.some(x) -> { x <- 5; } ~ -> {} }
+                ret b;
+            }";
+        let diagnostics = testing::pass_diagnostics(check, source);
+        let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, [Some(codes::ASSIGN_TO_IMMUTABLE); 4]);
+        assert_eq!(diagnostics[0].notes, ["help: make the variable mutable: `let mut b`"]);
+        assert_eq!(diagnostics[1].notes, ["help: make the parameter mutable: `mut a`"]);
+    }
+
+    #[test]
+    fn an_unneeded_mut_is_a_warning() {
+        let diagnostics = testing::pass_diagnostics(check, "fn f() -> i32 { let mut a | i32 <- 1; ret a; }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::UNUSED_MUT));
+        assert!(!diagnostics[0].is_error());
+    }
+}
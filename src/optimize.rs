@@ -0,0 +1,1054 @@
+use crate::ir::{
+    BinaryOp, Block, BlockId, Constant, Function, Instruction, InstructionKind, Module, Terminator, Type, UnaryOp,
+    Value,
+};
+use std::collections::{HashMap, HashSet};
+
+/// A transformation of the IR that keeps what the program does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pass {
+    /// Copies the bodies of small leaf functions into their callers.
+    Inline,
+    /// Evaluates instructions whose operands are known and follows branches
+    /// on known conditions.
+    ConstFold,
+    /// Replaces copies and phis of a single value with that value.
+    CopyProp,
+    /// Reuses an earlier instruction that computed the same thing.
+    Cse,
+    /// Moves instructions that compute the same thing on every iteration in
+    /// front of the loop.
+    Licm,
+    /// Removes instructions nothing uses and merges straight-line blocks.
+    Dce,
+}
+
+/// Every pass in the order a round of the pipeline runs them, with its name
+/// on the command line.
+const PASSES: [(Pass, &str); 6] = [
+    (Pass::Inline, "inline"),
+    (Pass::ConstFold, "const-fold"),
+    (Pass::CopyProp, "copy-prop"),
+    (Pass::Cse, "cse"),
+    (Pass::Licm, "licm"),
+    (Pass::Dce, "dce"),
+];
+
+/// How often the pipeline runs at most, since one pass often gives the
+/// others more to do.
+const MAX_ROUNDS: usize = 8;
+
+/// How many instructions a function can have and still be inlined.
+const INLINE_LIMIT: usize = 16;
+
+impl Pass {
+    pub fn name(self) -> &'static str {
+        PASSES.iter().find(|(pass, _)| *pass == self).map(|(_, name)| *name).unwrap_or_default()
+    }
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        PASSES.iter().find(|(_, n)| *n == name).map(|(pass, _)| *pass)
+    }
+
+    /// The names of every pass, for error messages.
+    pub fn names() -> String {
+        PASSES.iter().map(|(_, name)| format!("`{}`", name)).collect::<Vec<_>>().join(", ")
+    }
+
+    /// Runs the pass over the module and returns whether it changed anything.
+    fn run(self, module: &mut Module) -> bool {
+        if self == Pass::Inline {
+            return inline_calls(module);
+        }
+        let mut changed = false;
+        for function in &mut module.functions {
+            changed |= match self {
+                Pass::Inline => unreachable!("inlining works on the whole module"),
+                Pass::ConstFold => fold_constants(function),
+                Pass::CopyProp => propagate_copies(function),
+                Pass::Cse => eliminate_common_subexpressions(function),
+                Pass::Licm => hoist_loop_invariants(function),
+                Pass::Dce => eliminate_dead_code(function),
+            };
+        }
+        changed
+    }
+}
+
+/// How hard to optimize, picked with `-O0`, `-O1` or `-O2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Level {
+    /// Leave the IR as it was lowered.
+    O0,
+    /// Cheap cleanups within each function.
+    O1,
+    /// Everything, including inlining and moving code out of loops.
+    O2,
+}
+
+/// The passes to run over a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Pipeline {
+    enabled: Vec<Pass>,
+}
+
+impl Pipeline {
+    pub fn new(level: Level) -> Pipeline {
+        let enabled = match level {
+            Level::O0 => Vec::new(),
+            Level::O1 => vec![Pass::ConstFold, Pass::CopyProp, Pass::Cse, Pass::Dce],
+            Level::O2 => PASSES.iter().map(|(pass, _)| *pass).collect(),
+        };
+        Pipeline { enabled }
+    }
+
+    /// Turns a single pass on or off, whatever the level picked.
+    pub fn set(&mut self, pass: Pass, enabled: bool) {
+        self.enabled.retain(|p| *p != pass);
+        if enabled {
+            self.enabled.push(pass);
+        }
+    }
+
+    /// Runs the enabled passes in rounds until they stop finding anything
+    /// to do. The module is verified after every pass, so a broken pass is
+    /// caught right where it went wrong: the error is the pass and what the
+    /// verifier found.
+    pub fn run(&self, module: &mut Module) -> Result<(), (Pass, Vec<String>)> {
+        if self.enabled.is_empty() {
+            return Ok(());
+        }
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for (pass, _) in PASSES {
+                if !self.enabled.contains(&pass) {
+                    continue;
+                }
+                changed |= pass.run(module);
+                module.verify().map_err(|errors| (pass, errors))?;
+            }
+            if !changed {
+                break;
+            }
+        }
+        for function in &mut module.functions {
+            function.renumber_values();
+        }
+        Ok(())
+    }
+}
+
+/// What defines each value that an instruction defines.
+fn definitions(function: &Function) -> HashMap<Value, InstructionKind> {
+    function
+        .blocks
+        .iter()
+        .flat_map(|block| &block.instructions)
+        .filter_map(|instruction| Some((instruction.result?, instruction.kind.clone())))
+        .collect()
+}
+
+fn constant(definitions: &HashMap<Value, InstructionKind>, value: Value) -> Option<&Constant> {
+    match definitions.get(&value) {
+        Some(InstructionKind::Const(constant)) => Some(constant),
+        _ => None,
+    }
+}
+
+/// Whether an instruction can't be removed even if nothing uses what it
+/// returns: calls, and divisions that might divide by zero.
+fn has_side_effects(kind: &InstructionKind, definitions: &HashMap<Value, InstructionKind>) -> bool {
+    match kind {
+        InstructionKind::Call(..) => true,
+        InstructionKind::Binary(BinaryOp::Div | BinaryOp::Rem, _, divisor) => {
+            constant(definitions, *divisor).is_none_or(is_zero)
+        }
+        _ => false,
+    }
+}
+
+fn is_zero(constant: &Constant) -> bool {
+    matches!(
+        constant,
+        Constant::I32(0) | Constant::I64(0) | Constant::U32(0) | Constant::U64(0)
+    )
+}
+
+/// Replaces instructions whose operands are constants with their result,
+/// reads out of structs and variants built in the same function with what
+/// went into them, and branches on constants with jumps.
+fn fold_constants(function: &mut Function) -> bool {
+    let mut changed = false;
+    loop {
+        let definitions = definitions(function);
+        let mut skipped_edges = Vec::new();
+        let mut round = false;
+        for (b, block) in function.blocks.iter_mut().enumerate() {
+            for instruction in &mut block.instructions {
+                if let Some(kind) = fold(&instruction.kind, &definitions) {
+                    if matches!(kind, InstructionKind::Const(_)) {
+                        instruction.location = None;
+                    }
+                    instruction.kind = kind;
+                    round = true;
+                }
+            }
+            // A phi that became a constant has to move after the ones left.
+            block.instructions.sort_by_key(|instruction| !instruction.is_phi());
+
+            match block.terminator {
+                Terminator::Branch(_, then, otherwise) if then == otherwise => {
+                    block.terminator = Terminator::Jump(then);
+                    round = true;
+                }
+                Terminator::Branch(condition, then, otherwise) => {
+                    if let Some(Constant::Bool(taken)) = constant(&definitions, condition) {
+                        let (target, skipped) = if *taken { (then, otherwise) } else { (otherwise, then) };
+                        block.terminator = Terminator::Jump(target);
+                        skipped_edges.push((BlockId(b as u32), skipped));
+                        round = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (from, to) in skipped_edges {
+            remove_incoming(function.block_mut(to), from);
+        }
+        if !round {
+            break;
+        }
+        changed = true;
+    }
+    if changed {
+        function.remove_unreachable_blocks();
+    }
+    changed
+}
+
+/// What an instruction simplifies to, if it does.
+fn fold(kind: &InstructionKind, definitions: &HashMap<Value, InstructionKind>) -> Option<InstructionKind> {
+    let known = |value: Value| constant(definitions, value);
+    match kind {
+        InstructionKind::Copy(a) => known(*a).cloned().map(InstructionKind::Const),
+        InstructionKind::Unary(op, a) => fold_unary(*op, known(*a)?).map(InstructionKind::Const),
+        InstructionKind::Binary(op, a, b) => fold_binary(*op, known(*a)?, known(*b)?).map(InstructionKind::Const),
+        InstructionKind::Phi(incoming) => {
+            let first = known(incoming.first()?.1)?;
+            let same = incoming.iter().all(|(_, value)| known(*value) == Some(first));
+            same.then(|| InstructionKind::Const(first.clone()))
+        }
+        InstructionKind::Field(a, index) => match definitions.get(a) {
+            Some(InstructionKind::Struct(fields)) => Some(InstructionKind::Copy(fields[*index as usize])),
+            _ => None,
+        },
+        InstructionKind::Tag(a) => match definitions.get(a) {
+            Some(InstructionKind::Variant(tag, _)) => Some(InstructionKind::Const(Constant::U32(*tag))),
+            _ => None,
+        },
+        InstructionKind::Payload(a, tag, index) => match definitions.get(a) {
+            Some(InstructionKind::Variant(built, fields)) if built == tag => {
+                Some(InstructionKind::Copy(fields[*index as usize]))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn fold_unary(op: UnaryOp, value: &Constant) -> Option<Constant> {
+    Some(match (op, value) {
+        (UnaryOp::Neg, Constant::I32(v)) => Constant::I32(v.wrapping_neg()),
+        (UnaryOp::Neg, Constant::I64(v)) => Constant::I64(v.wrapping_neg()),
+        (UnaryOp::Neg, Constant::F32(v)) => Constant::F32(-v),
+        (UnaryOp::Neg, Constant::F64(v)) => Constant::F64(-v),
+        (UnaryOp::Not, Constant::Bool(v)) => Constant::Bool(!v),
+        (UnaryOp::Not, Constant::I32(v)) => Constant::I32(!v),
+        (UnaryOp::Not, Constant::I64(v)) => Constant::I64(!v),
+        (UnaryOp::Not, Constant::U32(v)) => Constant::U32(!v),
+        (UnaryOp::Not, Constant::U64(v)) => Constant::U64(!v),
+        _ => return None,
+    })
+}
+
+macro_rules! integer_op {
+    ($variant:ident, $a:expr, $b:expr, $op:expr) => {{
+        let (a, b) = ($a, $b);
+        match $op {
+            BinaryOp::Add => Constant::$variant(a.wrapping_add(b)),
+            BinaryOp::Sub => Constant::$variant(a.wrapping_sub(b)),
+            BinaryOp::Mul => Constant::$variant(a.wrapping_mul(b)),
+            // Left for the program to fail on when it runs.
+            BinaryOp::Div | BinaryOp::Rem if b == 0 => return None,
+            BinaryOp::Div => Constant::$variant(a.wrapping_div(b)),
+            BinaryOp::Rem => Constant::$variant(a.wrapping_rem(b)),
+            BinaryOp::And => Constant::$variant(a & b),
+            BinaryOp::Xor => Constant::$variant(a ^ b),
+            op => compare(op, a, b)?,
+        }
+    }};
+}
+
+macro_rules! float_op {
+    ($variant:ident, $a:expr, $b:expr, $op:expr) => {{
+        let (a, b) = ($a, $b);
+        match $op {
+            BinaryOp::Add => Constant::$variant(a + b),
+            BinaryOp::Sub => Constant::$variant(a - b),
+            BinaryOp::Mul => Constant::$variant(a * b),
+            BinaryOp::Div => Constant::$variant(a / b),
+            BinaryOp::Rem => Constant::$variant(a % b),
+            op => compare(op, a, b)?,
+        }
+    }};
+}
+
+fn compare<T: PartialOrd>(op: BinaryOp, a: T, b: T) -> Option<Constant> {
+    Some(Constant::Bool(match op {
+        BinaryOp::Eq => a == b,
+        BinaryOp::Ne => a != b,
+        BinaryOp::Lt => a < b,
+        BinaryOp::Le => a <= b,
+        BinaryOp::Gt => a > b,
+        BinaryOp::Ge => a >= b,
+        _ => return None,
+    }))
+}
+
+/// Evaluates a binary instruction the way the VM does.
+fn fold_binary(op: BinaryOp, left: &Constant, right: &Constant) -> Option<Constant> {
+    if matches!(op, BinaryOp::Shl | BinaryOp::Shr) {
+        let amount = match *right {
+            Constant::I32(v) => v as u32,
+            Constant::I64(v) => v as u32,
+            Constant::U32(v) => v,
+            Constant::U64(v) => v as u32,
+            _ => return None,
+        };
+        return Some(match (op, left) {
+            (BinaryOp::Shl, Constant::I32(a)) => Constant::I32(a.wrapping_shl(amount)),
+            (BinaryOp::Shl, Constant::I64(a)) => Constant::I64(a.wrapping_shl(amount)),
+            (BinaryOp::Shl, Constant::U32(a)) => Constant::U32(a.wrapping_shl(amount)),
+            (BinaryOp::Shl, Constant::U64(a)) => Constant::U64(a.wrapping_shl(amount)),
+            (_, Constant::I32(a)) => Constant::I32(a.wrapping_shr(amount)),
+            (_, Constant::I64(a)) => Constant::I64(a.wrapping_shr(amount)),
+            (_, Constant::U32(a)) => Constant::U32(a.wrapping_shr(amount)),
+            (_, Constant::U64(a)) => Constant::U64(a.wrapping_shr(amount)),
+            _ => return None,
+        });
+    }
+    Some(match (left, right) {
+        (Constant::I32(a), Constant::I32(b)) => integer_op!(I32, *a, *b, op),
+        (Constant::I64(a), Constant::I64(b)) => integer_op!(I64, *a, *b, op),
+        (Constant::U32(a), Constant::U32(b)) => integer_op!(U32, *a, *b, op),
+        (Constant::U64(a), Constant::U64(b)) => integer_op!(U64, *a, *b, op),
+        (Constant::F32(a), Constant::F32(b)) => float_op!(F32, *a, *b, op),
+        (Constant::F64(a), Constant::F64(b)) => float_op!(F64, *a, *b, op),
+        (Constant::Bool(a), Constant::Bool(b)) => match op {
+            BinaryOp::And => Constant::Bool(a & b),
+            BinaryOp::Xor => Constant::Bool(a ^ b),
+            op => compare(op, a, b)?,
+        },
+        (Constant::Char(a), Constant::Char(b)) => compare(op, a, b)?,
+        (Constant::String(a), Constant::String(b)) => compare(op, a, b)?,
+        _ => return None,
+    })
+}
+
+/// Drops what a block's phis receive from `from`, which no longer jumps
+/// to it.
+fn remove_incoming(block: &mut Block, from: BlockId) {
+    for instruction in &mut block.instructions {
+        if let InstructionKind::Phi(incoming) = &mut instruction.kind {
+            incoming.retain(|(block, _)| *block != from);
+        }
+    }
+}
+
+/// Makes a block's phis receive from `to` what they received from `from`.
+fn rename_incoming(block: &mut Block, from: BlockId, to: BlockId) {
+    for instruction in &mut block.instructions {
+        if let InstructionKind::Phi(incoming) = &mut instruction.kind {
+            for (block, _) in incoming.iter_mut().filter(|(block, _)| *block == from) {
+                *block = to;
+            }
+        }
+    }
+}
+
+/// Removes `copy` instructions and phis of a single value, using the value
+/// instead.
+fn propagate_copies(function: &mut Function) -> bool {
+    let mut replacements = HashMap::new();
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| match (instruction.result, &instruction.kind) {
+            (Some(result), InstructionKind::Copy(value)) => {
+                replacements.insert(result, *value);
+                false
+            }
+            _ => true,
+        });
+    }
+    function.replace_values(&replacements);
+    let count = |function: &Function| function.blocks.iter().map(|block| block.instructions.len()).sum::<usize>();
+    let before = count(function);
+    function.remove_trivial_phis();
+    !replacements.is_empty() || count(function) != before
+}
+
+/// Swaps the operands of commutative instructions into one order, so `a + b`
+/// and `b + a` look the same.
+fn normalize(kind: &mut InstructionKind) {
+    if let InstructionKind::Binary(op, a, b) = kind
+        && matches!(
+            op,
+            BinaryOp::Add | BinaryOp::Mul | BinaryOp::And | BinaryOp::Xor | BinaryOp::Eq | BinaryOp::Ne
+        )
+        && b < a
+    {
+        std::mem::swap(a, b);
+    }
+}
+
+/// Replaces instructions with an equal one that dominates them. Blocks are
+/// visited down the dominator tree, so what a block computed is only
+/// reused in the blocks it dominates.
+fn eliminate_common_subexpressions(function: &mut Function) -> bool {
+    let dominators = function.dominators();
+    let mut children = vec![Vec::new(); function.blocks.len()];
+    for (block, idom) in dominators.idom.iter().enumerate() {
+        if let Some(parent) = idom
+            && parent.0 as usize != block
+        {
+            children[parent.0 as usize].push(BlockId(block as u32));
+        }
+    }
+
+    let mut available: HashMap<(InstructionKind, Type), Value> = HashMap::new();
+    let mut added = vec![Vec::new(); function.blocks.len()];
+    let mut replacements: HashMap<Value, Value> = HashMap::new();
+    // Each entry is a block and whether it is being entered rather than
+    // left, at which point what it made available is forgotten.
+    let mut stack = vec![(BlockId(0), true)];
+    while let Some((block, entering)) = stack.pop() {
+        if !entering {
+            for key in &added[block.0 as usize] {
+                available.remove(key);
+            }
+            continue;
+        }
+        stack.push((block, false));
+        for instruction in &function.block(block).instructions {
+            let Some(result) = instruction.result else {
+                continue;
+            };
+            if matches!(instruction.kind, InstructionKind::Call(..) | InstructionKind::Phi(_)) {
+                continue;
+            }
+            let mut kind = instruction.kind.clone();
+            let mut instruction = Instruction { result: None, kind, location: None };
+            for operand in instruction.operands_mut() {
+                while let Some(replacement) = replacements.get(operand) {
+                    *operand = *replacement;
+                }
+            }
+            kind = instruction.kind;
+            normalize(&mut kind);
+            let key = (kind, function.type_of(result));
+            match available.get(&key) {
+                Some(earlier) => {
+                    replacements.insert(result, *earlier);
+                }
+                None => {
+                    available.insert(key.clone(), result);
+                    added[block.0 as usize].push(key);
+                }
+            }
+        }
+        stack.extend(children[block.0 as usize].iter().map(|child| (*child, true)));
+    }
+
+    if replacements.is_empty() {
+        return false;
+    }
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| !instruction.result.is_some_and(|r| replacements.contains_key(&r)));
+    }
+    function.replace_values(&replacements);
+    true
+}
+
+/// Removes instructions whose results are never used and that have no
+/// side effects, then merges blocks into their only predecessor.
+fn eliminate_dead_code(function: &mut Function) -> bool {
+    let definitions = definitions(function);
+    let operands: HashMap<Value, Vec<Value>> = function
+        .blocks
+        .iter()
+        .flat_map(|block| &block.instructions)
+        .filter_map(|instruction| Some((instruction.result?, instruction.operands())))
+        .collect();
+    let needed = |instruction: &Instruction| {
+        instruction.result.is_none() || has_side_effects(&instruction.kind, &definitions)
+    };
+
+    let mut live = HashSet::new();
+    let mut work = Vec::new();
+    for block in &function.blocks {
+        work.extend(block.terminator.operands());
+        for instruction in block.instructions.iter().filter(|instruction| needed(instruction)) {
+            work.extend(instruction.result);
+        }
+    }
+    while let Some(value) = work.pop() {
+        if live.insert(value)
+            && let Some(operands) = operands.get(&value)
+        {
+            work.extend(operands);
+        }
+    }
+    let mut changed = false;
+    for block in &mut function.blocks {
+        let before = block.instructions.len();
+        block
+            .instructions
+            .retain(|instruction| needed(instruction) || instruction.result.is_some_and(|r| live.contains(&r)));
+        changed |= block.instructions.len() != before;
+    }
+    merge_blocks(function) || changed
+}
+
+/// Merges each block into its predecessor when that is the only one and
+/// jumps straight to it.
+fn merge_blocks(function: &mut Function) -> bool {
+    let mut changed = false;
+    loop {
+        let predecessors = function.predecessors();
+        let found = (1..function.blocks.len()).find_map(|b| match predecessors[b].as_slice() {
+            [into] if into.0 as usize != b && function.block(*into).terminator == Terminator::Jump(BlockId(b as u32)) => {
+                Some((*into, BlockId(b as u32)))
+            }
+            _ => None,
+        });
+        let Some((into, from)) = found else {
+            break;
+        };
+        let empty = Block { instructions: Vec::new(), terminator: Terminator::Unreachable };
+        let block = std::mem::replace(function.block_mut(from), empty);
+        // With one predecessor, every phi has one value.
+        let mut replacements = HashMap::new();
+        let mut instructions = Vec::new();
+        for instruction in block.instructions {
+            match (instruction.result, &instruction.kind) {
+                (Some(phi), InstructionKind::Phi(incoming)) => {
+                    replacements.insert(phi, incoming[0].1);
+                }
+                _ => instructions.push(instruction),
+            }
+        }
+        for successor in block.terminator.successors() {
+            rename_incoming(function.block_mut(successor), from, into);
+        }
+        let target = function.block_mut(into);
+        target.instructions.extend(instructions);
+        target.terminator = block.terminator;
+        function.replace_values(&replacements);
+        changed = true;
+    }
+    if changed {
+        function.remove_unreachable_blocks();
+    }
+    changed
+}
+
+/// Inlines every call to a function small enough and without calls of its
+/// own, then drops the functions nothing calls any more.
+fn inline_calls(module: &mut Module) -> bool {
+    let inlineable: Vec<bool> = module
+        .functions
+        .iter()
+        .map(|function| {
+            let mut instructions = function.blocks.iter().flat_map(|block| &block.instructions);
+            instructions.clone().count() <= INLINE_LIMIT
+                && !instructions.any(|instruction| matches!(instruction.kind, InstructionKind::Call(..)))
+        })
+        .collect();
+    let mut changed = false;
+    for caller in 0..module.functions.len() {
+        let mut inlined = false;
+        // Each block is split at its first inlined call. What comes after
+        // goes into a new block at the end, so it is still looked at.
+        let mut block = 0;
+        while block < module.functions[caller].blocks.len() {
+            let call = module.functions[caller].blocks[block].instructions.iter().enumerate().find_map(
+                |(index, instruction)| match instruction.kind {
+                    InstructionKind::Call(callee, _) if inlineable[callee as usize] => Some((index, callee)),
+                    _ => None,
+                },
+            );
+            if let Some((index, callee)) = call {
+                let callee = module.functions[callee as usize].clone();
+                inline_call(&mut module.functions[caller], BlockId(block as u32), index, &callee);
+                inlined = true;
+            }
+            block += 1;
+        }
+        if inlined {
+            // A callee that never returns leaves the rest of the caller's
+            // block unreachable.
+            module.functions[caller].remove_unreachable_blocks();
+            changed = true;
+        }
+    }
+    if changed {
+        remove_unused_functions(module);
+    }
+    changed
+}
+
+/// Replaces the call at `index` in `block` with a copy of the callee's
+/// blocks. The parameters become copies of the arguments, and returns
+/// jump to a new block with the rest of the caller's, where a phi collects
+/// the value returned.
+fn inline_call(function: &mut Function, block: BlockId, index: usize, callee: &Function) {
+    let offset = function.blocks.len() as u32;
+    let continuation = BlockId(offset + callee.blocks.len() as u32);
+    let values: Vec<Value> = callee.values.iter().map(|ty| function.new_value(*ty)).collect();
+    let map = |value: Value| values[value.0 as usize];
+
+    let caller = function.block_mut(block);
+    let rest = caller.instructions.split_off(index + 1);
+    let call = caller.instructions.pop().expect("the call being inlined is in the block");
+    let InstructionKind::Call(_, arguments) = call.kind else {
+        unreachable!("only calls are inlined");
+    };
+    for (param, argument) in callee.params.iter().zip(arguments) {
+        caller.instructions.push(Instruction {
+            result: Some(map(*param)),
+            kind: InstructionKind::Copy(argument),
+            location: None,
+        });
+    }
+    let terminator = std::mem::replace(&mut caller.terminator, Terminator::Jump(BlockId(offset)));
+    for successor in terminator.successors() {
+        rename_incoming(function.block_mut(successor), block, continuation);
+    }
+
+    let mut returns = Vec::new();
+    for (index, original) in callee.blocks.iter().enumerate() {
+        let mut copy = original.clone();
+        for instruction in &mut copy.instructions {
+            instruction.result = instruction.result.map(map);
+            for operand in instruction.operands_mut() {
+                *operand = map(*operand);
+            }
+            if let InstructionKind::Phi(incoming) = &mut instruction.kind {
+                for (from, _) in incoming.iter_mut() {
+                    from.0 += offset;
+                }
+            }
+        }
+        for operand in copy.terminator.operands_mut() {
+            *operand = map(*operand);
+        }
+        for successor in copy.terminator.successors_mut() {
+            successor.0 += offset;
+        }
+        if let Terminator::Return(value) = copy.terminator {
+            returns.extend(value.map(|value| (BlockId(offset + index as u32), value)));
+            copy.terminator = Terminator::Jump(continuation);
+        }
+        function.blocks.push(copy);
+    }
+
+    let mut instructions = Vec::new();
+    if let Some(result) = call.result {
+        instructions.push(Instruction { result: Some(result), kind: InstructionKind::Phi(returns), location: None });
+    }
+    instructions.extend(rest);
+    function.blocks.push(Block { instructions, terminator });
+}
+
+/// Drops the functions `main` can't reach through calls.
+fn remove_unused_functions(module: &mut Module) {
+    let mut used = vec![false; module.functions.len()];
+    used[module.main as usize] = true;
+    let mut work = vec![module.main];
+    while let Some(function) = work.pop() {
+        for instruction in module.functions[function as usize].blocks.iter().flat_map(|block| &block.instructions) {
+            if let InstructionKind::Call(callee, _) = instruction.kind
+                && !used[callee as usize]
+            {
+                used[callee as usize] = true;
+                work.push(callee);
+            }
+        }
+    }
+    let mut numbers = Vec::new();
+    let mut next = 0;
+    for is_used in &used {
+        numbers.push(next);
+        next += u32::from(*is_used);
+    }
+
+    let functions = std::mem::take(&mut module.functions);
+    module.functions = functions.into_iter().zip(&used).filter(|(_, used)| **used).map(|(f, _)| f).collect();
+    for block in module.functions.iter_mut().flat_map(|function| &mut function.blocks) {
+        for instruction in &mut block.instructions {
+            if let InstructionKind::Call(callee, _) = &mut instruction.kind {
+                *callee = numbers[*callee as usize];
+            }
+        }
+    }
+    module.main = numbers[module.main as usize];
+}
+
+/// Moves instructions out of loops when their operands don't change while
+/// the loop runs. Loops are found through their back edges, outer ones
+/// first.
+fn hoist_loop_invariants(function: &mut Function) -> bool {
+    let mut visited = HashSet::new();
+    let mut changed = false;
+    while let Some((header, latches)) = next_loop(function, &visited) {
+        visited.insert(header);
+        changed |= hoist_out_of(function, header, &latches);
+    }
+    changed
+}
+
+/// The header of a loop not visited yet, and the blocks that jump back to
+/// it.
+fn next_loop(function: &Function, visited: &HashSet<BlockId>) -> Option<(BlockId, Vec<BlockId>)> {
+    let dominators = function.dominators();
+    let predecessors = function.predecessors();
+    function.reverse_postorder().into_iter().filter(|header| !visited.contains(header)).find_map(|header| {
+        let latches: Vec<BlockId> = predecessors[header.0 as usize]
+            .iter()
+            .copied()
+            .filter(|latch| dominators.dominates(header, *latch))
+            .collect();
+        (!latches.is_empty()).then_some((header, latches))
+    })
+}
+
+/// Whether an instruction can run before the loop it is in even on paths
+/// that never reach it: it can't fail or have side effects. Reading a
+/// payload can fail on a variant with another tag.
+fn can_hoist(kind: &InstructionKind, definitions: &HashMap<Value, InstructionKind>) -> bool {
+    match kind {
+        InstructionKind::Const(_)
+        | InstructionKind::Copy(_)
+        | InstructionKind::Unary(..)
+        | InstructionKind::Struct(_)
+        | InstructionKind::Variant(..)
+        | InstructionKind::Field(..)
+        | InstructionKind::Tag(_) => true,
+        InstructionKind::Binary(..) => !has_side_effects(kind, definitions),
+        InstructionKind::Call(..) | InstructionKind::Phi(_) | InstructionKind::Payload(..) => false,
+    }
+}
+
+fn hoist_out_of(function: &mut Function, header: BlockId, latches: &[BlockId]) -> bool {
+    let predecessors = function.predecessors();
+    let mut body = HashSet::from([header]);
+    let mut work = latches.to_vec();
+    while let Some(block) = work.pop() {
+        if body.insert(block) {
+            work.extend(&predecessors[block.0 as usize]);
+        }
+    }
+    let entries: Vec<BlockId> =
+        predecessors[header.0 as usize].iter().copied().filter(|block| !body.contains(block)).collect();
+    let [entry] = entries[..] else {
+        return false;
+    };
+
+    let definitions = definitions(function);
+    let order: Vec<BlockId> = function.reverse_postorder().into_iter().filter(|block| body.contains(block)).collect();
+    let mut inside: HashSet<Value> = order
+        .iter()
+        .flat_map(|block| &function.block(*block).instructions)
+        .filter_map(|instruction| instruction.result)
+        .collect();
+    // Instructions are hoisted in an order that keeps each after what it
+    // uses.
+    let mut hoisted = Vec::new();
+    loop {
+        let mut found = false;
+        for instruction in order.iter().flat_map(|block| &function.block(*block).instructions) {
+            let Some(result) = instruction.result else {
+                continue;
+            };
+            if inside.contains(&result)
+                && can_hoist(&instruction.kind, &definitions)
+                && instruction.operands().iter().all(|operand| !inside.contains(operand))
+            {
+                inside.remove(&result);
+                hoisted.push(result);
+                found = true;
+            }
+        }
+        if !found {
+            break;
+        }
+    }
+    if hoisted.is_empty() {
+        return false;
+    }
+
+    let preheader = preheader(function, header, entry);
+    let mut moved = HashMap::new();
+    for block in &order {
+        let instructions = std::mem::take(&mut function.block_mut(*block).instructions);
+        for instruction in instructions {
+            match instruction.result {
+                Some(result) if !inside.contains(&result) => {
+                    moved.insert(result, instruction);
+                }
+                _ => function.block_mut(*block).instructions.push(instruction),
+            }
+        }
+    }
+    let instructions = hoisted.iter().map(|result| moved.remove(result).expect("hoisted instructions were moved"));
+    function.block_mut(preheader).instructions.extend(instructions);
+    true
+}
+
+/// The block that runs right before a loop and only jumps to its header,
+/// made out of the edge from `entry` if `entry` goes elsewhere too.
+fn preheader(function: &mut Function, header: BlockId, entry: BlockId) -> BlockId {
+    if function.block(entry).terminator == Terminator::Jump(header) {
+        return entry;
+    }
+    let preheader = BlockId(function.blocks.len() as u32);
+    for successor in function.block_mut(entry).terminator.successors_mut() {
+        if *successor == header {
+            *successor = preheader;
+        }
+    }
+    rename_incoming(function.block_mut(header), entry, preheader);
+    function.blocks.push(Block { instructions: Vec::new(), terminator: Terminator::Jump(header) });
+    preheader
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing::{self, programs};
+    use crate::{codegen, ir, vm};
+
+    /// A module whose `main` calls `@f` with two `i32`s, `@f` being the
+    /// function in `functions`.
+    fn with_main(functions: &str) -> String {
+        format!(
+            "entry @main
+            fn @main() -> i32 {{
+            bb0:
+                %0: i32 = const 5
+                %1: i32 = call @f(%0, %0)
+                ret %1
+            }}
+            {}",
+            functions
+        )
+    }
+
+    fn parse(text: &str) -> Module {
+        ir::parse(text).unwrap_or_else(|error| panic!("the text didn't parse: {}", error))
+    }
+
+    /// Runs only `pass` over `before` and checks it gives `after`.
+    fn assert_pass(pass: Pass, before: &str, after: &str) {
+        let mut pipeline = Pipeline::new(Level::O0);
+        pipeline.set(pass, true);
+        let mut module = parse(&with_main(before));
+        pipeline.run(&mut module).unwrap_or_else(|(pass, errors)| panic!("`{}` broke the IR: {:?}", pass.name(), errors));
+        assert_eq!(module.print(), parse(&with_main(after)).print());
+    }
+
+    #[test]
+    fn constant_folding_evaluates_known_values_and_branches() {
+        let before = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = const 6
+                %3: i32 = const 7
+                %4: i32 = mul %2, %3
+                %5: bool = lt %4, %2
+                br %5, bb1, bb2
+            bb1:
+                ret %0
+            bb2:
+                ret %4
+            }";
+        let after = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = const 6
+                %3: i32 = const 7
+                %4: i32 = const 42
+                %5: bool = const false
+                jump bb1
+            bb1:
+                ret %4
+            }";
+        assert_pass(Pass::ConstFold, before, after);
+    }
+
+    #[test]
+    fn copy_propagation_uses_the_original_value() {
+        let before = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = copy %0
+                %3: i32 = copy %2
+                %4: i32 = add %3, %2
+                ret %4
+            }";
+        let after = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = add %0, %0
+                ret %2
+            }";
+        assert_pass(Pass::CopyProp, before, after);
+    }
+
+    #[test]
+    fn common_subexpressions_are_computed_once_whatever_the_operand_order() {
+        let before = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = add %0, %1
+                %3: i32 = add %1, %0
+                %4: i32 = mul %2, %3
+                ret %4
+            }";
+        let after = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = add %0, %1
+                %3: i32 = mul %2, %2
+                ret %3
+            }";
+        assert_pass(Pass::Cse, before, after);
+    }
+
+    #[test]
+    fn loop_invariants_are_hoisted_in_front_of_the_loop() {
+        let before = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = const 0
+                jump bb1
+            bb1:
+                %3: i32 = phi [bb0: %2], [bb2: %7]
+                %4: bool = lt %3, %1
+                br %4, bb2, bb3
+            bb2:
+                %5: i32 = mul %0, %1
+                %6: i32 = const 1
+                %7: i32 = add %3, %6
+                jump bb1
+            bb3:
+                ret %3
+            }";
+        let after = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = const 0
+                %3: i32 = mul %0, %1
+                %4: i32 = const 1
+                jump bb1
+            bb1:
+                %5: i32 = phi [bb0: %2], [bb2: %7]
+                %6: bool = lt %5, %1
+                br %6, bb2, bb3
+            bb2:
+                %7: i32 = add %5, %4
+                jump bb1
+            bb3:
+                ret %5
+            }";
+        assert_pass(Pass::Licm, before, after);
+    }
+
+    #[test]
+    fn dead_code_elimination_keeps_divisions_that_may_fail() {
+        let before = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = add %0, %1
+                %3: i32 = div %0, %1 at \"main.c4l:1:1\"
+                %4: i32 = const 2
+                %5: i32 = div %0, %4 at \"main.c4l:2:1\"
+                ret %0
+            }";
+        let after = "
+            fn @f(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = div %0, %1 at \"main.c4l:1:1\"
+                ret %0
+            }";
+        assert_pass(Pass::Dce, before, after);
+    }
+
+    #[test]
+    fn small_functions_are_inlined_and_then_removed() {
+        let text = "
+            entry @main
+            fn @main() -> i32 {
+            bb0:
+                %0: i32 = const 5
+                %1: i32 = const 3
+                %2: i32 = call @mul(%0, %1)
+                ret %2
+            }
+
+            fn @mul(%0: i32, %1: i32) -> i32 {
+            bb0:
+                %2: i32 = mul %0, %1
+                ret %2
+            }";
+        let mut module = parse(text);
+        let mut pipeline = Pipeline::new(Level::O0);
+        pipeline.set(Pass::Inline, true);
+        pipeline.run(&mut module).expect("inlining should keep the IR valid");
+        let names: Vec<&str> = module.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["main"]);
+        assert!(!module.print().contains("call"), "{}", module.print());
+        assert_eq!(vm::run(&codegen::compile(&module)).ok(), Some(15));
+    }
+
+    #[test]
+    fn levels_pick_their_passes_and_flags_toggle_them() {
+        assert_eq!(Pipeline::new(Level::O0).enabled, []);
+        assert_eq!(Pipeline::new(Level::O1).enabled, [Pass::ConstFold, Pass::CopyProp, Pass::Cse, Pass::Dce]);
+        assert_eq!(
+            Pipeline::new(Level::O2).enabled,
+            [Pass::Inline, Pass::ConstFold, Pass::CopyProp, Pass::Cse, Pass::Licm, Pass::Dce]
+        );
+
+        let mut pipeline = Pipeline::new(Level::O1);
+        pipeline.set(Pass::Licm, true);
+        pipeline.set(Pass::Licm, true);
+        pipeline.set(Pass::Cse, false);
+        pipeline.set(Pass::Inline, false);
+        assert_eq!(pipeline.enabled, [Pass::ConstFold, Pass::CopyProp, Pass::Dce, Pass::Licm]);
+        assert_eq!(Pass::from_name("copy-prop"), Some(Pass::CopyProp));
+        assert_eq!(Pass::from_name("copyprop"), None);
+    }
+
+    #[test]
+    fn optimizing_keeps_what_programs_do() {
+        for (source, code) in programs::ALL {
+            let unoptimized = testing::lower(source);
+            let mut optimized = unoptimized.clone();
+            Pipeline::new(Level::O2).run(&mut optimized).expect("the passes should keep the IR valid");
+            // Runtime errors exit with 101, as `c4 run` does.
+            let run = |module: &Module| vm::run(&codegen::compile(module)).unwrap_or(101);
+            assert_eq!(run(&unoptimized), code, "{}", source);
+            assert_eq!(run(&optimized), code, "{}", source);
+        }
+    }
+}
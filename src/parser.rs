@@ -1,9 +1,21 @@
-use crate::tokenizer::Token;
+use crate::diagnostic::{codes, Diagnostic};
+use crate::span::Span;
+use crate::tokenizer::{SpannedToken, Token};
 
 #[derive(Debug)]
-pub(crate) enum Statement{
+pub(crate) struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub(crate) enum StatementKind {
+    /// `let name | type <- value;`, or `let mut ...` for a variable that
+    /// can be assigned to again.
     Let {
         name: String,
+        name_span: Span,
+        mutable: bool,
         type_annotation: Type,
         value: Expression,
     },
@@ -16,39 +28,286 @@ pub(crate) enum Statement{
     },
     FunctionCall {
         call: Expression
-    }
+    },
+    /// `if cond { ... } else ...`, where `else_branch` is either another
+    /// `If` (for `else if`) or a `Block`.
+    If {
+        condition: Expression,
+        then_block: Block,
+        else_branch: Option<Box<Statement>>,
+    },
+    Block(Block),
+    While {
+        condition: Expression,
+        body: Block,
+    },
+    /// `for variable in iterable { ... }`
+    For {
+        variable: String,
+        iterable: ForIterable,
+        body: Block,
+    },
+    Break,
+    Continue,
+    /// `@scrutinee { pattern -> statement ... }`
+    Match {
+        scrutinee: Expression,
+        arms: Vec<MatchArm<Statement>>,
+    },
+
+
+}
+
+/// One `pattern -> body` arm. Match statements have statement bodies,
+/// match expressions have expression bodies.
+#[derive(Debug)]
+pub(crate) struct MatchArm<T> {
+    pub pattern: Pattern,
+    pub body: T,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub(crate) struct Pattern {
+    pub kind: PatternKind,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub(crate) enum PatternKind {
+    /// `~`, matches anything.
+    Wildcard,
+    /// A bare name, matches anything and binds it.
+    Binding(String),
+    Literal(Literal),
+    /// `start..end`, matching integers or characters from `start` up to but
+    /// not including `end`.
+    Range {
+        start: Literal,
+        end: Literal,
+    },
+    /// `a | b | c`
+    Or(Vec<Pattern>),
+    /// Destructures a struct or enum variant: `point { x, y }`,
+    /// `shape.circle(r)` or the unit variant `shape.empty`.
+    Constructor {
+        path: Vec<String>,
+        fields: PatternFields,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) enum PatternFields {
+    Unit,
+    Tuple(Vec<Pattern>),
+    /// `{ field, field <- pattern }`; a bare field name binds the field to a
+    /// variable of the same name.
+    Struct(Vec<(String, Pattern)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Literal {
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    String(String),
+}
 
+/// What a `for` loop walks over.
+#[derive(Debug)]
+pub(crate) enum ForIterable {
+    /// `start..end`, counting up from `start` and stopping before `end`.
+    Range {
+        start: Expression,
+        end: Expression,
+    },
+}
+
+/// A `{ ... }` delimited list of statements.
+#[derive(Debug)]
+pub(crate) struct Block {
+    pub statements: Vec<Statement>,
+    pub span: Span,
+}
 
+#[derive(Debug)]
+pub(crate) struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub(crate) enum Expression {
-    IntLiteral(i64),
+pub(crate) enum ExpressionKind {
+    IntLiteral(i128),
     FloatLiteral(f64),
     StringLiteral(String),
+    CharLiteral(char),
+    BoolLiteral(bool),
     Variable(String),
     Binary {
         op: BinaryOp,
         left: Box<Expression>,
         right: Box<Expression>
     },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expression>
+    },
     FunctionCall {
-        name: String,
+        callee: Box<Expression>,
         args: Vec<Expression>
+    },
+    /// `if cond { a } else { b }`; both branches are single expressions and
+    /// the `else` is required.
+    If {
+        condition: Box<Expression>,
+        then_value: Box<Expression>,
+        else_value: Box<Expression>,
+    },
+    /// `@scrutinee { pattern -> value; ... }`
+    Match {
+        scrutinee: Box<Expression>,
+        arms: Vec<MatchArm<Expression>>,
+    },
+    /// `name { field <- value, ... }`. The path has more than one segment
+    /// for struct-style enum variants, as in `shape.rect { w <- 1.0 }`.
+    StructLiteral {
+        path: Vec<String>,
+        fields: Vec<FieldInit>,
+    },
+    /// `object.field`
+    FieldAccess {
+        object: Box<Expression>,
+        field: String,
     }
 }
 
+impl Expression {
+    /// The names in a chain of field accesses on a variable, such as
+    /// `["math", "add"]` for `math.add`. Whether that is a module path, an
+    /// enum variant or real field access is up to name resolution.
+    pub fn as_path(&self) -> Option<Vec<String>> {
+        match &self.kind {
+            ExpressionKind::Variable(name) => Some(vec![name.clone()]),
+            ExpressionKind::FieldAccess { object, field } => {
+                let mut path = object.as_path()?;
+                path.push(field.clone());
+                Some(path)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `field <- value` inside a struct literal.
 #[derive(Debug)]
+pub(crate) struct FieldInit {
+    pub name: String,
+    pub value: Expression,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum BinaryOp {
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    BitAnd,
+    BitXor,
+    Shl,
+    Shr,
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnaryOp {
+    Neg,
+    /// Logical not on `bool`, bitwise not on integers.
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Associativity {
+    Left,
+    Right,
+    /// `a < b < c` is an error rather than `(a < b) < c`.
+    None,
+}
+
+impl BinaryOp {
+    /// Binding power of the operator; higher binds tighter.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 3,
+            BinaryOp::BitXor => 4,
+            BinaryOp::BitAnd => 5,
+            BinaryOp::Shl | BinaryOp::Shr => 6,
+            BinaryOp::Add | BinaryOp::Sub => 7,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 8,
+        }
+    }
 
+    pub fn associativity(self) -> Associativity {
+        match self {
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => Associativity::None,
+            _ => Associativity::Left,
+        }
+    }
+
+    pub fn is_comparison(self) -> bool {
+        self.associativity() == Associativity::None
+    }
+
+    pub fn is_logical(self) -> bool {
+        matches!(self, BinaryOp::And | BinaryOp::Or)
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitXor => "^",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+        }
+    }
+}
+
+/// A type annotation, such as the `i32` in `let x | i32 <- 1;`.
+#[derive(Debug)]
+pub(crate) struct Type {
+    pub kind: TypeKind,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub(crate) enum Type {
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum TypeKind {
     I32,
     U32,
     F32,
@@ -58,408 +317,1007 @@ pub(crate) enum Type {
     BOOL,
     CHAR,
     STRING,
-    Custom(String),
+    /// Any one of the numeric types. Every `num` in a function signature
+    /// stands for the same type, picked separately at each call.
+    NUM,
+    /// A struct or enum, by its path: `["math", "point"]` for `math.point`.
+    Custom(Vec<String>),
 
 }
 
+type ParseResult<T> = Result<T, Diagnostic>;
+
 #[derive(Debug)]
 pub struct Parser<'a> {
-    tokens: Vec<Token<'a>>,
+    tokens: Vec<SpannedToken<'a>>,
     current: usize,
+    /// Errors that the parser recovered from; see `synchronize_statement`.
+    diagnostics: Vec<Diagnostic>,
+    /// How many loops enclose the statement being parsed, so `break` and
+    /// `continue` can be rejected outside of one.
+    loop_depth: usize,
+    /// Set while parsing the head of an `if`, `while`, `for` or `@`, where
+    /// `name {` starts the body rather than a struct literal.
+    no_struct_literal: bool,
 }
 
-impl Parser<'_> {
+impl<'a> Parser<'a> {
 
-    pub fn new<'a>(tokens: Vec<Token<'a>>) -> Parser<'a> {
+    pub fn new(tokens: Vec<SpannedToken<'a>>) -> Parser<'a> {
         Parser {
-            tokens: tokens,
-            current: 0
+            tokens,
+            current: 0,
+            diagnostics: Vec::new(),
+            loop_depth: 0,
+            no_struct_literal: false,
+        }
+    }
+    /// Parses the whole token stream. Syntax errors don't stop the parser:
+    /// every error is collected, and the returned `Program` holds whatever
+    /// could be parsed around them.
+    pub fn parse(&mut self) -> (Program, Vec<Diagnostic>) {
+        let mut objs = self.parse_objects();
+        // `parse_objects` stops at a `}`, which at the top level has nothing
+        // to close.
+        while let Some(Token::RBrace) = self.peek() {
+            let diagnostic = Diagnostic::error("unexpected `}`")
+                .with_code(codes::UNEXPECTED_TOKEN)
+                .with_primary(self.peek_span(), "this doesn't close anything");
+            self.diagnostics.push(diagnostic);
+            self.advance();
+            objs.extend(self.parse_objects());
         }
+        (Program { objs }, std::mem::take(&mut self.diagnostics))
     }
-    pub fn parse(&mut self) -> Result<Program, String> {
+
+    /// Program objects up to the end of the file, or up to (but not
+    /// including) the `}` closing an inline module.
+    fn parse_objects(&mut self) -> Vec<ProgramObject> {
         let mut objs: Vec<ProgramObject> = Vec::new();
-        loop {
+        while !matches!(self.peek(), None | Some(Token::EOF) | Some(Token::RBrace)) {
             match self.parse_object() {
                 Ok(obj) => objs.push(obj),
-                Err(_) => break
+                Err(diagnostic) => {
+                    self.diagnostics.push(diagnostic);
+                    self.synchronize_object();
+                }
+            }
+        }
+        objs
+    }
+
+    /// Skips to the next token that can start a program object, or to an
+    /// unmatched `}` that may close the enclosing inline module.
+    fn synchronize_object(&mut self) {
+        // Always make progress, even if the error was on an `fn` keyword.
+        let mut depth = match self.advance() {
+            Some(Token::LBrace) => 1,
+            _ => 0,
+        };
+        loop {
+            match self.peek() {
+                None | Some(Token::EOF) => return,
+                Some(Token::RBrace) if depth == 0 => return,
+                Some(Token::RBrace) => depth -= 1,
+                Some(Token::LBrace) => depth += 1,
+                Some(_) if self.at_object_start() => return,
+                Some(_) => {}
             }
+            self.advance();
+        }
+    }
 
+    /// Skips past the end of a broken statement: up to and including the
+    /// next `;`, or up to the `}` closing the current block. Nested blocks
+    /// are skipped whole. Stops early at a program object keyword, since
+    /// that almost always means the block was never closed.
+    fn synchronize_statement(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                None | Some(Token::EOF) => return,
+                Some(Token::StatementEnd) if depth == 0 => {
+                    self.advance();
+                    return
+                }
+                Some(Token::RBrace) if depth == 0 => return,
+                Some(Token::RBrace) => depth -= 1,
+                Some(Token::LBrace) => depth += 1,
+                Some(_) if self.at_object_start() => return,
+                Some(_) => {}
+            }
+            self.advance();
         }
-        Ok( Program { objs } )
     }
 
-    pub fn parse_object(&mut self) -> Result<ProgramObject, String> {
-        match self.peek().ok_or("Failed to find program object to parse")? {
-            Token::Function => {
+    fn at_object_start(&self) -> bool {
+        matches!(self.peek(), Some(Token::Function | Token::Struct | Token::Enum | Token::Module | Token::Use | Token::Pub))
+    }
+
+    pub fn parse_object(&mut self) -> ParseResult<ProgramObject> {
+        let pub_span = self.peek_span();
+        let is_pub = matches!(self.peek(), Some(Token::Pub));
+        if is_pub {
+            self.advance();
+        }
+        let mut obj = match self.peek() {
+            Some(Token::Function) => {
                 self.parse_function()
             }
-            _ => Err("Unexpected token in place of the start of a program object".to_string())
+            Some(Token::Struct) => {
+                self.parse_struct()
+            }
+            Some(Token::Enum) => {
+                self.parse_enum()
+            }
+            Some(Token::Module) => {
+                self.parse_module()
+            }
+            Some(Token::Use) => {
+                self.parse_use()
+            }
+            _ => Err(self.unexpected("`fn`, `struct`, `enum`, `mod` or `use`"))
+        }?;
+        if is_pub {
+            match &mut obj {
+                ProgramObject::Function { public, .. }
+                | ProgramObject::Struct { public, .. }
+                | ProgramObject::Enum { public, .. } => *public = true,
+                ProgramObject::Module { .. } | ProgramObject::Use(_) => {
+                    return Err(Diagnostic::error("`pub` can only be used on `fn`, `struct` and `enum`")
+                        .with_code(codes::UNEXPECTED_TOKEN)
+                        .with_primary(pub_span, "")
+                        .with_note("modules are always visible; the items inside them are private unless marked `pub`"))
+                }
+            }
         }
-        
+        Ok(obj)
     }
 
-    pub fn parse_function(&mut self) -> Result<ProgramObject, String> {
+    /// `use a.b;`, `use a.b as c;` or `use a.*;`
+    pub fn parse_use(&mut self) -> ParseResult<ProgramObject> {
+        let start = self.peek_span();
+        self.expect_and_consume(Token::Use)?;
+        let mut path = vec![self.expect_identifier("a path after `use`")?];
+        let mut kind = UseKind::Single { alias: None };
+        while self.expect(Token::FieldAccessor).is_ok() {
+            self.advance();
+            if let Some(Token::Operator("*")) = self.peek() {
+                self.advance();
+                kind = UseKind::Glob;
+                break
+            }
+            path.push(self.expect_identifier("a name or `*` after `.`")?);
+        }
+        if kind != UseKind::Glob && self.expect(Token::As).is_ok() {
+            self.advance();
+            kind = UseKind::Single { alias: Some(self.expect_identifier("a name after `as`")?) };
+        }
+        self.expect_and_consume(Token::StatementEnd)?;
+        Ok(ProgramObject::Use(UseDecl { path, kind, span: start.to(self.previous_span()) }))
+    }
+
+    pub fn parse_function(&mut self) -> ParseResult<ProgramObject> {
+        let start = self.peek_span();
         self.expect_and_consume(Token::Function)?;
-        let name: String = match self.advance() {
-            Some(Token::Identifier(string)) => string.to_string(),
-            _ => return Err("Expected function name after 'fn' keyword".into())
-        };
+        let name = self.expect_identifier("a function name after `fn`")?;
         self.expect_and_consume(Token::LParen)?;
-        let mut args: Vec<(String, Type)> = Vec::new();
-        match self.expect(Token::RParen) {
-            Ok(_) => {},
-            Err(_) => {
-
-                loop {
-                    
-
-                    let label: String = match self.advance() {
-                        Some(Token::Identifier(string)) => string.to_string(),
-                        _ => return Err("Expected function name after 'fn' keyword".into())
-                    };
-                    self.expect_and_consume(Token::TypeDecl)?;
-                    
-                    let type_annotation: Type = match self.advance() {
-                        Some(Token::Identifier(string)) => {
-                            match *string {
-                                "i32" => Type::I32,
-                                "i64" => Type::I64,
-                                "u32" => Type::U32,
-                                "u64" => Type::U64,
-                                "f32" => Type::F32,
-                                "f64" => Type::F64,
-                                "bool" => Type::BOOL,
-                                "char" => Type::CHAR,
-                                "string" => Type::STRING,
-                                _ => Type::Custom((*string).to_string())
-                            }
-                        },
-                        _ => return Err("Expected type identifier after assignment symbol in function signature".to_string())
-                    };
-                    args.push((label.clone(), type_annotation));
-                    
-                    match self.expect(Token::RParen) {
-                        Ok(_) => break,
-                        Err(_) => {}
-                    }
-                    self.expect_and_consume(Token::ArgumentSeparator)?;
+        let mut args: Vec<Parameter> = Vec::new();
+        if self.expect(Token::RParen).is_err() {
+            loop {
+                let param_start = self.peek_span();
+                let mutable = matches!(self.peek(), Some(Token::Mut));
+                if mutable {
+                    self.advance();
+                }
+                let label = self.expect_identifier("an argument name")?;
+                self.expect_and_consume(Token::TypeDecl)?;
+                let type_annotation = self.parse_type()?;
+                args.push(Parameter { name: label, mutable, type_annotation, span: param_start.to(self.previous_span()) });
 
+                if self.expect(Token::RParen).is_ok() {
+                    break
                 }
+                self.expect_and_consume(Token::ArgumentSeparator)?;
             }
         }
         self.expect_and_consume(Token::RParen)?;
         let return_type: Option<Type> = match self.expect(Token::RetType) {
             Ok(_) => {
                 self.current += 1;
-                Some(match self.advance() {
-                    Some(Token::Identifier(string)) => {
-                        match *string {
-                            "i32" => Type::I32,
-                            "i64" => Type::I64,
-                            "u32" => Type::U32,
-                            "u64" => Type::U64,
-                            "f32" => Type::F32,
-                            "f64" => Type::F64,
-                            "bool" => Type::BOOL,
-                            "char" => Type::CHAR,
-                            "string" => Type::STRING,
-                            _ => Type::Custom((*string).to_string())
-                        }
-                    },
-                    _ => return Err("Expected type identifier after assignment symbol in function signature".to_string())
-                })
+                Some(self.parse_type()?)
             }
             Err(_) => { None }
         };
+        let signature_span = start.to(self.previous_span());
+        let open = self.peek_span();
         self.expect_and_consume(Token::LBrace)?;
 
-        let statements = self.parse_statements()?;
-        Ok(ProgramObject::Function { name, arguments: args, return_type, statements})
+        let statements = self.parse_statements(open);
+        Ok(ProgramObject::Function { name, public: false, arguments: args, return_type, statements, signature_span, span: start.to(self.previous_span()) })
     }
 
+    pub fn parse_struct(&mut self) -> ParseResult<ProgramObject> {
+        let start = self.peek_span();
+        self.expect_and_consume(Token::Struct)?;
+        let name = self.expect_identifier("a struct name after `struct`")?;
+        self.expect_and_consume(Token::LBrace)?;
+        let fields = self.parse_struct_fields()?;
+        Ok(ProgramObject::Struct { name, public: false, fields, span: start.to(self.previous_span()) })
+    }
 
-    fn peek(&self) -> Option<&Token> {
-        self.tokens.get(self.current)
+    /// `mod name;`, which the module loader fills in from `name.c4l`, or
+    /// `mod name { ... }` with the objects written inline.
+    pub fn parse_module(&mut self) -> ParseResult<ProgramObject> {
+        let start = self.peek_span();
+        self.expect_and_consume(Token::Module)?;
+        let name = self.expect_identifier("a module name after `mod`")?;
+        let body = match self.peek() {
+            Some(Token::StatementEnd) => {
+                self.advance();
+                None
+            }
+            Some(Token::LBrace) => {
+                let open = self.peek_span();
+                self.advance();
+                let objs = self.parse_objects();
+                if self.expect(Token::RBrace).is_err() {
+                    return Err(self.unexpected("`}`").with_secondary(open, "unclosed module starts here"))
+                }
+                self.advance();
+                Some(objs)
+            }
+            _ => return Err(self.unexpected("`;` or `{` after the module name")),
+        };
+        Ok(ProgramObject::Module { name, body, span: start.to(self.previous_span()) })
+    }
+
+    pub fn parse_enum(&mut self) -> ParseResult<ProgramObject> {
+        let start = self.peek_span();
+        self.expect_and_consume(Token::Enum)?;
+        let name = self.expect_identifier("an enum name after `enum`")?;
+        self.expect_and_consume(Token::LBrace)?;
+        let mut variants: Vec<EnumVariant> = Vec::new();
+        while self.expect(Token::RBrace).is_err() {
+            let variant_start = self.peek_span();
+            let variant_name = self.expect_identifier("a variant name")?;
+            let payload = match self.peek() {
+                Some(Token::LParen) => {
+                    self.advance();
+                    let mut types: Vec<Type> = Vec::new();
+                    while self.expect(Token::RParen).is_err() {
+                        types.push(self.parse_type()?);
+                        match self.peek() {
+                            Some(Token::ArgumentSeparator) => {
+                                self.advance();
+                            }
+                            Some(Token::RParen) => {}
+                            _ => return Err(self.unexpected("`,` or `)`")),
+                        }
+                    }
+                    self.expect_and_consume(Token::RParen)?;
+                    VariantPayload::Tuple(types)
+                }
+                Some(Token::LBrace) => {
+                    self.advance();
+                    VariantPayload::Struct(self.parse_struct_fields()?)
+                }
+                _ => VariantPayload::Unit,
+            };
+            variants.push(EnumVariant { name: variant_name, payload, span: variant_start.to(self.previous_span()) });
+            match self.peek() {
+                Some(Token::ArgumentSeparator) => {
+                    self.advance();
+                }
+                Some(Token::RBrace) => {}
+                _ => return Err(self.unexpected("`,` or `}`")),
+            }
+        }
+        self.expect_and_consume(Token::RBrace)?;
+        Ok(ProgramObject::Enum { name, public: false, variants, span: start.to(self.previous_span()) })
+    }
+
+    /// `name | type, ...` up to and including the closing `}`. A trailing
+    /// comma is allowed.
+    fn parse_struct_fields(&mut self) -> ParseResult<Vec<StructField>> {
+        let mut fields: Vec<StructField> = Vec::new();
+        while self.expect(Token::RBrace).is_err() {
+            let field_start = self.peek_span();
+            let name = self.expect_identifier("a field name")?;
+            self.expect_and_consume(Token::TypeDecl)?;
+            let type_annotation = self.parse_type()?;
+            fields.push(StructField { name, type_annotation, span: field_start.to(self.previous_span()) });
+            match self.peek() {
+                Some(Token::ArgumentSeparator) => {
+                    self.advance();
+                }
+                Some(Token::RBrace) => {}
+                _ => return Err(self.unexpected("`,` or `}`")),
+            }
+        }
+        self.expect_and_consume(Token::RBrace)?;
+        Ok(fields)
+    }
+
+    fn parse_type(&mut self) -> ParseResult<Type> {
+        if !matches!(self.peek(), Some(Token::Identifier(_))) {
+            return Err(self.unexpected("a type"));
+        }
+        let start = self.peek_span();
+        let path = self.parse_path()?;
+        let kind = match path.as_slice() {
+            [name] => match name.as_str() {
+                "i32" => TypeKind::I32,
+                "i64" => TypeKind::I64,
+                "u32" => TypeKind::U32,
+                "u64" => TypeKind::U64,
+                "f32" => TypeKind::F32,
+                "f64" => TypeKind::F64,
+                "bool" => TypeKind::BOOL,
+                "char" => TypeKind::CHAR,
+                "string" => TypeKind::STRING,
+                "num" => TypeKind::NUM,
+                _ => TypeKind::Custom(path)
+            },
+            _ => TypeKind::Custom(path),
+        };
+        Ok(Type { kind, span: start.to(self.previous_span()) })
+    }
+
+
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.current).map(|t| &t.token)
     }
 
-    fn advance(&mut self) -> Option<&Token> {
+    fn advance(&mut self) -> Option<&Token<'a>> {
         if self.current < self.tokens.len() {
             self.current += 1;
         }
-        self.tokens.get(self.current - 1)
+        self.tokens.get(self.current - 1).map(|t| &t.token)
+    }
+
+    /// Span of the next token, or an empty span at the end of the input.
+    fn peek_span(&self) -> Span {
+        match self.tokens.get(self.current) {
+            Some(tok) => tok.span,
+            None => self.end_span(),
+        }
+    }
+
+    /// Span of the most recently consumed token.
+    fn previous_span(&self) -> Span {
+        match self.current.checked_sub(1).and_then(|i| self.tokens.get(i)) {
+            Some(tok) => tok.span,
+            None => self.peek_span(),
+        }
+    }
+
+    fn end_span(&self) -> Span {
+        match self.tokens.last() {
+            Some(tok) => Span::new(tok.span.file, tok.span.end, tok.span.end),
+            None => Span::default(),
+        }
+    }
+
+    /// An "expected X, found Y" error pointing at the next token.
+    fn unexpected(&self, expected: &str) -> Diagnostic {
+        let found = self.peek().copied().unwrap_or(Token::EOF);
+        let code = if found == Token::EOF { codes::UNEXPECTED_EOF } else { codes::UNEXPECTED_TOKEN };
+        Diagnostic::error(format!("expected {}, found {}", expected, found.describe()))
+            .with_code(code)
+            .with_primary(self.peek_span(), format!("expected {}", expected))
+    }
+
+    fn expect_identifier(&mut self, what: &str) -> ParseResult<String> {
+        match self.peek().copied() {
+            Some(Token::Identifier(string)) => {
+                self.advance();
+                Ok(string.to_string())
+            }
+            _ => Err(self.unexpected(what)),
+        }
     }
 
     fn is_end(&self) -> bool {
         self.current >= self.tokens.len()
     }
 
-    fn expect(&self, tok: Token) -> Result<&Token, String> {
-        if let Some(parser_tok) = self.peek() {
-            if parser_tok.eq(&tok) {
-                return Ok(parser_tok)
-            }
-            return Err(format!("Failed an expect. Expected {:?}, got {:?}", tok, parser_tok ).into())
-        };
-        Err(format!("ran out of tokens while expecting {:?}.", tok).into())
+    fn expect(&self, tok: Token) -> ParseResult<&Token<'a>> {
+        match self.peek() {
+            Some(parser_tok) if parser_tok.eq(&tok) => Ok(parser_tok),
+            _ => Err(self.unexpected(&tok.describe())),
+        }
     }
 
-    fn expect_and_consume(&mut self, tok: Token) -> Result<&Token, String> {
-        if let Some(parser_tok) = self.advance() {
-            if parser_tok.eq(&tok) {
-                return Ok(parser_tok)
-            }
-            return Err(format!("Failed an expect. Expected {:?}, got {:?}", tok, parser_tok ).into())
-        };
-        Err(format!("ran out of tokens while expecting {:?}.", tok).into())
+    fn expect_and_consume(&mut self, tok: Token) -> ParseResult<&Token<'a>> {
+        self.expect(tok)?;
+        Ok(self.advance().expect("expect checked that a token is available"))
     }
 
 
-    fn parse_statements(&mut self) -> Result<Vec<Statement>, String> {
-        
+    /// Parses statements up to and including the `}` closing the block
+    /// opened at `open`. Broken statements are reported and skipped.
+    fn parse_statements(&mut self, open: Span) -> Vec<Statement> {
+
         let mut statements: Vec<Statement> = Vec::new();
         loop {
             match self.peek() {
-                Some(token) => {
-                    match token {
-                        Token::RBrace => {
-                            self.current += 1;
-                            return Ok(statements)
-                        },
-                        Token::EOF => {
-                            return Err("unecpected EOF".to_string())
+                Some(Token::RBrace) => {
+                    self.current += 1;
+                    return statements
+                },
+                None | Some(Token::EOF) => {
+                    let diagnostic = self.unexpected("`}`").with_secondary(open, "unclosed block starts here");
+                    self.diagnostics.push(diagnostic);
+                    return statements
+                }
+                Some(_) if self.at_object_start() => {
+                    let diagnostic = self.unexpected("`}`").with_secondary(open, "unclosed block starts here");
+                    self.diagnostics.push(diagnostic);
+                    return statements
+                }
+                Some(_) => match self.parse_statement() {
+                    Ok(statement) => statements.push(statement),
+                    Err(diagnostic) => {
+                        self.diagnostics.push(diagnostic);
+                        self.synchronize_statement();
+                    }
+                }
+            }
+        }
+    }
+    fn parse_statement(&mut self) -> ParseResult<Statement> {
+        let start = self.peek_span();
+        let kind = match self.peek().copied() {
+            Some(Token::Let) => {
+                self.advance();
+                self.parse_let_statement()
+            }
+            Some(Token::Return) => {
+                self.advance();
+                self.parse_ret_statement()
+            }
+            Some(Token::Identifier(_)) => self.parse_identifier_statement(),
+            Some(Token::If) => self.parse_if_statement(),
+            Some(Token::LBrace) => Ok(StatementKind::Block(self.parse_block()?)),
+            Some(Token::While) => self.parse_while_statement(),
+            Some(Token::For) => self.parse_for_statement(),
+            Some(Token::Break) => self.parse_loop_control(StatementKind::Break),
+            Some(Token::Continue) => self.parse_loop_control(StatementKind::Continue),
+            Some(Token::PatternMatch) => self.parse_match_statement(),
+            _ => Err(self.unexpected("a statement"))
+        }?;
+        Ok(Statement { kind, span: start.to(self.previous_span()) })
+    }
+
+    fn parse_block(&mut self) -> ParseResult<Block> {
+        let open = self.peek_span();
+        self.expect_and_consume(Token::LBrace)?;
+        let statements = self.parse_statements(open);
+        Ok(Block { statements, span: open.to(self.previous_span()) })
+    }
+
+    fn parse_match_statement(&mut self) -> ParseResult<StatementKind> {
+        let scrutinee = self.parse_match_head()?;
+        let mut arms: Vec<MatchArm<Statement>> = Vec::new();
+        while !matches!(self.peek(), Some(Token::RBrace) | Some(Token::EOF) | None) {
+            let start = self.peek_span();
+            let pattern = self.parse_pattern()?;
+            self.expect_and_consume(Token::RetType)?;
+            let body = match self.peek() {
+                Some(Token::LBrace) => {
+                    let block_start = self.peek_span();
+                    let block = self.parse_block()?;
+                    let body = Statement { kind: StatementKind::Block(block), span: block_start.to(self.previous_span()) };
+                    // The `;` after a block arm is optional.
+                    if let Some(Token::StatementEnd) = self.peek() {
+                        self.advance();
+                    }
+                    body
+                }
+                _ => self.parse_statement()?,
+            };
+            arms.push(MatchArm { pattern, body, span: start.to(self.previous_span()) });
+        }
+        self.expect_and_consume(Token::RBrace)?;
+        Ok(StatementKind::Match { scrutinee, arms })
+    }
+
+    /// `@ scrutinee {`
+    fn parse_match_head(&mut self) -> ParseResult<Expression> {
+        self.expect_and_consume(Token::PatternMatch)?;
+        let scrutinee = self.parse_condition()?;
+        self.expect_and_consume(Token::LBrace)?;
+        Ok(scrutinee)
+    }
+
+    /// A pattern, including `|`-separated alternatives.
+    fn parse_pattern(&mut self) -> ParseResult<Pattern> {
+        let first = self.parse_single_pattern()?;
+        if self.expect(Token::TypeDecl).is_err() {
+            return Ok(first)
+        }
+        let start = first.span;
+        let mut alternatives = vec![first];
+        while self.expect(Token::TypeDecl).is_ok() {
+            self.advance();
+            alternatives.push(self.parse_single_pattern()?);
+        }
+        Ok(Pattern { kind: PatternKind::Or(alternatives), span: start.to(self.previous_span()) })
+    }
+
+    fn parse_single_pattern(&mut self) -> ParseResult<Pattern> {
+        let start = self.peek_span();
+        let kind = match self.peek().copied() {
+            Some(Token::Wildcard) => {
+                self.advance();
+                PatternKind::Wildcard
+            }
+            Some(Token::Identifier(_)) => {
+                let path = self.parse_path()?;
+                let fields = match self.peek() {
+                    Some(Token::LParen) => PatternFields::Tuple(self.parse_tuple_patterns()?),
+                    Some(Token::LBrace) => PatternFields::Struct(self.parse_field_patterns()?),
+                    _ if path.len() == 1 => return Ok(Pattern {
+                        kind: PatternKind::Binding(path.into_iter().next().unwrap_or_default()),
+                        span: start,
+                    }),
+                    _ => PatternFields::Unit,
+                };
+                PatternKind::Constructor { path, fields }
+            }
+            _ => {
+                let literal = self.parse_literal()?;
+                if self.expect(Token::Range).is_ok() {
+                    self.advance();
+                    let end = self.parse_literal()?;
+                    for (literal, span) in [(&literal, start), (&end, self.previous_span())] {
+                        if !matches!(literal, Literal::Int(_) | Literal::Char(_)) {
+                            return Err(Diagnostic::error("range patterns must be integers or characters")
+                                .with_code(codes::UNEXPECTED_TOKEN)
+                                .with_primary(span, ""))
                         }
-                        _ => statements.push(self.parse_statement()?)
                     }
+                    PatternKind::Range { start: literal, end }
+                } else {
+                    PatternKind::Literal(literal)
                 }
-                None => {
-                    return Err("NONE: unecpected EOF".to_string())
+            }
+        };
+        Ok(Pattern { kind, span: start.to(self.previous_span()) })
+    }
+
+    /// `(pattern, ...)`
+    fn parse_tuple_patterns(&mut self) -> ParseResult<Vec<Pattern>> {
+        self.expect_and_consume(Token::LParen)?;
+        let mut patterns: Vec<Pattern> = Vec::new();
+        while self.expect(Token::RParen).is_err() {
+            patterns.push(self.parse_pattern()?);
+            match self.peek() {
+                Some(Token::ArgumentSeparator) => {
+                    self.advance();
                 }
+                Some(Token::RParen) => {}
+                _ => return Err(self.unexpected("`,` or `)`")),
             }
         }
+        self.expect_and_consume(Token::RParen)?;
+        Ok(patterns)
     }
-    fn parse_statement(&mut self) -> Result<Statement, String> {
-        if let Some(token) = self.advance() {
-            match token {
-                Token::Let => self.parse_let_statement(),
-                Token::Return => self.parse_ret_statement(),
-                Token::Identifier(str) => {
-                    let owned: String = str.to_owned().to_string();
-                    self.parse_identifier_statement(owned)
-                },
-                _ => Err("Unexpected Statement".into())
+
+    /// `{ field, field <- pattern, ... }`
+    fn parse_field_patterns(&mut self) -> ParseResult<Vec<(String, Pattern)>> {
+        self.expect_and_consume(Token::LBrace)?;
+        let mut fields: Vec<(String, Pattern)> = Vec::new();
+        while self.expect(Token::RBrace).is_err() {
+            let field_span = self.peek_span();
+            let field = self.expect_identifier("a field name")?;
+            let pattern = if self.expect(Token::Assign).is_ok() {
+                self.advance();
+                self.parse_pattern()?
+            } else {
+                Pattern { kind: PatternKind::Binding(field.clone()), span: field_span }
+            };
+            fields.push((field, pattern));
+            match self.peek() {
+                Some(Token::ArgumentSeparator) => {
+                    self.advance();
+                }
+                Some(Token::RBrace) => {}
+                _ => return Err(self.unexpected("`,` or `}`")),
             }
-        } else {
-            Err("Unexpected End of File".into())
         }
+        self.expect_and_consume(Token::RBrace)?;
+        Ok(fields)
     }
 
-    fn parse_ret_statement(&mut self) -> Result<Statement, String> {
-        let value: Expression = self.parse_expression()?;
+    /// `name.name.name`
+    fn parse_path(&mut self) -> ParseResult<Vec<String>> {
+        let mut path = vec![self.expect_identifier("a name")?];
+        while self.expect(Token::FieldAccessor).is_ok() {
+            self.advance();
+            path.push(self.expect_identifier("a name after `.`")?);
+        }
+        Ok(path)
+    }
+
+    /// Whether the tokens ahead are `name.name... {`, which starts a struct
+    /// literal unless we are in the head of an `if`, `while`, `for` or `@`.
+    fn at_struct_literal(&self) -> bool {
+        if self.no_struct_literal {
+            return false
+        }
+        let mut index = self.current;
+        loop {
+            match self.tokens.get(index).map(|t| t.token) {
+                Some(Token::Identifier(_)) => index += 1,
+                _ => return false,
+            }
+            match self.tokens.get(index).map(|t| t.token) {
+                Some(Token::FieldAccessor) => index += 1,
+                Some(Token::LBrace) => return true,
+                _ => return false,
+            }
+        }
+    }
+
+    /// A literal as written in a pattern; numbers may be negated.
+    fn parse_literal(&mut self) -> ParseResult<Literal> {
+        let start = self.peek_span();
+        let negative = matches!(self.peek(), Some(Token::Operator("-")));
+        if negative {
+            self.advance();
+        }
+        let literal = match self.peek().copied() {
+            Some(Token::Number(number_str)) => {
+                let number = parse_number(number_str).map_err(|e| {
+                    Diagnostic::error(e).with_code(codes::INVALID_NUMBER).with_primary(self.peek_span(), "")
+                })?;
+                match (number, negative) {
+                    (Number::Integer(int), false) => Literal::Int(int),
+                    (Number::Integer(int), true) => Literal::Int(-int),
+                    (Number::Float(float), false) => Literal::Float(float),
+                    (Number::Float(float), true) => Literal::Float(-float),
+                }
+            }
+            Some(Token::BoolLiteral(literal)) if !negative => Literal::Bool(literal == "true"),
+            Some(Token::CharLiteral(literal)) if !negative => Literal::Char(literal.chars().nth(1).unwrap_or_default()),
+            Some(Token::StringLiteral(literal)) if !negative => Literal::String(unescape(&literal[1..literal.len() - 1])),
+            _ if negative => return Err(self.unexpected("a number after `-`").with_secondary(start, "")),
+            _ => return Err(self.unexpected("a pattern")),
+        };
+        self.advance();
+        Ok(literal)
+    }
+
+    fn parse_while_statement(&mut self) -> ParseResult<StatementKind> {
+        self.expect_and_consume(Token::While)?;
+        let condition = self.parse_condition()?;
+        let body = self.parse_loop_body()?;
+        Ok(StatementKind::While { condition, body })
+    }
+
+    fn parse_for_statement(&mut self) -> ParseResult<StatementKind> {
+        self.expect_and_consume(Token::For)?;
+        let variable = self.expect_identifier("a loop variable after `for`")?;
+        self.expect_and_consume(Token::In)?;
+        let start = self.parse_condition()?;
+        if self.expect(Token::Range).is_err() {
+            return Err(self.unexpected("`..`")
+                .with_note("`for` loops iterate over a range such as `0..10`"))
+        }
+        self.advance();
+        let end = self.parse_condition()?;
+        let body = self.parse_loop_body()?;
+        Ok(StatementKind::For { variable, iterable: ForIterable::Range { start, end }, body })
+    }
+
+    fn parse_loop_body(&mut self) -> ParseResult<Block> {
+        self.loop_depth += 1;
+        let body = self.parse_block();
+        self.loop_depth -= 1;
+        body
+    }
+
+    /// `break;` or `continue;`. Using one outside a loop is reported, but the
+    /// statement is still kept so later passes see the whole function.
+    fn parse_loop_control(&mut self, kind: StatementKind) -> ParseResult<StatementKind> {
+        let keyword_span = self.peek_span();
+        let keyword = self.advance().copied().unwrap_or(Token::EOF);
+        if self.loop_depth == 0 {
+            self.diagnostics.push(
+                Diagnostic::error(format!("{} outside of a loop", keyword.describe()))
+                    .with_code(codes::BREAK_OUTSIDE_LOOP)
+                    .with_primary(keyword_span, "can only be used inside `while` or `for`"),
+            );
+        }
         self.expect_and_consume(Token::StatementEnd)?;
-        Ok(Statement::Ret { value: value })
+        Ok(kind)
     }
-    fn parse_let_statement(&mut self) -> Result<Statement, String> {
-        let name: String = match self.advance() {
-            Some(Token::Identifier(string)) => string.to_string(),
-            _ => return Err("Expected Identifier after 'let' keyword".into())
+
+    fn parse_if_statement(&mut self) -> ParseResult<StatementKind> {
+        self.expect_and_consume(Token::If)?;
+        let condition = self.parse_condition()?;
+        let then_block = self.parse_block()?;
+        let else_branch = match self.peek() {
+            Some(Token::Else) => {
+                self.advance();
+                let start = self.peek_span();
+                let kind = match self.peek() {
+                    Some(Token::If) => self.parse_if_statement()?,
+                    Some(Token::LBrace) => StatementKind::Block(self.parse_block()?),
+                    _ => return Err(self.unexpected("`{` or `if` after `else`")),
+                };
+                Some(Box::new(Statement { kind, span: start.to(self.previous_span()) }))
+            }
+            _ => None,
         };
+        Ok(StatementKind::If { condition, then_block, else_branch })
+    }
+
+    fn parse_ret_statement(&mut self) -> ParseResult<StatementKind> {
+        let value: Expression = self.parse_expression()?;
+        self.expect_and_consume(Token::StatementEnd)?;
+        Ok(StatementKind::Ret { value })
+    }
+    fn parse_let_statement(&mut self) -> ParseResult<StatementKind> {
+        let mutable = matches!(self.peek(), Some(Token::Mut));
+        if mutable {
+            self.advance();
+        }
+        let name_span = self.peek_span();
+        let name = self.expect_identifier("a variable name after `let`")?;
         self.expect_and_consume(Token::TypeDecl)?;
-        let type_annotation: Type = match self.advance() {
-            Some(Token::Identifier(string)) => {
-                match *string {
-                    "i32" => Type::I32,
-                    "i64" => Type::I64,
-                    "u32" => Type::U32,
-                    "u64" => Type::U64,
-                    "f32" => Type::F32,
-                    "f64" => Type::F64,
-                    "bool" => Type::BOOL,
-                    "char" => Type::CHAR,
-                    "string" => Type::STRING,
-                    _ => Type::Custom((*string).to_string())
-                }
-            },
-            _ => return Err("Expected type identifier after assignment symbol in let statement.".to_string())
-        };
+        let type_annotation = self.parse_type()?;
 
         self.expect_and_consume(Token::Assign)?;
 
         let value: Expression = self.parse_expression()?;
         self.expect_and_consume(Token::StatementEnd)?;
 
-        Ok(Statement::Let { name, type_annotation, value })
+        Ok(StatementKind::Let { name, name_span, mutable, type_annotation, value })
     }
 
-    fn parse_identifier_statement(&mut self, name: String) -> Result<Statement, String> {
-        if let Some(next_token) = self.peek() {
-            match next_token {
-                Token::LParen => {
-                    let call = self.parse_function_call(name)?;
-                    self.expect_and_consume(Token::StatementEnd)?;
-                    Ok(Statement::FunctionCall { call })
-
-                },
-                _ => {
-                    self.expect_and_consume(Token::Assign)?;
-                    let new_value: Expression = self.parse_expression()?;
-                    self.expect_and_consume(Token::StatementEnd)?;
-                    Ok(Statement::Set { name, new_value })
-                }
+    fn parse_identifier_statement(&mut self) -> ParseResult<StatementKind> {
+        if let Some(Token::Identifier(name)) = self.peek().copied()
+            && self.tokens.get(self.current + 1).map(|t| t.token) == Some(Token::Assign)
+        {
+            self.current += 2;
+            let new_value: Expression = self.parse_expression()?;
+            self.expect_and_consume(Token::StatementEnd)?;
+            return Ok(StatementKind::Set { name: name.to_string(), new_value })
+        }
 
-            }
-        } else {
-            Err("Unexpected EOF".to_string())
+        let call = self.parse_expression()?;
+        if !matches!(call.kind, ExpressionKind::FunctionCall { .. }) {
+            return Err(Diagnostic::error("expression statements must be function calls")
+                .with_code(codes::UNEXPECTED_TOKEN)
+                .with_primary(call.span, "this value is computed and then thrown away")
+                .with_note("help: assign it with `<-` or return it with `ret`"))
         }
+        self.expect_and_consume(Token::StatementEnd)?;
+        Ok(StatementKind::FunctionCall { call })
     }
 
-    fn parse_expression(&mut self) -> Result<Expression, String> {
+    pub fn parse_expression(&mut self) -> ParseResult<Expression> {
+        self.parse_expression_with(false)
+    }
 
-        self.parse_binary_expr()
+    /// An expression directly followed by a `{` that isn't its own, such as
+    /// the condition of an `if`.
+    fn parse_condition(&mut self) -> ParseResult<Expression> {
+        self.parse_expression_with(true)
     }
 
-    fn parse_binary_expr(&mut self) -> Result<Expression, String> {
-        let left = match self.advance().ok_or("Unecpected EOF".to_string())? {
-            Token::LParen => {
-                self.parse_binary_expr()?
-            },
-            Token::Number(number_str) => {
-                match parse_number(&number_str)? {
-                    Number::Integer(int) => {Expression::IntLiteral(int)}
-                    Number::Float(int) => {Expression::FloatLiteral(int)}
-                }
+    fn parse_expression_with(&mut self, no_struct_literal: bool) -> ParseResult<Expression> {
+        let saved = std::mem::replace(&mut self.no_struct_literal, no_struct_literal);
+        let expression = self.parse_expression_bp(0);
+        self.no_struct_literal = saved;
+        expression
+    }
+
+    /// Precedence climbing: parses a prefix expression, then keeps folding in
+    /// binary operators that bind at least as tightly as `min_precedence`.
+    fn parse_expression_bp(&mut self, min_precedence: u8) -> ParseResult<Expression> {
+        let mut left = self.parse_unary()?;
+        while let Some(op) = self.peek().and_then(token_to_bin_op) {
+            let precedence = op.precedence();
+            if precedence < min_precedence {
+                break
             }
-            Token::Identifier(id_str) => {
-                let id = id_str.to_owned().to_string();
-                if let Some(token) = self.peek() {
-                    match token {
-                        Token::LParen => {
-                            self.parse_function_call(id)?
-                        },
-                        _ => Expression::Variable(id)
-                    }
-                } else {
-                    return Ok(Expression::Variable(id))
-                }
+            self.advance();
+            let next_min = match op.associativity() {
+                Associativity::Left | Associativity::None => precedence + 1,
+                Associativity::Right => precedence,
+            };
+            let right = self.parse_expression_bp(next_min)?;
+            let span = left.span.to(right.span);
+            left = Expression { kind: ExpressionKind::Binary { op, left: Box::new(left), right: Box::new(right) }, span };
+
+            if op.associativity() == Associativity::None
+                && let Some(next) = self.peek().and_then(token_to_bin_op)
+                && next.precedence() == precedence
+            {
+                return Err(Diagnostic::error("comparison operators cannot be chained")
+                    .with_code(codes::CHAINED_COMPARISON)
+                    .with_primary(self.peek_span(), "")
+                    .with_secondary(span, "this comparison already produces a `bool`")
+                    .with_note(format!("help: split the comparison up with `&&`, as in `a {} b && b {} c`", op.symbol(), next.symbol())))
             }
-            _ => {return Err("Unexpected Token in Expression".to_string())}
-        };
-        if self.is_end() {
-            return Ok(left)
         }
-        match self.expect(Token::RParen) {
-            Ok(_) => {self.advance();},
-            Err(_) => {}
-        };
+        Ok(left)
+    }
 
-        match self.expect(Token::StatementEnd) {
-            Ok(_) => {return Ok(left)},
-            Err(_) => {}
+    fn parse_unary(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
+        let op = match self.peek() {
+            Some(Token::Operator("-")) => UnaryOp::Neg,
+            Some(Token::Operator("!")) => UnaryOp::Not,
+            _ => return self.parse_postfix(),
         };
-        let op_token = self.advance().ok_or("Unecpected EOF".to_string())?;
-        let op = match op_token {
-            Token::Operator(op_string) => {
-                match *op_string {
-                    "+" => BinaryOp::Add,
-                    "-" => BinaryOp::Sub,
-                    "/" => BinaryOp::Div,
-                    "*" => BinaryOp::Mul,
-                    _   => return Err("NOT A BINARY Operator".to_string())
+        self.advance();
+        let operand = self.parse_unary()?;
+        let span = start.to(operand.span);
+        Ok(Expression { kind: ExpressionKind::Unary { op, operand: Box::new(operand) }, span })
+    }
+
+    /// A primary expression followed by any number of call argument lists
+    /// and field accesses, so `f(a)(b)` calls the result of `f(a)` and
+    /// `a.b.c` reads `c` out of `a.b`.
+    fn parse_postfix(&mut self) -> ParseResult<Expression> {
+        let mut expression = self.parse_primary()?;
+        loop {
+            match self.peek() {
+                Some(Token::LParen) => expression = self.parse_function_call(expression)?,
+                Some(Token::FieldAccessor) => {
+                    self.advance();
+                    let field = self.expect_identifier("a field name after `.`")?;
+                    let span = expression.span.to(self.previous_span());
+                    expression = Expression {
+                        kind: ExpressionKind::FieldAccess { object: Box::new(expression), field },
+                        span,
+                    };
                 }
-            
+                _ => return Ok(expression),
             }
-            Token::RParen => {return Ok(left)}
-            Token::StatementEnd => {self.current -= 1; return Ok(left)}
-            _ => {return Err(format!("Unexpected Token {:#?} in Position of Binary Operator", op_token).to_string())}
-        };
-        let right = match self.advance().ok_or("Unecpected EOF".to_string())? {
+        }
+    }
+
+    /// `name { field <- value, ... }`
+    fn parse_struct_literal(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
+        let path = self.parse_path()?;
+        self.expect_and_consume(Token::LBrace)?;
+        let mut fields: Vec<FieldInit> = Vec::new();
+        while self.expect(Token::RBrace).is_err() {
+            let field_start = self.peek_span();
+            let field = self.expect_identifier("a field name")?;
+            self.expect_and_consume(Token::Assign)?;
+            let value = self.parse_expression()?;
+            fields.push(FieldInit { name: field, value, span: field_start.to(self.previous_span()) });
+            match self.peek() {
+                Some(Token::ArgumentSeparator) => {
+                    self.advance();
+                }
+                Some(Token::RBrace) => {}
+                _ => return Err(self.unexpected("`,` or `}`")),
+            }
+        }
+        self.expect_and_consume(Token::RBrace)?;
+        Ok(Expression {
+            kind: ExpressionKind::StructLiteral { path, fields },
+            span: start.to(self.previous_span()),
+        })
+    }
+
+    fn parse_primary(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
+        let token = self.peek().copied().unwrap_or(Token::EOF);
+        let kind = match token {
             Token::LParen => {
-                self.parse_binary_expr()?
+                self.advance();
+                let mut inner = self.parse_expression()?;
+                self.expect_and_consume(Token::RParen)?;
+                inner.span = start.to(self.previous_span());
+                return Ok(inner)
             },
             Token::Number(number_str) => {
-                match parse_number(&number_str)? {
-                    Number::Integer(int) => {Expression::IntLiteral(int)}
-                    Number::Float(int) => {Expression::FloatLiteral(int)}
-                }
-            }
-            Token::Identifier(id_str) => {
-                let id = id_str.to_owned().to_string();
-                if let Some(token) = self.peek() {
-                    match token {
-                        Token::LParen => {
-                            self.parse_function_call(id)?
-                        },
-                        _ => Expression::Variable(id)
-                    }
-                } else {
-                    return Ok(Expression::Variable(id))
+                let number = parse_number(number_str).map_err(|e| {
+                    Diagnostic::error(e).with_code(codes::INVALID_NUMBER).with_primary(start, "")
+                })?;
+                match number {
+                    Number::Integer(int) => {ExpressionKind::IntLiteral(int)}
+                    Number::Float(int) => {ExpressionKind::FloatLiteral(int)}
                 }
             }
-            _ => {return Err("Unexpected Token in Expression".to_string())}
+            Token::StringLiteral(literal) => ExpressionKind::StringLiteral(unescape(&literal[1..literal.len() - 1])),
+            Token::CharLiteral(literal) => ExpressionKind::CharLiteral(literal.chars().nth(1).unwrap_or_default()),
+            Token::BoolLiteral(literal) => ExpressionKind::BoolLiteral(literal == "true"),
+            Token::Identifier(_) if self.at_struct_literal() => return self.parse_struct_literal(),
+            Token::Identifier(id_str) => ExpressionKind::Variable(id_str.to_string()),
+            Token::If => return self.parse_if_expression(),
+            Token::PatternMatch => return self.parse_match_expression(),
+            _ => return Err(self.unexpected("an expression"))
         };
+        self.advance();
+        Ok(Expression { kind, span: start })
+    }
 
-        match self.expect(Token::RParen) {
-            Ok(_) => {self.advance();},
-            Err(_) => {}
+    fn parse_if_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
+        self.expect_and_consume(Token::If)?;
+        let condition = self.parse_condition()?;
+        let then_value = self.parse_braced_expression()?;
+        if self.expect(Token::Else).is_err() {
+            return Err(self.unexpected("`else`")
+                .with_note("an `if` used as a value needs an `else` branch to produce a value when the condition is false"))
+        }
+        self.advance();
+        let else_value = match self.peek() {
+            Some(Token::If) => self.parse_if_expression()?,
+            _ => self.parse_braced_expression()?,
         };
-        Ok(Expression::Binary { op, left: Box::new(left), right: Box::new(right) }) 
+        Ok(Expression {
+            kind: ExpressionKind::If {
+                condition: Box::new(condition),
+                then_value: Box::new(then_value),
+                else_value: Box::new(else_value),
+            },
+            span: start.to(self.previous_span()),
+        })
     }
-    
-    fn parse_function_call(&mut self, name: String) -> Result<Expression, String> {
+
+    fn parse_match_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
+        let scrutinee = self.parse_match_head()?;
+        let mut arms: Vec<MatchArm<Expression>> = Vec::new();
+        while !matches!(self.peek(), Some(Token::RBrace) | Some(Token::EOF) | None) {
+            let arm_start = self.peek_span();
+            let pattern = self.parse_pattern()?;
+            self.expect_and_consume(Token::RetType)?;
+            let body = self.parse_expression()?;
+            self.expect_and_consume(Token::StatementEnd)?;
+            arms.push(MatchArm { pattern, body, span: arm_start.to(self.previous_span()) });
+        }
+        self.expect_and_consume(Token::RBrace)?;
+        Ok(Expression {
+            kind: ExpressionKind::Match { scrutinee: Box::new(scrutinee), arms },
+            span: start.to(self.previous_span()),
+        })
+    }
+
+    /// `{ expression }`, as used by the branches of an if expression.
+    fn parse_braced_expression(&mut self) -> ParseResult<Expression> {
+        self.expect_and_consume(Token::LBrace)?;
+        let value = self.parse_expression()?;
+        self.expect_and_consume(Token::RBrace)?;
+        Ok(value)
+    }
+
+    fn parse_function_call(&mut self, callee: Expression) -> ParseResult<Expression> {
         self.expect_and_consume(Token::LParen)?;
         let mut args: Vec<Expression> = Vec::new();
-        let mut outer = true;
-        let mut depth = 0;
-        let mut index = self.current;
-        while outer == true {
-            let mut inner = true;
-            let mut expression_tokens: Vec<Token> = Vec::new();
-
-            while inner == true {
-                
-                let mut add_token = true;
-                index += 1;
-                if let Some(tok) = self.tokens.get(index - 1) {
-                    match tok {
-                        Token::LParen => {
-                            depth += 1
-                        }
-                        Token::RParen => {
-                            if depth == 0 {
-                                outer = false;
-                                break;
-                            }
-                            depth -= 1;
-                        }
-                        Token::ArgumentSeparator => {
-                            if depth == 0 {
-                                inner = false;
-                                add_token = false;
-                            }
-                        }
-                        _ => {}
-                    }
-                    if add_token {
-                        expression_tokens.push(tok.clone());
-                    }
-
+        if self.expect(Token::RParen).is_err() {
+            loop {
+                args.push(self.parse_expression()?);
+                if self.expect(Token::RParen).is_ok() {
+                    break
+                }
+                if self.expect(Token::ArgumentSeparator).is_err() {
+                    return Err(self.unexpected("`,` or `)`"))
                 }
+                self.advance();
             }
-            args.push(evaluate_expression_from_tokens(expression_tokens)?)
         }
-        self.current = index;
-        return Ok(Expression::FunctionCall {
-            name,
-            args
+        self.expect_and_consume(Token::RParen)?;
+        let span = callee.span.to(self.previous_span());
+        Ok(Expression {
+            kind: ExpressionKind::FunctionCall { callee: Box::new(callee), args },
+            span,
         })
     }
 }
 
-fn evaluate_expression_from_tokens(tokens: Vec<Token>) -> Result<Expression, String> {
-    Parser::new(tokens).parse_expression()
-    
-}
-
-
 fn token_to_bin_op(tok: &Token) -> Option<BinaryOp> {
     match tok {
         Token::Operator(op_string) => {
@@ -469,6 +1327,19 @@ fn token_to_bin_op(tok: &Token) -> Option<BinaryOp> {
                     "-" => BinaryOp::Sub,
                     "/" => BinaryOp::Div,
                     "*" => BinaryOp::Mul,
+                    "%" => BinaryOp::Mod,
+                    "==" => BinaryOp::Eq,
+                    "!=" => BinaryOp::Ne,
+                    "<" => BinaryOp::Lt,
+                    "<=" => BinaryOp::Le,
+                    ">" => BinaryOp::Gt,
+                    ">=" => BinaryOp::Ge,
+                    "&&" => BinaryOp::And,
+                    "||" => BinaryOp::Or,
+                    "&" => BinaryOp::BitAnd,
+                    "^" => BinaryOp::BitXor,
+                    "<<" => BinaryOp::Shl,
+                    ">>" => BinaryOp::Shr,
                     _   => return None
                 }
             )
@@ -478,14 +1349,40 @@ fn token_to_bin_op(tok: &Token) -> Option<BinaryOp> {
     }
 }
 
+/// Resolves the backslash escapes in the body of a string literal.
+fn unescape(body: &str) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
 enum Number {
-    Integer(i64),
+    /// Wide enough for every `u64` and its negation; the type checker
+    /// decides whether it fits the literal's type.
+    Integer(i128),
     Float(f64),
 }
 
 fn parse_number(input: &str) -> Result<Number, String> {
-    if let Ok(i) = input.parse::<i64>() {
-        Ok(Number::Integer(i))
+    if !input.contains('.') {
+        input
+            .parse::<i128>()
+            .map(Number::Integer)
+            .map_err(|_| format!("integer literal `{}` is too large", input))
     } else if let Ok(f) = input.parse::<f64>() {
         Ok(Number::Float(f))
     } else {
@@ -500,16 +1397,549 @@ fn parse_number(input: &str) -> Result<Number, String> {
 
 #[derive(Debug)]
 pub(crate) struct Program {
-    objs: Vec<ProgramObject>
+    pub objs: Vec<ProgramObject>
+}
+
+/// A named, typed function argument such as `a | num` or `mut a | num`.
+#[derive(Debug)]
+pub(crate) struct Parameter {
+    pub name: String,
+    pub mutable: bool,
+    pub type_annotation: Type,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub(crate) enum ProgramObject {
     Function {
         name: String,
-        arguments: Vec<(String, Type)>,
+        public: bool,
+        arguments: Vec<Parameter>,
         return_type: Option<Type>,
         statements: Vec<Statement>,
+        /// Covers `fn name(...) -> type`, without the body.
+        signature_span: Span,
+        span: Span,
+    },
+    Struct {
+        name: String,
+        public: bool,
+        fields: Vec<StructField>,
+        span: Span,
+    },
+    /// An algebraic data type. Variants are built with the same syntax as
+    /// field access and calls (`shape.empty`, `shape.circle(1.0)`) or
+    /// struct literals (`shape.rect { w <- 1.0, h <- 2.0 }`).
+    Enum {
+        name: String,
+        public: bool,
+        variants: Vec<EnumVariant>,
+        span: Span,
+    },
+    /// `mod name;` has no body until the module loader reads `name.c4l`;
+    /// `mod name { ... }` carries its objects inline.
+    Module {
+        name: String,
+        body: Option<Vec<ProgramObject>>,
+        span: Span,
+    },
+    Use(UseDecl),
+}
+
+/// A `use` import; see `Parser::parse_use`.
+#[derive(Debug, Clone)]
+pub(crate) struct UseDecl {
+    pub path: Vec<String>,
+    pub kind: UseKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum UseKind {
+    /// Imports the last segment of the path, optionally renamed with `as`.
+    Single { alias: Option<String> },
+    /// `a.*` imports every public item and submodule of module `a`.
+    Glob,
+}
+
+impl ProgramObject {
+    pub fn name(&self) -> &str {
+        match self {
+            ProgramObject::Function { name, .. }
+            | ProgramObject::Struct { name, .. }
+            | ProgramObject::Enum { name, .. }
+            | ProgramObject::Module { name, .. } => name,
+            ProgramObject::Use(decl) => match &decl.kind {
+                UseKind::Single { alias: Some(alias) } => alias,
+                UseKind::Single { alias: None } => decl.path.last().map_or("", |s| s.as_str()),
+                UseKind::Glob => "*",
+            },
+        }
+    }
+
+    pub fn is_public(&self) -> bool {
+        match self {
+            ProgramObject::Function { public, .. }
+            | ProgramObject::Struct { public, .. }
+            | ProgramObject::Enum { public, .. } => *public,
+            ProgramObject::Module { .. } => true,
+            ProgramObject::Use(_) => false,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            ProgramObject::Function { span, .. }
+            | ProgramObject::Struct { span, .. }
+            | ProgramObject::Enum { span, .. }
+            | ProgramObject::Module { span, .. } => *span,
+            ProgramObject::Use(decl) => decl.span,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EnumVariant {
+    pub name: String,
+    pub payload: VariantPayload,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub(crate) enum VariantPayload {
+    /// `empty`
+    Unit,
+    /// `circle(f64)`
+    Tuple(Vec<Type>),
+    /// `rect { w | f64, h | f64 }`
+    Struct(Vec<StructField>),
+}
+
+/// A named, typed field in a struct declaration such as `sad | bool`.
+#[derive(Debug)]
+pub(crate) struct StructField {
+    pub name: String,
+    pub type_annotation: Type,
+    pub span: Span,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::FileId;
+    use crate::tokenizer;
+
+    fn parse(source: &str) -> (Program, Vec<Diagnostic>) {
+        let (tokens, diagnostics) = tokenizer::parse(source, FileId(0));
+        assert!(diagnostics.is_empty(), "the source should lex: {:?}", diagnostics);
+        Parser::new(tokens).parse()
+    }
+
+    fn object_names(program: &Program) -> Vec<&str> {
+        program.objs.iter().map(ProgramObject::name).collect()
+    }
+
+    #[test]
+    fn every_syntax_error_is_reported_in_one_pass() {
+        let (program, diagnostics) = parse(
+            "fn broken( -> i32 {
+                ret 1;
+            }
+
+            fn statements() -> i32 {
+                let x | i32 <- ;
+                let y | i32 <- 2 +;
+                ret y;
+            }
+
+            struct point { x | i32, y | }
+
+            fn fine() -> i32 {
+                ret 2;
+            }",
+        );
+        let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, [Some(codes::UNEXPECTED_TOKEN); 4]);
+        assert_eq!(object_names(&program), ["statements", "fine"]);
+    }
+
+    #[test]
+    fn statements_after_a_broken_one_are_kept() {
+        let (program, diagnostics) = parse("fn f() -> i32 { let x | i32 <- ; let y | i32 <- 2; ret y; }");
+        assert_eq!(diagnostics.len(), 1);
+        let ProgramObject::Function { statements, .. } = &program.objs[0] else {
+            panic!("`f` should parse as a function");
+        };
+        assert_eq!(statements.len(), 2);
+    }
+
+    #[test]
+    fn a_stray_closing_brace_is_reported_and_skipped() {
+        let (program, diagnostics) = parse("fn a() {} } fn b() {}");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "unexpected `}`");
+        assert_eq!(object_names(&program), ["a", "b"]);
+    }
+
+    #[test]
+    fn type_annotations_and_let_names_have_spans_of_their_own() {
+        let source = "struct s { p | math.point }\nfn f(a | i32) -> num { let mut total | u64 <- 0; ret a; }";
+        let (program, diagnostics) = parse(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let text = |span: Span| &source[span.start..span.end];
+        let ProgramObject::Struct { fields, .. } = &program.objs[0] else {
+            panic!("`s` should parse as a struct");
+        };
+        assert_eq!(text(fields[0].type_annotation.span), "math.point");
+        let ProgramObject::Function { arguments, return_type: Some(return_type), statements, .. } = &program.objs[1] else {
+            panic!("`f` should parse as a function returning a value");
+        };
+        assert_eq!(text(arguments[0].type_annotation.span), "i32");
+        assert_eq!(text(return_type.span), "num");
+        let StatementKind::Let { name_span, type_annotation, .. } = &statements[0].kind else {
+            panic!("the first statement should be a `let`");
+        };
+        assert_eq!(text(*name_span), "total");
+        assert_eq!(text(type_annotation.span), "u64");
+    }
+
+    /// An expression as an S-expression, with every operator's operands in
+    /// parentheses after it.
+    fn tree(source: &str) -> String {
+        let (tokens, _) = tokenizer::parse(source, FileId(0));
+        let mut parser = Parser::new(tokens);
+        let expression = parser.parse_expression().unwrap_or_else(|d| panic!("`{}` should parse: {}", source, d.message));
+        assert_eq!(parser.peek(), Some(&Token::EOF), "`{}` should parse whole", source);
+        render(&expression)
+    }
+
+    fn render(expression: &Expression) -> String {
+        match &expression.kind {
+            ExpressionKind::IntLiteral(value) => value.to_string(),
+            ExpressionKind::BoolLiteral(value) => value.to_string(),
+            ExpressionKind::Variable(name) => name.clone(),
+            ExpressionKind::Binary { op, left, right } => format!("({} {} {})", op.symbol(), render(left), render(right)),
+            ExpressionKind::Unary { op: UnaryOp::Neg, operand } => format!("(- {})", render(operand)),
+            ExpressionKind::Unary { op: UnaryOp::Not, operand } => format!("(! {})", render(operand)),
+            ExpressionKind::FunctionCall { callee, args } => {
+                let args: Vec<String> = args.iter().map(render).collect();
+                format!("{}({})", render(callee), args.join(" "))
+            }
+            ExpressionKind::FieldAccess { object, field } => format!("{}.{}", render(object), field),
+            ExpressionKind::If { condition, then_value, else_value } => {
+                format!("(if {} {} {})", render(condition), render(then_value), render(else_value))
+            }
+            ExpressionKind::StructLiteral { path, fields } => {
+                let fields: Vec<String> =
+                    fields.iter().map(|field| format!("{}: {}", field.name, render(&field.value))).collect();
+                format!("{}{{{}}}", path.join("."), fields.join(", "))
+            }
+            ExpressionKind::Match { scrutinee, arms } => {
+                let arms: Vec<String> = arms
+                    .iter()
+                    .map(|arm| format!("[{} {}]", render_pattern(&arm.pattern), render(&arm.body)))
+                    .collect();
+                format!("(@ {} {})", render(scrutinee), arms.join(" "))
+            }
+            kind => panic!("no rendering for {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        assert_eq!(tree("a + b * c - d"), "(- (+ a (* b c)) d)");
+        assert_eq!(tree("a * b + c * d"), "(+ (* a b) (* c d))");
+        assert_eq!(tree("a + b << c & d ^ e"), "(^ (& (<< (+ a b) c) d) e)");
+        assert_eq!(tree("a || b && c == d + 1"), "(|| a (&& b (== c (+ d 1))))");
+    }
+
+    #[test]
+    fn operators_of_equal_precedence_associate_left() {
+        assert_eq!(tree("a - b - c"), "(- (- a b) c)");
+        assert_eq!(tree("a / b * c % d"), "(% (* (/ a b) c) d)");
+        assert_eq!(tree("a << b >> c"), "(>> (<< a b) c)");
+        assert_eq!(tree("a && b && c"), "(&& (&& a b) c)");
+    }
+
+    #[test]
+    fn unary_operators_bind_tighter_than_binary_ones() {
+        assert_eq!(tree("-a * b"), "(* (- a) b)");
+        assert_eq!(tree("!a && -b < c"), "(&& (! a) (< (- b) c))");
+        assert_eq!(tree("- -a"), "(- (- a))");
+    }
+
+    #[test]
+    fn parentheses_and_calls_nest() {
+        assert_eq!(tree("(a + b) * c"), "(* (+ a b) c)");
+        assert_eq!(tree("((((a))))"), "a");
+        assert_eq!(tree("f(a + b, g(c) * 2) - h()"), "(- f((+ a b) (* g(c) 2)) h())");
+        assert_eq!(tree("m.f(x).y"), "m.f(x).y");
+    }
+
+    /// The diagnostic parsing `source` as an expression fails with.
+    fn expression_error(source: &str) -> Diagnostic {
+        let (tokens, _) = tokenizer::parse(source, FileId(0));
+        Parser::new(tokens).parse_expression().expect_err("the expression should be rejected")
+    }
+
+    #[test]
+    fn comparison_and_bitwise_operators_parse() {
+        assert_eq!(tree("a != b"), "(!= a b)");
+        assert_eq!(tree("a <= b"), "(<= a b)");
+        assert_eq!(tree("a >= b"), "(>= a b)");
+        assert_eq!(tree("a < -b"), "(< a (- b))");
+        assert_eq!(tree("a >> 1 > b << 2"), "(> (>> a 1) (<< b 2))");
+        assert_eq!(tree("a & b ^ c"), "(^ (& a b) c)");
+    }
+
+    #[test]
+    fn chained_comparisons_are_rejected() {
+        for source in ["a < b < c", "a == b != c", "a <= b > c"] {
+            assert_eq!(expression_error(source).code, Some(codes::CHAINED_COMPARISON), "{}", source);
+        }
+        assert_eq!(tree("a < b && b < c"), "(&& (< a b) (< b c))");
+        assert_eq!(tree("(a < b) == c"), "(== (< a b) c)");
+    }
+
+    #[test]
+    fn less_than_and_pipes_coexist_with_assignment_and_type_tokens() {
+        let (program, diagnostics) = parse(
+            "fn f(a | i32, b | i32) -> bool {
+                let mut x | bool <- a < b || b < -a;
+                x <- x && !(a <= b);
+                ret x;
+            }",
+        );
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let ProgramObject::Function { statements, .. } = &program.objs[0] else {
+            panic!("`f` should parse as a function");
+        };
+        let StatementKind::Let { value, .. } = &statements[0].kind else {
+            panic!("the first statement should be a `let`");
+        };
+        assert_eq!(render(value), "(|| (< a b) (< b (- a)))");
+    }
+
+    /// The statements of a function body, each as an S-expression.
+    fn body(source: &str) -> Vec<String> {
+        let (program, diagnostics) = parse(&format!("fn f() {{ {} }}", source));
+        assert!(diagnostics.is_empty(), "`{}` should parse: {:?}", source, diagnostics);
+        let ProgramObject::Function { statements, .. } = &program.objs[0] else {
+            panic!("`f` should parse as a function");
+        };
+        statements.iter().map(render_statement).collect()
+    }
+
+    fn render_statement(statement: &Statement) -> String {
+        match &statement.kind {
+            StatementKind::Let { name, value, .. } => format!("(let {} {})", name, render(value)),
+            StatementKind::Ret { value } => format!("(ret {})", render(value)),
+            StatementKind::If { condition, then_block, else_branch: None } => {
+                format!("(if {} {})", render(condition), render_block(then_block))
+            }
+            StatementKind::If { condition, then_block, else_branch: Some(else_branch) } => {
+                format!("(if {} {} {})", render(condition), render_block(then_block), render_statement(else_branch))
+            }
+            StatementKind::Block(block) => render_block(block),
+            StatementKind::Set { name, new_value } => format!("(set {} {})", name, render(new_value)),
+            StatementKind::While { condition, body } => format!("(while {} {})", render(condition), render_block(body)),
+            StatementKind::For { variable, iterable: ForIterable::Range { start, end }, body } => {
+                format!("(for {} {} {} {})", variable, render(start), render(end), render_block(body))
+            }
+            StatementKind::Break => "break".to_string(),
+            StatementKind::Continue => "continue".to_string(),
+            StatementKind::Match { scrutinee, arms } => {
+                let arms: Vec<String> = arms
+                    .iter()
+                    .map(|arm| format!("[{} {}]", render_pattern(&arm.pattern), render_statement(&arm.body)))
+                    .collect();
+                format!("(@ {} {})", render(scrutinee), arms.join(" "))
+            }
+            kind => panic!("no rendering for {:?}", kind),
+        }
+    }
+
+    fn render_pattern(pattern: &Pattern) -> String {
+        let literal = |literal: &Literal| match literal {
+            Literal::Int(value) => value.to_string(),
+            Literal::Float(value) => value.to_string(),
+            Literal::Bool(value) => value.to_string(),
+            Literal::Char(value) => format!("{:?}", value),
+            Literal::String(value) => format!("{:?}", value),
+        };
+        match &pattern.kind {
+            PatternKind::Wildcard => "~".to_string(),
+            PatternKind::Binding(name) => name.clone(),
+            PatternKind::Literal(value) => literal(value),
+            PatternKind::Range { start, end } => format!("(.. {} {})", literal(start), literal(end)),
+            PatternKind::Or(alternatives) => {
+                let alternatives: Vec<String> = alternatives.iter().map(render_pattern).collect();
+                format!("(| {})", alternatives.join(" "))
+            }
+            PatternKind::Constructor { path, fields: PatternFields::Unit } => path.join("."),
+            PatternKind::Constructor { path, fields: PatternFields::Tuple(patterns) } => {
+                let patterns: Vec<String> = patterns.iter().map(render_pattern).collect();
+                format!("{}({})", path.join("."), patterns.join(" "))
+            }
+            PatternKind::Constructor { path, fields: PatternFields::Struct(fields) } => {
+                let fields: Vec<String> =
+                    fields.iter().map(|(name, pattern)| format!("{}: {}", name, render_pattern(pattern))).collect();
+                format!("{}{{{}}}", path.join("."), fields.join(", "))
+            }
+        }
+    }
+
+    fn render_block(block: &Block) -> String {
+        let statements: Vec<String> = block.statements.iter().map(render_statement).collect();
+        format!("{{{}}}", statements.join(" "))
+    }
+
+    #[test]
+    fn else_if_chains_nest_in_the_else_branch() {
+        assert_eq!(
+            body("if a { ret 1; } else if b { ret 2; } else { ret 3; }"),
+            ["(if a {(ret 1)} (if b {(ret 2)} {(ret 3)}))"]
+        );
+        assert_eq!(body("if a { ret 1; } ret 2;"), ["(if a {(ret 1)})", "(ret 2)"]);
+        assert_eq!(body("if a < b { } else { if c { ret 1; } }"), ["(if (< a b) {} {(if c {(ret 1)})})"]);
+    }
+
+    #[test]
+    fn if_expressions_need_an_else() {
+        assert_eq!(tree("if a { 1 } else if b { 2 } else { 3 }"), "(if a 1 (if b 2 3))");
+        assert_eq!(tree("1 + if a { b } else { c * 2 }"), "(+ 1 (if a b (* c 2)))");
+
+        let (_, diagnostics) = parse("fn f(a | bool) -> i32 { let x | i32 <- if a { 1 }; ret x; }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "expected `else`, found `;`");
+        assert!(diagnostics[0].notes[0].starts_with("an `if` used as a value needs an `else`"));
+    }
+
+    #[test]
+    fn else_must_be_followed_by_a_block_or_if() {
+        let (_, diagnostics) = parse("fn f(a | bool) { if a { } else ret 1; }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "expected `{` or `if` after `else`, found `ret`");
+    }
+
+    #[test]
+    fn loops_hold_their_bodies() {
+        assert_eq!(
+            body("while i < 10 { i <- i + 1; if i == 5 { break; } continue; }"),
+            ["(while (< i 10) {(set i (+ i 1)) (if (== i 5) {break}) continue})"]
+        );
+        assert_eq!(body("for i in 0..n + 1 { ret i; }"), ["(for i 0 (+ n 1) {(ret i)})"]);
+        assert_eq!(body("for i in a..b { for j in i..b { break; } }"), ["(for i a b {(for j i b {break})})"]);
+    }
+
+    #[test]
+    fn break_and_continue_outside_a_loop_are_reported_and_kept() {
+        let (program, diagnostics) = parse("fn f() { break; if true { continue; } while true { } break; }");
+        let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, [Some(codes::BREAK_OUTSIDE_LOOP); 3]);
+        assert_eq!(diagnostics[1].message, "`continue` outside of a loop");
+        let ProgramObject::Function { statements, .. } = &program.objs[0] else {
+            panic!("`f` should parse as a function");
+        };
+        assert_eq!(statements.len(), 4);
+    }
+
+    #[test]
+    fn for_loops_need_a_range() {
+        let (_, diagnostics) = parse("fn f() { for i in 10 { } }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "expected `..`, found `{`");
+        assert_eq!(diagnostics[0].notes, ["`for` loops iterate over a range such as `0..10`"]);
+    }
+
+    #[test]
+    fn match_arms_take_or_range_literal_and_wildcard_patterns() {
+        assert_eq!(
+            body("@n { 0 | 1 -> ret 1; 2..10 -> { ret 2; } -5 -> ret 3; 'a'..'z' | x -> ret x; ~ -> {}; }"),
+            ["(@ n [(| 0 1) (ret 1)] [(.. 2 10) {(ret 2)}] [-5 (ret 3)] [(| (.. 'a' 'z') x) (ret x)] [~ {}])"]
+        );
+        assert_eq!(tree(r#"@s { "yes" | "y" -> true; ~ -> false; }"#), r#"(@ s [(| "yes" "y") true] [~ false])"#);
+        assert_eq!(tree("1 + @a < b { true -> 1; false -> 2; }"), "(+ 1 (@ (< a b) [true 1] [false 2]))");
+    }
+
+    #[test]
+    fn range_patterns_need_integers_or_characters() {
+        let (_, diagnostics) = parse("fn f(x | f64) { @x { 1.0..2.0 -> {} ~ -> {} } }");
+        assert_eq!(diagnostics[0].message, "range patterns must be integers or characters");
+        let (_, diagnostics) = parse("fn f(x | i32) { @x { -true -> {} } }");
+        assert_eq!(diagnostics[0].message, "expected a number after `-`, found `true`");
+    }
+
+    #[test]
+    fn struct_declarations_literals_and_field_access() {
+        let (program, diagnostics) = parse("struct point { x | i32, y | i32, }\nstruct empty {}");
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let ProgramObject::Struct { fields, .. } = &program.objs[0] else {
+            panic!("`point` should parse as a struct");
+        };
+        let fields: Vec<&str> = fields.iter().map(|field| field.name.as_str()).collect();
+        assert_eq!(fields, ["x", "y"]);
+        assert_eq!(object_names(&program), ["point", "empty"]);
+
+        assert_eq!(tree("point { x <- 1, y <- a + 1 }"), "point{x: 1, y: (+ a 1)}");
+        assert_eq!(tree("line { from <- point { x <- 0 }, to <- p }.from.x"), "line{from: point{x: 0}, to: p}.from.x");
+        assert_eq!(tree("shape.rect { w <- 2 }"), "shape.rect{w: 2}");
+        assert_eq!(tree("empty {}"), "empty{}");
+    }
+
+    #[test]
+    fn a_brace_after_a_condition_starts_the_body_not_a_struct_literal() {
+        assert_eq!(body("if x { ret 1; }"), ["(if x {(ret 1)})"]);
+        assert_eq!(body("while p.x < n { ret p; }"), ["(while (< p.x n) {(ret p)})"]);
+        assert_eq!(body("for i in 0..n { }"), ["(for i 0 n {})"]);
+        assert_eq!(body("@p { ~ -> {} }"), ["(@ p [~ {}])"]);
+        // Inside parentheses or arguments a struct literal is unambiguous.
+        assert_eq!(body("if (p { x <- 1 }).x == f(p { x <- 2 }) { }"), ["(if (== p{x: 1}.x f(p{x: 2})) {})"]);
+        assert_eq!(body("ret p { x <- 1 };"), ["(ret p{x: 1})"]);
+    }
+
+    #[test]
+    fn struct_literal_fields_are_assigned_with_arrows() {
+        let (_, diagnostics) = parse("fn f() -> point { ret point { x | 1 }; }");
+        assert_eq!(diagnostics[0].message, "expected `<-`, found `|`");
+    }
+
+    #[test]
+    fn enum_variants_take_unit_tuple_and_struct_payloads() {
+        let source = "enum shape { empty, circle(f64), pair(i32, math.point), rect { w | f64, h | f64 } }";
+        let (program, diagnostics) = parse(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let ProgramObject::Enum { variants, .. } = &program.objs[0] else {
+            panic!("`shape` should parse as an enum");
+        };
+        let payloads: Vec<(&str, String)> = variants
+            .iter()
+            .map(|variant| {
+                let payload = match &variant.payload {
+                    VariantPayload::Unit => "unit".to_string(),
+                    VariantPayload::Tuple(types) => format!("tuple of {}", types.len()),
+                    VariantPayload::Struct(fields) => {
+                        let names: Vec<&str> = fields.iter().map(|field| field.name.as_str()).collect();
+                        format!("struct of {}", names.join(" "))
+                    }
+                };
+                (variant.name.as_str(), payload)
+            })
+            .collect();
+        let expected = [("empty", "unit"), ("circle", "tuple of 1"), ("pair", "tuple of 2"), ("rect", "struct of w h")];
+        assert_eq!(payloads, expected.map(|(name, payload)| (name, payload.to_string())));
+    }
+
+    #[test]
+    fn variant_patterns_destructure_their_payloads() {
+        assert_eq!(
+            body("@s { shape.empty -> {} shape.circle(r) -> ret r; shape.rect { w, h <- 0 } -> ret w; }"),
+            ["(@ s [shape.empty {}] [shape.circle(r) (ret r)] [shape.rect{w: w, h: 0} (ret w)])"]
+        );
+        assert_eq!(
+            tree("@o { opt.some(point { x, y <- 1..5 }) | opt.none -> x; opt.pair(~, b) -> b; }"),
+            "(@ o [(| opt.some(point{x: x, y: (.. 1 5)}) opt.none) x] [opt.pair(~ b) b])"
+        );
+        assert_eq!(tree("shape.circle(2) == shape.empty"), "(== shape.circle(2) shape.empty)");
     }
-    //TODO: ADD STRUCTS AND ENUMS (THAT SOUNDS HARD)
 }
@@ -0,0 +1,530 @@
+use crate::diagnostic::{codes, Diagnostic};
+use crate::module::{ModuleId, ModuleTree, Resolved, ROOT};
+use crate::parser::*;
+use crate::span::Span;
+use std::collections::HashMap;
+
+/// A local variable: a parameter, `let`, `for` variable or pattern binding.
+struct Local<'p> {
+    span: Span,
+    /// The declared type, when there is one, used to suggest `a.field`
+    /// when a type name is used like a variable.
+    type_annotation: Option<&'p Type>,
+}
+
+/// Checks that every name used in a function body refers to something: a
+/// local in scope, or an item reachable through the module tree. Types are
+/// left to the type checker.
+pub(crate) fn resolve(modules: &ModuleTree) -> Vec<Diagnostic> {
+    let mut resolver = Resolver {
+        modules,
+        module: ROOT,
+        scopes: Vec::new(),
+        declared: HashMap::new(),
+        diagnostics: Vec::new(),
+    };
+    for (module, obj) in modules.objects() {
+        if let ProgramObject::Function { arguments, statements, .. } = obj {
+            resolver.module = module;
+            resolver.resolve_function(arguments, statements);
+        }
+    }
+    resolver.diagnostics
+}
+
+struct Resolver<'p> {
+    modules: &'p ModuleTree,
+    /// The module of the function being resolved.
+    module: ModuleId,
+    /// Innermost scope last.
+    scopes: Vec<HashMap<String, Local<'p>>>,
+    /// Every binding declared anywhere in the current function, so a use
+    /// before the `let` can say so.
+    declared: HashMap<String, Span>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'p> Resolver<'p> {
+    fn resolve_function(&mut self, arguments: &'p [Parameter], statements: &'p [Statement]) {
+        self.declared.clear();
+        for statement in statements {
+            collect_declarations(statement, &mut self.declared);
+        }
+
+        self.scopes.push(HashMap::new());
+        for param in arguments {
+            if let Some(previous) = self.scopes[0].get(&param.name) {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("parameter `{}` is bound more than once", param.name))
+                        .with_code(codes::DUPLICATE_BINDING)
+                        .with_primary(param.span, "")
+                        .with_secondary(previous.span, "first bound here"),
+                );
+                continue;
+            }
+            self.scopes[0].insert(param.name.clone(), Local { span: param.span, type_annotation: Some(&param.type_annotation) });
+        }
+        for statement in statements {
+            self.resolve_statement(statement);
+        }
+        self.scopes.pop();
+    }
+
+    fn resolve_statement(&mut self, statement: &'p Statement) {
+        match &statement.kind {
+            StatementKind::Let { name, name_span, type_annotation, value, .. } => {
+                self.resolve_expression(value);
+                self.declare(name, *name_span, Some(type_annotation));
+            }
+            StatementKind::Set { name, new_value } => {
+                self.resolve_expression(new_value);
+                if self.local(name).is_none() {
+                    match self.modules.lookup_name(self.module, name) {
+                        Some(_) => self.diagnostics.push(
+                            Diagnostic::error(format!("cannot assign to `{}`", name))
+                                .with_code(codes::EXPECTED_VALUE)
+                                .with_primary(statement.span, "only variables can be assigned to"),
+                        ),
+                        None => self.undefined(name, statement.span),
+                    }
+                }
+            }
+            StatementKind::Ret { value } => self.resolve_expression(value),
+            StatementKind::FunctionCall { call } => self.resolve_expression(call),
+            StatementKind::If { condition, then_block, else_branch } => {
+                self.resolve_expression(condition);
+                self.resolve_block(then_block);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_statement(else_branch);
+                }
+            }
+            StatementKind::Block(block) => self.resolve_block(block),
+            StatementKind::While { condition, body } => {
+                self.resolve_expression(condition);
+                self.resolve_block(body);
+            }
+            StatementKind::For { variable, iterable, body } => {
+                let ForIterable::Range { start, end } = iterable;
+                self.resolve_expression(start);
+                self.resolve_expression(end);
+                self.scopes.push(HashMap::new());
+                self.declare(variable, statement.span, None);
+                self.resolve_block(body);
+                self.scopes.pop();
+            }
+            StatementKind::Break | StatementKind::Continue => {}
+            StatementKind::Match { scrutinee, arms } => {
+                self.resolve_expression(scrutinee);
+                for arm in arms {
+                    self.scopes.push(HashMap::new());
+                    self.bind_pattern(&arm.pattern);
+                    self.resolve_statement(&arm.body);
+                    self.scopes.pop();
+                }
+            }
+        }
+    }
+
+    fn resolve_block(&mut self, block: &'p Block) {
+        self.scopes.push(HashMap::new());
+        for statement in &block.statements {
+            self.resolve_statement(statement);
+        }
+        self.scopes.pop();
+    }
+
+    fn resolve_expression(&mut self, expression: &'p Expression) {
+        match &expression.kind {
+            ExpressionKind::IntLiteral(_)
+            | ExpressionKind::FloatLiteral(_)
+            | ExpressionKind::StringLiteral(_)
+            | ExpressionKind::CharLiteral(_)
+            | ExpressionKind::BoolLiteral(_) => {}
+            ExpressionKind::Variable(name) => self.resolve_path(std::slice::from_ref(name), expression.span),
+            ExpressionKind::FieldAccess { object, .. } => match expression.as_path() {
+                Some(path) => self.resolve_path(&path, expression.span),
+                None => self.resolve_expression(object),
+            },
+            ExpressionKind::Binary { left, right, .. } => {
+                self.resolve_expression(left);
+                self.resolve_expression(right);
+            }
+            ExpressionKind::Unary { operand, .. } => self.resolve_expression(operand),
+            ExpressionKind::FunctionCall { callee, args } => {
+                self.resolve_expression(callee);
+                for arg in args {
+                    self.resolve_expression(arg);
+                }
+            }
+            ExpressionKind::If { condition, then_value, else_value } => {
+                self.resolve_expression(condition);
+                self.resolve_expression(then_value);
+                self.resolve_expression(else_value);
+            }
+            ExpressionKind::Match { scrutinee, arms } => {
+                self.resolve_expression(scrutinee);
+                for arm in arms {
+                    self.scopes.push(HashMap::new());
+                    self.bind_pattern(&arm.pattern);
+                    self.resolve_expression(&arm.body);
+                    self.scopes.pop();
+                }
+            }
+            ExpressionKind::StructLiteral { path, fields } => {
+                self.resolve_constructor(path, expression.span);
+                for field in fields {
+                    self.resolve_expression(&field.value);
+                }
+            }
+        }
+    }
+
+    /// A value path: a local (possibly followed by field accesses), or an
+    /// item such as a function or enum variant.
+    fn resolve_path(&mut self, path: &[String], span: Span) {
+        if self.local(&path[0]).is_some() {
+            return;
+        }
+        let Some((resolved, used)) = self.modules.lookup_prefix(self.module, path) else {
+            self.undefined(&path[0], span);
+            return;
+        };
+        self.check_visible(resolved, &path[..used], span);
+        let rest = &path[used..];
+        let shown = path[..used].join(".");
+        let diagnostic = match resolved {
+            Resolved::Module(_) if rest.is_empty() => Diagnostic::error(format!("expected a value, found module `{}`", shown))
+                .with_code(codes::EXPECTED_VALUE)
+                .with_primary(span, "not a value"),
+            Resolved::Module(_) => Diagnostic::error(format!("cannot find `{}` in module `{}`", rest[0], shown))
+                .with_code(codes::UNDEFINED_NAME)
+                .with_primary(span, ""),
+            Resolved::Item(module, index) => match self.modules.object(module, index) {
+                ProgramObject::Function { .. } if rest.is_empty() => return,
+                ProgramObject::Function { .. } => Diagnostic::error(format!("`{}` is a function, it has no field `{}`", shown, rest[0]))
+                    .with_code(codes::EXPECTED_VALUE)
+                    .with_primary(span, ""),
+                ProgramObject::Enum { variants, .. } => match rest {
+                    [variant] if variants.iter().any(|v| v.name == *variant) => return,
+                    [] => Diagnostic::error(format!("expected a value, found enum `{}`", shown))
+                        .with_code(codes::EXPECTED_VALUE)
+                        .with_primary(span, "not a value")
+                        .with_note(match variants.first() {
+                            Some(variant) => format!("help: use one of its variants, such as `{}.{}`", shown, variant.name),
+                            None => format!("`{}` has no variants", shown),
+                        }),
+                    [variant, ..] if variants.iter().any(|v| v.name == *variant) => {
+                        Diagnostic::error(format!("enum variant `{}.{}` has no fields to access", shown, variant))
+                            .with_code(codes::EXPECTED_VALUE)
+                            .with_primary(span, "")
+                            .with_note("help: destructure it with `@` to get at its payload")
+                    }
+                    [variant, ..] => Diagnostic::error(format!("no variant `{}` in enum `{}`", variant, shown))
+                        .with_code(codes::UNDEFINED_NAME)
+                        .with_primary(span, "")
+                        .with_secondary(self.modules.definition_span(resolved), "enum declared here"),
+                },
+                ProgramObject::Struct { name, .. } => {
+                    let mut diagnostic = Diagnostic::error(format!("expected a value, found struct `{}`", shown))
+                        .with_code(codes::EXPECTED_VALUE)
+                        .with_primary(span, "this is a type, not a variable");
+                    if let Some(local) = self.local_of_type(name) {
+                        let suggestion = std::iter::once(local).chain(rest.iter().cloned()).collect::<Vec<_>>().join(".");
+                        diagnostic = diagnostic.with_note(format!("help: `{}` has type `{}`; did you mean `{}`?", local_name(&suggestion), name, suggestion));
+                    }
+                    diagnostic
+                }
+                ProgramObject::Module { .. } | ProgramObject::Use(_) => return,
+            },
+        };
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// The path of a struct literal or constructor pattern, which must name
+    /// a struct or an enum variant.
+    fn resolve_constructor(&mut self, path: &[String], span: Span) {
+        let Some((resolved, used)) = self.modules.lookup_prefix(self.module, path) else {
+            self.undefined(&path[0], span);
+            return;
+        };
+        self.check_visible(resolved, &path[..used], span);
+        let rest = &path[used..];
+        let shown = path[..used].join(".");
+        let ok = match resolved {
+            Resolved::Item(module, index) => match self.modules.object(module, index) {
+                ProgramObject::Struct { .. } => rest.is_empty(),
+                ProgramObject::Enum { variants, .. } => match rest {
+                    [variant] => {
+                        if !variants.iter().any(|v| v.name == *variant) {
+                            self.diagnostics.push(
+                                Diagnostic::error(format!("no variant `{}` in enum `{}`", variant, shown))
+                                    .with_code(codes::UNDEFINED_NAME)
+                                    .with_primary(span, "")
+                                    .with_secondary(self.modules.definition_span(resolved), "enum declared here"),
+                            );
+                        }
+                        true
+                    }
+                    _ => false,
+                },
+                _ => false,
+            },
+            Resolved::Module(_) => false,
+        };
+        if !ok {
+            self.diagnostics.push(
+                Diagnostic::error(format!("expected a struct or enum variant, found `{}`", path.join(".")))
+                    .with_code(codes::EXPECTED_VALUE)
+                    .with_primary(span, ""),
+            );
+        }
+    }
+
+    /// Declares the variables a match pattern binds in the current scope.
+    fn bind_pattern(&mut self, pattern: &'p Pattern) {
+        let mut bindings: Vec<(String, Span)> = Vec::new();
+        self.pattern_bindings(pattern, &mut bindings);
+        for (i, (name, span)) in bindings.iter().enumerate() {
+            if let Some((_, first)) = bindings[..i].iter().find(|(other, _)| other == name) {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("`{}` is bound more than once in the same pattern", name))
+                        .with_code(codes::DUPLICATE_BINDING)
+                        .with_primary(*span, "")
+                        .with_secondary(*first, "first bound here"),
+                );
+                continue;
+            }
+            self.declare(name, *span, None);
+        }
+    }
+
+    fn pattern_bindings(&mut self, pattern: &'p Pattern, out: &mut Vec<(String, Span)>) {
+        match &pattern.kind {
+            PatternKind::Wildcard | PatternKind::Literal(_) | PatternKind::Range { .. } => {}
+            PatternKind::Binding(name) => out.push((name.clone(), pattern.span)),
+            PatternKind::Constructor { path, fields } => {
+                self.resolve_constructor(path, pattern.span);
+                match fields {
+                    PatternFields::Unit => {}
+                    PatternFields::Tuple(patterns) => {
+                        for pattern in patterns {
+                            self.pattern_bindings(pattern, out);
+                        }
+                    }
+                    PatternFields::Struct(fields) => {
+                        for (_, pattern) in fields {
+                            self.pattern_bindings(pattern, out);
+                        }
+                    }
+                }
+            }
+            PatternKind::Or(alternatives) => {
+                let mut first: Option<Vec<(String, Span)>> = None;
+                for alternative in alternatives {
+                    let mut names = Vec::new();
+                    self.pattern_bindings(alternative, &mut names);
+                    match &first {
+                        None => first = Some(names),
+                        Some(expected) => {
+                            for (name, span) in expected.iter().filter(|(n, _)| !names.iter().any(|(m, _)| m == n)) {
+                                self.diagnostics.push(
+                                    Diagnostic::error(format!("`{}` is not bound in every alternative of the pattern", name))
+                                        .with_code(codes::INCONSISTENT_BINDINGS)
+                                        .with_primary(alternative.span, format!("this alternative doesn't bind `{}`", name))
+                                        .with_secondary(*span, "bound here"),
+                                );
+                            }
+                            for (name, span) in names.iter().filter(|(n, _)| !expected.iter().any(|(m, _)| m == n)) {
+                                self.diagnostics.push(
+                                    Diagnostic::error(format!("`{}` is not bound in every alternative of the pattern", name))
+                                        .with_code(codes::INCONSISTENT_BINDINGS)
+                                        .with_primary(*span, "only bound in this alternative"),
+                                );
+                            }
+                        }
+                    }
+                }
+                out.extend(first.unwrap_or_default());
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, span: Span, type_annotation: Option<&'p Type>) {
+        if let Some(previous) = self.local(name) {
+            self.diagnostics.push(
+                Diagnostic::warning(format!("`{}` shadows an earlier binding", name))
+                    .with_code(codes::SHADOWED_BINDING)
+                    .with_primary(span, "")
+                    .with_secondary(previous.span, "previously bound here")
+                    .with_note("help: pick a different name if the earlier value is still needed"),
+            );
+        }
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), Local { span, type_annotation });
+        }
+    }
+
+    fn local(&self, name: &str) -> Option<&Local<'p>> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    /// The name of a local in scope whose declared type is `type_name`.
+    fn local_of_type(&self, type_name: &str) -> Option<String> {
+        self.scopes.iter().rev().find_map(|scope| {
+            scope.iter().find_map(|(name, local)| match local.type_annotation {
+                Some(Type { kind: TypeKind::Custom(path), .. }) if path.last().is_some_and(|t| t == type_name) => Some(name.clone()),
+                _ => None,
+            })
+        })
+    }
+
+    fn check_visible(&mut self, resolved: Resolved, path: &[String], span: Span) {
+        if !self.modules.is_visible(self.module, resolved) {
+            self.diagnostics.push(
+                Diagnostic::error(format!("`{}` is private", path.join(".")))
+                    .with_code(codes::PRIVATE_ITEM)
+                    .with_primary(span, "used here")
+                    .with_secondary(self.modules.definition_span(resolved), "declared here without `pub`")
+                    .with_note("help: mark the item `pub` to use it from other modules"),
+            );
+        }
+    }
+
+    fn undefined(&mut self, name: &str, span: Span) {
+        let mut diagnostic = Diagnostic::error(format!("cannot find `{}` in this scope", name))
+            .with_code(codes::UNDEFINED_NAME)
+            .with_primary(span, "not found");
+        if let Some(declared) = self.declared.get(name) {
+            diagnostic = if declared.start > span.start {
+                diagnostic
+                    .with_secondary(*declared, format!("`{}` is declared here, after it is used", name))
+                    .with_note("help: move the declaration before the first use")
+            } else {
+                diagnostic.with_secondary(*declared, format!("a `{}` is declared here, but its scope has ended", name))
+            };
+        }
+        self.diagnostics.push(diagnostic);
+    }
+}
+
+/// The variable at the start of a dotted suggestion such as `a.sad`.
+fn local_name(path: &str) -> &str {
+    path.split('.').next().unwrap_or(path)
+}
+
+/// Records the first declaration of every `let` and `for` variable in a
+/// statement and the statements nested in it.
+fn collect_declarations(statement: &Statement, out: &mut HashMap<String, Span>) {
+    let block = |block: &Block, out: &mut HashMap<String, Span>| {
+        for statement in &block.statements {
+            collect_declarations(statement, out);
+        }
+    };
+    match &statement.kind {
+        StatementKind::Let { name, name_span, .. } => {
+            out.entry(name.clone()).or_insert(*name_span);
+        }
+        StatementKind::For { variable, body, .. } => {
+            out.entry(variable.clone()).or_insert(statement.span);
+            block(body, out);
+        }
+        StatementKind::If { then_block, else_branch, .. } => {
+            block(then_block, out);
+            if let Some(else_branch) = else_branch {
+                collect_declarations(else_branch, out);
+            }
+        }
+        StatementKind::Block(body) | StatementKind::While { body, .. } => block(body, out),
+        StatementKind::Match { arms, .. } => {
+            for arm in arms {
+                collect_declarations(&arm.body, out);
+            }
+        }
+        StatementKind::Set { .. }
+        | StatementKind::Ret { .. }
+        | StatementKind::FunctionCall { .. }
+        | StatementKind::Break
+        | StatementKind::Continue => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing;
+
+    #[test]
+    fn names_in_scope_resolve() {
+        let source = "
+            enum color { red, green }
+            fn add(a | i32, b | i32) -> i32 { ret a + b; }
+            fn main() -> i32 {
+                let x | i32 <- add(1, 2);
+                let c | color <- color.red;
+                if x > 2 { let y | i32 <- x; ret y; }
+                ret x;
+            }";
+        assert_eq!(testing::pass_codes(resolve, source), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn undefined_names_are_reported() {
+        let source = "
+            fn main() -> i32 {
+                let x | i32 <- missing(1);
+                { let inner | i32 <- 1; }
+                ret x + inner + nothing;
+            }";
+        assert_eq!(testing::pass_codes(resolve, source), [codes::UNDEFINED_NAME; 3]);
+    }
+
+    #[test]
+    fn a_variable_used_before_its_let_is_undefined() {
+        let source = "fn main() -> i32 { let a | i32 <- b; let b | i32 <- 1; ret a; }";
+        let diagnostics = testing::pass_diagnostics(resolve, source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::UNDEFINED_NAME));
+        assert_eq!(diagnostics[0].labels[1].message, "`b` is declared here, after it is used");
+    }
+
+    #[test]
+    fn shadowing_is_a_warning() {
+        let diagnostics = testing::pass_diagnostics(resolve, "fn f(a | i32) -> i32 { let a | i32 <- 2; ret a; }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::SHADOWED_BINDING));
+        assert!(!diagnostics[0].is_error());
+    }
+
+    #[test]
+    fn types_and_modules_are_not_values() {
+        let source = "
+            struct point { x | i32 }
+            enum color { red }
+            mod math { pub fn one() -> i32 { ret 1; } }
+            fn main() -> i32 {
+                let p | point <- point { x <- 1 };
+                let a | i32 <- point;
+                let b | color <- color;
+                let c | i32 <- math;
+                ret math.two();
+            }";
+        let expected = [codes::EXPECTED_VALUE, codes::EXPECTED_VALUE, codes::EXPECTED_VALUE, codes::UNDEFINED_NAME];
+        assert_eq!(testing::pass_codes(resolve, source), expected);
+    }
+
+    #[test]
+    fn private_items_can_only_be_used_from_within_their_module() {
+        let source = "
+            mod m {
+                fn hidden() -> i32 { ret 1; }
+                pub fn shown() -> i32 { ret hidden(); }
+                mod inner { fn f() -> i32 { ret m.hidden(); } }
+            }
+            fn main() -> i32 { ret m.shown() + m.hidden(); }";
+        let diagnostics = testing::pass_diagnostics(resolve, source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::PRIVATE_ITEM));
+        assert_eq!(diagnostics[0].message, "`m.hidden` is private");
+        assert_eq!(diagnostics[0].labels[0].message, "used here");
+    }
+}
@@ -0,0 +1,145 @@
+/// Index of a file in the `SourceMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub(crate) struct FileId(pub usize);
+
+/// A half-open byte range `start..end` into a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub(crate) struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file: FileId, start: usize, end: usize) -> Span {
+        Span { file, start, end }
+    }
+
+    /// Returns the smallest span covering both `self` and `other`, which
+    /// must be in the same file.
+    pub fn to(self, other: Span) -> Span {
+        debug_assert_eq!(self.file, other.file, "spans from different files can't be joined");
+        Span {
+            file: self.file,
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+/// Every source file loaded during a compilation, so a `Span` can be traced
+/// back to its file.
+#[derive(Debug, Default)]
+pub(crate) struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { files: Vec::new() }
+    }
+
+    pub fn add(&mut self, name: String, text: String) -> FileId {
+        self.files.push(SourceFile::new(name, text));
+        FileId(self.files.len() - 1)
+    }
+
+    pub fn get(&self, file: FileId) -> &SourceFile {
+        &self.files[file.0]
+    }
+}
+
+/// The text of one source file along with a table of line start offsets,
+/// used to turn a byte offset from a `Span` back into a line and column.
+#[derive(Debug)]
+pub(crate) struct SourceFile {
+    pub name: String,
+    pub text: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: String, text: String) -> SourceFile {
+        let mut line_starts = vec![0];
+        for (i, byte) in text.bytes().enumerate() {
+            if byte == b'\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        SourceFile { name, text, line_starts }
+    }
+
+    /// 1-based line and column of a byte offset. Columns count characters,
+    /// not bytes, so they line up with what an editor shows.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.text.len());
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let line_start = self.line_starts[line];
+        let column = self.text[line_start..offset].chars().count();
+        (line + 1, column + 1)
+    }
+
+    /// The text of a 1-based line, without its trailing newline.
+    pub fn line_text(&self, line: usize) -> &str {
+        let start = self.line_starts[line - 1];
+        let end = match self.line_starts.get(line) {
+            Some(next) => next - 1,
+            None => self.text.len(),
+        };
+        self.text[start..end].trim_end_matches('\r')
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// `file:line:col` for the start of a span.
+    pub fn location(&self, span: Span) -> String {
+        let (line, column) = self.line_col(span.start);
+        format!("{}:{}:{}", self.name, line, column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(text: &str) -> SourceFile {
+        SourceFile::new("main.c4l".to_string(), text.to_string())
+    }
+
+    #[test]
+    fn columns_count_characters_not_bytes() {
+        let file = source("let é | char <- 'ü';\n  ret π;");
+        assert_eq!(file.line_col(0), (1, 1));
+        assert_eq!(file.line_col("let é".len()), (1, 6));
+        assert_eq!(file.line_col("let é | char <- 'ü'".len()), (1, 20));
+        assert_eq!(file.line_col("let é | char <- 'ü';\n  ret ".len()), (2, 7));
+        assert_eq!(file.line_text(2), "  ret π;");
+    }
+
+    #[test]
+    fn crlf_line_endings_are_not_part_of_the_line() {
+        let file = source("fn a() {}\r\nfn b() {}\r\n");
+        assert_eq!(file.line_count(), 3);
+        assert_eq!(file.line_text(1), "fn a() {}");
+        assert_eq!(file.line_text(2), "fn b() {}");
+        assert_eq!(file.line_col("fn a() {}\r\nfn ".len()), (2, 4));
+    }
+
+    #[test]
+    fn offsets_at_and_past_the_end_of_the_file() {
+        let file = source("ret 1;\nret 2;");
+        assert_eq!(file.line_col(13), (2, 7));
+        assert_eq!(file.line_col(100), (2, 7));
+        assert_eq!(file.line_text(2), "ret 2;");
+
+        let file = source("ret 1;\n");
+        assert_eq!(file.line_col(7), (2, 1));
+        assert_eq!(file.line_text(2), "");
+        assert_eq!(file.location(Span::new(FileId(0), 4, 5)), "main.c4l:1:5");
+    }
+}
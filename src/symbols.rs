@@ -0,0 +1,129 @@
+use crate::diagnostic::{codes, Diagnostic};
+use crate::module::{ModuleTree, Resolved};
+use crate::parser::{ProgramObject, StructField, VariantPayload};
+use crate::span::Span;
+use std::collections::HashMap;
+
+/// The names declared in one namespace, such as the items of a module or
+/// the fields of a struct, with where each was first declared.
+struct SymbolTable<'p> {
+    symbols: HashMap<&'p str, (Span, &'static str)>,
+}
+
+impl<'p> SymbolTable<'p> {
+    fn new() -> SymbolTable<'p> {
+        SymbolTable { symbols: HashMap::new() }
+    }
+
+    /// Adds `name`, reporting it if the namespace already has something by
+    /// that name. `kind` says what it is, for the message.
+    fn declare(&mut self, name: &'p str, span: Span, kind: &'static str, diagnostics: &mut Vec<Diagnostic>) {
+        let Some((first, first_kind)) = self.symbols.get(name).copied() else {
+            self.symbols.insert(name, (span, kind));
+            return;
+        };
+        let mut diagnostic = Diagnostic::error(format!("the name `{}` is defined more than once", name))
+            .with_code(codes::DUPLICATE_DEFINITION)
+            .with_primary(span, format!("{} `{}` redefined here", kind, name))
+            .with_secondary(first, format!("previous definition of {} `{}` here", first_kind, name));
+        diagnostic = match (first_kind, kind) {
+            ("function", "function") => diagnostic.with_note(
+                "functions can't be overloaded; give each one its own name, or use `num` for one that works on every number type",
+            ),
+            _ if first_kind == kind => diagnostic.with_note(format!("help: rename one of the {}s", kind)),
+            _ => diagnostic.with_note("help: rename one of them"),
+        };
+        diagnostics.push(diagnostic);
+    }
+}
+
+/// Reports names declared twice in the same namespace: items and
+/// submodules in a module, fields in a struct, and variants and their
+/// fields in an enum. Every name is only looked up as its first
+/// definition, so later ones would otherwise be silently ignored.
+pub(crate) fn check_duplicates(modules: &ModuleTree) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for (id, module) in modules.modules.iter().enumerate() {
+        // Items and submodules share a namespace. Declare them in source
+        // order so the later one is reported as the duplicate.
+        let mut names: Vec<(&str, Span, &'static str)> = Vec::new();
+        for child in &module.children {
+            let child = modules.get(*child);
+            names.push((&child.name, child.span, "module"));
+        }
+        for (index, obj) in module.objs.iter().enumerate() {
+            let kind = match obj {
+                ProgramObject::Function { .. } => "function",
+                ProgramObject::Struct { .. } => "struct",
+                ProgramObject::Enum { .. } => "enum",
+                ProgramObject::Module { .. } | ProgramObject::Use(_) => continue,
+            };
+            names.push((obj.name(), modules.definition_span(Resolved::Item(id, index)), kind));
+        }
+        names.sort_by_key(|(_, span, _)| (span.file.0, span.start));
+        let mut items = SymbolTable::new();
+        for (name, span, kind) in names {
+            items.declare(name, span, kind, &mut diagnostics);
+        }
+
+        for obj in &module.objs {
+            match obj {
+                ProgramObject::Struct { fields, .. } => check_fields(fields, &mut diagnostics),
+                ProgramObject::Enum { variants, .. } => {
+                    let mut table = SymbolTable::new();
+                    for variant in variants {
+                        table.declare(&variant.name, variant.span, "variant", &mut diagnostics);
+                        if let VariantPayload::Struct(fields) = &variant.payload {
+                            check_fields(fields, &mut diagnostics);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    diagnostics
+}
+
+fn check_fields(fields: &[StructField], diagnostics: &mut Vec<Diagnostic>) {
+    let mut table = SymbolTable::new();
+    for field in fields {
+        table.declare(&field.name, field.span, "field", diagnostics);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing;
+
+    #[test]
+    fn a_second_function_of_the_same_name_is_reported_with_both_locations() {
+        let source = "fn add(a | i32) -> i32 { ret a; }\nfn add(a | i64) -> i64 { ret a; }";
+        let diagnostics = testing::pass_diagnostics(check_duplicates, source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::DUPLICATE_DEFINITION));
+        let labels: Vec<(bool, &str)> = diagnostics[0].labels.iter().map(|l| (l.primary, l.message.as_str())).collect();
+        assert_eq!(labels, [(true, "function `add` redefined here"), (false, "previous definition of function `add` here")]);
+    }
+
+    #[test]
+    fn items_fields_and_variants_are_each_checked() {
+        let source = "
+            struct point { x | i32, x | i32 }
+            enum shape { dot, dot, rect { w | f64, w | f64 } }
+            fn point() {}
+            mod shape {}";
+        assert_eq!(testing::pass_codes(check_duplicates, source), [codes::DUPLICATE_DEFINITION; 5]);
+    }
+
+    #[test]
+    fn the_same_name_in_different_namespaces_is_fine() {
+        let source = "
+            struct a { x | i32 }
+            struct b { x | i32 }
+            enum c { x, y { x | i32 } }
+            mod m { fn a() {} }";
+        assert!(testing::pass_codes(check_duplicates, source).is_empty());
+    }
+}
@@ -1,7 +1,10 @@
 use logos::Logos;
 use colored::Colorize;
+use crate::diagnostic::{codes, Diagnostic};
+use crate::span::{FileId, Span};
 #[derive(Logos, Debug, PartialEq, Clone, Copy)]
 #[logos(skip r"[ \t\n\f]+")] // Ignore this regex pattern between tokens
+#[allow(clippy::upper_case_acronyms)]
 pub enum Token<'a> {
     EOF,
     // Reserved keywords
@@ -29,6 +32,15 @@ pub enum Token<'a> {
     #[token("for")]
     For,
 
+    #[token("in")]
+    In,
+
+    #[token("break")]
+    Break,
+
+    #[token("continue")]
+    Continue,
+
     #[token("fn")]
     Function,
 
@@ -41,6 +53,15 @@ pub enum Token<'a> {
     #[token("enum")]
     Enum,
 
+    #[token("pub")]
+    Pub,
+
+    #[token("use")]
+    Use,
+
+    #[token("as")]
+    As,
+
     //Operators
     #[token("<-")]
     Assign,
@@ -88,6 +109,9 @@ pub enum Token<'a> {
     #[token(".")]
     FieldAccessor,
 
+    #[token("..")]
+    Range,
+
     #[regex("[a-zA-Z0-9_]*")]
     Identifier(&'a str),
 
@@ -103,33 +127,99 @@ pub enum Token<'a> {
     #[regex("true|false")]
     BoolLiteral(&'a str),
 
-    #[regex("\\+|-|\\*|\\/|%")]
+    // `<-`, `->`, `|` and `|>` are their own tokens; the lexer always takes the
+    // longest match, so `<=`/`<<`/`||` and those tokens never get confused.
+    // A lone `|` is always `TypeDecl`, so there is no bitwise or.
+    #[regex("\\+|-|\\*|\\/|%|==|!=|<=|>=|<<|>>|&&|\\|\\||<|>|!|&|\\^")]
     Operator(&'a str),
 }
 
 
-pub fn parse(input: &str) -> Result<Vec<Token>, String> {
+impl Token<'_> {
+    /// How the token is named in diagnostics, e.g. "`(`" or "identifier `x`".
+    pub fn describe(&self) -> String {
+        let text = match self {
+            Token::EOF => return "end of file".to_string(),
+            Token::Identifier(s) => return format!("identifier `{}`", s),
+            Token::Number(s) => return format!("number `{}`", s),
+            Token::StringLiteral(s) => return format!("string {}", s),
+            Token::CharLiteral(s) => return format!("character {}", s),
+            Token::BoolLiteral(s) => s,
+            Token::Operator(s) => s,
+            Token::Let => "let",
+            Token::Mut => "mut",
+            Token::Return => "ret",
+            Token::FnArrow => "=>",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::While => "while",
+            Token::For => "for",
+            Token::In => "in",
+            Token::Break => "break",
+            Token::Continue => "continue",
+            Token::Function => "fn",
+            Token::Module => "mod",
+            Token::Struct => "struct",
+            Token::Enum => "enum",
+            Token::Pub => "pub",
+            Token::Use => "use",
+            Token::As => "as",
+            Token::Assign => "<-",
+            Token::FnPipe => "|>",
+            Token::TypeDecl => "|",
+            Token::RetType => "->",
+            Token::PatternMatch => "@",
+            Token::Wildcard => "~",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::ArgumentSeparator => ",",
+            Token::StatementEnd => ";",
+            Token::FieldAccessor => ".",
+            Token::Range => "..",
+        };
+        format!("`{}`", text)
+    }
+}
+
+/// A token together with the byte range of source text it was lexed from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SpannedToken<'a> {
+    pub token: Token<'a>,
+    pub span: Span,
+}
+
+/// Lexes the whole input. Text that isn't part of any token is reported
+/// and skipped, so one stray character doesn't hide the rest of the file.
+pub fn parse(input: &str, file: FileId) -> (Vec<SpannedToken<'_>>, Vec<Diagnostic>) {
     let mut lex = Token::lexer(input);
-    let mut tokens: Vec<Token> = Vec::new();
+    let mut tokens: Vec<SpannedToken> = Vec::new();
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
 
-    let mut next = lex.next();
-    while next != None {
-        match next.unwrap() {
+    while let Some(next) = lex.next() {
+        let span = Span::new(file, lex.span().start, lex.span().end);
+        match next {
             Ok(token) => {
-                tokens.push(token);
+                tokens.push(SpannedToken { token, span });
+            }
+            Err(_) => {
+                diagnostics.push(Diagnostic::error(format!("unrecognised text `{}`", lex.slice()))
+                    .with_code(codes::UNRECOGNISED_TEXT)
+                    .with_primary(span, "this is not part of any c4l token"))
             }
-            Err(_) => return Err("failed to parse file :(".to_string()),
         }
-        next = lex.next()
     }
-    tokens.push(Token::EOF);
-    return Ok(tokens);
+    tokens.push(SpannedToken { token: Token::EOF, span: Span::new(file, input.len(), input.len()) });
+    (tokens, diagnostics)
 }
 
-pub fn print_tokens(tokens: &Vec<Token>) {
+pub fn print_tokens(tokens: &[SpannedToken]) {
     println!();
     let mut depth: u32 = 0;
-    for token in tokens {
+    for SpannedToken { token, .. } in tokens {
         match token {
             Token::LBrace => {
                 depth += 1;
@@ -156,7 +246,7 @@ pub fn print_tokens(tokens: &Vec<Token>) {
                 }
             }
             Token::StatementEnd => {
-                print!(";\n");
+                println!(";");
                 let mut i = 0;
                 while i < depth {
                     print!("\t");
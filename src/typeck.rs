@@ -0,0 +1,1398 @@
+use crate::diagnostic::{codes, Diagnostic};
+use crate::module::{ModuleId, ModuleTree, Resolved, ROOT};
+use crate::parser::*;
+use crate::span::Span;
+use std::collections::HashMap;
+
+/// A struct, enum or function, by its module and index into that module's
+/// `objs`.
+pub(crate) type ItemId = (ModuleId, usize);
+
+/// The type of a value once the names written in the source have been
+/// resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Ty {
+    I32,
+    I64,
+    U32,
+    U64,
+    F32,
+    F64,
+    Bool,
+    Char,
+    String,
+    /// The type parameter of a generic function: whichever numeric type
+    /// the `num`s in its signature stand for at a particular call.
+    Num,
+    Struct(ItemId),
+    Enum(ItemId),
+    /// What calling a function without a return type produces.
+    Unit,
+    /// The type of something that already failed to check. It is
+    /// compatible with everything, so one mistake is only reported once.
+    Error,
+}
+
+impl Ty {
+    pub fn is_integer(self) -> bool {
+        matches!(self, Ty::I32 | Ty::I64 | Ty::U32 | Ty::U64)
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(self, Ty::I32 | Ty::I64 | Ty::F32 | Ty::F64)
+    }
+
+    pub fn is_float(self) -> bool {
+        matches!(self, Ty::F32 | Ty::F64)
+    }
+
+    pub fn is_numeric(self) -> bool {
+        self.is_integer() || self.is_float() || self == Ty::Num
+    }
+
+    /// The types `num` can stand for.
+    pub const NUMERIC: [Ty; 6] = [Ty::I32, Ty::I64, Ty::U32, Ty::U64, Ty::F32, Ty::F64];
+
+    /// Whether a value of type `found` can be used where `self` is expected.
+    fn accepts(self, found: Ty) -> bool {
+        self == found || self == Ty::Error || found == Ty::Error
+    }
+
+    /// Whether the integer literal `value` can be stored in this type.
+    fn fits(self, value: i128) -> bool {
+        match self {
+            Ty::I32 => i32::try_from(value).is_ok(),
+            Ty::I64 => i64::try_from(value).is_ok(),
+            Ty::U32 => u32::try_from(value).is_ok(),
+            Ty::U64 => u64::try_from(value).is_ok(),
+            Ty::Num => Ty::NUMERIC.iter().all(|ty| ty.fits(value)),
+            _ => true,
+        }
+    }
+
+    /// The name of the type as it is written in source.
+    pub fn name(self, modules: &ModuleTree) -> String {
+        match self {
+            Ty::I32 => "i32".to_string(),
+            Ty::I64 => "i64".to_string(),
+            Ty::U32 => "u32".to_string(),
+            Ty::U64 => "u64".to_string(),
+            Ty::F32 => "f32".to_string(),
+            Ty::F64 => "f64".to_string(),
+            Ty::Bool => "bool".to_string(),
+            Ty::Char => "char".to_string(),
+            Ty::String => "string".to_string(),
+            Ty::Num => "num".to_string(),
+            Ty::Struct((module, index)) | Ty::Enum((module, index)) => {
+                modules.qualified_name(module, modules.object(module, index).name())
+            }
+            Ty::Unit => "()".to_string(),
+            Ty::Error => "{error}".to_string(),
+        }
+    }
+}
+
+/// The parameter and return types of a function.
+#[derive(Debug, Clone)]
+pub(crate) struct Signature {
+    pub params: Vec<Ty>,
+    pub ret: Ty,
+}
+
+impl Signature {
+    /// Whether `num` appears in the signature, making the function generic.
+    pub fn is_generic(&self) -> bool {
+        self.ret == Ty::Num || self.params.contains(&Ty::Num)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Field {
+    pub name: String,
+    pub ty: Ty,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Variant {
+    pub name: String,
+    pub payload: Payload,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Payload {
+    Unit,
+    Tuple(Vec<Ty>),
+    Struct(Vec<Field>),
+}
+
+/// The declared types of every item, and the numeric types each generic
+/// function is used with.
+#[derive(Debug, Default)]
+pub(crate) struct Types {
+    pub functions: HashMap<ItemId, Signature>,
+    pub structs: HashMap<ItemId, Vec<Field>>,
+    pub enums: HashMap<ItemId, Vec<Variant>>,
+    /// The types `num` stands for in each generic function that is called,
+    /// one copy of the function per type.
+    pub instances: HashMap<ItemId, Vec<Ty>>,
+    /// The type of every expression, by its span. Inside a generic function
+    /// this may be `Ty::Num`, standing for whichever type the copy is for.
+    pub expressions: HashMap<Span, Ty>,
+    /// The type `num` stands for in each call of a generic function, by the
+    /// span of the callee.
+    pub generic_calls: HashMap<Span, Ty>,
+    /// The type of every variable bound by a match pattern, by the span of
+    /// the binding.
+    pub bindings: HashMap<Span, Ty>,
+}
+
+impl Types {
+    /// The types of every field of a struct, or of every variant of an
+    /// enum.
+    pub fn field_types(&self, id: ItemId) -> Vec<Ty> {
+        if let Some(fields) = self.structs.get(&id) {
+            return fields.iter().map(|f| f.ty).collect();
+        }
+        let mut types = Vec::new();
+        for variant in self.enums.get(&id).into_iter().flatten() {
+            match &variant.payload {
+                Payload::Unit => {}
+                Payload::Tuple(tys) => types.extend(tys),
+                Payload::Struct(fields) => types.extend(fields.iter().map(|f| f.ty)),
+            }
+        }
+        types
+    }
+
+    /// Every struct and enum, each after the types stored in its fields,
+    /// which is the order backends that store fields inline have to lay
+    /// them out in. Fails with a type that contains itself, which has no
+    /// finite size.
+    pub fn layout_order(&self) -> Result<Vec<ItemId>, ItemId> {
+        let mut ids: Vec<ItemId> = self.structs.keys().chain(self.enums.keys()).copied().collect();
+        ids.sort();
+        let mut order = Vec::new();
+        let mut visiting = Vec::new();
+        for id in ids {
+            self.visit_layout(id, &mut visiting, &mut order)?;
+        }
+        Ok(order)
+    }
+
+    fn visit_layout(&self, id: ItemId, visiting: &mut Vec<ItemId>, order: &mut Vec<ItemId>) -> Result<(), ItemId> {
+        if order.contains(&id) {
+            return Ok(());
+        }
+        if visiting.contains(&id) {
+            return Err(id);
+        }
+        visiting.push(id);
+        for ty in self.field_types(id) {
+            if let Ty::Struct(inner) | Ty::Enum(inner) = ty {
+                self.visit_layout(inner, visiting, order)?;
+            }
+        }
+        visiting.pop();
+        order.push(id);
+        Ok(())
+    }
+}
+
+/// Checks the types of every function body against the declared types of
+/// parameters, `let` bindings, return values and struct fields.
+pub(crate) fn check(modules: &ModuleTree) -> (Types, Vec<Diagnostic>) {
+    let mut checker = Checker {
+        modules,
+        types: Types::default(),
+        function: (ROOT, 0),
+        ret: Ty::Unit,
+        num_allowed: true,
+        signature_span: Span::default(),
+        scopes: Vec::new(),
+        generic_calls: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    checker.collect_declarations();
+    for (module, m) in modules.modules.iter().enumerate() {
+        for (index, obj) in m.objs.iter().enumerate() {
+            if let ProgramObject::Function { statements, signature_span, .. } = obj {
+                checker.check_function((module, index), statements, *signature_span);
+            }
+        }
+    }
+    checker.instantiate();
+    (checker.types, checker.diagnostics)
+}
+
+struct Checker<'p> {
+    modules: &'p ModuleTree,
+    types: Types,
+
+    /// The function being checked.
+    function: ItemId,
+    /// The return type of the function being checked.
+    ret: Ty,
+    /// Whether `num` may be written here: in signatures and in the bodies
+    /// of generic functions.
+    num_allowed: bool,
+    signature_span: Span,
+    /// Innermost scope last.
+    scopes: Vec<HashMap<String, Ty>>,
+    /// Every call of a generic function as (caller, callee, type of
+    /// `num`). The type is `Ty::Num` when a generic caller passes its own
+    /// `num` along.
+    generic_calls: Vec<(ItemId, ItemId, Ty)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'p> Checker<'p> {
+    /// Resolves the types written in every signature, struct field and
+    /// enum payload, so bodies can refer to items declared after them.
+    fn collect_declarations(&mut self) {
+        let modules = self.modules;
+        for (module, m) in modules.modules.iter().enumerate() {
+            for (index, obj) in m.objs.iter().enumerate() {
+                match obj {
+                    ProgramObject::Function { arguments, return_type, .. } => {
+                        self.num_allowed = true;
+                        let params = arguments
+                            .iter()
+                            .map(|param| self.resolve_type(&param.type_annotation, module))
+                            .collect();
+                        let ret = match return_type {
+                            Some(ty) => self.resolve_type(ty, module),
+                            None => Ty::Unit,
+                        };
+                        self.types.functions.insert((module, index), Signature { params, ret });
+                    }
+                    ProgramObject::Struct { fields, .. } => {
+                        self.num_allowed = false;
+                        let fields = self.resolve_fields(fields, module);
+                        self.types.structs.insert((module, index), fields);
+                    }
+                    ProgramObject::Enum { variants, .. } => {
+                        self.num_allowed = false;
+                        let variants = variants
+                            .iter()
+                            .map(|variant| Variant {
+                                name: variant.name.clone(),
+                                payload: match &variant.payload {
+                                    VariantPayload::Unit => Payload::Unit,
+                                    VariantPayload::Tuple(types) => Payload::Tuple(
+                                        types.iter().map(|ty| self.resolve_type(ty, module)).collect(),
+                                    ),
+                                    VariantPayload::Struct(fields) => Payload::Struct(self.resolve_fields(fields, module)),
+                                },
+                                span: variant.span,
+                            })
+                            .collect();
+                        self.types.enums.insert((module, index), variants);
+                    }
+                    ProgramObject::Module { .. } | ProgramObject::Use(_) => {}
+                }
+            }
+        }
+    }
+
+    fn resolve_fields(&mut self, fields: &[StructField], module: ModuleId) -> Vec<Field> {
+        fields
+            .iter()
+            .map(|field| Field {
+                name: field.name.clone(),
+                ty: self.resolve_type(&field.type_annotation, module),
+                span: field.span,
+            })
+            .collect()
+    }
+
+    /// The type a type annotation written in `module` refers to.
+    fn resolve_type(&mut self, ty: &Type, module: ModuleId) -> Ty {
+        let span = ty.span;
+        let path = match &ty.kind {
+            TypeKind::I32 => return Ty::I32,
+            TypeKind::I64 => return Ty::I64,
+            TypeKind::U32 => return Ty::U32,
+            TypeKind::U64 => return Ty::U64,
+            TypeKind::F32 => return Ty::F32,
+            TypeKind::F64 => return Ty::F64,
+            TypeKind::BOOL => return Ty::Bool,
+            TypeKind::CHAR => return Ty::Char,
+            TypeKind::STRING => return Ty::String,
+            TypeKind::NUM if self.num_allowed => return Ty::Num,
+            TypeKind::NUM => {
+                self.diagnostics.push(
+                    Diagnostic::error("`num` can only be used in function signatures and generic functions")
+                        .with_code(codes::UNKNOWN_TYPE)
+                        .with_primary(span, "")
+                        .with_note("a function is generic when `num` appears in its parameters or return type"),
+                );
+                return Ty::Error;
+            }
+            TypeKind::Custom(path) => path,
+        };
+        let name = path.join(".");
+        let Some(resolved) = self.modules.lookup(module, path) else {
+            self.diagnostics.push(
+                Diagnostic::error(format!("cannot find type `{}` in this scope", name))
+                    .with_code(codes::UNKNOWN_TYPE)
+                    .with_primary(span, "unknown type"),
+            );
+            return Ty::Error;
+        };
+        if !self.modules.is_visible(module, resolved) {
+            self.diagnostics.push(
+                Diagnostic::error(format!("type `{}` is private", name))
+                    .with_code(codes::PRIVATE_ITEM)
+                    .with_primary(span, "used here")
+                    .with_secondary(self.modules.definition_span(resolved), "declared here without `pub`"),
+            );
+        }
+        match resolved {
+            Resolved::Item(m, i) => match self.modules.object(m, i) {
+                ProgramObject::Struct { .. } => return Ty::Struct((m, i)),
+                ProgramObject::Enum { .. } => return Ty::Enum((m, i)),
+                _ => {}
+            },
+            Resolved::Module(_) => {}
+        }
+        self.diagnostics.push(
+            Diagnostic::error(format!("expected a type, found `{}`", name))
+                .with_code(codes::UNKNOWN_TYPE)
+                .with_primary(span, "not a type")
+                .with_secondary(self.modules.definition_span(resolved), format!("`{}` is declared here", name)),
+        );
+        Ty::Error
+    }
+
+    /// The module of the function being checked.
+    fn module(&self) -> ModuleId {
+        self.function.0
+    }
+
+    fn check_function(&mut self, id: ItemId, statements: &[Statement], signature_span: Span) {
+        let (module, index) = id;
+        let ProgramObject::Function { arguments, .. } = self.modules.object(module, index) else {
+            return;
+        };
+        let signature = self.types.functions[&id].clone();
+        self.function = id;
+        self.ret = signature.ret;
+        self.num_allowed = signature.is_generic();
+        self.signature_span = signature_span;
+        self.scopes.push(arguments.iter().map(|p| p.name.clone()).zip(signature.params).collect());
+        for statement in statements {
+            self.check_statement(statement);
+        }
+        self.scopes.pop();
+    }
+
+    fn check_statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let { name, type_annotation, value, .. } => {
+                let ty = self.resolve_type(type_annotation, self.module());
+                self.check(value, ty);
+                self.declare(name, ty);
+            }
+            StatementKind::Set { name, new_value } => match self.local(name) {
+                Some(ty) => {
+                    self.check(new_value, ty);
+                }
+                None => {
+                    self.infer(new_value, None);
+                }
+            },
+            StatementKind::Ret { value } => {
+                if self.ret == Ty::Unit {
+                    self.infer(value, None);
+                    self.diagnostics.push(
+                        Diagnostic::error("this function doesn't return a value")
+                            .with_code(codes::MISMATCHED_TYPES)
+                            .with_primary(value.span, "value returned here")
+                            .with_secondary(self.signature_span, "no return type declared")
+                            .with_note("help: declare what it returns with `-> type` after the parameters"),
+                    );
+                } else {
+                    let span = self.signature_span;
+                    self.check_with(value, self.ret, Some((span, "return type declared here")));
+                }
+            }
+            StatementKind::FunctionCall { call } => {
+                self.infer(call, None);
+            }
+            StatementKind::If { condition, then_block, else_branch } => {
+                self.check(condition, Ty::Bool);
+                self.check_block(then_block);
+                if let Some(else_branch) = else_branch {
+                    self.check_statement(else_branch);
+                }
+            }
+            StatementKind::Block(block) => self.check_block(block),
+            StatementKind::While { condition, body } => {
+                self.check(condition, Ty::Bool);
+                self.check_block(body);
+            }
+            StatementKind::For { variable, iterable, body } => {
+                let ForIterable::Range { start, end } = iterable;
+                let (ty, end_ty) = self.infer_operands(start, end, None);
+                let ty = if ty == Ty::Error { end_ty } else { ty };
+                if !ty.accepts(end_ty) {
+                    let diagnostic = self.mismatch(ty, end_ty, end.span);
+                    self.diagnostics.push(diagnostic);
+                } else if !ty.is_integer() && ty != Ty::Error {
+                    self.diagnostics.push(
+                        Diagnostic::error(format!("cannot iterate over a range of `{}`", ty.name(self.modules)))
+                            .with_code(codes::INVALID_OPERANDS)
+                            .with_primary(start.span.to(end.span), "ranges must be of an integer type"),
+                    );
+                }
+                self.scopes.push(HashMap::new());
+                self.declare(variable, ty);
+                self.check_block(body);
+                self.scopes.pop();
+            }
+            StatementKind::Break | StatementKind::Continue => {}
+            StatementKind::Match { scrutinee, arms } => {
+                let ty = self.infer(scrutinee, None);
+                for arm in arms {
+                    self.scopes.push(HashMap::new());
+                    self.check_pattern(&arm.pattern, ty);
+                    self.check_statement(&arm.body);
+                    self.scopes.pop();
+                }
+            }
+        }
+    }
+
+    fn check_block(&mut self, block: &Block) {
+        self.scopes.push(HashMap::new());
+        for statement in &block.statements {
+            self.check_statement(statement);
+        }
+        self.scopes.pop();
+    }
+
+    /// Infers the type of `expression`, reporting a mismatch if it isn't
+    /// `expected`.
+    fn check(&mut self, expression: &Expression, expected: Ty) -> Ty {
+        self.check_with(expression, expected, None)
+    }
+
+    /// Like `check`, with a secondary label explaining where the expected
+    /// type comes from.
+    fn check_with(&mut self, expression: &Expression, expected: Ty, origin: Option<(Span, &str)>) -> Ty {
+        let found = self.infer(expression, Some(expected));
+        if !expected.accepts(found) {
+            let mut diagnostic = self.mismatch(expected, found, expression.span);
+            if let Some((span, message)) = origin {
+                diagnostic = diagnostic.with_secondary(span, message);
+            }
+            self.diagnostics.push(diagnostic);
+        }
+        found
+    }
+
+    fn mismatch(&self, expected: Ty, found: Ty, span: Span) -> Diagnostic {
+        Diagnostic::error("mismatched types")
+            .with_code(codes::MISMATCHED_TYPES)
+            .with_primary(
+                span,
+                format!("expected `{}`, found `{}`", expected.name(self.modules), found.name(self.modules)),
+            )
+    }
+
+    /// The type of `expression`. `hint` is the type the context expects, if
+    /// any, which decides the type of number literals.
+    fn infer(&mut self, expression: &Expression, hint: Option<Ty>) -> Ty {
+        let ty = self.infer_kind(expression, hint);
+        self.types.expressions.insert(expression.span, ty);
+        ty
+    }
+
+    fn infer_kind(&mut self, expression: &Expression, hint: Option<Ty>) -> Ty {
+        match &expression.kind {
+            ExpressionKind::IntLiteral(value) => {
+                let ty = match hint {
+                    Some(ty) if ty.is_integer() || ty == Ty::Num => ty,
+                    _ => Ty::I32,
+                };
+                self.check_literal(*value, ty, expression.span);
+                ty
+            }
+            ExpressionKind::FloatLiteral(_) => match hint {
+                Some(ty) if ty.is_float() => ty,
+                _ => Ty::F64,
+            },
+            ExpressionKind::StringLiteral(_) => Ty::String,
+            ExpressionKind::CharLiteral(_) => Ty::Char,
+            ExpressionKind::BoolLiteral(_) => Ty::Bool,
+            ExpressionKind::Variable(name) => match self.local(name) {
+                Some(ty) => ty,
+                None => self.infer_path(std::slice::from_ref(name), expression.span),
+            },
+            ExpressionKind::FieldAccess { object, field } => {
+                if let Some(path) = expression.as_path()
+                    && self.local(&path[0]).is_none()
+                {
+                    return self.infer_path(&path, expression.span);
+                }
+                let ty = self.infer(object, None);
+                self.field_type(ty, field, expression.span)
+            }
+            ExpressionKind::Binary { op, left, right } => self.infer_binary(*op, left, right, hint, expression.span),
+            ExpressionKind::Unary { op, operand } => self.infer_unary(*op, operand, hint, expression.span),
+            ExpressionKind::FunctionCall { callee, args } => self.infer_call(callee, args, hint),
+            ExpressionKind::If { condition, then_value, else_value } => {
+                self.check(condition, Ty::Bool);
+                let then_ty = self.infer(then_value, hint);
+                if then_ty == Ty::Error {
+                    return self.infer(else_value, hint);
+                }
+                self.check_with(else_value, then_ty, Some((then_value.span, "expected because of this branch")));
+                then_ty
+            }
+            ExpressionKind::Match { scrutinee, arms } => {
+                let scrutinee_ty = self.infer(scrutinee, None);
+                let mut result: Option<(Ty, Span)> = None;
+                for arm in arms {
+                    self.scopes.push(HashMap::new());
+                    self.check_pattern(&arm.pattern, scrutinee_ty);
+                    match result {
+                        Some((ty, span)) if ty != Ty::Error => {
+                            self.check_with(&arm.body, ty, Some((span, "expected because of this arm")));
+                        }
+                        _ => result = Some((self.infer(&arm.body, hint), arm.body.span)),
+                    }
+                    self.scopes.pop();
+                }
+                result.map_or(Ty::Error, |(ty, _)| ty)
+            }
+            ExpressionKind::StructLiteral { path, fields } => self.infer_struct_literal(path, fields, expression.span),
+        }
+    }
+
+    fn check_literal(&mut self, value: i128, ty: Ty, span: Span) {
+        if !ty.fits(value) {
+            self.diagnostics.push(
+                Diagnostic::error(format!("literal out of range for `{}`", ty.name(self.modules)))
+                    .with_code(codes::LITERAL_OUT_OF_RANGE)
+                    .with_primary(span, format!("`{}` doesn't fit in `{}`", value, ty.name(self.modules))),
+            );
+        }
+    }
+
+    /// The type of a path that doesn't start with a local: a unit enum
+    /// variant. Anything else is either reported by the resolver already or
+    /// is a function or variant that needs arguments.
+    fn infer_path(&mut self, path: &[String], span: Span) -> Ty {
+        let Some((resolved, used)) = self.modules.lookup_prefix(self.module(), path) else {
+            return Ty::Error;
+        };
+        let Resolved::Item(module, index) = resolved else {
+            return Ty::Error;
+        };
+        let shown = path.join(".");
+        match (self.modules.object(module, index), &path[used..]) {
+            (ProgramObject::Function { .. }, []) => {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("function `{}` is used as a value", shown))
+                        .with_code(codes::MISMATCHED_TYPES)
+                        .with_primary(span, "functions can only be called")
+                        .with_note(format!("help: call it with `{}(...)`", shown)),
+                );
+                Ty::Error
+            }
+            (ProgramObject::Enum { .. }, [variant]) => match self.variant(resolved, variant).map(|v| v.payload) {
+                Some(Payload::Unit) => Ty::Enum((module, index)),
+                Some(Payload::Tuple(_)) => {
+                    self.diagnostics.push(
+                        Diagnostic::error(format!("enum variant `{}` needs a payload", shown))
+                            .with_code(codes::MISMATCHED_TYPES)
+                            .with_primary(span, "missing payload")
+                            .with_note(format!("help: pass it like a call: `{}(...)`", shown)),
+                    );
+                    Ty::Error
+                }
+                Some(Payload::Struct(_)) => {
+                    self.diagnostics.push(
+                        Diagnostic::error(format!("enum variant `{}` needs its fields", shown))
+                            .with_code(codes::MISSING_FIELD)
+                            .with_primary(span, "missing fields")
+                            .with_note(format!("help: build it like a struct: `{} {{ ... }}`", shown)),
+                    );
+                    Ty::Error
+                }
+                None => Ty::Error,
+            },
+            _ => Ty::Error,
+        }
+    }
+
+    fn variant(&self, resolved: Resolved, name: &str) -> Option<Variant> {
+        let Resolved::Item(module, index) = resolved else {
+            return None;
+        };
+        self.types.enums.get(&(module, index))?.iter().find(|v| v.name == name).cloned()
+    }
+
+    fn field_type(&mut self, ty: Ty, field: &str, span: Span) -> Ty {
+        let fields = match ty {
+            Ty::Error => return Ty::Error,
+            Ty::Struct(id) => &self.types.structs[&id],
+            _ => {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("type `{}` has no fields", ty.name(self.modules)))
+                        .with_code(codes::NO_FIELD)
+                        .with_primary(span, format!("no field `{}`", field)),
+                );
+                return Ty::Error;
+            }
+        };
+        match fields.iter().find(|f| f.name == field) {
+            Some(f) => f.ty,
+            None => {
+                let mut diagnostic = Diagnostic::error(format!("no field `{}` on type `{}`", field, ty.name(self.modules)))
+                    .with_code(codes::NO_FIELD)
+                    .with_primary(span, "unknown field");
+                if !fields.is_empty() {
+                    let names: Vec<String> = fields.iter().map(|f| format!("`{}`", f.name)).collect();
+                    diagnostic = diagnostic.with_note(format!("available fields are: {}", names.join(", ")));
+                }
+                self.diagnostics.push(diagnostic);
+                Ty::Error
+            }
+        }
+    }
+
+    fn infer_call(&mut self, callee: &Expression, args: &[Expression], hint: Option<Ty>) -> Ty {
+        let target = match callee.as_path() {
+            Some(path) if self.local(&path[0]).is_none() => {
+                self.modules.lookup_prefix(self.module(), &path).map(|(resolved, used)| (resolved, path, used))
+            }
+            _ => None,
+        };
+        let Some((resolved, path, used)) = target else {
+            let ty = self.infer(callee, None);
+            for arg in args {
+                self.infer(arg, None);
+            }
+            if ty != Ty::Error {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("expected a function, found `{}`", ty.name(self.modules)))
+                        .with_code(codes::NOT_CALLABLE)
+                        .with_primary(callee.span, "not a function"),
+                );
+            }
+            return Ty::Error;
+        };
+
+        let shown = path.join(".");
+        let rest = &path[used..];
+        if let Resolved::Item(module, index) = resolved {
+            match (self.modules.object(module, index), rest) {
+                (ProgramObject::Function { arguments, .. }, []) => {
+                    let signature = self.types.functions[&(module, index)].clone();
+                    let spans: Vec<Span> = arguments.iter().map(|p| p.span).collect();
+                    let hint = if signature.ret == Ty::Num { hint } else { None };
+                    let num = self.check_arguments(&shown, &signature.params, &spans, args, callee.span, resolved, hint);
+                    if !signature.is_generic() {
+                        return signature.ret;
+                    }
+                    if num != Ty::Error {
+                        self.generic_calls.push((self.function, (module, index), num));
+                        self.types.generic_calls.insert(callee.span, num);
+                    }
+                    return if signature.ret == Ty::Num { num } else { signature.ret };
+                }
+                (ProgramObject::Enum { .. }, [variant]) => match self.variant(resolved, variant).map(|v| (v.payload, v.span)) {
+                    Some((Payload::Tuple(types), span)) => {
+                        let spans = vec![span; types.len()];
+                        self.check_arguments(&shown, &types, &spans, args, callee.span, resolved, None);
+                        return Ty::Enum((module, index));
+                    }
+                    Some((payload, _)) => {
+                        let help = match payload {
+                            Payload::Struct(_) => format!("help: build it like a struct: `{} {{ ... }}`", shown),
+                            _ => format!("help: write it without parentheses: `{}`", shown),
+                        };
+                        self.diagnostics.push(
+                            Diagnostic::error(format!("enum variant `{}` can't be called", shown))
+                                .with_code(codes::NOT_CALLABLE)
+                                .with_primary(callee.span, "")
+                                .with_note(help),
+                        );
+                    }
+                    None => {}
+                },
+                (ProgramObject::Struct { .. }, []) => {
+                    self.diagnostics.push(
+                        Diagnostic::error(format!("struct `{}` can't be called", shown))
+                            .with_code(codes::NOT_CALLABLE)
+                            .with_primary(callee.span, "")
+                            .with_note(format!("help: build it with a struct literal: `{} {{ ... }}`", shown)),
+                    );
+                }
+                _ => {}
+            }
+        }
+        for arg in args {
+            self.infer(arg, None);
+        }
+        Ty::Error
+    }
+
+    /// Checks call arguments against the types a function or tuple variant
+    /// declares. `spans` point at each declared parameter.
+    ///
+    /// For a generic function, the arguments passed as `num` decide what
+    /// type `num` is for this call, and that type is returned. Until one of
+    /// them has, they are inferred expecting the type the caller expects
+    /// back (`hint`). If they are all number literals, `hint` decides
+    /// instead, falling back to the type of the first literal.
+    #[allow(clippy::too_many_arguments)]
+    fn check_arguments(
+        &mut self,
+        name: &str,
+        params: &[Ty],
+        spans: &[Span],
+        args: &[Expression],
+        call: Span,
+        target: Resolved,
+        hint: Option<Ty>,
+    ) -> Ty {
+        if params.len() != args.len() {
+            let plural = |n: usize| if n == 1 { "" } else { "s" };
+            self.diagnostics.push(
+                Diagnostic::error(format!(
+                    "`{}` takes {} argument{} but {} {} supplied",
+                    name,
+                    params.len(),
+                    plural(params.len()),
+                    args.len(),
+                    if args.len() == 1 { "was" } else { "were" }
+                ))
+                .with_code(codes::ARGUMENT_COUNT)
+                .with_primary(call, "")
+                .with_secondary(self.modules.definition_span(target), "declared here"),
+            );
+        }
+
+        let mut num: Option<(Ty, Span)> = None;
+        let mut checked = vec![false; args.len()];
+        for (i, arg) in args.iter().enumerate() {
+            if params.get(i) != Some(&Ty::Num) || is_number_literal(arg) {
+                continue;
+            }
+            checked[i] = true;
+            let expected = num.map(|(ty, _)| ty).or(hint.filter(|ty| ty.is_numeric()));
+            let ty = self.infer(arg, expected);
+            match num {
+                _ if ty == Ty::Error => {}
+                None if ty.is_numeric() => num = Some((ty, arg.span)),
+                None => self.diagnostics.push(
+                    Diagnostic::error("mismatched types")
+                        .with_code(codes::MISMATCHED_TYPES)
+                        .with_primary(arg.span, format!("expected a number, found `{}`", ty.name(self.modules)))
+                        .with_secondary(spans[i], "parameter declared here")
+                        .with_note("`num` can be any of i32, i64, u32, u64, f32 or f64"),
+                ),
+                Some((expected, span)) if ty != expected => {
+                    let diagnostic = self.mismatch(expected, ty, arg.span).with_secondary(
+                        span,
+                        format!("`num` is `{}` in this call because of this argument", expected.name(self.modules)),
+                    );
+                    self.diagnostics.push(diagnostic);
+                }
+                Some(_) => {}
+            }
+        }
+        let num = match (num, hint) {
+            (Some((ty, _)), _) => ty,
+            (None, Some(ty)) if ty.is_numeric() => ty,
+            (None, _) => params
+                .iter()
+                .zip(args)
+                .find(|(param, _)| **param == Ty::Num)
+                .map_or(Ty::I32, |(_, arg)| literal_type(arg)),
+        };
+
+        for (i, arg) in args.iter().enumerate() {
+            match params.get(i) {
+                _ if checked[i] => {}
+                Some(ty) => {
+                    let ty = if *ty == Ty::Num { num } else { *ty };
+                    self.check_with(arg, ty, Some((spans[i], "parameter declared here")));
+                }
+                None => {
+                    self.infer(arg, None);
+                }
+            }
+        }
+        num
+    }
+
+    /// Works out which copies of each generic function are needed, starting
+    /// from the calls in ordinary functions and following calls that pass a
+    /// `num` on to another generic function.
+    fn instantiate(&mut self) {
+        let mut work: Vec<(ItemId, Ty)> = self
+            .generic_calls
+            .iter()
+            .filter(|(caller, _, _)| !self.types.functions[caller].is_generic())
+            .map(|(_, callee, ty)| (*callee, *ty))
+            .collect();
+        while let Some((function, ty)) = work.pop() {
+            let instances = self.types.instances.entry(function).or_default();
+            if instances.contains(&ty) {
+                continue;
+            }
+            instances.push(ty);
+            for (caller, callee, num) in &self.generic_calls {
+                if *caller == function {
+                    work.push((*callee, if *num == Ty::Num { ty } else { *num }));
+                }
+            }
+        }
+    }
+
+    fn infer_struct_literal(&mut self, path: &[String], fields: &[FieldInit], span: Span) -> Ty {
+        let shown = path.join(".");
+        let (declared, ty) = match self.modules.lookup_prefix(self.module(), path) {
+            Some((Resolved::Item(module, index), used)) => match (self.modules.object(module, index), &path[used..]) {
+                (ProgramObject::Struct { .. }, []) => (Some(self.types.structs[&(module, index)].clone()), Ty::Struct((module, index))),
+                (ProgramObject::Enum { .. }, [variant]) => {
+                    match self.variant(Resolved::Item(module, index), variant).map(|v| v.payload) {
+                        Some(Payload::Struct(fields)) => (Some(fields), Ty::Enum((module, index))),
+                        Some(_) => {
+                            self.diagnostics.push(
+                                Diagnostic::error(format!("enum variant `{}` has no named fields", shown))
+                                    .with_code(codes::NO_FIELD)
+                                    .with_primary(span, "")
+                                    .with_secondary(self.modules.definition_span(Resolved::Item(module, index)), "declared here"),
+                            );
+                            (None, Ty::Enum((module, index)))
+                        }
+                        None => (None, Ty::Error),
+                    }
+                }
+                _ => (None, Ty::Error),
+            },
+            _ => (None, Ty::Error),
+        };
+        let Some(declared) = declared else {
+            for field in fields {
+                self.infer(&field.value, None);
+            }
+            return ty;
+        };
+
+        for (i, init) in fields.iter().enumerate() {
+            if let Some(first) = fields[..i].iter().find(|f| f.name == init.name) {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("field `{}` is given more than once", init.name))
+                        .with_code(codes::DUPLICATE_BINDING)
+                        .with_primary(init.span, "")
+                        .with_secondary(first.span, "first given here"),
+                );
+            }
+            match declared.iter().find(|f| f.name == init.name) {
+                Some(field) => {
+                    self.check_with(&init.value, field.ty, Some((field.span, "field declared here")));
+                }
+                None => {
+                    self.infer(&init.value, None);
+                    self.diagnostics.push(
+                        Diagnostic::error(format!("`{}` has no field named `{}`", shown, init.name))
+                            .with_code(codes::NO_FIELD)
+                            .with_primary(init.span, "unknown field"),
+                    );
+                }
+            }
+        }
+        let missing: Vec<String> = declared
+            .iter()
+            .filter(|f| !fields.iter().any(|init| init.name == f.name))
+            .map(|f| format!("`{}`", f.name))
+            .collect();
+        if !missing.is_empty() {
+            self.diagnostics.push(
+                Diagnostic::error(format!("missing field{} {} in `{}`", if missing.len() == 1 { "" } else { "s" }, missing.join(", "), shown))
+                    .with_code(codes::MISSING_FIELD)
+                    .with_primary(span, ""),
+            );
+        }
+        ty
+    }
+
+    /// A number literal on its own has no type until the other operand
+    /// gives it one, so `1 + x` is checked as if it were `x + 1`.
+    fn infer_operands(&mut self, left: &Expression, right: &Expression, hint: Option<Ty>) -> (Ty, Ty) {
+        if is_number_literal(left) && !is_number_literal(right) {
+            let right_ty = self.infer(right, hint);
+            let left_ty = self.infer(left, Some(right_ty));
+            (left_ty, right_ty)
+        } else {
+            let left_ty = self.infer(left, hint);
+            let right_ty = self.infer(right, Some(left_ty));
+            (left_ty, right_ty)
+        }
+    }
+
+    fn infer_binary(&mut self, op: BinaryOp, left: &Expression, right: &Expression, hint: Option<Ty>, span: Span) -> Ty {
+        if op.is_logical() {
+            self.check(left, Ty::Bool);
+            self.check(right, Ty::Bool);
+            return Ty::Bool;
+        }
+        let hint = if op.is_comparison() { None } else { hint };
+        let (left_ty, right_ty) = self.infer_operands(left, right, hint);
+        if left_ty == Ty::Error || right_ty == Ty::Error {
+            return if op.is_comparison() { Ty::Bool } else { Ty::Error };
+        }
+
+        let (allowed, what) = match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => (left_ty.is_numeric(), "numbers"),
+            BinaryOp::Eq | BinaryOp::Ne => (
+                !matches!(left_ty, Ty::Struct(_) | Ty::Enum(_) | Ty::Unit),
+                "numbers, booleans, characters and strings",
+            ),
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+                (left_ty.is_numeric() || left_ty == Ty::Char, "numbers and characters")
+            }
+            BinaryOp::BitAnd | BinaryOp::BitXor => (left_ty.is_integer() || left_ty == Ty::Bool, "integers and booleans"),
+            BinaryOp::Shl | BinaryOp::Shr => (left_ty.is_integer() && right_ty.is_integer(), "integers"),
+            BinaryOp::And | BinaryOp::Or => unreachable!("logical operators are checked above"),
+        };
+        let same_type = matches!(op, BinaryOp::Shl | BinaryOp::Shr) || left_ty == right_ty;
+        if !allowed || !same_type {
+            let message = if same_type {
+                format!("`{}` can't be applied to `{}`", op.symbol(), left_ty.name(self.modules))
+            } else {
+                format!(
+                    "`{}` can't be applied to `{}` and `{}`",
+                    op.symbol(),
+                    left_ty.name(self.modules),
+                    right_ty.name(self.modules)
+                )
+            };
+            let mut diagnostic = Diagnostic::error(message)
+                .with_code(codes::INVALID_OPERANDS)
+                .with_primary(span, "")
+                .with_secondary(left.span, left_ty.name(self.modules))
+                .with_secondary(right.span, right_ty.name(self.modules));
+            diagnostic = if !same_type {
+                diagnostic.with_note("both operands must have the same type; there are no implicit conversions")
+            } else {
+                diagnostic.with_note(format!("`{}` works on {}", op.symbol(), what))
+            };
+            self.diagnostics.push(diagnostic);
+            return if op.is_comparison() { Ty::Bool } else { Ty::Error };
+        }
+        if op.is_comparison() { Ty::Bool } else { left_ty }
+    }
+
+    fn infer_unary(&mut self, op: UnaryOp, operand: &Expression, hint: Option<Ty>, span: Span) -> Ty {
+        let ty = match (&operand.kind, op) {
+            // Negative literals are checked for range as a whole, so
+            // `-2147483648` fits in an `i32`.
+            (ExpressionKind::IntLiteral(value), UnaryOp::Neg) => {
+                let ty = match hint {
+                    Some(ty) if ty.is_integer() || ty == Ty::Num => ty,
+                    _ => Ty::I32,
+                };
+                if ty.is_signed() {
+                    self.check_literal(value.wrapping_neg(), ty, span);
+                }
+                self.types.expressions.insert(operand.span, ty);
+                ty
+            }
+            _ => self.infer(operand, hint),
+        };
+        let allowed = match op {
+            UnaryOp::Neg => ty.is_signed(),
+            UnaryOp::Not => ty == Ty::Bool || ty.is_integer(),
+        };
+        if !allowed && ty != Ty::Error {
+            let symbol = match op {
+                UnaryOp::Neg => "-",
+                UnaryOp::Not => "!",
+            };
+            let mut diagnostic = Diagnostic::error(format!("`{}` can't be applied to `{}`", symbol, ty.name(self.modules)))
+                .with_code(codes::INVALID_OPERANDS)
+                .with_primary(span, "");
+            if ty == Ty::Num {
+                diagnostic = diagnostic.with_note("`num` may stand for an unsigned type such as `u32`");
+            }
+            self.diagnostics.push(diagnostic);
+            return Ty::Error;
+        }
+        ty
+    }
+
+    /// Checks that `pattern` can match a value of type `ty` and declares
+    /// the variables it binds.
+    fn check_pattern(&mut self, pattern: &Pattern, ty: Ty) {
+        match &pattern.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Binding(name) => {
+                self.types.bindings.insert(pattern.span, ty);
+                self.declare(name, ty);
+            }
+            PatternKind::Literal(literal) => self.check_literal_pattern(literal, ty, pattern.span),
+            PatternKind::Range { start, end } => {
+                if !(ty.is_numeric() || ty == Ty::Char || ty == Ty::Error) {
+                    self.diagnostics.push(
+                        Diagnostic::error(format!("range patterns can't match `{}`", ty.name(self.modules)))
+                            .with_code(codes::MISMATCHED_TYPES)
+                            .with_primary(pattern.span, "only numbers and characters have ranges"),
+                    );
+                    return;
+                }
+                self.check_literal_pattern(start, ty, pattern.span);
+                self.check_literal_pattern(end, ty, pattern.span);
+            }
+            PatternKind::Or(alternatives) => {
+                for alternative in alternatives {
+                    self.check_pattern(alternative, ty);
+                }
+            }
+            PatternKind::Constructor { path, fields } => self.check_constructor_pattern(path, fields, ty, pattern.span),
+        }
+    }
+
+    fn check_literal_pattern(&mut self, literal: &Literal, ty: Ty, span: Span) {
+        let matches = match literal {
+            Literal::Int(value) => {
+                if ty.is_integer() {
+                    self.check_literal(*value, ty, span);
+                }
+                ty.is_integer()
+            }
+            Literal::Float(_) => ty.is_float(),
+            Literal::Bool(_) => ty == Ty::Bool,
+            Literal::Char(_) => ty == Ty::Char,
+            Literal::String(_) => ty == Ty::String,
+        };
+        if !matches && ty != Ty::Error {
+            let found = match literal {
+                Literal::Int(_) => "integer",
+                Literal::Float(_) => "float",
+                Literal::Bool(_) => "bool",
+                Literal::Char(_) => "char",
+                Literal::String(_) => "string",
+            };
+            self.diagnostics.push(
+                Diagnostic::error("mismatched types")
+                    .with_code(codes::MISMATCHED_TYPES)
+                    .with_primary(span, format!("expected `{}`, found {} literal", ty.name(self.modules), found)),
+            );
+        }
+    }
+
+    fn check_constructor_pattern(&mut self, path: &[String], fields: &PatternFields, ty: Ty, span: Span) {
+        let shown = path.join(".");
+        let (pattern_ty, payload) = match self.modules.lookup_prefix(self.module(), path) {
+            Some((Resolved::Item(module, index), used)) => match (self.modules.object(module, index), &path[used..]) {
+                (ProgramObject::Struct { .. }, []) => {
+                    (Ty::Struct((module, index)), Some(Payload::Struct(self.types.structs[&(module, index)].clone())))
+                }
+                (ProgramObject::Enum { .. }, [variant]) => (
+                    Ty::Enum((module, index)),
+                    self.variant(Resolved::Item(module, index), variant).map(|v| v.payload),
+                ),
+                _ => (Ty::Error, None),
+            },
+            _ => (Ty::Error, None),
+        };
+        if !ty.accepts(pattern_ty) {
+            let diagnostic = self.mismatch(ty, pattern_ty, span);
+            self.diagnostics.push(diagnostic);
+        }
+        let Some(payload) = payload else {
+            self.bind_unchecked(fields);
+            return;
+        };
+
+        match (&payload, fields) {
+            (Payload::Unit, PatternFields::Unit) => {}
+            (Payload::Tuple(types), PatternFields::Tuple(patterns)) => {
+                if types.len() != patterns.len() {
+                    self.diagnostics.push(
+                        Diagnostic::error(format!(
+                            "this pattern has {} field{}, but `{}` has {}",
+                            patterns.len(),
+                            if patterns.len() == 1 { "" } else { "s" },
+                            shown,
+                            types.len()
+                        ))
+                        .with_code(codes::ARGUMENT_COUNT)
+                        .with_primary(span, ""),
+                    );
+                }
+                for (i, pattern) in patterns.iter().enumerate() {
+                    self.check_pattern(pattern, types.get(i).copied().unwrap_or(Ty::Error));
+                }
+            }
+            (Payload::Struct(declared), PatternFields::Struct(patterns)) => {
+                for (name, pattern) in patterns {
+                    match declared.iter().find(|f| f.name == *name) {
+                        Some(field) => self.check_pattern(pattern, field.ty),
+                        None => {
+                            self.diagnostics.push(
+                                Diagnostic::error(format!("`{}` has no field named `{}`", shown, name))
+                                    .with_code(codes::NO_FIELD)
+                                    .with_primary(pattern.span, "unknown field"),
+                            );
+                            self.check_pattern(pattern, Ty::Error);
+                        }
+                    }
+                }
+            }
+            // A struct pattern without fields, such as `point`, matches any
+            // value of that struct.
+            (Payload::Struct(_), PatternFields::Unit) if matches!(pattern_ty, Ty::Struct(_)) => {}
+            _ => {
+                let help = match payload {
+                    Payload::Unit => format!("`{}`", shown),
+                    Payload::Tuple(_) => format!("`{}(...)`", shown),
+                    Payload::Struct(_) => format!("`{} {{ ... }}`", shown),
+                };
+                self.diagnostics.push(
+                    Diagnostic::error(format!("this pattern doesn't have the shape of `{}`", shown))
+                        .with_code(codes::MISMATCHED_TYPES)
+                        .with_primary(span, "")
+                        .with_note(format!("help: match it with {}", help)),
+                );
+                self.bind_unchecked(fields);
+            }
+        }
+    }
+
+    /// Declares the variables in sub-patterns that can't be checked, so the
+    /// arm's body doesn't report them as errors too.
+    fn bind_unchecked(&mut self, fields: &PatternFields) {
+        match fields {
+            PatternFields::Unit => {}
+            PatternFields::Tuple(patterns) => {
+                for pattern in patterns {
+                    self.check_pattern(pattern, Ty::Error);
+                }
+            }
+            PatternFields::Struct(patterns) => {
+                for (_, pattern) in patterns {
+                    self.check_pattern(pattern, Ty::Error);
+                }
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, ty: Ty) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), ty);
+        }
+    }
+
+    fn local(&self, name: &str) -> Option<Ty> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+}
+
+/// The type a number literal has when nothing says otherwise.
+fn literal_type(expression: &Expression) -> Ty {
+    match &expression.kind {
+        ExpressionKind::FloatLiteral(_) => Ty::F64,
+        ExpressionKind::Unary { operand, .. } => literal_type(operand),
+        _ => Ty::I32,
+    }
+}
+
+fn is_number_literal(expression: &Expression) -> bool {
+    match &expression.kind {
+        ExpressionKind::IntLiteral(_) | ExpressionKind::FloatLiteral(_) => true,
+        ExpressionKind::Unary { op: UnaryOp::Neg, operand } => is_number_literal(operand),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing;
+
+    fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        testing::pass_diagnostics(|modules| check(modules).1, source)
+    }
+
+    /// The code and primary label of every diagnostic.
+    fn errors(source: &str) -> Vec<(&'static str, String)> {
+        diagnostics(source)
+            .into_iter()
+            .map(|d| (d.code.unwrap_or(""), d.labels.iter().find(|l| l.primary).map_or(String::new(), |l| l.message.clone())))
+            .collect()
+    }
+
+    fn error(code: &'static str, label: &str) -> (&'static str, String) {
+        (code, label.to_string())
+    }
+
+    #[test]
+    fn mismatched_lets_returns_and_arguments_name_both_types() {
+        let source = "
+            fn takes(a | i32) -> i32 { ret a; }
+            fn main() -> i32 {
+                let b | bool <- 1;
+                let c | i64 <- takes(true);
+                ret c;
+            }";
+        assert_eq!(
+            errors(source),
+            [
+                error(codes::MISMATCHED_TYPES, "expected `bool`, found `i32`"),
+                error(codes::MISMATCHED_TYPES, "expected `i32`, found `bool`"),
+                error(codes::MISMATCHED_TYPES, "expected `i64`, found `i32`"),
+                error(codes::MISMATCHED_TYPES, "expected `i32`, found `i64`"),
+            ]
+        );
+    }
+
+    #[test]
+    fn operands_of_different_types_are_invalid() {
+        let source = "fn f(a | i32, b | bool, c | u32) -> i32 { ret a + b; }\nfn g(b | bool) -> bool { ret b < b; }";
+        let diagnostics = diagnostics(source);
+        let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+        assert_eq!(
+            messages,
+            [
+                (Some(codes::INVALID_OPERANDS), "`+` can't be applied to `i32` and `bool`"),
+                (Some(codes::INVALID_OPERANDS), "`<` can't be applied to `bool`"),
+            ]
+        );
+        assert_eq!(diagnostics[0].notes, ["both operands must have the same type; there are no implicit conversions"]);
+        assert_eq!(diagnostics[1].notes, ["`<` works on numbers and characters"]);
+    }
+
+    #[test]
+    fn integer_literals_must_fit_their_type() {
+        let source = "
+            fn main() {
+                let a | i32 <- 2147483647;
+                let b | i32 <- -2147483648;
+                let c | i32 <- 2147483648;
+                let d | u32 <- 4294967295;
+                let e | u32 <- 4294967296;
+                let f | u32 <- -1;
+                let g | u64 <- 18446744073709551615;
+                let h | u64 <- 18446744073709551616;
+            }";
+        assert_eq!(
+            errors(source),
+            [
+                error(codes::LITERAL_OUT_OF_RANGE, "`2147483648` doesn't fit in `i32`"),
+                error(codes::LITERAL_OUT_OF_RANGE, "`4294967296` doesn't fit in `u32`"),
+                error(codes::INVALID_OPERANDS, ""),
+                error(codes::LITERAL_OUT_OF_RANGE, "`18446744073709551616` doesn't fit in `u64`"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_reported_in_literals_and_accesses() {
+        let source = "
+            struct point { x | i32, y | i32 }
+            fn main() -> i32 {
+                let p | point <- point { x <- 1, y <- 2, z <- 3 };
+                ret p.w;
+            }";
+        let diagnostics = diagnostics(source);
+        let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+        assert_eq!(
+            messages,
+            [
+                (Some(codes::NO_FIELD), "`point` has no field named `z`"),
+                (Some(codes::NO_FIELD), "no field `w` on type `point`"),
+            ]
+        );
+        assert_eq!(diagnostics[1].notes, ["available fields are: `x`, `y`"]);
+    }
+
+    #[test]
+    fn if_and_match_branches_must_agree() {
+        let source = "
+            fn f(a | bool, n | i32) -> i32 {
+                let x | i32 <- if a { 1 } else { false };
+                let y | i32 <- @ n { 0 -> 1; _ -> true; };
+                let z | i64 <- if a { n } else { 2 };
+                ret x + y;
+            }";
+        let diagnostics = diagnostics(source);
+        let labels: Vec<Vec<_>> = diagnostics.iter().map(|d| d.labels.iter().map(|l| l.message.as_str()).collect()).collect();
+        assert_eq!(
+            labels,
+            [
+                vec!["expected `i32`, found `bool`", "expected because of this branch"],
+                vec!["expected `i32`, found `bool`", "expected because of this arm"],
+                vec!["expected `i64`, found `i32`"],
+            ]
+        );
+    }
+
+    const ADD: &str = "fn add(a | num, b | num) -> num { ret a + b; }\n";
+
+    /// The type `num` is in each call of a generic function, in source order.
+    fn num_types(source: &str) -> Vec<Ty> {
+        let modules = testing::modules(source);
+        let (types, diagnostics) = check(&modules);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let mut calls: Vec<_> = types.generic_calls.into_iter().collect();
+        calls.sort_by_key(|(span, _)| span.start);
+        calls.into_iter().map(|(_, ty)| ty).collect()
+    }
+
+    #[test]
+    fn num_is_decided_by_typed_arguments_over_literals() {
+        let source = format!(
+            "{}fn main() -> i32 {{
+                let x | i64 <- 5;
+                let y | u32 <- 1;
+                let a | i64 <- add(1, x);
+                let b | u32 <- add(y, 2);
+                ret add(1, 2);
+            }}",
+            ADD
+        );
+        assert_eq!(num_types(&source), [Ty::I64, Ty::U32, Ty::I32]);
+    }
+
+    #[test]
+    fn num_returns_take_the_type_the_caller_expects() {
+        let source = format!(
+            "{}fn main() -> i32 {{
+                let a | u64 <- add(add(1, 2), 3);
+                let b | i64 <- add(1, 2) * 2;
+                ret 0;
+            }}",
+            ADD
+        );
+        assert_eq!(num_types(&source), [Ty::U64, Ty::U64, Ty::I64]);
+    }
+
+    #[test]
+    fn arguments_disagreeing_on_num_point_at_the_one_that_decided_it() {
+        let source = format!("{}fn f(x | i64, y | u32) -> i64 {{ ret add(x, y); }}", ADD);
+        let diagnostics = diagnostics(&source);
+        assert_eq!(diagnostics.len(), 1);
+        let labels: Vec<_> = diagnostics[0].labels.iter().map(|l| l.message.as_str()).collect();
+        assert_eq!(labels, ["expected `i64`, found `u32`", "`num` is `i64` in this call because of this argument"]);
+    }
+
+    #[test]
+    fn num_arguments_must_be_numbers() {
+        let diagnostics = diagnostics(&format!("{}fn main() -> i32 {{ add(true, false); ret 0; }}", ADD));
+        assert!(!diagnostics.is_empty());
+        assert!(diagnostics.iter().all(|d| d.code == Some(codes::MISMATCHED_TYPES)));
+        assert_eq!(diagnostics[0].labels[0].message, "expected a number, found `bool`");
+        assert_eq!(diagnostics[0].labels[1].message, "parameter declared here");
+        assert_eq!(diagnostics[0].notes, ["`num` can be any of i32, i64, u32, u64, f32 or f64"]);
+    }
+}
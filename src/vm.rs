@@ -0,0 +1,318 @@
+use crate::bytecode::{Constant, Function, Op, Program};
+use crate::compiler::MAX_CALL_DEPTH;
+use crate::diagnostic::{codes, Diagnostic};
+use std::rc::Rc;
+
+/// A value on the VM's stack. Integer arithmetic wraps on overflow.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    I32(i32),
+    I64(i64),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Char(char),
+    String(Rc<str>),
+    /// Fields in declaration order.
+    Struct(Rc<[Value]>),
+    /// A variant's tag and its fields.
+    Variant(u16, Rc<[Value]>),
+    Unit,
+}
+
+impl Value {
+    fn from_constant(constant: &Constant) -> Value {
+        match constant {
+            Constant::I32(v) => Value::I32(*v),
+            Constant::I64(v) => Value::I64(*v),
+            Constant::U32(v) => Value::U32(*v),
+            Constant::U64(v) => Value::U64(*v),
+            Constant::F32(v) => Value::F32(*v),
+            Constant::F64(v) => Value::F64(*v),
+            Constant::Bool(v) => Value::Bool(*v),
+            Constant::Char(v) => Value::Char(*v),
+            Constant::String(v) => Value::String(v.as_str().into()),
+        }
+    }
+
+    fn as_integer(&self) -> Option<i128> {
+        match *self {
+            Value::I32(v) => Some(v.into()),
+            Value::I64(v) => Some(v.into()),
+            Value::U32(v) => Some(v.into()),
+            Value::U64(v) => Some(v.into()),
+            _ => None,
+        }
+    }
+}
+
+/// Why the program stopped early.
+#[derive(Debug, Clone, Copy)]
+enum Fault {
+    DivisionByZero,
+    StackOverflow,
+    NoMatch,
+    /// The instruction found too few values on the stack, or values of
+    /// the wrong kind. `Program::decode` only checks indices, so a
+    /// corrupted `.c4b` file can get this far.
+    Invalid(Op),
+}
+
+/// A function call in progress. Its local slots start at `base` in the
+/// value stack, and its operands sit above them.
+struct Frame {
+    function: usize,
+    pc: usize,
+    base: usize,
+}
+
+/// Runs the program from its entry function and returns the process exit
+/// code: the `i32` the entry function returns, or 0 if it returns nothing.
+pub(crate) fn run(program: &Program) -> Result<i32, Diagnostic> {
+    let constants: Vec<Value> = program.constants.iter().map(Value::from_constant).collect();
+    let mut stack: Vec<Value> = Vec::with_capacity(1024);
+    let mut frames: Vec<Frame> = Vec::new();
+
+    let mut function_index = program.main as usize;
+    let mut function: &Function = &program.functions[function_index];
+    let mut pc = 0;
+    let mut base = 0;
+    stack.resize(function.locals as usize, Value::Unit);
+
+    macro_rules! fail {
+        ($fault:expr) => {
+            return Err(fault($fault, function, pc - 1))
+        };
+    }
+
+    loop {
+        let op = function.code[pc];
+        pc += 1;
+        // How many values sit above the frame's local slots. Instructions
+        // never take more than that, so they can't eat into the locals.
+        macro_rules! operands {
+            () => {
+                stack.len() - base - function.locals as usize
+            };
+        }
+        macro_rules! pop {
+            () => {{
+                if operands!() == 0 {
+                    fail!(Fault::Invalid(op));
+                }
+                stack.pop().expect("there is an operand above the locals")
+            }};
+        }
+        // Takes the top `count` values off the stack.
+        macro_rules! split_off {
+            ($count:expr) => {{
+                let count = $count;
+                if count > operands!() {
+                    fail!(Fault::Invalid(op));
+                }
+                stack.split_off(stack.len() - count)
+            }};
+        }
+        match op {
+            Op::Const(i) => stack.push(constants[i as usize].clone()),
+            Op::Unit => stack.push(Value::Unit),
+            Op::Load(slot) => stack.push(stack[base + slot as usize].clone()),
+            Op::Store(slot) => {
+                let value = pop!();
+                stack[base + slot as usize] = value;
+            }
+            Op::Pop => {
+                pop!();
+            }
+            Op::Neg | Op::Not => {
+                let value = pop!();
+                match unary(op, value) {
+                    Some(value) => stack.push(value),
+                    None => fail!(Fault::Invalid(op)),
+                }
+            }
+            Op::Jump(target) => pc = target as usize,
+            Op::JumpIfFalse(target) => {
+                if pop!() == Value::Bool(false) {
+                    pc = target as usize;
+                }
+            }
+            Op::Call(callee) => {
+                if frames.len() >= MAX_CALL_DEPTH {
+                    fail!(Fault::StackOverflow);
+                }
+                let callee_function = &program.functions[callee as usize];
+                if operands!() < callee_function.params as usize {
+                    fail!(Fault::Invalid(op));
+                }
+                frames.push(Frame { function: function_index, pc, base });
+                function_index = callee as usize;
+                function = callee_function;
+                base = stack.len() - function.params as usize;
+                stack.resize(base + function.locals as usize, Value::Unit);
+                pc = 0;
+            }
+            Op::Ret => {
+                let value = pop!();
+                stack.truncate(base);
+                let Some(caller) = frames.pop() else {
+                    return Ok(match value {
+                        Value::I32(code) => code,
+                        _ => 0,
+                    });
+                };
+                stack.push(value);
+                function_index = caller.function;
+                function = &program.functions[function_index];
+                pc = caller.pc;
+                base = caller.base;
+            }
+            Op::Struct(count) => {
+                let fields: Rc<[Value]> = split_off!(count as usize).into();
+                stack.push(Value::Struct(fields));
+            }
+            Op::Variant(tag, count) => {
+                let fields: Rc<[Value]> = split_off!(count as usize).into();
+                stack.push(Value::Variant(tag, fields));
+            }
+            Op::Field(index) => {
+                let field = match pop!() {
+                    Value::Struct(fields) | Value::Variant(_, fields) if (index as usize) < fields.len() => {
+                        fields[index as usize].clone()
+                    }
+                    _ => fail!(Fault::Invalid(op)),
+                };
+                stack.push(field);
+            }
+            Op::Tag => {
+                let tag = match pop!() {
+                    Value::Variant(tag, _) => tag,
+                    _ => fail!(Fault::Invalid(op)),
+                };
+                stack.push(Value::U32(tag.into()));
+            }
+            Op::NoMatch => fail!(Fault::NoMatch),
+            _ => {
+                let right = pop!();
+                let left = pop!();
+                match binary(op, left, right) {
+                    Ok(value) => stack.push(value),
+                    Err(fault) => fail!(fault),
+                }
+            }
+        }
+    }
+}
+
+/// A runtime error at instruction `pc` of `function`.
+fn fault(fault: Fault, function: &Function, pc: usize) -> Diagnostic {
+    let diagnostic = match fault {
+        Fault::DivisionByZero => Diagnostic::error("attempt to divide by zero").with_code(codes::DIVISION_BY_ZERO),
+        Fault::StackOverflow => Diagnostic::error("stack overflow")
+            .with_code(codes::STACK_OVERFLOW)
+            .with_note(format!("calls were nested more than {} deep", MAX_CALL_DEPTH)),
+        Fault::NoMatch => Diagnostic::error("no match arm matched the value").with_code(codes::NO_MATCHING_ARM),
+        Fault::Invalid(op) => Diagnostic::error(format!("`{}` found the wrong values on the stack", op.name()))
+            .with_code(codes::INVALID_BYTECODE)
+            .with_note("the bytecode is corrupted; rebuild it with `c4 build`"),
+    };
+    match function.location(pc) {
+        Some(location) => diagnostic.with_note(format!("in `{}` at {}", function.name, location)),
+        None => diagnostic.with_note(format!("in `{}`", function.name)),
+    }
+}
+
+macro_rules! integer_op {
+    ($variant:ident, $a:expr, $b:expr, $op:expr) => {{
+        let (a, b) = ($a, $b);
+        match $op {
+            Op::Add => Value::$variant(a.wrapping_add(b)),
+            Op::Sub => Value::$variant(a.wrapping_sub(b)),
+            Op::Mul => Value::$variant(a.wrapping_mul(b)),
+            Op::Div | Op::Mod if b == 0 => return Err(Fault::DivisionByZero),
+            Op::Div => Value::$variant(a.wrapping_div(b)),
+            Op::Mod => Value::$variant(a.wrapping_rem(b)),
+            Op::BitAnd => Value::$variant(a & b),
+            Op::BitXor => Value::$variant(a ^ b),
+            op => compare(op, a, b)?,
+        }
+    }};
+}
+
+macro_rules! float_op {
+    ($variant:ident, $a:expr, $b:expr, $op:expr) => {{
+        let (a, b) = ($a, $b);
+        match $op {
+            Op::Add => Value::$variant(a + b),
+            Op::Sub => Value::$variant(a - b),
+            Op::Mul => Value::$variant(a * b),
+            Op::Div => Value::$variant(a / b),
+            Op::Mod => Value::$variant(a % b),
+            op => compare(op, a, b)?,
+        }
+    }};
+}
+
+fn compare<T: PartialOrd>(op: Op, a: T, b: T) -> Result<Value, Fault> {
+    Ok(Value::Bool(match op {
+        Op::Eq => a == b,
+        Op::Ne => a != b,
+        Op::Lt => a < b,
+        Op::Le => a <= b,
+        Op::Gt => a > b,
+        Op::Ge => a >= b,
+        _ => return Err(Fault::Invalid(op)),
+    }))
+}
+
+/// Applies a binary instruction. Fails on division by zero, or if the
+/// operands don't suit it.
+fn binary(op: Op, left: Value, right: Value) -> Result<Value, Fault> {
+    if matches!(op, Op::Shl | Op::Shr) {
+        let amount = right.as_integer().ok_or(Fault::Invalid(op))? as u32;
+        return Ok(match (op, left) {
+            (Op::Shl, Value::I32(a)) => Value::I32(a.wrapping_shl(amount)),
+            (Op::Shl, Value::I64(a)) => Value::I64(a.wrapping_shl(amount)),
+            (Op::Shl, Value::U32(a)) => Value::U32(a.wrapping_shl(amount)),
+            (Op::Shl, Value::U64(a)) => Value::U64(a.wrapping_shl(amount)),
+            (_, Value::I32(a)) => Value::I32(a.wrapping_shr(amount)),
+            (_, Value::I64(a)) => Value::I64(a.wrapping_shr(amount)),
+            (_, Value::U32(a)) => Value::U32(a.wrapping_shr(amount)),
+            (_, Value::U64(a)) => Value::U64(a.wrapping_shr(amount)),
+            _ => return Err(Fault::Invalid(op)),
+        });
+    }
+    Ok(match (left, right) {
+        (Value::I32(a), Value::I32(b)) => integer_op!(I32, a, b, op),
+        (Value::I64(a), Value::I64(b)) => integer_op!(I64, a, b, op),
+        (Value::U32(a), Value::U32(b)) => integer_op!(U32, a, b, op),
+        (Value::U64(a), Value::U64(b)) => integer_op!(U64, a, b, op),
+        (Value::F32(a), Value::F32(b)) => float_op!(F32, a, b, op),
+        (Value::F64(a), Value::F64(b)) => float_op!(F64, a, b, op),
+        (Value::Bool(a), Value::Bool(b)) => match op {
+            Op::BitAnd => Value::Bool(a & b),
+            Op::BitXor => Value::Bool(a ^ b),
+            op => compare(op, a, b)?,
+        },
+        (Value::Char(a), Value::Char(b)) => compare(op, a, b)?,
+        (Value::String(a), Value::String(b)) => compare(op, a, b)?,
+        _ => return Err(Fault::Invalid(op)),
+    })
+}
+
+fn unary(op: Op, value: Value) -> Option<Value> {
+    Some(match (op, value) {
+        (Op::Neg, Value::I32(v)) => Value::I32(v.wrapping_neg()),
+        (Op::Neg, Value::I64(v)) => Value::I64(v.wrapping_neg()),
+        (Op::Neg, Value::F32(v)) => Value::F32(-v),
+        (Op::Neg, Value::F64(v)) => Value::F64(-v),
+        (Op::Not, Value::Bool(v)) => Value::Bool(!v),
+        (Op::Not, Value::I32(v)) => Value::I32(!v),
+        (Op::Not, Value::I64(v)) => Value::I64(!v),
+        (Op::Not, Value::U32(v)) => Value::U32(!v),
+        (Op::Not, Value::U64(v)) => Value::U64(!v),
+        _ => return None,
+    })
+}
@@ -0,0 +1,859 @@
+use crate::diagnostic::codes;
+use crate::ir::{self, BinaryOp, BlockId, Constant, InstructionKind, Terminator, Type, TypeKind, UnaryOp};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Translates the program's IR into GNU-syntax x86-64 assembly for the
+/// System V ABI, ready to be assembled and linked by `cc` into an
+/// executable. `root` is the file it was compiled from.
+///
+/// Every value is made of 8-byte words: scalars take one, structs take the
+/// words of their fields in order, and enums take a tag word followed by
+/// the fields of the largest variant. Every IR value has a `%rbp`-relative
+/// stack slot of its own, and every phi a second one its predecessors
+/// write before jumping to its block. Scalar arguments and results go in
+/// registers as the ABI says; structs and enums are passed as pointers to
+/// the caller's copy, and returned through a pointer the caller passes in
+/// `%rdi`, the way the ABI passes large structs.
+pub(crate) fn generate(module: &ir::Module, root: &str) -> String {
+    let mut generator = Generator {
+        module,
+        sizes: vec![0; module.types.len()],
+        data: Vec::new(),
+        labels: 0,
+        state: FunctionState::default(),
+    };
+    for index in module.layout_order() {
+        generator.sizes[index as usize] = generator.size_of_fields(&module.types[index as usize]);
+    }
+
+    let mut text = String::new();
+    for function in &module.functions {
+        text.push_str(&generator.compile_function(function));
+    }
+
+    let main = &module.functions[module.main as usize];
+    let mut out = String::new();
+    let _ = writeln!(out, "# Generated by c4 from {}.", root);
+    out.push_str("\n    .text\n    .globl main\nmain:\n    pushq %rbp\n    movq %rsp, %rbp\n");
+    let _ = writeln!(out, "    call {}", main.symbol());
+    if main.ret != Type::I32 {
+        out.push_str("    xorl %eax, %eax\n");
+    }
+    out.push_str("    popq %rbp\n    ret\n");
+    out.push_str(RUNTIME);
+    out.push_str(&text);
+    if !generator.data.is_empty() {
+        out.push_str("\n    .section .rodata\n");
+        for line in &generator.data {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out.push_str("\n    .section .note.GNU-stack,\"\",@progbits\n");
+    out
+}
+
+
+/// `c4_fail` prints the message at `%rsi`, `%rdx` bytes long, to stderr
+/// and exits with status 101. `c4_strings_equal` compares the strings at
+/// `%rdi` and `%rsi` by their lengths, stored in the word before each, and
+/// their bytes.
+const RUNTIME: &str = "
+c4_fail:
+    pushq %rbp
+    movq %rsp, %rbp
+    movl $2, %edi
+    call write@PLT
+    movl $101, %edi
+    call exit@PLT
+
+c4_strings_equal:
+    movq -8(%rdi), %rdx
+    cmpq -8(%rsi), %rdx
+    jne 1f
+    pushq %rbp
+    movq %rsp, %rbp
+    call memcmp@PLT
+    popq %rbp
+    testl %eax, %eax
+    sete %al
+    movzbl %al, %eax
+    ret
+1:
+    xorl %eax, %eax
+    ret
+";
+
+/// Registers for the first integer and pointer arguments.
+const INTEGER_ARGS: [&str; 6] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+/// Registers for the first floating-point arguments.
+const FLOAT_ARGS: usize = 8;
+
+/// A stack slot, as its offset from `%rbp`. Word `i` of the value in it is
+/// at `offset + 8 * i`.
+type Slot = i32;
+
+/// Where an argument goes in a call.
+#[derive(Debug, Clone, Copy)]
+enum ArgumentLocation {
+    Integer(usize),
+    Float(usize),
+    /// The index of the 8-byte stack word it is passed in.
+    Stack(usize),
+}
+
+/// Assigns argument locations the way the System V ABI does: integers,
+/// pointers and structs passed by pointer in the integer registers,
+/// floats in `%xmm0`-`%xmm7`, and the rest on the stack in order.
+fn argument_locations(params: &[Type], hidden_pointer: bool) -> Vec<ArgumentLocation> {
+    let mut integers = usize::from(hidden_pointer);
+    let mut floats = 0;
+    let mut stack = 0;
+    let mut locations = Vec::new();
+    for ty in params {
+        let location = if ty.is_float() && floats < FLOAT_ARGS {
+            floats += 1;
+            ArgumentLocation::Float(floats - 1)
+        } else if !ty.is_float() && integers < INTEGER_ARGS.len() {
+            integers += 1;
+            ArgumentLocation::Integer(integers - 1)
+        } else {
+            stack += 1;
+            ArgumentLocation::Stack(stack - 1)
+        };
+        locations.push(location);
+    }
+    locations
+}
+
+fn is_compound(ty: Type) -> bool {
+    matches!(ty, Type::Named(_))
+}
+
+/// Whether a scalar is handled as 64 bits rather than 32.
+fn is_wide(ty: Type) -> bool {
+    matches!(ty, Type::I64 | Type::U64 | Type::String)
+}
+
+#[derive(Debug, Default)]
+struct FunctionState {
+    lines: Vec<String>,
+    /// The slot of every IR value, by number.
+    slots: Vec<Slot>,
+    /// The slot the predecessors of each phi's block write its value to.
+    incoming: HashMap<ir::Value, Slot>,
+    /// The label of every block.
+    labels: HashMap<BlockId, String>,
+    /// Bytes of stack used by slots.
+    frame: i32,
+    /// The most bytes of stack arguments any call in the function passes.
+    outgoing: i32,
+    /// The slot holding the pointer a struct or enum is returned through.
+    return_pointer: Slot,
+    /// Code placed after the function, for runtime errors.
+    failures: Vec<String>,
+}
+
+struct Generator<'m> {
+    module: &'m ir::Module,
+    /// How many words every struct and enum takes, by index.
+    sizes: Vec<i32>,
+    /// Lines of the read-only data section.
+    data: Vec<String>,
+    labels: usize,
+    state: FunctionState,
+}
+
+impl<'m> Generator<'m> {
+    fn emit(&mut self, line: impl AsRef<str>) {
+        self.state.lines.push(format!("    {}", line.as_ref()));
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(".L{}", self.labels)
+    }
+
+    fn place_label(&mut self, label: &str) {
+        self.state.lines.push(format!("{}:", label));
+    }
+
+    /// How many words a value of type `ty` takes.
+    fn words(&self, ty: Type) -> i32 {
+        match ty {
+            Type::Named(index) => self.sizes[index as usize],
+            _ => 1,
+        }
+    }
+
+    fn size_of_fields(&self, definition: &ir::TypeDefinition) -> i32 {
+        match &definition.kind {
+            TypeKind::Struct(fields) => fields.iter().map(|(_, ty)| self.words(*ty)).sum(),
+            TypeKind::Enum(variants) => {
+                let largest = variants.iter().map(|(_, fields)| fields.iter().map(|ty| self.words(*ty)).sum::<i32>()).max();
+                1 + largest.unwrap_or(0)
+            }
+        }
+    }
+
+    fn definition(&self, ty: Type) -> &'m TypeKind {
+        match ty {
+            Type::Named(index) => &self.module.types[index as usize].kind,
+            _ => unreachable!("the verifier checked only structs and enums have fields"),
+        }
+    }
+
+    /// The type and word offset of field `index` of a struct.
+    fn struct_field(&self, ty: Type, index: u32) -> (Type, i32) {
+        let TypeKind::Struct(fields) = self.definition(ty) else {
+            unreachable!("the verifier checked `field` is on a struct");
+        };
+        let offset = fields[..index as usize].iter().map(|(_, ty)| self.words(*ty)).sum();
+        (fields[index as usize].1, offset)
+    }
+
+    /// The type and word offset of field `index` of a variant. Fields start
+    /// after the tag.
+    fn variant_field(&self, ty: Type, tag: u32, index: u32) -> (Type, i32) {
+        let TypeKind::Enum(variants) = self.definition(ty) else {
+            unreachable!("the verifier checked variants belong to enums");
+        };
+        let fields = &variants[tag as usize].1;
+        let offset: i32 = fields[..index as usize].iter().map(|ty| self.words(*ty)).sum();
+        (fields[index as usize], 1 + offset)
+    }
+
+    /// A new slot of `words` words.
+    fn slot(&mut self, words: i32) -> Slot {
+        self.state.frame += 8 * words;
+        -self.state.frame
+    }
+
+    fn value_slot(&self, value: ir::Value) -> Slot {
+        self.state.slots[value.0 as usize]
+    }
+
+    /// Adds a read-only constant and returns its label.
+    fn constant(&mut self, directive: String) -> String {
+        let label = format!(".LC{}", self.data.len());
+        self.data.push(format!("{}:\n    {}", label, directive));
+        label
+    }
+
+    /// Adds a string constant and returns its label. Its length is stored
+    /// in the word before it, as strings may contain NULs.
+    fn string_constant(&mut self, value: &str) -> String {
+        let label = format!(".LC{}", self.data.len());
+        self.data.push(format!("    .balign 8\n    .quad {}\n{}:\n    .ascii {}", value.len(), label, assembler_string(value)));
+        label
+    }
+
+    /// A label that prints a runtime error and exits when jumped to.
+    fn failure(&mut self, code: &str, message: &str, location: &str) -> String {
+        let text = format!("error[{}]: {}\n  = note: at {}\n", code, message, location);
+        let data = self.constant(format!(".ascii {}", assembler_string(&text)));
+        let label = self.label();
+        self.state.failures.push(format!(
+            "{}:\n    leaq {}(%rip), %rsi\n    movq ${}, %rdx\n    call c4_fail",
+            label,
+            data,
+            text.len()
+        ));
+        label
+    }
+
+    fn compile_function(&mut self, function: &ir::Function) -> String {
+        self.state = FunctionState::default();
+        let hidden_pointer = is_compound(function.ret);
+        if hidden_pointer {
+            self.state.return_pointer = self.slot(1);
+            self.emit(format!("movq %rdi, {}(%rbp)", self.state.return_pointer));
+        }
+        for ty in &function.values {
+            let slot = self.slot(self.words(*ty));
+            self.state.slots.push(slot);
+        }
+        for block in &function.blocks {
+            for phi in block.instructions.iter().take_while(|i| i.is_phi()).filter_map(|i| i.result) {
+                let slot = self.slot(self.words(function.type_of(phi)));
+                self.state.incoming.insert(phi, slot);
+            }
+        }
+
+        let params: Vec<Type> = function.params.iter().map(|param| function.type_of(*param)).collect();
+        for ((param, ty), location) in function.params.iter().zip(&params).zip(argument_locations(&params, hidden_pointer)) {
+            let slot = self.value_slot(*param);
+            match location {
+                ArgumentLocation::Integer(register) if is_compound(*ty) => {
+                    self.copy_from_pointer(INTEGER_ARGS[register], slot, self.words(*ty));
+                }
+                ArgumentLocation::Integer(register) => {
+                    let register = if is_wide(*ty) { INTEGER_ARGS[register].to_string() } else { register_32(register) };
+                    self.emit(format!("{} {}, {}(%rbp)", move_for(*ty), register, slot));
+                }
+                ArgumentLocation::Float(register) => {
+                    self.emit(format!("{} %xmm{}, {}(%rbp)", move_for(*ty), register, slot));
+                }
+                ArgumentLocation::Stack(word) => {
+                    let offset = 16 + 8 * word;
+                    if is_compound(*ty) {
+                        self.emit(format!("movq {}(%rbp), %r11", offset));
+                        self.copy_from_pointer("%r11", slot, self.words(*ty));
+                    } else {
+                        self.emit(format!("movq {}(%rbp), %rax", offset));
+                        self.emit(format!("movq %rax, {}(%rbp)", slot));
+                    }
+                }
+            }
+        }
+
+        // Blocks are laid out so that most jumps go to the next one, which
+        // is left out.
+        let order = function.reverse_postorder();
+        for block in &order {
+            let label = self.label();
+            self.state.labels.insert(*block, label);
+        }
+        for (position, block) in order.iter().enumerate() {
+            let label = self.state.labels[block].clone();
+            self.place_label(&label);
+            self.block(function, *block, order.get(position + 1).copied());
+        }
+
+        let frame = (self.state.frame + self.state.outgoing + 15) / 16 * 16;
+        let mut out = format!("\n{}:\n    pushq %rbp\n    movq %rsp, %rbp\n", function.symbol());
+        if frame > 0 {
+            let _ = writeln!(out, "    subq ${}, %rsp", frame);
+        }
+        for line in &self.state.lines {
+            out.push_str(line);
+            out.push('\n');
+        }
+        for failure in &self.state.failures {
+            out.push_str(failure);
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Copies `words` words from the memory `pointer` points at into
+    /// `slot`, using `%rax`.
+    fn copy_from_pointer(&mut self, pointer: &str, slot: Slot, words: i32) {
+        for word in 0..words {
+            self.emit(format!("movq {}({}), %rax", 8 * word, pointer));
+            self.emit(format!("movq %rax, {}(%rbp)", slot + 8 * word));
+        }
+    }
+
+    fn copy(&mut self, from: Slot, to: Slot, words: i32) {
+        if from == to {
+            return;
+        }
+        for word in 0..words {
+            self.emit(format!("movq {}(%rbp), %rax", from + 8 * word));
+            self.emit(format!("movq %rax, {}(%rbp)", to + 8 * word));
+        }
+    }
+
+    /// Loads a scalar from a slot into `%rax` or `%xmm0`.
+    fn load(&mut self, ty: Type, slot: Slot) {
+        self.emit(format!("{} {}(%rbp), {}", move_for(ty), slot, result_register(ty)));
+    }
+
+    /// Stores a scalar from `%rax` or `%xmm0` into a slot.
+    fn store(&mut self, ty: Type, slot: Slot) {
+        self.emit(format!("{} {}, {}(%rbp)", move_for(ty), result_register(ty), slot));
+    }
+
+    /// Emits a block, leaving out a jump to `following`, the block emitted
+    /// after it.
+    fn block(&mut self, function: &ir::Function, id: BlockId, following: Option<BlockId>) {
+        let block = function.block(id);
+        for instruction in &block.instructions {
+            match (instruction.result, &instruction.kind) {
+                (Some(phi), InstructionKind::Phi(_)) => {
+                    let words = self.words(function.type_of(phi));
+                    self.copy(self.state.incoming[&phi], self.value_slot(phi), words);
+                }
+                _ => self.instruction(function, instruction),
+            }
+        }
+        match &block.terminator {
+            Terminator::Jump(to) => self.edge(function, id, *to, following),
+            Terminator::Branch(condition, then, otherwise) => {
+                self.load(Type::Bool, self.value_slot(*condition));
+                self.emit("testl %eax, %eax");
+                let then_label = self.state.labels[then].clone();
+                if function.phi_moves(id, *then).is_empty() {
+                    self.emit(format!("jne {}", then_label));
+                } else {
+                    let skip = self.label();
+                    self.emit(format!("je {}", skip));
+                    self.edge(function, id, *then, None);
+                    self.place_label(&skip);
+                }
+                self.edge(function, id, *otherwise, following);
+            }
+            Terminator::Return(value) => {
+                match value {
+                    Some(value) if is_compound(function.ret) => {
+                        let slot = self.value_slot(*value);
+                        self.emit(format!("movq {}(%rbp), %r11", self.state.return_pointer));
+                        for word in 0..self.words(function.ret) {
+                            self.emit(format!("movq {}(%rbp), %rax", slot + 8 * word));
+                            self.emit(format!("movq %rax, {}(%r11)", 8 * word));
+                        }
+                        self.emit("movq %r11, %rax");
+                    }
+                    Some(value) if function.ret != Type::Unit => self.load(function.ret, self.value_slot(*value)),
+                    _ => {}
+                }
+                self.emit("leave");
+                self.emit("ret");
+            }
+            Terminator::NoMatch(location) => {
+                let location = location.as_deref().unwrap_or(&function.name);
+                let fail = self.failure(codes::NO_MATCHING_ARM, "no match arm matched the value", location);
+                self.emit(format!("jmp {}", fail));
+            }
+            Terminator::Unreachable => self.emit("ud2"),
+        }
+    }
+
+    /// Emits the way from `from` to `to`: the phi moves of the edge, then a
+    /// jump unless `to` is emitted next.
+    fn edge(&mut self, function: &ir::Function, from: BlockId, to: BlockId, following: Option<BlockId>) {
+        for (phi, source) in function.phi_moves(from, to) {
+            let words = self.words(function.type_of(phi));
+            self.copy(self.value_slot(source), self.state.incoming[&phi], words);
+        }
+        if following != Some(to) {
+            let label = self.state.labels[&to].clone();
+            self.emit(format!("jmp {}", label));
+        }
+    }
+
+    fn instruction(&mut self, function: &ir::Function, instruction: &ir::Instruction) {
+        let location = instruction.location.as_deref().unwrap_or(&function.name);
+        let dest = instruction.result.map(|result| self.value_slot(result));
+        let result_type = instruction.result.map_or(Type::Unit, |result| function.type_of(result));
+        match &instruction.kind {
+            InstructionKind::Const(constant) => {
+                match constant {
+                    Constant::I32(v) => self.integer_constant(i64::from(*v), Type::I32, "%rax"),
+                    Constant::I64(v) => self.integer_constant(*v, Type::I64, "%rax"),
+                    Constant::U32(v) => self.integer_constant(i64::from(*v), Type::U32, "%rax"),
+                    Constant::U64(v) => self.integer_constant(*v as i64, Type::U64, "%rax"),
+                    Constant::F32(v) => self.float_constant(f64::from(*v), Type::F32, "%xmm0"),
+                    Constant::F64(v) => self.float_constant(*v, Type::F64, "%xmm0"),
+                    Constant::Bool(v) => self.integer_constant(i64::from(*v), Type::Bool, "%rax"),
+                    Constant::Char(v) => self.integer_constant(i64::from(*v as u32), Type::Char, "%rax"),
+                    Constant::String(v) => {
+                        let label = self.string_constant(v);
+                        self.emit(format!("leaq {}(%rip), %rax", label));
+                    }
+                    // `unit` values are never looked at.
+                    Constant::Unit => return,
+                }
+                self.store(result_type, dest.expect("constants have a result"));
+            }
+            InstructionKind::Copy(a) => {
+                self.copy(self.value_slot(*a), dest.expect("copies have a result"), self.words(result_type));
+            }
+            InstructionKind::Unary(op, a) => {
+                self.load(result_type, self.value_slot(*a));
+                match (op, result_type) {
+                    (UnaryOp::Neg, Type::F32) => {
+                        self.emit("movd %xmm0, %eax");
+                        self.emit("xorl $0x80000000, %eax");
+                        self.emit("movd %eax, %xmm0");
+                    }
+                    (UnaryOp::Neg, Type::F64) => {
+                        self.emit("movq %xmm0, %rax");
+                        self.emit("btcq $63, %rax");
+                        self.emit("movq %rax, %xmm0");
+                    }
+                    (UnaryOp::Neg, ty) => self.emit(format!("{} {}", suffixed("neg", ty), result_register(ty))),
+                    (UnaryOp::Not, Type::Bool) => self.emit("xorl $1, %eax"),
+                    (UnaryOp::Not, ty) => self.emit(format!("{} {}", suffixed("not", ty), result_register(ty))),
+                }
+                self.store(result_type, dest.expect("operators have a result"));
+            }
+            InstructionKind::Binary(op, a, b) => {
+                let (left, right) = (function.type_of(*a), function.type_of(*b));
+                self.emit(format!("{} {}(%rbp), {}", move_for(right), self.value_slot(*b), scratch_register(right)));
+                self.load(left, self.value_slot(*a));
+                self.binary(*op, left, location);
+                self.store(result_type, dest.expect("operators have a result"));
+            }
+            InstructionKind::Call(callee, args) => self.call(function, *callee, args, dest),
+            InstructionKind::Phi(_) => unreachable!("phis are copied from their incoming slots"),
+            InstructionKind::Struct(fields) => {
+                let dest = dest.expect("structs have a result");
+                let mut offset = 0;
+                for field in fields {
+                    let words = self.words(function.type_of(*field));
+                    self.copy(self.value_slot(*field), dest + 8 * offset, words);
+                    offset += words;
+                }
+            }
+            InstructionKind::Variant(tag, fields) => {
+                let dest = dest.expect("variants have a result");
+                let mut offset = 1;
+                for field in fields {
+                    let words = self.words(function.type_of(*field));
+                    self.copy(self.value_slot(*field), dest + 8 * offset, words);
+                    offset += words;
+                }
+                self.emit(format!("movq ${}, {}(%rbp)", tag, dest));
+            }
+            InstructionKind::Field(a, index) => {
+                let (ty, offset) = self.struct_field(function.type_of(*a), *index);
+                self.copy(self.value_slot(*a) + 8 * offset, dest.expect("fields have a result"), self.words(ty));
+            }
+            InstructionKind::Tag(a) => {
+                self.emit(format!("movq {}(%rbp), %rax", self.value_slot(*a)));
+                self.store(Type::U32, dest.expect("tags have a result"));
+            }
+            InstructionKind::Payload(a, tag, index) => {
+                let (ty, offset) = self.variant_field(function.type_of(*a), *tag, *index);
+                self.copy(self.value_slot(*a) + 8 * offset, dest.expect("payloads have a result"), self.words(ty));
+            }
+        }
+    }
+
+    /// Loads an integer constant of type `ty` into `register` (a 64-bit
+    /// register name).
+    fn integer_constant(&mut self, value: i64, ty: Type, register: &str) {
+        if is_wide(ty) {
+            self.emit(format!("movabsq ${}, {}", value, register));
+        } else {
+            let value = if ty == Type::I32 { i64::from(value as i32) } else { i64::from(value as u32) };
+            self.emit(format!("movl ${}, {}", value, register_32_of(register)));
+        }
+    }
+
+    fn float_constant(&mut self, value: f64, ty: Type, register: &str) {
+        let label = match ty {
+            Type::F32 => self.constant(format!(".long {:#x}", (value as f32).to_bits())),
+            _ => self.constant(format!(".quad {:#x}", value.to_bits())),
+        };
+        self.emit(format!("{} {}(%rip), {}", move_for(ty), label, register));
+    }
+
+    /// Calls function `callee`, leaving its result in `dest`. Arguments are
+    /// already in their slots, so only registers and the stack are loaded.
+    fn call(&mut self, function: &ir::Function, callee: u32, args: &[ir::Value], dest: Option<Slot>) {
+        let target = &self.module.functions[callee as usize];
+        let params: Vec<Type> = args.iter().map(|arg| function.type_of(*arg)).collect();
+        let hidden_pointer = is_compound(target.ret);
+        let locations = argument_locations(&params, hidden_pointer);
+        let stack_words = locations.iter().filter(|l| matches!(l, ArgumentLocation::Stack(_))).count() as i32;
+        self.state.outgoing = self.state.outgoing.max(8 * stack_words);
+
+        for ((ty, arg), location) in params.iter().zip(args).zip(&locations) {
+            let slot = self.value_slot(*arg);
+            match *location {
+                ArgumentLocation::Integer(register) if is_compound(*ty) => {
+                    self.emit(format!("leaq {}(%rbp), {}", slot, INTEGER_ARGS[register]));
+                }
+                ArgumentLocation::Integer(register) => {
+                    let register = if is_wide(*ty) { INTEGER_ARGS[register].to_string() } else { register_32(register) };
+                    self.emit(format!("{} {}(%rbp), {}", move_for(*ty), slot, register));
+                }
+                ArgumentLocation::Float(register) => {
+                    self.emit(format!("{} {}(%rbp), %xmm{}", move_for(*ty), slot, register));
+                }
+                ArgumentLocation::Stack(word) => {
+                    if is_compound(*ty) {
+                        self.emit(format!("leaq {}(%rbp), %rax", slot));
+                    } else {
+                        self.emit(format!("movq {}(%rbp), %rax", slot));
+                    }
+                    self.emit(format!("movq %rax, {}(%rsp)", 8 * word));
+                }
+            }
+        }
+        if hidden_pointer {
+            let dest = dest.expect("compound values have a destination");
+            self.emit(format!("leaq {}(%rbp), %rdi", dest));
+        }
+        self.emit(format!("call {}", target.symbol()));
+        if let Some(dest) = dest
+            && !hidden_pointer
+            && target.ret != Type::Unit
+        {
+            self.store(target.ret, dest);
+        }
+    }
+
+    /// Applies a binary operator to the left operand in `%rax`/`%xmm0` and
+    /// the right in `%rcx`/`%xmm1`, of type `ty`, leaving the result in
+    /// `%rax` or `%xmm0`.
+    fn binary(&mut self, op: BinaryOp, ty: Type, location: &str) {
+        if ty.is_float() {
+            self.float_binary(op, ty);
+            return;
+        }
+        if ty == Type::String {
+            self.emit("movq %rax, %rdi");
+            self.emit("movq %rcx, %rsi");
+            self.emit("call c4_strings_equal");
+            if op == BinaryOp::Ne {
+                self.emit("xorl $1, %eax");
+            }
+            return;
+        }
+        let (result, right) = (result_register(ty), scratch_register(ty));
+        match op {
+            BinaryOp::Add => self.emit(format!("{} {}, {}", suffixed("add", ty), right, result)),
+            BinaryOp::Sub => self.emit(format!("{} {}, {}", suffixed("sub", ty), right, result)),
+            BinaryOp::Mul => self.emit(format!("{} {}, {}", suffixed("imul", ty), right, result)),
+            BinaryOp::And => self.emit(format!("{} {}, {}", suffixed("and", ty), right, result)),
+            BinaryOp::Xor => self.emit(format!("{} {}, {}", suffixed("xor", ty), right, result)),
+            BinaryOp::Shl => self.emit(format!("{} %cl, {}", suffixed("shl", ty), result)),
+            BinaryOp::Shr => {
+                let shift = if ty.is_signed() { "sar" } else { "shr" };
+                self.emit(format!("{} %cl, {}", suffixed(shift, ty), result));
+            }
+            BinaryOp::Div | BinaryOp::Rem => {
+                let fail = self.failure(codes::DIVISION_BY_ZERO, "attempt to divide by zero", location);
+                self.emit(format!("{} {}, {}", suffixed("test", ty), right, right));
+                self.emit(format!("je {}", fail));
+                let end = self.label();
+                if ty.is_signed() {
+                    // Dividing the minimum value by -1 overflows, which the
+                    // CPU traps on; the result wraps instead.
+                    let divide = self.label();
+                    self.emit(format!("{} $-1, {}", suffixed("cmp", ty), right));
+                    self.emit(format!("jne {}", divide));
+                    if op == BinaryOp::Div {
+                        self.emit(format!("{} {}", suffixed("neg", ty), result));
+                    } else {
+                        self.emit("xorl %eax, %eax");
+                    }
+                    self.emit(format!("jmp {}", end));
+                    self.place_label(&divide);
+                    self.emit(if is_wide(ty) { "cqto" } else { "cltd" });
+                    self.emit(format!("{} {}", suffixed("idiv", ty), right));
+                } else {
+                    self.emit("xorl %edx, %edx");
+                    self.emit(format!("{} {}", suffixed("div", ty), right));
+                }
+                if op == BinaryOp::Rem {
+                    self.emit(if is_wide(ty) { "movq %rdx, %rax" } else { "movl %edx, %eax" });
+                }
+                self.place_label(&end);
+            }
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+                self.emit(format!("{} {}, {}", suffixed("cmp", ty), right, result));
+                self.emit(format!("{} %al", set_for(op, ty.is_signed())));
+                self.emit("movzbl %al, %eax");
+            }
+        }
+    }
+
+    fn float_binary(&mut self, op: BinaryOp, ty: Type) {
+        let suffix = if ty == Type::F32 { "ss" } else { "sd" };
+        let compare = if ty == Type::F32 { "ucomiss" } else { "ucomisd" };
+        match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => {
+                let name = match op {
+                    BinaryOp::Add => "add",
+                    BinaryOp::Sub => "sub",
+                    BinaryOp::Mul => "mul",
+                    _ => "div",
+                };
+                self.emit(format!("{}{} %xmm1, %xmm0", name, suffix));
+            }
+            BinaryOp::Rem => self.emit(format!("call {}@PLT", if ty == Type::F32 { "fmodf" } else { "fmod" })),
+            BinaryOp::Eq | BinaryOp::Ne => {
+                // Unordered operands, where one is NaN, are never equal.
+                self.emit(format!("{} %xmm1, %xmm0", compare));
+                if op == BinaryOp::Eq {
+                    self.emit("sete %al");
+                    self.emit("setnp %cl");
+                    self.emit("andb %cl, %al");
+                } else {
+                    self.emit("setne %al");
+                    self.emit("setp %cl");
+                    self.emit("orb %cl, %al");
+                }
+                self.emit("movzbl %al, %eax");
+            }
+            _ => {
+                // `seta` and `setae` are false for unordered operands, so
+                // `<` and `<=` swap the operands to use them too.
+                let (left, right, set) = match op {
+                    BinaryOp::Gt => ("%xmm0", "%xmm1", "seta"),
+                    BinaryOp::Ge => ("%xmm0", "%xmm1", "setae"),
+                    BinaryOp::Lt => ("%xmm1", "%xmm0", "seta"),
+                    _ => ("%xmm1", "%xmm0", "setae"),
+                };
+                self.emit(format!("{} {}, {}", compare, right, left));
+                self.emit(format!("{} %al", set));
+                self.emit("movzbl %al, %eax");
+            }
+        }
+    }
+}
+
+/// The `set` instruction for an integer comparison, after a `cmp`.
+fn set_for(op: BinaryOp, signed: bool) -> &'static str {
+    match op {
+        BinaryOp::Eq => "sete",
+        BinaryOp::Ne => "setne",
+        BinaryOp::Lt if signed => "setl",
+        BinaryOp::Le if signed => "setle",
+        BinaryOp::Gt if signed => "setg",
+        BinaryOp::Ge if signed => "setge",
+        BinaryOp::Lt => "setb",
+        BinaryOp::Le => "setbe",
+        BinaryOp::Gt => "seta",
+        _ => "setae",
+    }
+}
+
+/// The register a scalar result is left in.
+fn result_register(ty: Type) -> &'static str {
+    match ty {
+        _ if ty.is_float() => "%xmm0",
+        _ if is_wide(ty) => "%rax",
+        _ => "%eax",
+    }
+}
+
+/// The register the right operand of a binary operator is put in.
+fn scratch_register(ty: Type) -> &'static str {
+    match ty {
+        _ if ty.is_float() => "%xmm1",
+        _ if is_wide(ty) => "%rcx",
+        _ => "%ecx",
+    }
+}
+
+/// The instruction that moves a scalar of type `ty` between a register
+/// and memory.
+fn move_for(ty: Type) -> &'static str {
+    match ty {
+        Type::F32 => "movss",
+        Type::F64 => "movsd",
+        _ if is_wide(ty) => "movq",
+        _ => "movl",
+    }
+}
+
+/// An integer instruction with the operand-size suffix for `ty`.
+fn suffixed(instruction: &str, ty: Type) -> String {
+    format!("{}{}", instruction, if is_wide(ty) { "q" } else { "l" })
+}
+
+/// The 32-bit name of an integer argument register.
+fn register_32(index: usize) -> String {
+    register_32_of(INTEGER_ARGS[index])
+}
+
+fn register_32_of(register: &str) -> String {
+    match register {
+        "%rax" => "%eax".to_string(),
+        "%rcx" => "%ecx".to_string(),
+        "%rdx" => "%edx".to_string(),
+        "%rdi" => "%edi".to_string(),
+        "%rsi" => "%esi".to_string(),
+        other => format!("{}d", other),
+    }
+}
+
+/// A string in the assembler's quoted syntax, with the same bytes.
+fn assembler_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in value.bytes() {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b' '..=b'~' => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "\\{:03o}", byte);
+            }
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing::{self, programs};
+    use std::process::Command;
+
+    /// Translates `source` to assembly, has `cc` assemble and link it and
+    /// runs it. Returns the exit code and what the program wrote to stderr.
+    fn run(source: &str) -> (i32, String) {
+        let root = testing::root_file(source);
+        let assembly = root.path().with_extension("s");
+        let executable = assembly.with_extension("");
+        std::fs::write(&assembly, generate(&testing::lower(source), "main.c4l")).expect("the temp directory is writable");
+        let built = Command::new("cc")
+            .arg("-o")
+            .arg(&executable)
+            .arg(&assembly)
+            .arg("-lm")
+            .output()
+            .expect("the tests need `cc` on the `PATH`");
+        assert!(built.status.success(), "`cc` rejected the assembly: {}", String::from_utf8_lossy(&built.stderr));
+        let ran = Command::new(&executable).output().expect("the program should start");
+        (ran.status.code().expect("the program should exit"), String::from_utf8_lossy(&ran.stderr).into_owned())
+    }
+
+    #[test]
+    fn integer_arithmetic_wraps() {
+        let (source, code) = programs::WRAPPING;
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn structs_are_passed_and_returned_through_pointers() {
+        let (source, code) = programs::STRUCTS;
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn enums_are_matched_by_variant_and_payload() {
+        let (source, code) = programs::ENUMS;
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn num_functions_get_an_instance_per_type() {
+        let (source, code) = programs::NUM_INSTANCES;
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn arguments_past_the_registers_go_on_the_stack() {
+        let (source, code) = programs::MANY_ARGUMENTS;
+        let module = testing::lower(source);
+        let mixed = module.functions.iter().find(|f| f.name == "mixed").expect("`mixed` is in the program");
+        let params: Vec<Type> = mixed.params.iter().map(|param| mixed.type_of(*param)).collect();
+        let on_stack = argument_locations(&params, false).iter().filter(|l| matches!(l, ArgumentLocation::Stack(_))).count();
+        assert_eq!(on_stack, 2);
+        assert_eq!(run(source).0, code);
+    }
+
+    #[test]
+    fn dividing_by_zero_exits_with_101() {
+        let (source, code) = programs::DIVIDE_BY_ZERO;
+        let (status, stderr) = run(source);
+        assert_eq!(status, code);
+        assert!(stderr.starts_with("error[R0001]: attempt to divide by zero\n  = note: at "), "{}", stderr);
+        assert!(stderr.trim_end().ends_with("main.c4l:3:21"), "{}", stderr);
+    }
+
+    #[test]
+    fn strings_compare_past_nul_bytes() {
+        let (source, code) = programs::STRING_NULS;
+        assert_eq!(run(source).0, code);
+    }
+}